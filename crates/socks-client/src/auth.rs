@@ -1,7 +1,17 @@
 use async_trait::async_trait;
 use bytes::{BufMut, BytesMut};
+use socks_rs_common::token::{self, BEARER_TOKEN};
 use socks_rs_common::{AuthMethod, Error, Result, Version};
+use std::future::Future;
+use std::time::{Duration, SystemTime};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::Mutex;
+
+/// Maximum length of the username and password of RFC 1929.
+const MAX_CREDENTIAL_LEN: usize = 255;
+/// Username sent along tokens short enough to fit in the password.
+const TOKEN_USERNAME: &str = "token";
+const DEFAULT_REFRESH_BEFORE: Duration = Duration::from_secs(30);
 
 #[async_trait]
 pub trait AuthProvider {
@@ -76,34 +86,381 @@ impl<'a> AuthProvider for BasicAuthProvider<'a> {
         match method {
             AuthMethod::None => Ok(()),
             AuthMethod::UsernamePassword => {
+                write_credentials(io, self.username.as_bytes(), self.password.as_bytes()).await
+            }
+            _ => Err(Error::AuthMethodNotSupported(method.into())),
+        }
+    }
+}
+
+/// A bearer token, such as a JWT, and when it expires.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Token {
+    pub value: String,
+    pub expires_at: Option<SystemTime>,
+}
+
+impl Token {
+    pub fn new<T: Into<String>>(value: T, expires_at: Option<SystemTime>) -> Token {
+        Token {
+            value: value.into(),
+            expires_at,
+        }
+    }
+
+    fn is_fresh(&self, refresh_before: Duration) -> bool {
+        match self.expires_at {
+            Some(expires_at) => SystemTime::now() + refresh_before < expires_at,
+            None => true,
+        }
+    }
+}
+
+/// Authenticates with a bearer token, such as a JWT.
+///
+/// Tokens are obtained from `provider`, which is called again when the current
+/// one is about to expire. They are sent with the bearer token method of
+/// `socks_rs_common::token`, up to 16 KiB. Servers not supporting it get them
+/// in the password of the username/password method, which only fits tokens of
+/// up to 255 bytes.
+pub struct TokenAuthProvider<F> {
+    provider: F,
+    refresh_before: Duration,
+    token: Mutex<Option<Token>>,
+}
+
+impl<F, Fut> TokenAuthProvider<F>
+where
+    F: Fn() -> Fut + Send + Sync,
+    Fut: Future<Output = Result<Token>> + Send,
+{
+    /// Refreshes tokens 30 seconds before they expire.
+    pub fn new(provider: F) -> TokenAuthProvider<F> {
+        TokenAuthProvider {
+            provider,
+            refresh_before: DEFAULT_REFRESH_BEFORE,
+            token: Mutex::new(None),
+        }
+    }
+
+    /// How long before its expiry a token is replaced.
+    pub fn refresh_before(mut self, refresh_before: Duration) -> Self {
+        self.refresh_before = refresh_before;
+        self
+    }
+
+    /// The current token, refreshed if it is about to expire.
+    pub async fn token(&self) -> Result<Token> {
+        let mut token = self.token.lock().await;
+        match &*token {
+            Some(token) if token.is_fresh(self.refresh_before) => Ok(token.clone()),
+            _ => {
+                let fresh = (self.provider)().await?;
+                *token = Some(fresh.clone());
+                Ok(fresh)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<F, Fut> AuthProvider for TokenAuthProvider<F>
+where
+    F: Fn() -> Fut + Send + Sync,
+    Fut: Future<Output = Result<Token>> + Send,
+{
+    async fn methods(&self) -> Vec<AuthMethod> {
+        vec![BEARER_TOKEN, AuthMethod::UsernamePassword]
+    }
+
+    async fn authenticate<IO: AsyncRead + AsyncWrite + Send + Unpin>(
+        &self,
+        version: Version,
+        method: AuthMethod,
+        io: &mut IO,
+    ) -> Result<()> {
+        if version != Version::V5 {
+            return Err(Error::VersionNotSupported(version.into()));
+        }
+        let token = self.token().await?;
+        let token = token.value.as_bytes();
+        match method {
+            BEARER_TOKEN => {
                 let (mut inbound, mut outbound) = tokio::io::split(io);
-                let username_bytes = self.username.as_bytes();
-                let ulen = username_bytes.len();
-                let password_bytes = self.password.as_bytes();
-                let plen = password_bytes.len();
-                if ulen > 255 {
-                    return Err(Error::AuthFailed("username is too long".to_owned()));
-                }
-                if plen > 255 {
-                    return Err(Error::AuthFailed("password is too long".to_owned()));
-                }
-                let mut buf = BytesMut::with_capacity(1 + 1 + ulen + 1 + plen);
-                buf.put_u8(0x01);
-                buf.put_u8(ulen as u8);
-                buf.put_slice(username_bytes);
-                buf.put_u8(plen as u8);
-                buf.put_slice(password_bytes);
-                outbound.write_all(&buf).await?;
-                // version
-                let _ = inbound.read_u8().await?;
-                let res = inbound.read_u8().await?;
-                if res == 0x00 {
-                    Ok(())
-                } else {
-                    return Err(Error::AuthFailed("incorrect credential".to_owned()));
-                }
+                token::write_token(&mut outbound, token).await?;
+                token::read_status(&mut inbound).await
+            }
+            AuthMethod::UsernamePassword if token.len() > MAX_CREDENTIAL_LEN => Err(
+                Error::AuthFailed("token doesn't fit in the password".to_owned()),
+            ),
+            AuthMethod::UsernamePassword => {
+                write_credentials(io, TOKEN_USERNAME.as_bytes(), token).await
             }
             _ => Err(Error::AuthMethodNotSupported(method.into())),
         }
     }
 }
+
+/// Sends RFC 1929 credentials and reads the reply.
+async fn write_credentials<IO: AsyncRead + AsyncWrite + Send + Unpin>(
+    io: &mut IO,
+    username: &[u8],
+    password: &[u8],
+) -> Result<()> {
+    let (mut inbound, mut outbound) = tokio::io::split(io);
+    let ulen = username.len();
+    let plen = password.len();
+    if ulen > MAX_CREDENTIAL_LEN {
+        return Err(Error::AuthFailed("username is too long".to_owned()));
+    }
+    if plen > MAX_CREDENTIAL_LEN {
+        return Err(Error::AuthFailed("password is too long".to_owned()));
+    }
+    let mut buf = BytesMut::with_capacity(1 + 1 + ulen + 1 + plen);
+    buf.put_u8(0x01);
+    buf.put_u8(ulen as u8);
+    buf.put_slice(username);
+    buf.put_u8(plen as u8);
+    buf.put_slice(password);
+    outbound.write_all(&buf).await?;
+    // version
+    let _ = inbound.read_u8().await?;
+    let res = inbound.read_u8().await?;
+    if res == 0x00 {
+        Ok(())
+    } else {
+        Err(Error::AuthFailed("incorrect credential".to_owned()))
+    }
+}
+
+#[cfg(feature = "scram")]
+pub mod scram {
+    use super::{write_credentials, AuthProvider};
+    use async_trait::async_trait;
+    use socks_rs_common::scram::{self, SCRAM_SHA_256};
+    use socks_rs_common::{AuthMethod, Error, Result, Version};
+    use tokio::io::{self, AsyncRead, AsyncWrite};
+
+    /// Most PBKDF2 iterations a server may ask for, so that it can't make the
+    /// client spin for long.
+    const MAX_ITERATIONS: u32 = 100_000;
+
+    /// Authenticates with SCRAM-SHA-256 (see `socks_rs_common::scram`), which
+    /// proves knowledge of the password without sending it and checks that the
+    /// server knows the credentials as well. Servers asking for more than
+    /// 100000 iterations are rejected.
+    #[derive(Debug)]
+    pub struct ScramAuthProvider {
+        username: String,
+        password: String,
+        password_fallback: bool,
+    }
+
+    impl ScramAuthProvider {
+        pub fn new(username: &str, password: &str) -> ScramAuthProvider {
+            ScramAuthProvider {
+                username: username.to_owned(),
+                password: password.to_owned(),
+                password_fallback: false,
+            }
+        }
+
+        /// Whether to send the password in clear to servers not supporting
+        /// SCRAM-SHA-256, disabled by default.
+        pub fn password_fallback(mut self, enabled: bool) -> Self {
+            self.password_fallback = enabled;
+            self
+        }
+
+        async fn exchange<IO>(&self, io: &mut IO) -> Result<()>
+        where
+            IO: AsyncRead + AsyncWrite + Send + Unpin,
+        {
+            let (mut inbound, mut outbound) = io::split(io);
+            let client_nonce = scram::nonce();
+            let client_first_bare = format!(
+                "n={},r={}",
+                scram::escape_username(&self.username),
+                client_nonce
+            );
+            let client_first = format!("{}{}", scram::GS2_HEADER, client_first_bare);
+            scram::write_message(&mut outbound, &client_first).await?;
+
+            let server_first = scram::read_message(&mut inbound).await?;
+            if let Ok(e) = scram::attribute(&server_first, 'e') {
+                return Err(Error::AuthFailed(format!("server error: {}", e)));
+            }
+            let nonce = scram::attribute(&server_first, 'r')?;
+            if !nonce.starts_with(&client_nonce) || nonce.len() == client_nonce.len() {
+                return Err(Error::AuthFailed("SCRAM nonce mismatch".to_owned()));
+            }
+            let salt = scram::decode(scram::attribute(&server_first, 's')?)?;
+            let iterations = scram::attribute(&server_first, 'i')?
+                .parse::<u32>()
+                .ok()
+                .filter(|iterations| (1..=MAX_ITERATIONS).contains(iterations))
+                .ok_or_else(|| Error::AuthFailed("invalid SCRAM iterations".to_owned()))?;
+
+            let salted_password = scram::salted_password(&self.password, &salt, iterations);
+            let client_key = scram::client_key(&salted_password);
+            let stored_key = scram::stored_key(&client_key);
+            let client_final_without_proof = format!("c={},r={}", scram::CHANNEL_BINDING, nonce);
+            let auth_message = format!(
+                "{},{},{}",
+                client_first_bare, server_first, client_final_without_proof
+            );
+            let signature = scram::hmac(&stored_key, auth_message.as_bytes());
+            let proof = scram::xor(&client_key, &signature);
+            let client_final =
+                format!("{},p={}", client_final_without_proof, scram::encode(&proof));
+            scram::write_message(&mut outbound, &client_final).await?;
+
+            let server_final = scram::read_message(&mut inbound).await?;
+            if let Ok(e) = scram::attribute(&server_final, 'e') {
+                return Err(Error::AuthFailed(format!("server error: {}", e)));
+            }
+            let verifier = scram::decode(scram::attribute(&server_final, 'v')?)?;
+            let server_key = scram::server_key(&salted_password);
+            if verifier != scram::hmac(&server_key, auth_message.as_bytes()) {
+                return Err(Error::AuthFailed(
+                    "server signature doesn't match".to_owned(),
+                ));
+            }
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl AuthProvider for ScramAuthProvider {
+        async fn methods(&self) -> Vec<AuthMethod> {
+            if self.password_fallback {
+                vec![SCRAM_SHA_256, AuthMethod::UsernamePassword]
+            } else {
+                vec![SCRAM_SHA_256]
+            }
+        }
+
+        async fn authenticate<IO: AsyncRead + AsyncWrite + Send + Unpin>(
+            &self,
+            version: Version,
+            method: AuthMethod,
+            io: &mut IO,
+        ) -> Result<()> {
+            if version != Version::V5 {
+                return Err(Error::VersionNotSupported(version.into()));
+            }
+            match method {
+                SCRAM_SHA_256 => self.exchange(io).await,
+                AuthMethod::UsernamePassword if self.password_fallback => {
+                    write_credentials(io, self.username.as_bytes(), self.password.as_bytes()).await
+                }
+                _ => Err(Error::AuthMethodNotSupported(method.into())),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::io::duplex;
+
+    fn token_provider(
+        value: &'static str,
+        lifetime: Option<Duration>,
+    ) -> (
+        TokenAuthProvider<impl Fn() -> std::future::Ready<Result<Token>> + Send + Sync>,
+        Arc<AtomicUsize>,
+    ) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let provider = TokenAuthProvider::new(move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+            let expires_at = lifetime.map(|lifetime| SystemTime::now() + lifetime);
+            std::future::ready(Ok(Token::new(value, expires_at)))
+        });
+        (provider, calls)
+    }
+
+    #[tokio::test]
+    async fn refreshes_expiring_tokens() {
+        let (provider, calls) = token_provider("token", Some(Duration::from_secs(3600)));
+        provider.token().await.unwrap();
+        provider.token().await.unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        // Tokens are refreshed 30 seconds before they expire by default.
+        let (provider, calls) = token_provider("token", Some(Duration::from_secs(10)));
+        provider.token().await.unwrap();
+        provider.token().await.unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn sends_long_tokens_with_the_bearer_method() {
+        let value: &'static str = "a".repeat(4096).leak();
+        let (provider, _) = token_provider(value, None);
+        assert_eq!(
+            provider.methods().await,
+            [BEARER_TOKEN, AuthMethod::UsernamePassword]
+        );
+        let (mut client, mut server) = duplex(8192);
+        let server = async move {
+            let token = token::read_token(&mut server).await.unwrap();
+            token::write_status(&mut server, token.len() == 4096)
+                .await
+                .unwrap();
+        };
+        let (res, _) = tokio::join!(
+            provider.authenticate(Version::V5, BEARER_TOKEN, &mut client),
+            server
+        );
+        res.unwrap();
+
+        let err = provider
+            .authenticate(Version::V5, AuthMethod::UsernamePassword, &mut client)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::AuthFailed(_)));
+    }
+
+    #[tokio::test]
+    async fn sends_short_tokens_in_the_password() {
+        let (provider, _) = token_provider("eyJ.e30.sig", None);
+        let (mut client, mut server) = duplex(1024);
+        server.write_all(&[0x01, 0x00]).await.unwrap();
+        provider
+            .authenticate(Version::V5, AuthMethod::UsernamePassword, &mut client)
+            .await
+            .unwrap();
+        let mut buf = [0; 19];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"\x01\x05token\x0beyJ.e30.sig");
+    }
+
+    #[cfg(feature = "scram")]
+    #[tokio::test]
+    async fn rejects_excessive_scram_iterations() {
+        use super::scram::ScramAuthProvider;
+        use socks_rs_common::scram;
+
+        let client = ScramAuthProvider::new("alice", "pencil");
+        let (mut client_io, mut server_io) = duplex(1024);
+        let server = async move {
+            let client_first = scram::read_message(&mut server_io).await.unwrap();
+            let nonce = scram::attribute(&client_first, 'r').unwrap();
+            let server_first = format!("r={}server,s=c2FsdA==,i=100000000", nonce);
+            scram::write_message(&mut server_io, &server_first)
+                .await
+                .unwrap();
+        };
+        let (res, _) = tokio::join!(
+            client.authenticate(Version::V5, scram::SCRAM_SHA_256, &mut client_io),
+            server
+        );
+        let e = res.unwrap_err();
+        assert!(matches!(e, Error::AuthFailed(msg) if msg == "invalid SCRAM iterations"));
+    }
+}
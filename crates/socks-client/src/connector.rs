@@ -1,6 +1,6 @@
 use async_trait::async_trait;
 use socks_rs_common::connector::{
-    tcp_connect, DNSResolver, PlainWrappedTcpStream, WrappedTcpStream,
+    tcp_connect_with_options, ConnectOptions, DNSResolver, PlainWrappedTcpStream, WrappedTcpStream,
 };
 use socks_rs_common::TargetAddr;
 use tokio::io;
@@ -12,20 +12,25 @@ pub trait Connector<T: WrappedTcpStream> {
 
 pub struct PlainConnector<'a, D: DNSResolver> {
     resolver: &'a D,
+    options: ConnectOptions,
 }
 
 impl<'a, D: DNSResolver> PlainConnector<'a, D> {
     pub fn new(resolver: &'a D) -> PlainConnector<'a, D> {
-        PlainConnector { resolver }
+        PlainConnector::with_options(resolver, ConnectOptions::default())
+    }
+
+    pub fn with_options(resolver: &'a D, options: ConnectOptions) -> PlainConnector<'a, D> {
+        PlainConnector { resolver, options }
     }
 }
 
 #[async_trait]
 impl<'a, D: DNSResolver + Send + Sync> Connector<PlainWrappedTcpStream> for PlainConnector<'a, D> {
     async fn connect(&self, addr: &TargetAddr) -> io::Result<PlainWrappedTcpStream> {
-        tcp_connect(addr, self.resolver)
+        tcp_connect_with_options(addr, self.resolver, &self.options)
             .await
-            .map(|x| PlainWrappedTcpStream::new(x))
+            .map(PlainWrappedTcpStream::new)
     }
 }
 
@@ -34,25 +39,38 @@ pub mod tls {
     use crate::connector::Connector;
     use async_trait::async_trait;
     use socks_rs_common::connector::tls::TlsWrappedTcpStream;
-    use socks_rs_common::connector::{tcp_connect, DNSResolver};
+    use socks_rs_common::connector::{tcp_connect_with_options, ConnectOptions, DNSResolver};
     use socks_rs_common::TargetAddr;
     use tokio::io;
 
     pub struct TlsConnector<'a, D: DNSResolver> {
         resolver: &'a D,
         tls: tokio_native_tls::TlsConnector,
+        options: ConnectOptions,
     }
 
     impl<'a, D: DNSResolver> TlsConnector<'a, D> {
         pub fn new(resolver: &'a D, tls: tokio_native_tls::TlsConnector) -> TlsConnector<'a, D> {
-            TlsConnector { resolver, tls }
+            TlsConnector::with_options(resolver, tls, ConnectOptions::default())
+        }
+
+        pub fn with_options(
+            resolver: &'a D,
+            tls: tokio_native_tls::TlsConnector,
+            options: ConnectOptions,
+        ) -> TlsConnector<'a, D> {
+            TlsConnector {
+                resolver,
+                tls,
+                options,
+            }
         }
     }
 
     #[async_trait]
     impl<D: DNSResolver + Sync> Connector<TlsWrappedTcpStream> for TlsConnector<'_, D> {
         async fn connect(&self, addr: &TargetAddr) -> io::Result<TlsWrappedTcpStream> {
-            let stream = tcp_connect(addr, self.resolver).await?;
+            let stream = tcp_connect_with_options(addr, self.resolver, &self.options).await?;
             let res = self
                 .tls
                 .connect(&addr.host(), stream)
@@ -1,12 +1,12 @@
 use crate::auth::{AuthProvider, BasicAuthProvider, PlainAuthProvider};
 use crate::connector::Connector;
+use log::{debug, warn};
 use socks_rs_common::connector::WrappedTcpStream;
 use socks_rs_common::request::{AuthMethodsRequest, Request};
 use socks_rs_common::response::{AuthMethodsResponse, Response, ResponseCode};
 use socks_rs_common::{
     Addr, BasicAuthConfig, Command, Error, ProxyAuthScheme, Result, TargetAddr, Version,
 };
-use log::{debug, warn};
 use std::time::Instant;
 use tokio::io::{self, AsyncRead, AsyncWrite};
 
@@ -50,7 +50,7 @@ impl ProxyScheme {
 pub struct SocksClient;
 
 impl SocksClient {
-    pub async fn connect<T: WrappedTcpStream, C: Connector<T>, AU: AuthProvider>(
+    pub async fn connect<T: WrappedTcpStream, C: Connector<T>>(
         scheme: &ProxyScheme,
         target: TargetAddr,
         command: Command,
@@ -64,7 +64,7 @@ impl SocksClient {
                     target,
                     command,
                     connector,
-                    PlainAuthProvider::new(),
+                    &PlainAuthProvider::new(),
                 )
                 .await
             }
@@ -74,20 +74,37 @@ impl SocksClient {
                     target,
                     command,
                     connector,
-                    BasicAuthProvider::new(cfg.username(), cfg.password()),
+                    &BasicAuthProvider::new(cfg.username(), cfg.password()),
                 )
                 .await
             }
         }
     }
 
+    /// Connect the client to target address via proxy scheme, authenticating
+    /// with `auth_provider` instead of the credentials of the scheme
+    pub async fn connect_with_auth_provider<
+        T: WrappedTcpStream,
+        C: Connector<T>,
+        AU: AuthProvider + Sync,
+    >(
+        scheme: &ProxyScheme,
+        target: TargetAddr,
+        command: Command,
+        connector: C,
+        auth_provider: &AU,
+    ) -> Result<(Response, T)> {
+        SocksClient::connect_inner(scheme, Addr::new(target), command, connector, auth_provider)
+            .await
+    }
+
     /// Connect the client to target address via proxy scheme, using a specific connector
-    async fn connect_inner<T: WrappedTcpStream, C: Connector<T>, AU: AuthProvider>(
+    async fn connect_inner<T: WrappedTcpStream, C: Connector<T>, AU: AuthProvider + Sync>(
         scheme: &ProxyScheme,
         target: Addr,
         command: Command,
         connector: C,
-        auth_provider: AU,
+        auth_provider: &AU,
     ) -> Result<(Response, T)> {
         debug!("Connecting to proxy...");
         let start = Instant::now();
@@ -112,7 +129,7 @@ impl SocksClient {
             command,
             scheme.version,
             &mut connection,
-            &auth_provider,
+            auth_provider,
         )
         .await?;
         debug!("Successfully handshake with proxy");
@@ -11,9 +11,9 @@ pub enum AddrType {
     Ipv6,
 }
 
-impl Into<u8> for AddrType {
-    fn into(self) -> u8 {
-        match self {
+impl From<AddrType> for u8 {
+    fn from(value: AddrType) -> Self {
+        match value {
             AddrType::Ipv4 => 0x01,
             AddrType::Domain => 0x03,
             AddrType::Ipv6 => 0x04,
@@ -82,7 +82,7 @@ impl fmt::Display for TargetAddr {
                 write!(f, "({}:{})", addr, port)
             }
             TargetAddr::Addr(addr) => {
-                write!(f, "({})", addr.to_string())
+                write!(f, "({})", addr)
             }
         }
     }
@@ -116,7 +116,7 @@ impl Addr {
         match &self.0 {
             TargetAddr::Addr(SocketAddr::V4(_)) => 1 + 4 + 2,
             TargetAddr::Addr(SocketAddr::V6(_)) => 1 + 16 + 2,
-            TargetAddr::Host(domain, _) => 1 + 1 + domain.as_bytes().len() + 2,
+            TargetAddr::Host(domain, _) => 1 + 1 + domain.len() + 2,
         }
     }
 
@@ -136,7 +136,7 @@ impl Addr {
                 buf.put_u8(AddrType::Domain.into());
                 let bytes = domain.as_bytes();
                 if bytes.len() > 255 {
-                    return Err(Error::DomainTooLong.into());
+                    return Err(Error::DomainTooLong);
                 }
                 buf.put_u8(bytes.len() as u8);
                 buf.put_slice(bytes);
@@ -172,9 +172,9 @@ impl Addr {
             }
             AddrType::Domain => {
                 let len = reader.read_u8().await?;
-                let mut str = Vec::with_capacity(len as usize);
+                let mut str = vec![0; len as usize];
                 reader.read_exact(&mut str).await?;
-                let domain = String::from_utf8(str).map_err(|e| Error::InvalidDomain(e))?;
+                let domain = String::from_utf8(str).map_err(Error::InvalidDomain)?;
                 let port = reader.read_u16().await?;
                 TargetAddr::Host(domain, port)
             }
@@ -182,3 +182,51 @@ impl Addr {
         Ok(Addr(addr))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn roundtrip(addr: TargetAddr) -> TargetAddr {
+        let addr = Addr::new(addr);
+        let mut buf = Vec::new();
+        addr.write_to_buf(&mut buf).unwrap();
+        assert_eq!(buf.len(), addr.serialize_len());
+        Addr::read_from(&mut &buf[..]).await.unwrap().0
+    }
+
+    #[tokio::test]
+    async fn roundtrips_addresses() {
+        for addr in [
+            TargetAddr::Addr("192.0.2.1:443".parse().unwrap()),
+            TargetAddr::Addr("[2001:db8::1]:8080".parse().unwrap()),
+            TargetAddr::Host("example.com".to_owned(), 80),
+        ] {
+            assert_eq!(roundtrip(addr.clone()).await, addr);
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_the_whole_domain() {
+        let buf = [0x03, 3, b'f', b'o', b'o', 0x01, 0xbb, 0xff];
+        let mut reader = &buf[..];
+        let addr = Addr::read_from(&mut reader).await.unwrap();
+        assert_eq!(addr.0, TargetAddr::Host("foo".to_owned(), 443));
+        assert_eq!(reader, &[0xff]);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_addresses() {
+        let res = Addr::read_from(&mut &[0x02, 0, 0][..]).await;
+        assert!(matches!(res, Err(Error::AddrTypeNotSupported(0x02))));
+        let res = Addr::read_from(&mut &[0x03, 2, 0xff, 0xfe, 0, 80][..]).await;
+        assert!(matches!(res, Err(Error::InvalidDomain(_))));
+        let res = Addr::read_from(&mut &[0x01, 127, 0][..]).await;
+        assert!(matches!(res, Err(Error::IoError(_))));
+        let long = Addr::new(TargetAddr::Host("a".repeat(256), 80));
+        assert!(matches!(
+            long.write_to_buf(&mut Vec::new()),
+            Err(Error::DomainTooLong)
+        ));
+    }
+}
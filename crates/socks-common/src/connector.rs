@@ -1,20 +1,37 @@
 use crate::TargetAddr;
 use async_trait::async_trait;
+use futures::stream::{FuturesUnordered, StreamExt};
+use socket2::{Domain, Protocol, Socket, TcpKeepalive, Type};
 use std::io::IoSlice;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
 use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
-use tokio::net::TcpStream;
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::time;
 
 #[async_trait]
 pub trait DNSResolver {
     async fn resolve(&self, addr: &TargetAddr) -> io::Result<Vec<SocketAddr>>;
+
+    /// Resolves `addr` along with how long the answer may be cached, when known.
+    async fn resolve_with_ttl(
+        &self,
+        addr: &TargetAddr,
+    ) -> io::Result<(Vec<SocketAddr>, Option<Duration>)> {
+        Ok((self.resolve(addr).await?, None))
+    }
 }
 
 pub trait WrappedTcpStream: AsyncRead + AsyncWrite + Unpin + Send + Sync {
     fn get_stream_ref(&self) -> &TcpStream;
     fn get_stream_mut_ref(&mut self) -> &mut TcpStream;
+
+    /// The DER encoded certificate the peer authenticated with, if any.
+    fn peer_certificate(&self) -> Option<&[u8]> {
+        None
+    }
 }
 
 macro_rules! async_read_proxy_impl {
@@ -65,6 +82,10 @@ impl PlainWrappedTcpStream {
     pub fn new(inner: TcpStream) -> PlainWrappedTcpStream {
         PlainWrappedTcpStream { inner }
     }
+
+    pub fn into_inner(self) -> TcpStream {
+        self.inner
+    }
 }
 
 impl AsyncRead for PlainWrappedTcpStream {
@@ -85,23 +106,368 @@ impl WrappedTcpStream for PlainWrappedTcpStream {
     }
 }
 
+/// Delay between two connection attempts, as recommended by RFC 8305.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Options applied to outbound TCP sockets.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ConnectOptions {
+    /// Deadline for establishing the connection, across all resolved addresses.
+    pub connect_timeout: Option<Duration>,
+    /// Local address to bind before connecting. Only remote addresses of the
+    /// same family are tried. On Linux, the local port is only picked at
+    /// connect time (`IP_BIND_ADDRESS_NO_PORT`), so that it can be reused
+    /// towards other destinations.
+    pub bind_addr: Option<IpAddr>,
+    /// Interface to bind with `SO_BINDTODEVICE` (Linux only).
+    pub bind_device: Option<String>,
+    /// Firewall mark set with `SO_MARK` for policy routing (Linux only).
+    pub fwmark: Option<u32>,
+    /// TCP keepalive parameters, keepalive is left disabled when unset.
+    pub keepalive: Option<KeepaliveOptions>,
+    /// Value of `TCP_NODELAY`, the system default is kept when unset.
+    pub nodelay: Option<bool>,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct KeepaliveOptions {
+    /// Idle time before the first keepalive probe is sent.
+    pub time: Option<Duration>,
+    /// Interval between two keepalive probes.
+    pub interval: Option<Duration>,
+    /// Number of unanswered probes before the connection is dropped.
+    pub retries: Option<u32>,
+}
+
+impl ConnectOptions {
+    pub fn new() -> ConnectOptions {
+        ConnectOptions::default()
+    }
+
+    fn socket(&self, addr: SocketAddr) -> io::Result<TcpSocket> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_nonblocking(true)?;
+        if let Some(device) = &self.bind_device {
+            bind_device(&socket, device)?;
+        }
+        if let Some(mark) = self.fwmark {
+            set_mark(&socket, mark)?;
+        }
+        if let Some(keepalive) = &self.keepalive {
+            socket.set_tcp_keepalive(&keepalive.to_socket2())?;
+        }
+        if let Some(nodelay) = self.nodelay {
+            socket.set_tcp_nodelay(nodelay)?;
+        }
+        if let Some(ip) = self.bind_addr {
+            // The port is picked at connect time, so that connections to
+            // different destinations can share it.
+            bind_address_no_port(&socket)?;
+            socket.bind(&SocketAddr::new(ip, 0).into())?;
+        }
+        Ok(TcpSocket::from_std_stream(socket.into()))
+    }
+
+    async fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        self.socket(addr)?.connect(addr).await
+    }
+}
+
+impl KeepaliveOptions {
+    fn to_socket2(&self) -> TcpKeepalive {
+        let mut keepalive = TcpKeepalive::new();
+        if let Some(time) = self.time {
+            keepalive = keepalive.with_time(time);
+        }
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "macos",
+            target_os = "ios",
+            target_os = "windows"
+        ))]
+        if let Some(interval) = self.interval {
+            keepalive = keepalive.with_interval(interval);
+        }
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "macos",
+            target_os = "ios"
+        ))]
+        if let Some(retries) = self.retries {
+            keepalive = keepalive.with_retries(retries);
+        }
+        keepalive
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_device(socket: &Socket, device: &str) -> io::Result<()> {
+    socket.bind_device(Some(device.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn bind_device(_socket: &Socket, _device: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_BINDTODEVICE is not supported on this platform",
+    ))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_mark(socket: &Socket, mark: u32) -> io::Result<()> {
+    socket.set_mark(mark)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_mark(_socket: &Socket, _mark: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_MARK is not supported on this platform",
+    ))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_address_no_port(socket: &Socket) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let enabled: libc::c_int = 1;
+    // SAFETY: the option value is a valid c_int of the given size.
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_BIND_ADDRESS_NO_PORT,
+            &enabled as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn bind_address_no_port(_socket: &Socket) -> io::Result<()> {
+    Ok(())
+}
+
 pub async fn tcp_connect<D: DNSResolver>(addr: &TargetAddr, resolver: &D) -> io::Result<TcpStream> {
+    tcp_connect_with_options(addr, resolver, &ConnectOptions::default()).await
+}
+
+pub async fn tcp_connect_with_options<D: DNSResolver>(
+    addr: &TargetAddr,
+    resolver: &D,
+    options: &ConnectOptions,
+) -> io::Result<TcpStream> {
     let remote_addr = resolver.resolve(addr).await?;
-    let mut err: io::Result<TcpStream> = Err(io::Error::new(
+    connect_happy_eyeballs(remote_addr, options).await
+}
+
+/// Connects to the first reachable address using Happy Eyeballs (RFC 8305).
+///
+/// Attempts alternate between address families and are started
+/// `CONNECTION_ATTEMPT_DELAY` apart, or immediately once the previous one fails.
+/// The first successful attempt wins and the others are cancelled.
+pub async fn connect_happy_eyeballs(
+    mut addrs: Vec<SocketAddr>,
+    options: &ConnectOptions,
+) -> io::Result<TcpStream> {
+    if let Some(bind_addr) = options.bind_addr {
+        addrs.retain(|addr| addr.is_ipv6() == bind_addr.is_ipv6());
+    }
+    match options.connect_timeout {
+        Some(timeout) => time::timeout(timeout, race(addrs, options))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connection timed out"))?,
+        None => race(addrs, options).await,
+    }
+}
+
+async fn race(addrs: Vec<SocketAddr>, options: &ConnectOptions) -> io::Result<TcpStream> {
+    let mut pending = interleave_families(addrs).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut err = io::Error::new(
         io::ErrorKind::AddrNotAvailable,
         "Couldn't resolve addr: result is empty",
-    ));
-    for addr in remote_addr {
-        match TcpStream::connect(addr).await {
-            Ok(socket) => {
-                return Ok(socket);
+    );
+    loop {
+        if attempts.is_empty() {
+            match pending.next() {
+                Some(addr) => attempts.push(options.connect(addr)),
+                None => return Err(err),
             }
-            Err(e) => {
-                err = Err(e);
+        }
+        let has_pending = pending.len() > 0;
+        tokio::select! {
+            res = attempts.next() => match res {
+                Some(Ok(socket)) => return Ok(socket),
+                Some(Err(e)) => {
+                    err = e;
+                    if let Some(addr) = pending.next() {
+                        attempts.push(options.connect(addr));
+                    }
+                }
+                None => {}
+            },
+            _ = time::sleep(CONNECTION_ATTEMPT_DELAY), if has_pending => {
+                if let Some(addr) = pending.next() {
+                    attempts.push(options.connect(addr));
+                }
             }
         }
     }
-    err
+}
+
+/// Reorders addresses so that the families alternate, starting with the family
+/// of the first address and keeping the resolver order within each family.
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let prefer_ipv6 = match addrs.first() {
+        Some(addr) => addr.is_ipv6(),
+        None => return addrs,
+    };
+    let (preferred, others): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == prefer_ipv6);
+    let mut res = Vec::with_capacity(preferred.len() + others.len());
+    let mut preferred = preferred.into_iter();
+    let mut others = others.into_iter();
+    loop {
+        match (preferred.next(), others.next()) {
+            (None, None) => return res,
+            (a, b) => res.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    /// An address nothing listens on, so connecting to it is refused.
+    async fn closed_addr() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    #[test]
+    fn interleaves_families() {
+        let res = interleave_families(addrs(&[
+            "[2001:db8::1]:80",
+            "[2001:db8::2]:80",
+            "[2001:db8::3]:80",
+            "192.0.2.1:80",
+            "192.0.2.2:80",
+        ]));
+        assert_eq!(
+            res,
+            addrs(&[
+                "[2001:db8::1]:80",
+                "192.0.2.1:80",
+                "[2001:db8::2]:80",
+                "192.0.2.2:80",
+                "[2001:db8::3]:80",
+            ])
+        );
+        let res = interleave_families(addrs(&["192.0.2.1:80", "[2001:db8::1]:80"]));
+        assert_eq!(res, addrs(&["192.0.2.1:80", "[2001:db8::1]:80"]));
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_next_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let options = ConnectOptions::new();
+        let stream = connect_happy_eyeballs(vec![closed_addr().await, target], &options)
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), target);
+    }
+
+    #[tokio::test]
+    async fn returns_the_last_error() {
+        let options = ConnectOptions::new();
+        let err = connect_happy_eyeballs(vec![closed_addr().await], &options)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        let err = connect_happy_eyeballs(Vec::new(), &options)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+    }
+
+    #[tokio::test]
+    async fn applies_connect_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let options = ConnectOptions {
+            bind_addr: Some("127.0.0.1".parse().unwrap()),
+            nodelay: Some(true),
+            keepalive: Some(KeepaliveOptions {
+                time: Some(Duration::from_secs(30)),
+                ..KeepaliveOptions::default()
+            }),
+            ..ConnectOptions::new()
+        };
+        // Addresses of the other family than the bound one are skipped.
+        let stream = connect_happy_eyeballs(vec!["[::1]:1".parse().unwrap(), target], &options)
+            .await
+            .unwrap();
+        assert_eq!(
+            stream.local_addr().unwrap().ip(),
+            options.bind_addr.unwrap()
+        );
+        assert!(stream.nodelay().unwrap());
+        let socket = socket2::SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+        let err = connect_happy_eyeballs(vec!["[::1]:1".parse().unwrap()], &options)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn defers_picking_the_local_port() {
+        use std::os::fd::AsRawFd;
+
+        let bound = |ip: &str| {
+            let options = ConnectOptions {
+                bind_addr: Some(ip.parse().unwrap()),
+                ..ConnectOptions::new()
+            };
+            let socket = options
+                .socket(SocketAddr::new(ip.parse().unwrap(), 1))
+                .unwrap();
+            let mut value: libc::c_int = 0;
+            let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+            let res = unsafe {
+                libc::getsockopt(
+                    socket.as_raw_fd(),
+                    libc::IPPROTO_IP,
+                    libc::IP_BIND_ADDRESS_NO_PORT,
+                    &mut value as *mut libc::c_int as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            assert_eq!(res, 0);
+            assert_eq!(socket.local_addr().unwrap().port(), 0);
+            value
+        };
+        assert_eq!(bound("127.0.0.1"), 1);
+        assert_eq!(bound("::1"), 1);
+    }
 }
 
 #[cfg(feature = "tls")]
@@ -144,3 +510,54 @@ pub mod tls {
         }
     }
 }
+
+#[cfg(feature = "rustls")]
+pub mod rustls {
+    use crate::connector::WrappedTcpStream;
+    use std::io::IoSlice;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io;
+    use tokio::io::ReadBuf;
+    use tokio::io::{AsyncRead, AsyncWrite};
+    use tokio::net::TcpStream;
+    use tokio_rustls::TlsStream;
+
+    pub struct RustlsWrappedTcpStream {
+        inner: TlsStream<TcpStream>,
+    }
+
+    impl RustlsWrappedTcpStream {
+        pub fn new<S: Into<TlsStream<TcpStream>>>(inner: S) -> RustlsWrappedTcpStream {
+            RustlsWrappedTcpStream {
+                inner: inner.into(),
+            }
+        }
+    }
+
+    impl AsyncRead for RustlsWrappedTcpStream {
+        async_read_proxy_impl!();
+    }
+
+    impl AsyncWrite for RustlsWrappedTcpStream {
+        async_write_proxy_impl!();
+    }
+
+    impl WrappedTcpStream for RustlsWrappedTcpStream {
+        fn get_stream_ref(&self) -> &TcpStream {
+            self.inner.get_ref().0
+        }
+
+        fn get_stream_mut_ref(&mut self) -> &mut TcpStream {
+            self.inner.get_mut().0
+        }
+
+        fn peer_certificate(&self) -> Option<&[u8]> {
+            let (_, state) = self.inner.get_ref();
+            state
+                .peer_certificates()
+                .and_then(|certificates| certificates.first())
+                .map(|certificate| certificate.as_ref())
+        }
+    }
+}
@@ -2,7 +2,11 @@ pub mod addr;
 pub mod connector;
 pub mod error;
 pub mod request;
+pub mod resolver;
 pub mod response;
+#[cfg(feature = "scram")]
+pub mod scram;
+pub mod token;
 
 pub use addr::{Addr, TargetAddr};
 pub use error::{Error, Result};
@@ -13,9 +17,9 @@ pub enum Version {
     V5,
 }
 
-impl Into<u8> for Version {
-    fn into(self) -> u8 {
-        match self {
+impl From<Version> for u8 {
+    fn from(value: Version) -> Self {
+        match value {
             Version::V4 => 0x04,
             Version::V5 => 0x05,
         }
@@ -48,9 +52,9 @@ pub enum AuthMethod {
     Private(u8),
 }
 
-impl Into<u8> for AuthMethod {
-    fn into(self) -> u8 {
-        match self {
+impl From<AuthMethod> for u8 {
+    fn from(value: AuthMethod) -> Self {
+        match value {
             AuthMethod::None => 0x00,
             AuthMethod::GssApi => 0x01,
             AuthMethod::UsernamePassword => 0x02,
@@ -108,9 +112,9 @@ pub enum Command {
     UdpAssociate,
 }
 
-impl Into<u8> for Command {
-    fn into(self) -> u8 {
-        match self {
+impl From<Command> for u8 {
+    fn from(value: Command) -> Self {
+        match value {
             Command::Connect => 0x01,
             Command::Bind => 0x02,
             Command::UdpAssociate => 0x03,
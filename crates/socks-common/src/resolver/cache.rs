@@ -0,0 +1,310 @@
+use crate::connector::DNSResolver;
+use crate::TargetAddr;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io;
+
+const DEFAULT_CAPACITY: usize = 1024;
+
+/// Caches answers of an inner resolver, including lookups of hosts that don't
+/// exist or have no address, i.e. that fail with `NotFound` or are empty.
+/// Other failures such as timeouts are not cached.
+pub struct CachedResolver<D: DNSResolver> {
+    inner: D,
+    ttl: Duration,
+    negative_ttl: Duration,
+    capacity: usize,
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+struct CacheEntry {
+    answer: Result<Vec<IpAddr>, (io::ErrorKind, String)>,
+    expires_at: Instant,
+}
+
+impl<D: DNSResolver> CachedResolver<D> {
+    /// Successful answers are kept for `ttl`, hosts without an address for
+    /// `negative_ttl`. A zero `negative_ttl` disables negative caching.
+    pub fn new(inner: D, ttl: Duration, negative_ttl: Duration) -> CachedResolver<D> {
+        CachedResolver::with_capacity(inner, ttl, negative_ttl, DEFAULT_CAPACITY)
+    }
+
+    pub fn with_capacity(
+        inner: D,
+        ttl: Duration,
+        negative_ttl: Duration,
+        capacity: usize,
+    ) -> CachedResolver<D> {
+        CachedResolver {
+            inner,
+            ttl,
+            negative_ttl,
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// Returns the cached answer for `host` along with its remaining TTL.
+    fn lookup(
+        &self,
+        host: &str,
+        now: Instant,
+    ) -> Option<(Result<Vec<IpAddr>, io::Error>, Duration)> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(host) {
+            Some(entry) if entry.expires_at > now => {
+                let answer = match &entry.answer {
+                    Ok(ips) => Ok(ips.clone()),
+                    Err((kind, msg)) => Err(io::Error::new(*kind, msg.clone())),
+                };
+                Some((answer, entry.expires_at - now))
+            }
+            Some(_) => {
+                entries.remove(host);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn store(
+        &self,
+        host: String,
+        answer: Result<Vec<IpAddr>, (io::ErrorKind, String)>,
+        ttl: Duration,
+    ) {
+        if ttl.is_zero() || self.capacity == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity && !entries.contains_key(&host) {
+            entries.retain(|_, entry| entry.expires_at > now);
+            if entries.len() >= self.capacity {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.expires_at)
+                    .map(|(host, _)| host.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        entries.insert(
+            host,
+            CacheEntry {
+                answer,
+                expires_at: now + ttl,
+            },
+        );
+    }
+}
+
+#[async_trait]
+impl<D: DNSResolver + Send + Sync> DNSResolver for CachedResolver<D> {
+    async fn resolve(&self, addr: &TargetAddr) -> io::Result<Vec<SocketAddr>> {
+        Ok(self.resolve_with_ttl(addr).await?.0)
+    }
+
+    async fn resolve_with_ttl(
+        &self,
+        addr: &TargetAddr,
+    ) -> io::Result<(Vec<SocketAddr>, Option<Duration>)> {
+        let (host, port) = match addr {
+            TargetAddr::Addr(addr) => return Ok((vec![*addr], None)),
+            TargetAddr::Host(host, port) => {
+                (host.trim_end_matches('.').to_ascii_lowercase(), *port)
+            }
+        };
+        if let Some((answer, ttl)) = self.lookup(&host, Instant::now()) {
+            let addrs = answer?
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, port))
+                .collect();
+            return Ok((addrs, Some(ttl)));
+        }
+        match self.inner.resolve_with_ttl(addr).await {
+            Ok((addrs, ttl)) => {
+                let ips: Vec<IpAddr> = addrs.iter().map(|x| x.ip()).collect();
+                // Answers are never kept longer than the TTL reported by the inner resolver.
+                let ttl = match (ips.is_empty(), ttl) {
+                    (true, _) => self.negative_ttl,
+                    (false, Some(ttl)) => ttl.min(self.ttl),
+                    (false, None) => self.ttl,
+                };
+                self.store(host, Ok(ips), ttl);
+                Ok((addrs, Some(ttl)))
+            }
+            Err(e) => {
+                if e.kind() == io::ErrorKind::NotFound {
+                    self.store(host, Err((e.kind(), e.to_string())), self.negative_ttl);
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Answers with `ips` and `ttl`, counting lookups.
+    struct Counting {
+        ips: Vec<IpAddr>,
+        ttl: Option<Duration>,
+        lookups: AtomicUsize,
+    }
+
+    impl Counting {
+        fn new(ips: &[&str], ttl: Option<Duration>) -> Counting {
+            Counting {
+                ips: ips.iter().map(|ip| ip.parse().unwrap()).collect(),
+                ttl,
+                lookups: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl DNSResolver for Counting {
+        async fn resolve(&self, addr: &TargetAddr) -> io::Result<Vec<SocketAddr>> {
+            Ok(self.resolve_with_ttl(addr).await?.0)
+        }
+
+        async fn resolve_with_ttl(
+            &self,
+            addr: &TargetAddr,
+        ) -> io::Result<(Vec<SocketAddr>, Option<Duration>)> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            let addrs = self
+                .ips
+                .iter()
+                .map(|ip| SocketAddr::new(*ip, addr.port()))
+                .collect();
+            Ok((addrs, self.ttl))
+        }
+    }
+
+    fn host(host: &str, port: u16) -> TargetAddr {
+        TargetAddr::Host(host.to_owned(), port)
+    }
+
+    #[tokio::test]
+    async fn caches_answers_per_host() {
+        let inner = Counting::new(&["192.0.2.1"], None);
+        let cache = CachedResolver::new(inner, Duration::from_secs(60), Duration::ZERO);
+        let addrs = cache.resolve(&host("example.com", 80)).await.unwrap();
+        assert_eq!(addrs, vec!["192.0.2.1:80".parse().unwrap()]);
+        // Hosts are matched regardless of case and trailing dot, ports come
+        // from the request.
+        let addrs = cache.resolve(&host("Example.COM.", 443)).await.unwrap();
+        assert_eq!(addrs, vec!["192.0.2.1:443".parse().unwrap()]);
+        assert_eq!(cache.inner.lookups.load(Ordering::SeqCst), 1);
+        cache.clear();
+        cache.resolve(&host("example.com", 80)).await.unwrap();
+        assert_eq!(cache.inner.lookups.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn keeps_answers_for_the_smallest_ttl() {
+        let inner = Counting::new(&["192.0.2.1"], Some(Duration::from_millis(50)));
+        let cache = CachedResolver::new(inner, Duration::from_secs(60), Duration::ZERO);
+        let (_, ttl) = cache
+            .resolve_with_ttl(&host("example.com", 80))
+            .await
+            .unwrap();
+        assert_eq!(ttl, Some(Duration::from_millis(50)));
+        cache.resolve(&host("example.com", 80)).await.unwrap();
+        assert_eq!(cache.inner.lookups.load(Ordering::SeqCst), 1);
+        std::thread::sleep(Duration::from_millis(60));
+        cache.resolve(&host("example.com", 80)).await.unwrap();
+        assert_eq!(cache.inner.lookups.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn evicts_the_oldest_entry_when_full() {
+        let inner = Counting::new(&["192.0.2.1"], None);
+        let cache =
+            CachedResolver::with_capacity(inner, Duration::from_secs(60), Duration::ZERO, 2);
+        for name in ["a.example", "b.example", "c.example"] {
+            cache.resolve(&host(name, 80)).await.unwrap();
+        }
+        assert_eq!(cache.entries.lock().unwrap().len(), 2);
+        cache.resolve(&host("c.example", 80)).await.unwrap();
+        assert_eq!(cache.inner.lookups.load(Ordering::SeqCst), 3);
+        cache.resolve(&host("a.example", 80)).await.unwrap();
+        assert_eq!(cache.inner.lookups.load(Ordering::SeqCst), 4);
+    }
+
+    /// Fails with `kind`, counting lookups.
+    struct Failing {
+        kind: io::ErrorKind,
+        lookups: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl DNSResolver for Failing {
+        async fn resolve(&self, _addr: &TargetAddr) -> io::Result<Vec<SocketAddr>> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            Err(io::Error::new(self.kind, "failed"))
+        }
+    }
+
+    #[tokio::test]
+    async fn caches_only_missing_hosts() {
+        let ttl = Duration::from_secs(60);
+        let inner = Counting::new(&[], None);
+        let cache = CachedResolver::new(inner, ttl, ttl);
+        for _ in 0..2 {
+            assert!(cache
+                .resolve(&host("example.com", 80))
+                .await
+                .unwrap()
+                .is_empty());
+        }
+        assert_eq!(cache.inner.lookups.load(Ordering::SeqCst), 1);
+
+        for (kind, lookups) in [
+            (io::ErrorKind::NotFound, 1),
+            (io::ErrorKind::TimedOut, 2),
+            (io::ErrorKind::Other, 2),
+        ] {
+            let inner = Failing {
+                kind,
+                lookups: AtomicUsize::new(0),
+            };
+            let cache = CachedResolver::new(inner, ttl, ttl);
+            for _ in 0..2 {
+                let err = cache.resolve(&host("example.com", 80)).await.unwrap_err();
+                assert_eq!(err.kind(), kind);
+            }
+            assert_eq!(
+                cache.inner.lookups.load(Ordering::SeqCst),
+                lookups,
+                "{:?}",
+                kind
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn passes_addresses_through() {
+        let inner = Counting::new(&[], None);
+        let cache = CachedResolver::new(inner, Duration::from_secs(60), Duration::ZERO);
+        let addr = "192.0.2.1:80".parse().unwrap();
+        assert_eq!(
+            cache.resolve(&TargetAddr::Addr(addr)).await.unwrap(),
+            vec![addr]
+        );
+        assert_eq!(cache.inner.lookups.load(Ordering::SeqCst), 0);
+    }
+}
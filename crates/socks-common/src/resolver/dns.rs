@@ -0,0 +1,882 @@
+use crate::connector::DNSResolver;
+use crate::TargetAddr;
+use async_trait::async_trait;
+use bytes::{BufMut, BytesMut};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time;
+
+const RESOLV_CONF_PATH: &str = "/etc/resolv.conf";
+const DNS_PORT: u16 = 53;
+/// UDP payload size advertised with EDNS(0), as recommended by DNS Flag Day 2020.
+const EDNS_UDP_PAYLOAD_SIZE: u16 = 1232;
+const MAX_CNAME_CHAIN: usize = 8;
+
+const TYPE_A: u16 = 1;
+const TYPE_CNAME: u16 = 5;
+const TYPE_AAAA: u16 = 28;
+const TYPE_OPT: u16 = 41;
+const CLASS_IN: u16 = 1;
+
+const RCODE_NOERROR: u8 = 0;
+const RCODE_NXDOMAIN: u8 = 3;
+
+/// Resolver configuration, usually read from `/etc/resolv.conf`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ResolvConf {
+    pub nameservers: Vec<SocketAddr>,
+    pub search: Vec<String>,
+    pub ndots: usize,
+    /// Timeout of a single query to a single nameserver.
+    pub timeout: Duration,
+    /// Number of rounds through the nameserver list before giving up.
+    pub attempts: usize,
+    /// Spread queries over the nameservers instead of always starting with the first one.
+    pub rotate: bool,
+}
+
+impl Default for ResolvConf {
+    fn default() -> Self {
+        ResolvConf {
+            nameservers: vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), DNS_PORT)],
+            search: Vec::new(),
+            ndots: 1,
+            timeout: Duration::from_secs(5),
+            attempts: 2,
+            rotate: false,
+        }
+    }
+}
+
+impl ResolvConf {
+    pub fn system() -> io::Result<ResolvConf> {
+        ResolvConf::from_file(RESOLV_CONF_PATH)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<ResolvConf> {
+        let contents = fs::read_to_string(path)?;
+        Ok(ResolvConf::parse(&contents))
+    }
+
+    /// Parses `resolv.conf` contents. Unknown or malformed directives are ignored,
+    /// and the local nameserver is used when none is configured.
+    pub fn parse(contents: &str) -> ResolvConf {
+        let mut conf = ResolvConf {
+            nameservers: Vec::new(),
+            ..ResolvConf::default()
+        };
+        for line in contents.lines() {
+            let line = match line.find(['#', ';']) {
+                Some(pos) => &line[..pos],
+                None => line,
+            };
+            let mut fields = line.split_whitespace();
+            match fields.next() {
+                Some("nameserver") => {
+                    // Scoped addresses such as `fe80::1%eth0` are not supported.
+                    if let Some(Ok(ip)) = fields.next().map(|x| x.parse::<IpAddr>()) {
+                        conf.nameservers.push(SocketAddr::new(ip, DNS_PORT));
+                    }
+                }
+                Some("search") | Some("domain") => {
+                    conf.search = fields
+                        .map(|x| x.trim_end_matches('.').to_owned())
+                        .filter(|x| !x.is_empty())
+                        .collect();
+                }
+                Some("options") => {
+                    for option in fields {
+                        let (name, value) = match option.split_once(':') {
+                            Some((name, value)) => (name, value.parse::<usize>().ok()),
+                            None => (option, None),
+                        };
+                        match (name, value) {
+                            ("ndots", Some(v)) => conf.ndots = v.min(15),
+                            ("timeout", Some(v)) => {
+                                conf.timeout = Duration::from_secs(v.max(1) as u64)
+                            }
+                            ("attempts", Some(v)) => conf.attempts = v.clamp(1, 5),
+                            ("rotate", _) => conf.rotate = true,
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        if conf.nameservers.is_empty() {
+            conf.nameservers = ResolvConf::default().nameservers;
+        }
+        conf
+    }
+}
+
+/// A stub resolver that queries the configured nameservers directly over UDP,
+/// retrying over TCP when an answer is truncated.
+///
+/// Unlike the system resolver it doesn't consult `/etc/hosts`; chain it behind a
+/// `HostsResolver` with a `FallbackResolver` when that is needed.
+#[derive(Debug)]
+pub struct StubResolver {
+    conf: ResolvConf,
+    next_server: AtomicUsize,
+}
+
+impl StubResolver {
+    pub fn new(conf: ResolvConf) -> StubResolver {
+        StubResolver {
+            conf,
+            next_server: AtomicUsize::new(0),
+        }
+    }
+
+    /// Creates a resolver configured from `/etc/resolv.conf`.
+    pub fn system() -> io::Result<StubResolver> {
+        Ok(StubResolver::new(ResolvConf::system()?))
+    }
+
+    pub fn conf(&self) -> &ResolvConf {
+        &self.conf
+    }
+
+    /// Looks up the IPv6 and IPv4 addresses of `host`, along with the smallest TTL
+    /// of the records in the answer.
+    pub async fn lookup_ip(&self, host: &str) -> io::Result<(Vec<IpAddr>, Option<Duration>)> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok((vec![ip], None));
+        }
+        let mut err = io::Error::new(io::ErrorKind::NotFound, format!("host not found: {}", host));
+        for name in self.candidates(host) {
+            let (v6, v4) = futures::join!(
+                self.lookup_type(&name, TYPE_AAAA),
+                self.lookup_type(&name, TYPE_A)
+            );
+            let answers = match (v6, v4) {
+                (Ok(v6), Ok(v4)) => vec![v6, v4],
+                (Ok(answer), Err(e)) | (Err(e), Ok(answer)) => {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        err = e;
+                    }
+                    vec![answer]
+                }
+                (Err(v6), Err(v4)) => {
+                    for e in [v6, v4] {
+                        if e.kind() != io::ErrorKind::NotFound {
+                            err = e;
+                        }
+                    }
+                    continue;
+                }
+            };
+            let ttl = answers.iter().filter_map(|x| x.1).min();
+            let ips: Vec<IpAddr> = answers.into_iter().flat_map(|x| x.0).collect();
+            if !ips.is_empty() {
+                return Ok((ips, ttl));
+            }
+        }
+        Err(err)
+    }
+
+    /// Names to query for `host`, following the `search` and `ndots` rules of resolv.conf.
+    fn candidates(&self, host: &str) -> Vec<String> {
+        if host.ends_with('.') {
+            return vec![host.trim_end_matches('.').to_owned()];
+        }
+        let suffixed = self
+            .conf
+            .search
+            .iter()
+            .map(|domain| format!("{}.{}", host, domain));
+        if host.matches('.').count() >= self.conf.ndots {
+            std::iter::once(host.to_owned()).chain(suffixed).collect()
+        } else {
+            suffixed.chain(std::iter::once(host.to_owned())).collect()
+        }
+    }
+
+    async fn lookup_type(
+        &self,
+        name: &str,
+        qtype: u16,
+    ) -> io::Result<(Vec<IpAddr>, Option<Duration>)> {
+        let id = rand::random::<u16>();
+        let query = encode_query(id, name, qtype)?;
+        let servers = &self.conf.nameservers;
+        let start = if self.conf.rotate {
+            self.next_server.fetch_add(1, Ordering::Relaxed)
+        } else {
+            0
+        };
+        let mut err = io::Error::new(io::ErrorKind::InvalidInput, "no nameserver is configured");
+        for _ in 0..self.conf.attempts.max(1) {
+            for i in 0..servers.len() {
+                let server = servers[(start + i) % servers.len()];
+                let message = match self.query(server, id, name, qtype, &query).await {
+                    Ok(message) => message,
+                    Err(e) => {
+                        err = e;
+                        continue;
+                    }
+                };
+                match message.rcode {
+                    RCODE_NOERROR => return Ok(message.addresses(name, qtype)),
+                    RCODE_NXDOMAIN => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::NotFound,
+                            format!("host not found: {}", name),
+                        ))
+                    }
+                    rcode => {
+                        err = io::Error::other(format!(
+                            "nameserver {} answered with rcode {}",
+                            server, rcode
+                        ));
+                    }
+                }
+            }
+        }
+        Err(err)
+    }
+
+    async fn query(
+        &self,
+        server: SocketAddr,
+        id: u16,
+        name: &str,
+        qtype: u16,
+        query: &[u8],
+    ) -> io::Result<Message> {
+        let timeout = self.conf.timeout;
+        let message = time::timeout(timeout, query_udp(server, id, name, qtype, query))
+            .await
+            .map_err(|_| timed_out(server))??;
+        if !message.truncated {
+            return Ok(message);
+        }
+        time::timeout(timeout, query_tcp(server, id, name, qtype, query))
+            .await
+            .map_err(|_| timed_out(server))?
+    }
+}
+
+#[async_trait]
+impl DNSResolver for StubResolver {
+    async fn resolve(&self, addr: &TargetAddr) -> io::Result<Vec<SocketAddr>> {
+        Ok(self.resolve_with_ttl(addr).await?.0)
+    }
+
+    async fn resolve_with_ttl(
+        &self,
+        addr: &TargetAddr,
+    ) -> io::Result<(Vec<SocketAddr>, Option<Duration>)> {
+        match addr {
+            TargetAddr::Addr(addr) => Ok((vec![*addr], None)),
+            TargetAddr::Host(host, port) => {
+                let (ips, ttl) = self.lookup_ip(host).await?;
+                let addrs = ips
+                    .into_iter()
+                    .map(|ip| SocketAddr::new(ip, *port))
+                    .collect();
+                Ok((addrs, ttl))
+            }
+        }
+    }
+}
+
+fn timed_out(server: SocketAddr) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("nameserver {} timed out", server),
+    )
+}
+
+async fn query_udp(
+    server: SocketAddr,
+    id: u16,
+    name: &str,
+    qtype: u16,
+    query: &[u8],
+) -> io::Result<Message> {
+    let local_addr = match server {
+        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    };
+    let socket = UdpSocket::bind(local_addr).await?;
+    socket.connect(server).await?;
+    socket.send(query).await?;
+    let mut buf = vec![0; EDNS_UDP_PAYLOAD_SIZE as usize];
+    loop {
+        let len = socket.recv(&mut buf).await?;
+        // Datagrams that don't answer our question are ignored, they may be
+        // late answers to an earlier query or spoofing attempts.
+        if let Ok(message) = Message::parse(&buf[..len]) {
+            if message.answers_query(id, name, qtype) {
+                return Ok(message);
+            }
+        }
+    }
+}
+
+async fn query_tcp(
+    server: SocketAddr,
+    id: u16,
+    name: &str,
+    qtype: u16,
+    query: &[u8],
+) -> io::Result<Message> {
+    let mut stream = TcpStream::connect(server).await?;
+    let mut buf = BytesMut::with_capacity(2 + query.len());
+    buf.put_u16(query.len() as u16);
+    buf.put_slice(query);
+    stream.write_all(&buf).await?;
+    let len = stream.read_u16().await?;
+    let mut buf = vec![0; len as usize];
+    stream.read_exact(&mut buf).await?;
+    let message = Message::parse(&buf)?;
+    if !message.answers_query(id, name, qtype) {
+        return Err(invalid_data("answer doesn't match the query"));
+    }
+    Ok(message)
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid DNS message: {}", msg),
+    )
+}
+
+fn encode_query(id: u16, name: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let mut buf = BytesMut::with_capacity(12 + name.len() + 2 + 4 + 11);
+    buf.put_u16(id);
+    // Standard query with recursion desired.
+    buf.put_u16(0x0100);
+    // QDCOUNT, ANCOUNT, NSCOUNT, ARCOUNT
+    buf.put_u16(1);
+    buf.put_u16(0);
+    buf.put_u16(0);
+    buf.put_u16(1);
+    encode_name(name, &mut buf)?;
+    buf.put_u16(qtype);
+    buf.put_u16(CLASS_IN);
+    // EDNS(0) OPT pseudo-record
+    buf.put_u8(0);
+    buf.put_u16(TYPE_OPT);
+    buf.put_u16(EDNS_UDP_PAYLOAD_SIZE);
+    buf.put_u32(0);
+    buf.put_u16(0);
+    Ok(buf.to_vec())
+}
+
+fn encode_name(name: &str, buf: &mut BytesMut) -> io::Result<()> {
+    let name = name.trim_end_matches('.');
+    if name.len() > 253 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "domain name is too long",
+        ));
+    }
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid domain name: {}", name),
+            ));
+        }
+        buf.put_u8(label.len() as u8);
+        buf.put_slice(label.as_bytes());
+    }
+    buf.put_u8(0);
+    Ok(())
+}
+
+struct Message {
+    id: u16,
+    is_response: bool,
+    truncated: bool,
+    rcode: u8,
+    question: Option<(String, u16)>,
+    answers: Vec<Record>,
+}
+
+struct Record {
+    name: String,
+    ttl: u32,
+    data: RecordData,
+}
+
+enum RecordData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Cname(String),
+    Other,
+}
+
+impl Message {
+    fn parse(buf: &[u8]) -> io::Result<Message> {
+        let mut reader = Reader { buf, pos: 0 };
+        let id = reader.u16()?;
+        let flags = reader.u16()?;
+        let qdcount = reader.u16()?;
+        let ancount = reader.u16()?;
+        // Authority and additional sections are not needed.
+        let _ = reader.u16()?;
+        let _ = reader.u16()?;
+        let mut question = None;
+        for _ in 0..qdcount {
+            let name = reader.name()?;
+            let qtype = reader.u16()?;
+            let _class = reader.u16()?;
+            question.get_or_insert((name, qtype));
+        }
+        let mut answers = Vec::with_capacity(ancount as usize);
+        for _ in 0..ancount {
+            let name = reader.name()?;
+            let rtype = reader.u16()?;
+            let class = reader.u16()?;
+            let ttl = reader.u32()?;
+            let len = reader.u16()? as usize;
+            let end = reader.pos + len;
+            if end > buf.len() {
+                return Err(invalid_data("record data is truncated"));
+            }
+            let data = match (rtype, class, len) {
+                (TYPE_A, CLASS_IN, 4) => {
+                    let mut octets = [0; 4];
+                    octets.copy_from_slice(&buf[reader.pos..end]);
+                    RecordData::A(Ipv4Addr::from(octets))
+                }
+                (TYPE_AAAA, CLASS_IN, 16) => {
+                    let mut octets = [0; 16];
+                    octets.copy_from_slice(&buf[reader.pos..end]);
+                    RecordData::Aaaa(Ipv6Addr::from(octets))
+                }
+                (TYPE_CNAME, CLASS_IN, _) => RecordData::Cname(reader.name()?),
+                _ => RecordData::Other,
+            };
+            reader.pos = end;
+            answers.push(Record { name, ttl, data });
+        }
+        Ok(Message {
+            id,
+            is_response: flags & 0x8000 != 0,
+            truncated: flags & 0x0200 != 0,
+            rcode: (flags & 0x000f) as u8,
+            question,
+            answers,
+        })
+    }
+
+    fn answers_query(&self, id: u16, name: &str, qtype: u16) -> bool {
+        self.is_response
+            && self.id == id
+            && match &self.question {
+                Some((qname, t)) => *t == qtype && names_eq(qname, name),
+                None => false,
+            }
+    }
+
+    /// Addresses of `name`, following CNAME records within the answer section.
+    fn addresses(&self, name: &str, qtype: u16) -> (Vec<IpAddr>, Option<Duration>) {
+        let mut name = name.to_owned();
+        let mut ips = Vec::new();
+        let mut ttl: Option<u32> = None;
+        for _ in 0..MAX_CNAME_CHAIN {
+            let mut alias = None;
+            for record in self.answers.iter().filter(|x| names_eq(&x.name, &name)) {
+                match &record.data {
+                    RecordData::A(ip) if qtype == TYPE_A => ips.push(IpAddr::V4(*ip)),
+                    RecordData::Aaaa(ip) if qtype == TYPE_AAAA => ips.push(IpAddr::V6(*ip)),
+                    RecordData::Cname(target) => alias = Some(target.clone()),
+                    _ => continue,
+                }
+                ttl = Some(ttl.map_or(record.ttl, |x| x.min(record.ttl)));
+            }
+            match alias {
+                Some(target) if ips.is_empty() => name = target,
+                _ => break,
+            }
+        }
+        if ips.is_empty() {
+            return (ips, None);
+        }
+        (ips, ttl.map(|x| Duration::from_secs(x as u64)))
+    }
+}
+
+fn names_eq(a: &str, b: &str) -> bool {
+    a.trim_end_matches('.')
+        .eq_ignore_ascii_case(b.trim_end_matches('.'))
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn u8(&mut self) -> io::Result<u8> {
+        let v = *self
+            .buf
+            .get(self.pos)
+            .ok_or_else(|| invalid_data("message is truncated"))?;
+        self.pos += 1;
+        Ok(v)
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(((self.u8()? as u16) << 8) | self.u8()? as u16)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(((self.u16()? as u32) << 16) | self.u16()? as u32)
+    }
+
+    /// Reads a possibly compressed domain name.
+    fn name(&mut self) -> io::Result<String> {
+        let mut labels: Vec<String> = Vec::new();
+        let mut pos = self.pos;
+        let mut jumped = false;
+        let mut jumps = 0;
+        loop {
+            let len = *self
+                .buf
+                .get(pos)
+                .ok_or_else(|| invalid_data("name is truncated"))?;
+            match len & 0xc0 {
+                0x00 if len == 0 => {
+                    pos += 1;
+                    break;
+                }
+                0x00 => {
+                    let start = pos + 1;
+                    let end = start + len as usize;
+                    let label = self
+                        .buf
+                        .get(start..end)
+                        .ok_or_else(|| invalid_data("label is truncated"))?;
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                    pos = end;
+                }
+                0xc0 => {
+                    let low = *self
+                        .buf
+                        .get(pos + 1)
+                        .ok_or_else(|| invalid_data("pointer is truncated"))?;
+                    jumps += 1;
+                    if jumps > 64 {
+                        return Err(invalid_data("too many compression pointers"));
+                    }
+                    if !jumped {
+                        self.pos = pos + 2;
+                        jumped = true;
+                    }
+                    pos = (((len & 0x3f) as usize) << 8) | low as usize;
+                }
+                _ => return Err(invalid_data("unknown label type")),
+            }
+        }
+        if !jumped {
+            self.pos = pos;
+        }
+        Ok(labels.join("."))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+    use tokio::net::TcpListener;
+
+    const FLAG_TC: u16 = 0x0200;
+
+    #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+    enum Transport {
+        Udp,
+        Tcp,
+    }
+
+    enum Data<'a> {
+        A(&'a str),
+        Aaaa(&'a str),
+        Cname(&'a str),
+    }
+
+    type Handler = dyn Fn(Transport, &str, u16, &[u8]) -> Vec<Vec<u8>> + Send + Sync;
+
+    /// A nameserver answering over UDP and TCP with the messages `handler`
+    /// returns for each query, along with the queries it received.
+    async fn stand_in<F>(handler: F) -> (SocketAddr, Arc<Mutex<Vec<(Transport, String)>>>)
+    where
+        F: Fn(Transport, &str, u16, &[u8]) -> Vec<Vec<u8>> + Send + Sync + 'static,
+    {
+        let handler: Arc<Handler> = Arc::new(handler);
+        let queries = Arc::new(Mutex::new(Vec::new()));
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = udp.local_addr().unwrap();
+        let tcp = TcpListener::bind(addr).await.unwrap();
+        let (handler2, queries2) = (handler.clone(), queries.clone());
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (len, peer) = udp.recv_from(&mut buf).await.unwrap();
+                let (name, qtype) = question(&buf[..len]);
+                queries2
+                    .lock()
+                    .unwrap()
+                    .push((Transport::Udp, name.clone()));
+                for reply in handler2(Transport::Udp, &name, qtype, &buf[..len]) {
+                    udp.send_to(&reply, peer).await.unwrap();
+                }
+            }
+        });
+        let queries2 = queries.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = tcp.accept().await.unwrap();
+                let len = stream.read_u16().await.unwrap();
+                let mut query = vec![0; len as usize];
+                stream.read_exact(&mut query).await.unwrap();
+                let (name, qtype) = question(&query);
+                queries2
+                    .lock()
+                    .unwrap()
+                    .push((Transport::Tcp, name.clone()));
+                for reply in handler(Transport::Tcp, &name, qtype, &query) {
+                    stream.write_u16(reply.len() as u16).await.unwrap();
+                    stream.write_all(&reply).await.unwrap();
+                }
+            }
+        });
+        (addr, queries)
+    }
+
+    fn question(query: &[u8]) -> (String, u16) {
+        Message::parse(query).unwrap().question.unwrap()
+    }
+
+    /// A reply to `query` with `flags` set besides QR, RD and RA.
+    fn reply(query: &[u8], flags: u16, answers: &[(&str, u32, Data)]) -> Vec<u8> {
+        let (name, qtype) = question(query);
+        let mut buf = BytesMut::new();
+        buf.put_slice(&query[..2]);
+        buf.put_u16(0x8180 | flags);
+        buf.put_u16(1);
+        buf.put_u16(answers.len() as u16);
+        buf.put_u16(0);
+        buf.put_u16(0);
+        encode_name(&name, &mut buf).unwrap();
+        buf.put_u16(qtype);
+        buf.put_u16(CLASS_IN);
+        for (name, ttl, data) in answers {
+            encode_name(name, &mut buf).unwrap();
+            let mut rdata = BytesMut::new();
+            let rtype = match data {
+                Data::A(ip) => {
+                    rdata.put_slice(&ip.parse::<Ipv4Addr>().unwrap().octets());
+                    TYPE_A
+                }
+                Data::Aaaa(ip) => {
+                    rdata.put_slice(&ip.parse::<Ipv6Addr>().unwrap().octets());
+                    TYPE_AAAA
+                }
+                Data::Cname(target) => {
+                    encode_name(target, &mut rdata).unwrap();
+                    TYPE_CNAME
+                }
+            };
+            buf.put_u16(rtype);
+            buf.put_u16(CLASS_IN);
+            buf.put_u32(*ttl);
+            buf.put_u16(rdata.len() as u16);
+            buf.put_slice(&rdata);
+        }
+        buf.to_vec()
+    }
+
+    fn resolver(nameservers: Vec<SocketAddr>) -> StubResolver {
+        StubResolver::new(ResolvConf {
+            nameservers,
+            timeout: Duration::from_millis(200),
+            attempts: 1,
+            ..ResolvConf::default()
+        })
+    }
+
+    fn ips(ips: &[&str]) -> Vec<IpAddr> {
+        ips.iter().map(|ip| ip.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn parses_resolv_conf() {
+        let conf = ResolvConf::parse(
+            "# generated\n\
+             nameserver 192.0.2.53\n\
+             nameserver 2001:db8::53 ; secondary\n\
+             nameserver fe80::1%eth0\n\
+             domain ignored.example\n\
+             search corp.example. example.com\n\
+             options ndots:2 timeout:0 attempts:9 rotate unknown:1\n",
+        );
+        assert_eq!(
+            conf,
+            ResolvConf {
+                nameservers: vec![
+                    "192.0.2.53:53".parse().unwrap(),
+                    "[2001:db8::53]:53".parse().unwrap(),
+                ],
+                search: vec!["corp.example".to_owned(), "example.com".to_owned()],
+                ndots: 2,
+                timeout: Duration::from_secs(1),
+                attempts: 5,
+                rotate: true,
+            }
+        );
+        assert_eq!(ResolvConf::parse(""), ResolvConf::default());
+    }
+
+    #[test]
+    fn applies_search_domains() {
+        let resolver = StubResolver::new(ResolvConf {
+            search: vec!["corp.example".to_owned()],
+            ..ResolvConf::default()
+        });
+        assert_eq!(resolver.candidates("db"), ["db.corp.example", "db"]);
+        assert_eq!(
+            resolver.candidates("db.internal"),
+            ["db.internal", "db.internal.corp.example"]
+        );
+        assert_eq!(resolver.candidates("db."), ["db"]);
+    }
+
+    #[tokio::test]
+    async fn retries_truncated_answers_over_tcp() {
+        let (addr, queries) = stand_in(|transport, _, qtype, query| match (transport, qtype) {
+            (Transport::Udp, _) => vec![reply(query, FLAG_TC, &[])],
+            (Transport::Tcp, TYPE_A) => vec![reply(
+                query,
+                0,
+                &[("example.com", 300, Data::A("192.0.2.1"))],
+            )],
+            (Transport::Tcp, _) => vec![reply(
+                query,
+                0,
+                &[("example.com", 60, Data::Aaaa("2001:db8::1"))],
+            )],
+        })
+        .await;
+        let (res, ttl) = resolver(vec![addr]).lookup_ip("example.com").await.unwrap();
+        assert_eq!(res, ips(&["2001:db8::1", "192.0.2.1"]));
+        assert_eq!(ttl, Some(Duration::from_secs(60)));
+        let queries = queries.lock().unwrap();
+        let tcp = queries.iter().filter(|(t, _)| *t == Transport::Tcp).count();
+        assert_eq!((queries.len(), tcp), (4, 2));
+    }
+
+    #[tokio::test]
+    async fn follows_cname_chains() {
+        let (addr, _) = stand_in(|_, _, qtype, query| {
+            let mut answers = vec![
+                ("www.example.com", 300, Data::Cname("edge.example.net")),
+                ("edge.example.net", 120, Data::Cname("pop.example.org")),
+            ];
+            if qtype == TYPE_A {
+                answers.push(("pop.example.org", 30, Data::A("192.0.2.7")));
+            }
+            vec![reply(query, 0, &answers)]
+        })
+        .await;
+        let (res, ttl) = resolver(vec![addr])
+            .lookup_ip("WWW.example.com")
+            .await
+            .unwrap();
+        assert_eq!(res, ips(&["192.0.2.7"]));
+        assert_eq!(ttl, Some(Duration::from_secs(30)));
+    }
+
+    #[tokio::test]
+    async fn reports_missing_hosts() {
+        let (addr, _) = stand_in(|_, name, _, query| match name {
+            "nodata.example" => vec![reply(query, 0, &[])],
+            _ => vec![reply(query, RCODE_NXDOMAIN as u16, &[])],
+        })
+        .await;
+        let resolver = resolver(vec![addr]);
+        for host in ["nxdomain.example", "nodata.example"] {
+            let err = resolver.lookup_ip(host).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::NotFound, "{}", host);
+        }
+    }
+
+    #[tokio::test]
+    async fn moves_to_the_next_nameserver() {
+        let (silent, _) = stand_in(|_, _, _, _| Vec::new()).await;
+        let (failing, _) = stand_in(|_, _, _, query| vec![reply(query, 2, &[])]).await;
+        let (working, _) = stand_in(|_, _, qtype, query| match qtype {
+            TYPE_A => vec![reply(
+                query,
+                0,
+                &[("example.com", 60, Data::A("192.0.2.1"))],
+            )],
+            _ => vec![reply(query, 0, &[])],
+        })
+        .await;
+        let start = Instant::now();
+        let (res, _) = resolver(vec![silent, failing, working])
+            .lookup_ip("example.com")
+            .await
+            .unwrap();
+        assert_eq!(res, ips(&["192.0.2.1"]));
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        // Timeouts are reported as such rather than as a missing host, so
+        // that they aren't cached.
+        let err = resolver(vec![silent])
+            .lookup_ip("example.com")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn ignores_replies_to_other_queries() {
+        let (addr, _) = stand_in(|_, _, qtype, query| {
+            let mut wrong_id = query.to_vec();
+            wrong_id[0] ^= 0xff;
+            let answer = |query: &[u8], ip| {
+                let data = match qtype {
+                    TYPE_A => Data::A(ip),
+                    _ => Data::Aaaa("2001:db8::1"),
+                };
+                reply(query, 0, &[("example.com", 60, data)])
+            };
+            vec![answer(&wrong_id, "192.0.2.66"), answer(query, "192.0.2.1")]
+        })
+        .await;
+        let (res, _) = resolver(vec![addr]).lookup_ip("example.com").await.unwrap();
+        assert_eq!(res, ips(&["2001:db8::1", "192.0.2.1"]));
+
+        let (addr, _) = stand_in(|_, _, _, query| {
+            let mut wrong_id = query.to_vec();
+            wrong_id[0] ^= 0xff;
+            vec![reply(
+                &wrong_id,
+                0,
+                &[("example.com", 60, Data::A("192.0.2.66"))],
+            )]
+        })
+        .await;
+        let err = resolver(vec![addr])
+            .lookup_ip("example.com")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,105 @@
+use crate::connector::DNSResolver;
+use crate::TargetAddr;
+use async_trait::async_trait;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io;
+
+/// Tries a chain of resolvers in order and returns the first non-empty answer.
+#[derive(Default)]
+pub struct FallbackResolver {
+    resolvers: Vec<Box<dyn DNSResolver + Send + Sync>>,
+}
+
+impl FallbackResolver {
+    pub fn new() -> FallbackResolver {
+        FallbackResolver {
+            resolvers: Vec::new(),
+        }
+    }
+
+    pub fn push<D: DNSResolver + Send + Sync + 'static>(&mut self, resolver: D) {
+        self.resolvers.push(Box::new(resolver));
+    }
+
+    pub fn with<D: DNSResolver + Send + Sync + 'static>(mut self, resolver: D) -> FallbackResolver {
+        self.push(resolver);
+        self
+    }
+}
+
+#[async_trait]
+impl DNSResolver for FallbackResolver {
+    async fn resolve(&self, addr: &TargetAddr) -> io::Result<Vec<SocketAddr>> {
+        Ok(self.resolve_with_ttl(addr).await?.0)
+    }
+
+    async fn resolve_with_ttl(
+        &self,
+        addr: &TargetAddr,
+    ) -> io::Result<(Vec<SocketAddr>, Option<Duration>)> {
+        if let TargetAddr::Addr(addr) = addr {
+            return Ok((vec![*addr], None));
+        }
+        let mut err = io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "Couldn't resolve addr: no resolver is configured",
+        );
+        for resolver in self.resolvers.iter() {
+            match resolver.resolve_with_ttl(addr).await {
+                Ok((addrs, ttl)) if !addrs.is_empty() => return Ok((addrs, ttl)),
+                Ok(_) => {
+                    err = io::Error::new(
+                        io::ErrorKind::AddrNotAvailable,
+                        "Couldn't resolve addr: result is empty",
+                    );
+                }
+                Err(e) => err = e,
+            }
+        }
+        Err(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolver::HostsResolver;
+
+    struct Failing(io::ErrorKind);
+
+    #[async_trait]
+    impl DNSResolver for Failing {
+        async fn resolve(&self, _addr: &TargetAddr) -> io::Result<Vec<SocketAddr>> {
+            Err(io::Error::new(self.0, "failed"))
+        }
+    }
+
+    fn host() -> TargetAddr {
+        TargetAddr::Host("example.com".to_owned(), 80)
+    }
+
+    #[tokio::test]
+    async fn returns_the_first_answer() {
+        let mut hosts = HostsResolver::new();
+        hosts.insert("example.com", "192.0.2.1".parse().unwrap());
+        let resolver = FallbackResolver::new()
+            .with(Failing(io::ErrorKind::TimedOut))
+            .with(HostsResolver::new())
+            .with(hosts)
+            .with(Failing(io::ErrorKind::Other));
+        let addrs = resolver.resolve(&host()).await.unwrap();
+        assert_eq!(addrs, vec!["192.0.2.1:80".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn returns_the_last_error() {
+        let resolver = FallbackResolver::new()
+            .with(HostsResolver::new())
+            .with(Failing(io::ErrorKind::TimedOut));
+        let err = resolver.resolve(&host()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        let err = FallbackResolver::new().resolve(&host()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+    }
+}
@@ -0,0 +1,136 @@
+use crate::connector::DNSResolver;
+use crate::TargetAddr;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use tokio::io;
+
+/// Resolves hosts from a static map, e.g. loaded from an `/etc/hosts` file.
+#[derive(Debug, Default, Clone)]
+pub struct HostsResolver {
+    hosts: HashMap<String, Vec<IpAddr>>,
+}
+
+impl HostsResolver {
+    pub fn new() -> HostsResolver {
+        HostsResolver {
+            hosts: HashMap::new(),
+        }
+    }
+
+    /// Loads entries from a file in `/etc/hosts` format.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<HostsResolver> {
+        let contents = fs::read_to_string(path)?;
+        Ok(HostsResolver::parse(&contents))
+    }
+
+    /// Parses entries in `/etc/hosts` format, skipping lines that are not valid.
+    pub fn parse(contents: &str) -> HostsResolver {
+        let mut resolver = HostsResolver::new();
+        for line in contents.lines() {
+            let line = match line.find('#') {
+                Some(pos) => &line[..pos],
+                None => line,
+            };
+            let mut fields = line.split_whitespace();
+            let ip = match fields.next().map(|x| x.parse::<IpAddr>()) {
+                Some(Ok(ip)) => ip,
+                _ => continue,
+            };
+            for host in fields {
+                resolver.insert(host, ip);
+            }
+        }
+        resolver
+    }
+
+    pub fn insert(&mut self, host: &str, ip: IpAddr) {
+        let ips = self.hosts.entry(normalize(host)).or_default();
+        if !ips.contains(&ip) {
+            ips.push(ip);
+        }
+    }
+
+    pub fn lookup(&self, host: &str) -> Option<&[IpAddr]> {
+        self.hosts.get(&normalize(host)).map(|x| &x[..])
+    }
+
+    pub fn len(&self) -> usize {
+        self.hosts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hosts.is_empty()
+    }
+}
+
+fn normalize(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[async_trait]
+impl DNSResolver for HostsResolver {
+    async fn resolve(&self, addr: &TargetAddr) -> io::Result<Vec<SocketAddr>> {
+        match addr {
+            TargetAddr::Addr(addr) => Ok(vec![*addr]),
+            TargetAddr::Host(host, port) => match self.lookup(host) {
+                Some(ips) => Ok(ips.iter().map(|ip| SocketAddr::new(*ip, *port)).collect()),
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("host not found in hosts map: {}", host),
+                )),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_hosts_files() {
+        let hosts = HostsResolver::parse(
+            "# comment\n\
+             127.0.0.1 localhost Localhost.localdomain.\n\
+             ::1 localhost # trailing comment\n\
+             not-an-ip example.com\n\
+             192.0.2.1\texample.org   www.example.org\n\
+             192.0.2.1 example.org\n",
+        );
+        assert_eq!(hosts.len(), 4);
+        assert_eq!(
+            hosts.lookup("LOCALHOST."),
+            Some(&["127.0.0.1".parse().unwrap(), "::1".parse().unwrap()][..])
+        );
+        assert!(hosts.lookup("localhost.localdomain").is_some());
+        assert_eq!(
+            hosts.lookup("example.org"),
+            Some(&["192.0.2.1".parse().unwrap()][..])
+        );
+        assert_eq!(hosts.lookup("example.com"), None);
+    }
+
+    #[tokio::test]
+    async fn resolves_from_the_map() {
+        let mut hosts = HostsResolver::new();
+        hosts.insert("example.com", "192.0.2.1".parse().unwrap());
+        let addrs = hosts
+            .resolve(&TargetAddr::Host("example.com".to_owned(), 443))
+            .await
+            .unwrap();
+        assert_eq!(addrs, vec!["192.0.2.1:443".parse().unwrap()]);
+        let err = hosts
+            .resolve(&TargetAddr::Host("example.org".to_owned(), 443))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        let addr = "[::1]:80".parse().unwrap();
+        assert_eq!(
+            hosts.resolve(&TargetAddr::Addr(addr)).await.unwrap(),
+            vec![addr]
+        );
+    }
+}
@@ -0,0 +1,13 @@
+mod cache;
+mod dns;
+mod fallback;
+mod hosts;
+mod policy;
+mod system;
+
+pub use cache::CachedResolver;
+pub use dns::{ResolvConf, StubResolver};
+pub use fallback::FallbackResolver;
+pub use hosts::HostsResolver;
+pub use policy::{nat64, AddrFamilyPolicy, PolicyResolver, WELL_KNOWN_NAT64_PREFIX};
+pub use system::SystemResolver;
@@ -0,0 +1,174 @@
+use crate::connector::DNSResolver;
+use crate::TargetAddr;
+use async_trait::async_trait;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io;
+
+/// The well-known NAT64 prefix `64:ff9b::/96` from RFC 6052.
+pub const WELL_KNOWN_NAT64_PREFIX: Ipv6Addr = Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0);
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum AddrFamilyPolicy {
+    /// Only IPv4 addresses are used.
+    Ipv4Only,
+    /// Only IPv6 addresses are used.
+    Ipv6Only,
+    /// IPv6 addresses are tried first.
+    PreferIpv6,
+    /// IPv4 addresses are tried first.
+    PreferIpv4,
+    /// Only IPv6 addresses are used, and IPv4-only destinations are translated
+    /// into the given /96 NAT64 prefix like DNS64 does (RFC 6147).
+    Nat64(Ipv6Addr),
+}
+
+impl AddrFamilyPolicy {
+    /// Applies the policy to a list of addresses, keeping their relative order
+    /// within each family.
+    pub fn apply(&self, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) =
+            addrs.into_iter().partition(|addr| addr.is_ipv6());
+        match *self {
+            AddrFamilyPolicy::Ipv4Only => v4,
+            AddrFamilyPolicy::Ipv6Only => v6,
+            AddrFamilyPolicy::PreferIpv6 => v6.into_iter().chain(v4).collect(),
+            AddrFamilyPolicy::PreferIpv4 => v4.into_iter().chain(v6).collect(),
+            AddrFamilyPolicy::Nat64(prefix) => {
+                if !v6.is_empty() {
+                    return v6;
+                }
+                v4.into_iter()
+                    .map(|addr| match addr.ip() {
+                        IpAddr::V4(ip) => {
+                            SocketAddr::new(IpAddr::V6(nat64(prefix, ip)), addr.port())
+                        }
+                        IpAddr::V6(_) => addr,
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Embeds `ip` into the last 32 bits of a /96 `prefix`.
+pub fn nat64(prefix: Ipv6Addr, ip: Ipv4Addr) -> Ipv6Addr {
+    let mut octets = prefix.octets();
+    octets[12..].copy_from_slice(&ip.octets());
+    Ipv6Addr::from(octets)
+}
+
+/// Applies an `AddrFamilyPolicy` to the answers of an inner resolver.
+///
+/// The policy is applied to IP literals too, so an IPv4 destination is
+/// rejected by `Ipv6Only` and translated by `Nat64`.
+pub struct PolicyResolver<D: DNSResolver> {
+    inner: D,
+    policy: AddrFamilyPolicy,
+}
+
+impl<D: DNSResolver> PolicyResolver<D> {
+    pub fn new(inner: D, policy: AddrFamilyPolicy) -> PolicyResolver<D> {
+        PolicyResolver { inner, policy }
+    }
+
+    pub fn policy(&self) -> AddrFamilyPolicy {
+        self.policy
+    }
+}
+
+#[async_trait]
+impl<D: DNSResolver + Send + Sync> DNSResolver for PolicyResolver<D> {
+    async fn resolve(&self, addr: &TargetAddr) -> io::Result<Vec<SocketAddr>> {
+        Ok(self.resolve_with_ttl(addr).await?.0)
+    }
+
+    async fn resolve_with_ttl(
+        &self,
+        addr: &TargetAddr,
+    ) -> io::Result<(Vec<SocketAddr>, Option<Duration>)> {
+        let (addrs, ttl) = self.inner.resolve_with_ttl(addr).await?;
+        let addrs = self.policy.apply(addrs);
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("no address of {} is allowed by {:?}", addr, self.policy),
+            ));
+        }
+        Ok((addrs, ttl))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolver::HostsResolver;
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn applies_family_policies() {
+        let mixed = addrs(&["192.0.2.1:80", "[2001:db8::1]:80", "192.0.2.2:80"]);
+        let cases = [
+            (
+                AddrFamilyPolicy::Ipv4Only,
+                &["192.0.2.1:80", "192.0.2.2:80"][..],
+            ),
+            (AddrFamilyPolicy::Ipv6Only, &["[2001:db8::1]:80"]),
+            (
+                AddrFamilyPolicy::PreferIpv6,
+                &["[2001:db8::1]:80", "192.0.2.1:80", "192.0.2.2:80"],
+            ),
+            (
+                AddrFamilyPolicy::PreferIpv4,
+                &["192.0.2.1:80", "192.0.2.2:80", "[2001:db8::1]:80"],
+            ),
+            (
+                AddrFamilyPolicy::Nat64(WELL_KNOWN_NAT64_PREFIX),
+                &["[2001:db8::1]:80"],
+            ),
+        ];
+        for (policy, expected) in cases {
+            assert_eq!(policy.apply(mixed.clone()), addrs(expected), "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn synthesizes_nat64_addresses() {
+        let prefix = "2001:db8:64::".parse().unwrap();
+        let policy = AddrFamilyPolicy::Nat64(prefix);
+        assert_eq!(
+            policy.apply(addrs(&["192.0.2.1:443"])),
+            addrs(&["[2001:db8:64::c000:201]:443"])
+        );
+        assert_eq!(
+            nat64(WELL_KNOWN_NAT64_PREFIX, Ipv4Addr::new(198, 51, 100, 7)),
+            "64:ff9b::c633:6407".parse::<Ipv6Addr>().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn applies_the_policy_to_answers_and_literals() {
+        let mut hosts = HostsResolver::new();
+        hosts.insert("example.com", "192.0.2.1".parse().unwrap());
+        let resolver = PolicyResolver::new(hosts, AddrFamilyPolicy::Ipv6Only);
+        let err = resolver
+            .resolve(&TargetAddr::Host("example.com".to_owned(), 80))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+        let literal = TargetAddr::Addr("192.0.2.1:80".parse().unwrap());
+        assert!(resolver.resolve(&literal).await.is_err());
+
+        let resolver = PolicyResolver::new(
+            HostsResolver::new(),
+            AddrFamilyPolicy::Nat64(WELL_KNOWN_NAT64_PREFIX),
+        );
+        assert_eq!(
+            resolver.resolve(&literal).await.unwrap(),
+            addrs(&["[64:ff9b::c000:201]:80"])
+        );
+    }
+}
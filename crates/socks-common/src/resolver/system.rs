@@ -0,0 +1,28 @@
+use crate::connector::DNSResolver;
+use crate::TargetAddr;
+use async_trait::async_trait;
+use std::net::SocketAddr;
+use tokio::io;
+use tokio::net::lookup_host;
+
+/// Resolves hosts with the operating system resolver.
+#[derive(Debug, Default, Clone)]
+pub struct SystemResolver;
+
+impl SystemResolver {
+    pub fn new() -> SystemResolver {
+        SystemResolver {}
+    }
+}
+
+#[async_trait]
+impl DNSResolver for SystemResolver {
+    async fn resolve(&self, addr: &TargetAddr) -> io::Result<Vec<SocketAddr>> {
+        match addr {
+            TargetAddr::Addr(addr) => Ok(vec![*addr]),
+            TargetAddr::Host(host, port) => {
+                Ok(lookup_host((host.as_str(), *port)).await?.collect())
+            }
+        }
+    }
+}
@@ -16,9 +16,9 @@ pub enum ResponseCode {
     AddrTypeNotSupported,
 }
 
-impl Into<u8> for ResponseCode {
-    fn into(self) -> u8 {
-        match self {
+impl From<ResponseCode> for u8 {
+    fn from(value: ResponseCode) -> Self {
+        match value {
             ResponseCode::Success => 0x00,
             ResponseCode::GeneralSocksServerFailure => 0x01,
             ResponseCode::ConnectionNotAllowedByRuleset => 0x02,
@@ -38,7 +38,7 @@ impl TryFrom<u8> for ResponseCode {
     fn try_from(value: u8) -> Result<Self> {
         match value {
             0x00 => Ok(ResponseCode::Success),
-            0x01 => Ok(ResponseCode::NetworkUnreachable),
+            0x01 => Ok(ResponseCode::GeneralSocksServerFailure),
             0x02 => Ok(ResponseCode::ConnectionNotAllowedByRuleset),
             0x03 => Ok(ResponseCode::NetworkUnreachable),
             0x04 => Ok(ResponseCode::HostUnreachable),
@@ -46,7 +46,7 @@ impl TryFrom<u8> for ResponseCode {
             0x06 => Ok(ResponseCode::TtlExpired),
             0x07 => Ok(ResponseCode::CommandNotSupported),
             0x08 => Ok(ResponseCode::AddrTypeNotSupported),
-            _ => Err(Error::ResponseCodeNotSupported(value).into()),
+            _ => Err(Error::ResponseCodeNotSupported(value)),
         }
     }
 }
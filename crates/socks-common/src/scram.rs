@@ -0,0 +1,205 @@
+//! SCRAM-SHA-256 (RFC 5802, RFC 7677) as a private authentication method, so
+//! that passwords never cross the wire.
+//!
+//! Once the method is selected, the client and the server exchange the four
+//! SCRAM messages, each framed as a version byte (`0x01`) followed by its
+//! length as a big-endian `u16` and its UTF-8 content. Channel binding isn't
+//! supported and passwords aren't normalized with SASLprep.
+
+use crate::{AuthMethod, Error, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const SCRAM_SHA_256: AuthMethod = AuthMethod::Private(0x80);
+pub const SCRAM_VERSION: u8 = 0x01;
+/// Iterations recommended by RFC 7677.
+pub const DEFAULT_ITERATIONS: u32 = 4096;
+/// Header of the client first message, the client doesn't support channel
+/// binding.
+pub const GS2_HEADER: &str = "n,,";
+/// `GS2_HEADER` in base64, as sent back in the client final message.
+pub const CHANNEL_BINDING: &str = "biws";
+
+const NONCE_LEN: usize = 18;
+const SALT_LEN: usize = 16;
+
+pub type Key = [u8; 32];
+
+pub fn salted_password(password: &str, salt: &[u8], iterations: u32) -> Key {
+    let mut key = [0; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut key);
+    key
+}
+
+pub fn client_key(salted_password: &Key) -> Key {
+    hmac(salted_password, b"Client Key")
+}
+
+pub fn server_key(salted_password: &Key) -> Key {
+    hmac(salted_password, b"Server Key")
+}
+
+pub fn stored_key(client_key: &Key) -> Key {
+    Sha256::digest(client_key).into()
+}
+
+pub fn hmac(key: &[u8], data: &[u8]) -> Key {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+pub fn xor(a: &Key, b: &Key) -> Key {
+    let mut out = [0; 32];
+    for (out, (a, b)) in out.iter_mut().zip(a.iter().zip(b)) {
+        *out = a ^ b;
+    }
+    out
+}
+
+/// A random printable nonce.
+pub fn nonce() -> String {
+    encode(&rand::random::<[u8; NONCE_LEN]>())
+}
+
+/// A random salt for new credentials.
+pub fn salt() -> Vec<u8> {
+    rand::random::<[u8; SALT_LEN]>().to_vec()
+}
+
+pub fn encode(data: &[u8]) -> String {
+    STANDARD.encode(data)
+}
+
+pub fn decode(data: &str) -> Result<Vec<u8>> {
+    STANDARD
+        .decode(data)
+        .map_err(|e| Error::AuthFailed(format!("invalid base64: {}", e)))
+}
+
+/// Escapes `,` and `=` in a username.
+pub fn escape_username(username: &str) -> String {
+    username.replace('=', "=3D").replace(',', "=2C")
+}
+
+pub fn unescape_username(username: &str) -> Result<String> {
+    let mut out = String::with_capacity(username.len());
+    let mut rest = username;
+    while let Some(i) = rest.find('=') {
+        out.push_str(&rest[..i]);
+        match rest.get(i..i + 3) {
+            Some("=2C") => out.push(','),
+            Some("=3D") => out.push('='),
+            _ => return Err(Error::AuthFailed("invalid username escape".to_owned())),
+        }
+        rest = &rest[i + 3..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// The value of the `name` attribute of a message.
+pub fn attribute(message: &str, name: char) -> Result<&str> {
+    message
+        .split(',')
+        .find_map(|part| {
+            part.strip_prefix(name)
+                .and_then(|part| part.strip_prefix('='))
+        })
+        .ok_or_else(|| Error::AuthFailed(format!("missing SCRAM attribute {}", name)))
+}
+
+pub async fn write_message<W>(writer: &mut W, message: &str) -> Result<()>
+where
+    W: AsyncWrite + Send + Unpin,
+{
+    let len = u16::try_from(message.len())
+        .map_err(|_| Error::AuthFailed("SCRAM message is too long".to_owned()))?;
+    let mut buf = Vec::with_capacity(1 + 2 + message.len());
+    buf.push(SCRAM_VERSION);
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(message.as_bytes());
+    writer.write_all(&buf).await?;
+    Ok(())
+}
+
+pub async fn read_message<R>(reader: &mut R) -> Result<String>
+where
+    R: AsyncRead + Send + Unpin,
+{
+    let version = reader.read_u8().await?;
+    if version != SCRAM_VERSION {
+        return Err(Error::VersionNotSupported(version));
+    }
+    let len = reader.read_u16().await?;
+    let mut message = vec![0; len as usize];
+    reader.read_exact(&mut message).await?;
+    String::from_utf8(message)
+        .map_err(|_| Error::AuthFailed("SCRAM message isn't valid UTF-8".to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    #[test]
+    fn computes_the_rfc7677_example() {
+        let client_first_bare = "n=user,r=rOprNGfwEbeRWgbNEkqO";
+        let server_first = "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                            s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+        let client_final_without_proof =
+            "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0";
+        let salt = decode(attribute(server_first, 's').unwrap()).unwrap();
+        let iterations = attribute(server_first, 'i').unwrap().parse().unwrap();
+        let salted_password = salted_password("pencil", &salt, iterations);
+        let auth_message = format!(
+            "{},{},{}",
+            client_first_bare, server_first, client_final_without_proof
+        );
+        let client_key = client_key(&salted_password);
+        let signature = hmac(&stored_key(&client_key), auth_message.as_bytes());
+        assert_eq!(
+            encode(&xor(&client_key, &signature)),
+            "dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+        );
+        let server_signature = hmac(&server_key(&salted_password), auth_message.as_bytes());
+        assert_eq!(
+            encode(&server_signature),
+            "6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4="
+        );
+    }
+
+    #[test]
+    fn escapes_usernames() {
+        assert_eq!(escape_username("a=b,c"), "a=3Db=2Cc");
+        assert_eq!(unescape_username("a=3Db=2Cc").unwrap(), "a=b,c");
+        assert!(unescape_username("a=2").is_err());
+        assert!(unescape_username("a=41").is_err());
+    }
+
+    #[test]
+    fn reads_attributes() {
+        assert_eq!(attribute("n=user,r=abc=", 'r').unwrap(), "abc=");
+        assert!(attribute("n=user", 'r').is_err());
+        assert!(attribute("rr=abc", 'r').is_err());
+    }
+
+    #[tokio::test]
+    async fn frames_messages() {
+        let (mut client, mut server) = duplex(1024);
+        write_message(&mut client, "n,,n=user,r=abc").await.unwrap();
+        assert_eq!(read_message(&mut server).await.unwrap(), "n,,n=user,r=abc");
+        assert!(write_message(&mut client, &"a".repeat(65536))
+            .await
+            .is_err());
+        client.write_all(&[0x02, 0, 0]).await.unwrap();
+        assert!(matches!(
+            read_message(&mut server).await,
+            Err(Error::VersionNotSupported(0x02))
+        ));
+    }
+}
@@ -0,0 +1,105 @@
+//! A private authentication method carrying a bearer token, such as a JWT,
+//! which may not fit in the 255 bytes of an RFC 1929 password.
+//!
+//! Once the method is selected, the client sends a version byte (`0x01`)
+//! followed by the length of the token as a big-endian `u16` and the token. The
+//! server replies with the version and a status, `0x00` meaning success, like
+//! RFC 1929.
+
+use crate::{AuthMethod, Error, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const BEARER_TOKEN: AuthMethod = AuthMethod::Private(0x81);
+pub const TOKEN_VERSION: u8 = 0x01;
+/// Longest token accepted, well above RS512 or ES512 JWTs with many claims.
+pub const MAX_TOKEN_LEN: usize = 16384;
+
+pub async fn write_token<W>(writer: &mut W, token: &[u8]) -> Result<()>
+where
+    W: AsyncWrite + Send + Unpin,
+{
+    if token.len() > MAX_TOKEN_LEN {
+        return Err(Error::AuthFailed("token is too long".to_owned()));
+    }
+    let mut buf = Vec::with_capacity(1 + 2 + token.len());
+    buf.push(TOKEN_VERSION);
+    buf.extend_from_slice(&(token.len() as u16).to_be_bytes());
+    buf.extend_from_slice(token);
+    writer.write_all(&buf).await?;
+    Ok(())
+}
+
+pub async fn read_token<R>(reader: &mut R) -> Result<Vec<u8>>
+where
+    R: AsyncRead + Send + Unpin,
+{
+    let version = reader.read_u8().await?;
+    if version != TOKEN_VERSION {
+        return Err(Error::VersionNotSupported(version));
+    }
+    let len = reader.read_u16().await? as usize;
+    if len > MAX_TOKEN_LEN {
+        return Err(Error::AuthFailed("token is too long".to_owned()));
+    }
+    let mut token = vec![0; len];
+    reader.read_exact(&mut token).await?;
+    Ok(token)
+}
+
+pub async fn write_status<W>(writer: &mut W, success: bool) -> Result<()>
+where
+    W: AsyncWrite + Send + Unpin,
+{
+    let status = if success { 0x00 } else { 0x01 };
+    writer.write_all(&[TOKEN_VERSION, status]).await?;
+    Ok(())
+}
+
+/// Reads the reply of the server, an error if the token was rejected.
+pub async fn read_status<R>(reader: &mut R) -> Result<()>
+where
+    R: AsyncRead + Send + Unpin,
+{
+    let version = reader.read_u8().await?;
+    if version != TOKEN_VERSION {
+        return Err(Error::VersionNotSupported(version));
+    }
+    match reader.read_u8().await? {
+        0x00 => Ok(()),
+        _ => Err(Error::AuthFailed("token rejected".to_owned())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn roundtrips_tokens() {
+        let (mut client, mut server) = duplex(64 * 1024);
+        let token = vec![b'a'; MAX_TOKEN_LEN];
+        write_token(&mut client, &token).await.unwrap();
+        assert_eq!(read_token(&mut server).await.unwrap(), token);
+        write_status(&mut server, true).await.unwrap();
+        read_status(&mut client).await.unwrap();
+        write_status(&mut server, false).await.unwrap();
+        assert!(read_status(&mut client).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_long_tokens() {
+        let (mut client, mut server) = duplex(64 * 1024);
+        let token = vec![b'a'; MAX_TOKEN_LEN + 1];
+        assert!(write_token(&mut client, &token).await.is_err());
+        let mut frame = vec![TOKEN_VERSION];
+        frame.extend_from_slice(&(token.len() as u16).to_be_bytes());
+        client.write_all(&frame).await.unwrap();
+        assert!(read_token(&mut server).await.is_err());
+        client.write_all(&[0x02, 0, 0]).await.unwrap();
+        assert!(matches!(
+            read_token(&mut server).await,
+            Err(Error::VersionNotSupported(0x02))
+        ));
+    }
+}
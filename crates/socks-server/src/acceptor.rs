@@ -8,6 +8,7 @@ pub trait Acceptor<T: WrappedTcpStream> {
     async fn accept(&self, socket: TcpStream) -> io::Result<T>;
 }
 
+#[derive(Default)]
 pub struct PlainAcceptor;
 
 impl PlainAcceptor {
@@ -53,3 +54,99 @@ pub mod tls {
         }
     }
 }
+
+#[cfg(feature = "rustls")]
+pub mod rustls {
+    use crate::acceptor::Acceptor;
+    use async_trait::async_trait;
+    use socks_rs_common::connector::rustls::RustlsWrappedTcpStream;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use tokio::io;
+    use tokio::net::TcpStream;
+    use tokio_rustls::rustls::pki_types::pem::PemObject;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    use tokio_rustls::rustls::server::WebPkiClientVerifier;
+    use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+
+    pub use tokio_rustls::rustls;
+
+    /// Whether clients authenticate with a certificate, see
+    /// `auth::CertificateAuthProvider`.
+    #[derive(Clone, Debug, Eq, PartialEq)]
+    pub enum ClientAuth {
+        None,
+        /// Clients may present a certificate issued by one of the CAs of a PEM
+        /// file.
+        Optional(PathBuf),
+        /// Clients must present a certificate issued by one of the CAs of a PEM
+        /// file.
+        Required(PathBuf),
+    }
+
+    /// Terminates TLS with rustls, which unlike `TlsAcceptor` can verify client
+    /// certificates.
+    pub struct RustlsAcceptor {
+        tls: tokio_rustls::TlsAcceptor,
+    }
+
+    impl RustlsAcceptor {
+        pub fn new(config: Arc<ServerConfig>) -> RustlsAcceptor {
+            RustlsAcceptor {
+                tls: tokio_rustls::TlsAcceptor::from(config),
+            }
+        }
+
+        /// Serves the certificate chain and private key of PEM files.
+        pub fn from_pem_files<P: AsRef<Path>>(
+            cert_chain: P,
+            private_key: P,
+            client_auth: ClientAuth,
+        ) -> io::Result<RustlsAcceptor> {
+            let cert_chain = read_certificates(cert_chain.as_ref())?;
+            let private_key = PrivateKeyDer::from_pem_file(private_key).map_err(invalid_data)?;
+            let builder = ServerConfig::builder();
+            let builder = match &client_auth {
+                ClientAuth::None => builder.with_no_client_auth(),
+                ClientAuth::Optional(ca) | ClientAuth::Required(ca) => {
+                    let mut roots = RootCertStore::empty();
+                    for certificate in read_certificates(ca)? {
+                        roots.add(certificate).map_err(invalid_data)?;
+                    }
+                    let mut verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+                    if let ClientAuth::Optional(_) = client_auth {
+                        verifier = verifier.allow_unauthenticated();
+                    }
+                    builder.with_client_cert_verifier(verifier.build().map_err(invalid_data)?)
+                }
+            };
+            let config = builder
+                .with_single_cert(cert_chain, private_key)
+                .map_err(invalid_data)?;
+            Ok(RustlsAcceptor::new(Arc::new(config)))
+        }
+    }
+
+    fn read_certificates(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+        CertificateDer::pem_file_iter(path)
+            .map_err(invalid_data)?
+            .collect::<Result<_, _>>()
+            .map_err(invalid_data)
+    }
+
+    fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+
+    #[async_trait]
+    impl Acceptor<RustlsWrappedTcpStream> for RustlsAcceptor {
+        async fn accept(&self, socket: TcpStream) -> io::Result<RustlsWrappedTcpStream> {
+            let res = self
+                .tls
+                .accept(socket)
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::ConnectionAborted, e))?;
+            Ok(RustlsWrappedTcpStream::new(res))
+        }
+    }
+}
@@ -0,0 +1,323 @@
+use crate::auth::{AuthContext, AuthProvider, Principal};
+use async_trait::async_trait;
+use socks_rs_common::{AuthMethod, Error, Result, Version};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// The certificate a client presented over TLS.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ClientCertificate {
+    /// The subject distinguished name, e.g. `CN=alice, O=Example`.
+    pub subject: String,
+    pub common_name: Option<String>,
+    /// DNS names of the subject alternative name extension.
+    pub dns_names: Vec<String>,
+    pub emails: Vec<String>,
+    pub uris: Vec<String>,
+    /// Hex SHA-256 fingerprint of the certificate.
+    pub fingerprint: String,
+}
+
+impl ClientCertificate {
+    /// Parses a DER certificate, which requires the `rustls` feature.
+    pub fn from_der(der: &[u8]) -> Result<ClientCertificate> {
+        #[cfg(feature = "rustls")]
+        {
+            parse(der)
+        }
+        #[cfg(not(feature = "rustls"))]
+        {
+            let _ = der;
+            Err(Error::AuthFailed(
+                "client certificates require the rustls feature".to_owned(),
+            ))
+        }
+    }
+}
+
+#[cfg(feature = "rustls")]
+fn parse(der: &[u8]) -> Result<ClientCertificate> {
+    use sha2::{Digest, Sha256};
+    use x509_parser::certificate::X509Certificate;
+    use x509_parser::extensions::GeneralName;
+    use x509_parser::prelude::FromDer;
+
+    let invalid = |e: String| Error::AuthFailed(format!("invalid client certificate: {}", e));
+    let (_, certificate) = X509Certificate::from_der(der).map_err(|e| invalid(e.to_string()))?;
+    let mut res = ClientCertificate {
+        subject: certificate.subject().to_string(),
+        common_name: certificate
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(str::to_owned),
+        fingerprint: Sha256::digest(der)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect(),
+        ..ClientCertificate::default()
+    };
+    let san = certificate
+        .subject_alternative_name()
+        .map_err(|e| invalid(e.to_string()))?;
+    for name in san.iter().flat_map(|san| &san.value.general_names) {
+        match name {
+            GeneralName::DNSName(name) => res.dns_names.push((*name).to_owned()),
+            GeneralName::RFC822Name(email) => res.emails.push((*email).to_owned()),
+            GeneralName::URI(uri) => res.uris.push((*uri).to_owned()),
+            _ => {}
+        }
+    }
+    Ok(res)
+}
+
+/// A part of a client certificate that may identify the user.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CertificateField {
+    Subject,
+    CommonName,
+    /// The first DNS name of the subject alternative name.
+    DnsName,
+    Email,
+    Uri,
+}
+
+impl CertificateField {
+    fn value(self, certificate: &ClientCertificate) -> Option<&str> {
+        match self {
+            CertificateField::Subject => Some(certificate.subject.as_str()),
+            CertificateField::CommonName => certificate.common_name.as_deref(),
+            CertificateField::DnsName => certificate.dns_names.first().map(String::as_str),
+            CertificateField::Email => certificate.emails.first().map(String::as_str),
+            CertificateField::Uri => certificate.uris.first().map(String::as_str),
+        }
+        .filter(|value| !value.is_empty())
+    }
+}
+
+/// Identifies clients by the certificate they presented over TLS, so that
+/// they negotiate no authentication yet have a username for rules and logs.
+///
+/// It requires a `RustlsAcceptor` verifying client certificates and doesn't
+/// apply to clients without one, so it can come first in a
+/// `CompositeAuthProvider` with password authentication for the others.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CertificateAuthProvider {
+    username_from: Vec<CertificateField>,
+}
+
+impl Default for CertificateAuthProvider {
+    fn default() -> Self {
+        CertificateAuthProvider {
+            username_from: vec![
+                CertificateField::CommonName,
+                CertificateField::Email,
+                CertificateField::DnsName,
+                CertificateField::Uri,
+                CertificateField::Subject,
+            ],
+        }
+    }
+}
+
+impl CertificateAuthProvider {
+    /// Takes the username from the common name, or else the first email, DNS
+    /// name or URI of the subject alternative name, or else the subject.
+    pub fn new() -> CertificateAuthProvider {
+        CertificateAuthProvider::default()
+    }
+
+    /// Takes the username from the first of `fields` present in the
+    /// certificate.
+    pub fn username_from(mut self, fields: &[CertificateField]) -> Self {
+        self.username_from = fields.to_vec();
+        self
+    }
+}
+
+#[async_trait]
+impl AuthProvider for CertificateAuthProvider {
+    async fn select(&self, ctx: &AuthContext, methods: &[AuthMethod]) -> Result<AuthMethod> {
+        if ctx.client_certificate.is_some() && methods.contains(&AuthMethod::None) {
+            return Ok(AuthMethod::None);
+        }
+        Err(Error::AuthMethodNotSupported(0xff))
+    }
+
+    async fn validate<IO: AsyncRead + AsyncWrite + Send + Unpin>(
+        &self,
+        ctx: &AuthContext,
+        version: Version,
+        method: AuthMethod,
+        _io: &mut IO,
+    ) -> Result<Principal> {
+        if version != Version::V5 {
+            return Err(Error::VersionNotSupported(version.into()));
+        }
+        if method != AuthMethod::None {
+            return Err(Error::AuthMethodNotSupported(method.into()));
+        }
+        let certificate = ctx
+            .client_certificate
+            .as_ref()
+            .ok_or_else(|| Error::AuthFailed("no client certificate".to_owned()))?;
+        let username = self
+            .username_from
+            .iter()
+            .find_map(|field| field.value(certificate))
+            .ok_or_else(|| Error::AuthFailed("client certificate has no username".to_owned()))?;
+        let mut principal = Principal::new(username, AuthMethod::None)
+            .with_attribute("subject", certificate.subject.as_str())
+            .with_attribute("fingerprint", certificate.fingerprint.as_str());
+        for (name, values) in [
+            ("dns_names", &certificate.dns_names),
+            ("emails", &certificate.emails),
+            ("uris", &certificate.uris),
+        ] {
+            if !values.is_empty() {
+                principal = principal.with_attribute(name, values.join(","));
+            }
+        }
+        Ok(principal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CertificateAuthProvider, CertificateField, ClientCertificate};
+    use crate::auth::{AuthContext, AuthProvider};
+    use crate::testing;
+    use socks_rs_common::{AuthMethod, Error, Version};
+    use tokio::io::duplex;
+
+    fn certificate() -> ClientCertificate {
+        ClientCertificate {
+            subject: "CN=alice, O=Example".to_owned(),
+            common_name: Some("alice".to_owned()),
+            dns_names: vec!["alice.example.com".to_owned()],
+            emails: vec!["alice@example.com".to_owned(), "a@example.com".to_owned()],
+            uris: Vec::new(),
+            fingerprint: "00".repeat(32),
+        }
+    }
+
+    fn context(certificate: Option<ClientCertificate>) -> AuthContext {
+        AuthContext {
+            client_certificate: certificate,
+            ..testing::auth_context("192.0.2.1:1234")
+        }
+    }
+
+    #[cfg(feature = "rustls")]
+    #[test]
+    fn parses_certificates() {
+        use rcgen::{CertificateParams, DnType, KeyPair, SanType};
+
+        let mut params = CertificateParams::new(vec!["alice.example.com".to_owned()]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, "alice");
+        params
+            .subject_alt_names
+            .push(SanType::Rfc822Name("alice@example.com".try_into().unwrap()));
+        params.subject_alt_names.push(SanType::URI(
+            "spiffe://example.com/alice".try_into().unwrap(),
+        ));
+        let key = KeyPair::generate().unwrap();
+        let der = params.self_signed(&key).unwrap().der().to_vec();
+
+        let certificate = ClientCertificate::from_der(&der).unwrap();
+        assert_eq!(certificate.subject, "CN=alice");
+        assert_eq!(certificate.common_name.as_deref(), Some("alice"));
+        assert_eq!(certificate.dns_names, ["alice.example.com"]);
+        assert_eq!(certificate.emails, ["alice@example.com"]);
+        assert_eq!(certificate.uris, ["spiffe://example.com/alice"]);
+        assert_eq!(certificate.fingerprint.len(), 64);
+        assert!(ClientCertificate::from_der(&der[..der.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_certificates() {
+        assert!(matches!(
+            ClientCertificate::from_der(b"not a certificate"),
+            Err(Error::AuthFailed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn applies_to_clients_with_a_certificate() {
+        let provider = CertificateAuthProvider::new();
+        let offered = [AuthMethod::UsernamePassword, AuthMethod::None];
+        assert_eq!(
+            provider
+                .select(&context(Some(certificate())), &offered)
+                .await
+                .unwrap(),
+            AuthMethod::None
+        );
+        assert!(provider.select(&context(None), &offered).await.is_err());
+        assert!(provider
+            .select(
+                &context(Some(certificate())),
+                &[AuthMethod::UsernamePassword]
+            )
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn takes_the_username_from_the_certificate() {
+        let (mut io, _) = duplex(64);
+        let validate = |provider: CertificateAuthProvider, certificate| async move {
+            let (mut io, _) = duplex(64);
+            provider
+                .validate(
+                    &context(certificate),
+                    Version::V5,
+                    AuthMethod::None,
+                    &mut io,
+                )
+                .await
+        };
+
+        let principal = validate(CertificateAuthProvider::new(), Some(certificate()))
+            .await
+            .unwrap();
+        assert_eq!(principal.username(), Some("alice"));
+        assert_eq!(principal.attributes["subject"], "CN=alice, O=Example");
+        assert_eq!(principal.attributes["fingerprint"], "00".repeat(32));
+        assert_eq!(principal.attributes["dns_names"], "alice.example.com");
+        assert_eq!(
+            principal.attributes["emails"],
+            "alice@example.com,a@example.com"
+        );
+        assert!(!principal.attributes.contains_key("uris"));
+
+        let provider = CertificateAuthProvider::new().username_from(&[CertificateField::Email]);
+        let principal = validate(provider, Some(certificate())).await.unwrap();
+        assert_eq!(principal.username(), Some("alice@example.com"));
+
+        // Empty fields are skipped.
+        let provider = CertificateAuthProvider::new()
+            .username_from(&[CertificateField::CommonName, CertificateField::Uri]);
+        let mut anonymous = certificate();
+        anonymous.common_name = Some(String::new());
+        assert!(matches!(
+            validate(provider.clone(), Some(anonymous)).await,
+            Err(Error::AuthFailed(_))
+        ));
+        assert!(matches!(
+            validate(provider, None).await,
+            Err(Error::AuthFailed(_))
+        ));
+
+        let provider = CertificateAuthProvider::new();
+        assert!(provider
+            .validate(
+                &context(Some(certificate())),
+                Version::V5,
+                AuthMethod::UsernamePassword,
+                &mut io
+            )
+            .await
+            .is_err());
+    }
+}
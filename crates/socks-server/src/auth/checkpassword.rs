@@ -0,0 +1,370 @@
+use crate::auth::{AuthContext, PasswordVerifier, Principal};
+use crate::expiring::{ExpiringMap, DEFAULT_CAPACITY};
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use socks_rs_common::{AuthMethod, Error, Result};
+use std::io;
+use std::path::PathBuf;
+use std::process::{Output, Stdio};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+#[cfg(unix)]
+use tokio::net::unix::pipe;
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use tokio::time;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_CONCURRENCY: usize = 16;
+
+/// How credentials are passed to the program.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CheckPasswordInput {
+    Stdin,
+    /// File descriptor 3, as checkpassword programs expect.
+    #[cfg(unix)]
+    Fd3,
+}
+
+/// Verifies credentials by running an external program, in the spirit of
+/// qmail's checkpassword interface.
+///
+/// The program receives `username\0password\0client address\0` on its input.
+/// Exiting with 0 accepts the credentials, 1 rejects them and any other status
+/// is treated as an error. Lines of its standard output in `key=value` format
+/// are added to the attributes of the principal.
+pub struct CheckPassword {
+    program: PathBuf,
+    args: Vec<String>,
+    input: CheckPasswordInput,
+    timeout: Duration,
+    permits: Semaphore,
+    cache_ttl: Option<Duration>,
+    cache: Mutex<ExpiringMap<[u8; 32], Principal>>,
+    /// Key of the HMAC of the credentials the cache is indexed with, so that
+    /// it doesn't hold anything a password could be checked against offline.
+    cache_key: [u8; 32],
+}
+
+impl CheckPassword {
+    /// Runs `program` with credentials on its standard input, a timeout of 5
+    /// seconds and at most 16 instances at once. Results aren't cached.
+    pub fn new<P: Into<PathBuf>>(program: P) -> CheckPassword {
+        CheckPassword {
+            program: program.into(),
+            args: Vec::new(),
+            input: CheckPasswordInput::Stdin,
+            timeout: DEFAULT_TIMEOUT,
+            permits: Semaphore::new(DEFAULT_CONCURRENCY),
+            cache_ttl: None,
+            cache: Mutex::new(ExpiringMap::new(DEFAULT_CAPACITY)),
+            cache_key: rand::random(),
+        }
+    }
+
+    pub fn arg<T: Into<String>>(mut self, arg: T) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn input(mut self, input: CheckPasswordInput) -> Self {
+        self.input = input;
+        self
+    }
+
+    /// How long the program may run before it is killed and the credentials
+    /// are rejected.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Maximum number of instances of the program running at once, further
+    /// verifications wait for one to complete.
+    pub fn max_concurrency(mut self, max: usize) -> Self {
+        self.permits = Semaphore::new(max);
+        self
+    }
+
+    /// Remembers accepted credentials for `ttl` along with the client IP they
+    /// were accepted from, rejections are never cached. Up to 65536 results
+    /// are cached, those expiring first are dropped first.
+    pub fn cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = Some(ttl);
+        self
+    }
+
+    fn cached(&self, key: &[u8; 32]) -> Option<Principal> {
+        let cache = self.cache.lock().unwrap();
+        cache.get(key, Instant::now()).cloned()
+    }
+
+    fn store(&self, key: [u8; 32], principal: &Principal) {
+        if let Some(ttl) = self.cache_ttl {
+            let now = Instant::now();
+            let mut cache = self.cache.lock().unwrap();
+            cache.insert(key, principal.clone(), now + ttl, now);
+        }
+    }
+
+    async fn run(&self, input: &[u8]) -> io::Result<Output> {
+        let mut command = Command::new(&self.program);
+        command
+            .args(&self.args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true);
+        match self.input {
+            CheckPasswordInput::Stdin => {
+                let mut child = command.stdin(Stdio::piped()).spawn()?;
+                let stdin = child.stdin.take().expect("stdin is piped");
+                write_input(stdin, input).await?;
+                child.wait_with_output().await
+            }
+            #[cfg(unix)]
+            CheckPasswordInput::Fd3 => {
+                let (writer, reader) = pipe::pipe()?;
+                fd3::pass(&mut command, reader.into_blocking_fd()?);
+                let child = command.stdin(Stdio::null()).spawn()?;
+                // Dropping the command closes our end of the reader.
+                drop(command);
+                write_input(writer, input).await?;
+                child.wait_with_output().await
+            }
+        }
+    }
+}
+
+/// Writes the credentials and closes the program's input. Programs may reject
+/// credentials without reading them, so a closed input is left for the exit
+/// status to tell.
+async fn write_input<W: AsyncWrite + Unpin>(mut writer: W, input: &[u8]) -> io::Result<()> {
+    match writer.write_all(input).await {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => result,
+    }
+}
+
+#[async_trait]
+impl PasswordVerifier for CheckPassword {
+    async fn verify(&self, ctx: &AuthContext, username: &str, password: &str) -> Result<Principal> {
+        if username.contains('\0') || password.contains('\0') {
+            return Err(Error::AuthFailed("credentials contain NUL".to_owned()));
+        }
+        let input = format!("{}\0{}\0{}\0", username, password, ctx.peer_addr.ip());
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.cache_key).expect("HMAC takes keys of any size");
+        mac.update(input.as_bytes());
+        let key: [u8; 32] = mac.finalize().into_bytes().into();
+        if let Some(principal) = self.cached(&key) {
+            return Ok(principal);
+        }
+        let _permit = self.permits.acquire().await.map_err(io::Error::other)?;
+        let output = time::timeout(self.timeout, self.run(input.as_bytes()))
+            .await
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("{} timed out", self.program.display()),
+                )
+            })??;
+        match output.status.code() {
+            Some(0) => {
+                let mut principal = Principal::new(username, AuthMethod::UsernamePassword);
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                for line in stdout.lines() {
+                    if let Some((key, value)) = line.split_once('=') {
+                        principal = principal.with_attribute(key.trim(), value.trim());
+                    }
+                }
+                self.store(key, &principal);
+                Ok(principal)
+            }
+            Some(1) => Err(Error::AuthFailed("incorrect credentials".to_owned())),
+            _ => Err(io::Error::other(format!(
+                "{} failed: {}",
+                self.program.display(),
+                output.status
+            ))
+            .into()),
+        }
+    }
+}
+
+#[cfg(unix)]
+mod fd3 {
+    use std::os::fd::{AsRawFd, OwnedFd};
+    use tokio::process::Command;
+
+    /// Makes `fd` available as file descriptor 3 of the child.
+    pub(super) fn pass(command: &mut Command, fd: OwnedFd) {
+        // SAFETY: only async-signal-safe functions are called between fork and
+        // exec, and `fd` is kept open by the closure until the command is dropped.
+        unsafe {
+            command.pre_exec(move || {
+                let raw = fd.as_raw_fd();
+                if raw == 3 {
+                    // dup2 would be a no-op and leave close-on-exec set.
+                    let flags = libc::fcntl(3, libc::F_GETFD);
+                    if flags == -1 || libc::fcntl(3, libc::F_SETFD, flags & !libc::FD_CLOEXEC) == -1
+                    {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                } else if libc::dup2(raw, 3) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A shell script accepting alice from 192.0.2.1, and logging its runs to
+    /// the returned path.
+    fn program(input: &str) -> (CheckPassword, PathBuf) {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let log = std::env::temp_dir().join(format!(
+            "socks-rs-checkpassword-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let script = format!(
+            r#"echo run >> '{}'
+            creds=$(tr '\000' ' ' {})
+            case "$creds" in
+              "alice secret 192.0.2.1 ") echo "team = infra"; echo "ignored"; exit 0;;
+              "slow "*) sleep 0.2; exit 0;;
+              "stuck "*) exec sleep 5;;
+              "broken "*) exit 2;;
+              *) exit 1;;
+            esac"#,
+            log.display(),
+            input
+        );
+        let checkpassword = CheckPassword::new("/bin/sh").arg("-c").arg(script);
+        (checkpassword, log)
+    }
+
+    fn runs(log: &PathBuf) -> usize {
+        fs::read_to_string(log).map_or(0, |log| log.lines().count())
+    }
+
+    async fn verify(
+        checkpassword: &CheckPassword,
+        peer: &str,
+        username: &str,
+        password: &str,
+    ) -> Result<Principal> {
+        let ctx = testing::auth_context(peer);
+        checkpassword.verify(&ctx, username, password).await
+    }
+
+    #[tokio::test]
+    async fn runs_the_program() {
+        let (checkpassword, log) = program("");
+        let principal = verify(&checkpassword, "192.0.2.1:1", "alice", "secret")
+            .await
+            .unwrap();
+        assert_eq!(principal.username(), Some("alice"));
+        assert_eq!(principal.attributes.len(), 1);
+        assert_eq!(principal.attributes["team"], "infra");
+        let e = verify(&checkpassword, "192.0.2.1:1", "alice", "wrong")
+            .await
+            .unwrap_err();
+        assert!(matches!(e, Error::AuthFailed(_)));
+        let e = verify(&checkpassword, "192.0.2.1:1", "broken", "")
+            .await
+            .unwrap_err();
+        assert!(matches!(e, Error::IoError(_)));
+        let e = verify(&checkpassword, "192.0.2.1:1", "alice\0", "secret")
+            .await
+            .unwrap_err();
+        assert!(matches!(e, Error::AuthFailed(_)));
+        assert_eq!(runs(&log), 3);
+        let _ = fs::remove_file(log);
+    }
+
+    #[tokio::test]
+    async fn judges_programs_not_reading_their_input_by_status() {
+        let checkpassword = CheckPassword::new("/bin/sh").arg("-c").arg("exit 1");
+        // More than a pipe holds, so that writing fails once the program exits.
+        let password = "x".repeat(1 << 20);
+        let e = verify(&checkpassword, "192.0.2.1:1", "alice", &password)
+            .await
+            .unwrap_err();
+        assert!(matches!(e, Error::AuthFailed(_)));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn passes_credentials_on_fd3() {
+        let (checkpassword, log) = program("<&3");
+        let checkpassword = checkpassword.input(CheckPasswordInput::Fd3);
+        verify(&checkpassword, "192.0.2.1:1", "alice", "secret")
+            .await
+            .unwrap();
+        assert!(verify(&checkpassword, "192.0.2.1:1", "alice", "wrong")
+            .await
+            .is_err());
+        let _ = fs::remove_file(log);
+    }
+
+    #[tokio::test]
+    async fn kills_stuck_programs() {
+        let (checkpassword, log) = program("");
+        let checkpassword = checkpassword.timeout(Duration::from_millis(100));
+        let start = Instant::now();
+        let e = verify(&checkpassword, "192.0.2.1:1", "stuck", "")
+            .await
+            .unwrap_err();
+        assert!(matches!(e, Error::IoError(e) if e.kind() == io::ErrorKind::TimedOut));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let _ = fs::remove_file(log);
+    }
+
+    #[tokio::test]
+    async fn limits_concurrency() {
+        let (checkpassword, log) = program("");
+        let checkpassword = checkpassword.max_concurrency(1);
+        let start = Instant::now();
+        let (a, b) = tokio::join!(
+            verify(&checkpassword, "192.0.2.1:1", "slow", ""),
+            verify(&checkpassword, "192.0.2.1:1", "slow", ""),
+        );
+        a.unwrap();
+        b.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        let _ = fs::remove_file(log);
+    }
+
+    #[tokio::test]
+    async fn caches_accepted_credentials_per_client() {
+        let (checkpassword, log) = program("");
+        let checkpassword = checkpassword.cache_ttl(Duration::from_secs(3600));
+        for _ in 0..2 {
+            verify(&checkpassword, "192.0.2.1:1", "alice", "secret")
+                .await
+                .unwrap();
+        }
+        assert_eq!(runs(&log), 1);
+        assert!(verify(&checkpassword, "192.0.2.2:1", "alice", "secret")
+            .await
+            .is_err());
+        for _ in 0..2 {
+            assert!(verify(&checkpassword, "192.0.2.1:1", "alice", "wrong")
+                .await
+                .is_err());
+        }
+        assert_eq!(runs(&log), 4);
+        let _ = fs::remove_file(log);
+    }
+}
@@ -0,0 +1,230 @@
+use crate::auth::{AuthContext, AuthProvider, Principal};
+use async_trait::async_trait;
+use ipnet::IpNet;
+use socks_rs_common::{AuthMethod, Error, Result, Version};
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Which clients a provider of a `CompositeAuthProvider` applies to. A
+/// condition matches when each of its parts matches any of its values, and an
+/// empty condition matches every client.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AuthCondition {
+    clients: Vec<IpNet>,
+    listeners: Vec<SocketAddr>,
+}
+
+impl AuthCondition {
+    pub fn new() -> AuthCondition {
+        AuthCondition::default()
+    }
+
+    /// Matches clients connecting from `net`.
+    pub fn client(mut self, net: IpNet) -> Self {
+        self.clients.push(net);
+        self
+    }
+
+    /// Matches clients connecting to `addr`, an unspecified IP matches any
+    /// local address with that port.
+    pub fn listener(mut self, addr: SocketAddr) -> Self {
+        self.listeners.push(addr);
+        self
+    }
+
+    fn matches(&self, ctx: &AuthContext) -> bool {
+        let client = ctx.peer_addr.ip();
+        let local = ctx.local_addr;
+        (self.clients.is_empty() || self.clients.iter().any(|net| net.contains(&client)))
+            && (self.listeners.is_empty()
+                || self.listeners.iter().any(|addr| {
+                    addr.port() == local.port()
+                        && (addr.ip().is_unspecified() || addr.ip() == local.ip())
+                }))
+    }
+}
+
+/// Combines several providers in order of preference.
+///
+/// The method is picked by the first provider that applies to the client and
+/// supports one of the methods it offered, whatever the client's own order,
+/// e.g. no authentication for trusted networks and username/password for
+/// everyone else.
+#[derive(Default)]
+pub struct CompositeAuthProvider {
+    providers: Vec<(AuthCondition, Box<dyn DynAuthProvider + Send + Sync>)>,
+}
+
+impl CompositeAuthProvider {
+    pub fn new() -> CompositeAuthProvider {
+        CompositeAuthProvider::default()
+    }
+
+    /// Adds a provider applying to every client.
+    pub fn provider<P: AuthProvider + Send + Sync + 'static>(self, provider: P) -> Self {
+        self.provider_if(AuthCondition::new(), provider)
+    }
+
+    /// Adds a provider applying to the clients matching `condition`.
+    pub fn provider_if<P: AuthProvider + Send + Sync + 'static>(
+        mut self,
+        condition: AuthCondition,
+        provider: P,
+    ) -> Self {
+        self.providers.push((condition, Box::new(provider)));
+        self
+    }
+
+    fn applicable<'a>(
+        &'a self,
+        ctx: &'a AuthContext,
+    ) -> impl Iterator<Item = &'a (dyn DynAuthProvider + Send + Sync)> + 'a {
+        self.providers
+            .iter()
+            .filter(|(condition, _)| condition.matches(ctx))
+            .map(|(_, provider)| provider.as_ref())
+    }
+}
+
+#[async_trait]
+impl AuthProvider for CompositeAuthProvider {
+    async fn select(&self, ctx: &AuthContext, methods: &[AuthMethod]) -> Result<AuthMethod> {
+        for provider in self.applicable(ctx) {
+            if let Ok(method) = provider.select(ctx, methods).await {
+                return Ok(method);
+            }
+        }
+        Err(Error::AuthMethodNotSupported(0xff))
+    }
+
+    async fn validate<IO: AsyncRead + AsyncWrite + Send + Unpin>(
+        &self,
+        ctx: &AuthContext,
+        version: Version,
+        method: AuthMethod,
+        io: &mut IO,
+    ) -> Result<Principal> {
+        // Providers are expected to select consistently, so the first one
+        // accepting the method alone is the one that selected it.
+        for provider in self.applicable(ctx) {
+            if provider.select(ctx, &[method]).await.is_ok() {
+                return provider.validate(ctx, version, method, io).await;
+            }
+        }
+        Err(Error::AuthMethodNotSupported(method.into()))
+    }
+}
+
+trait AuthIo: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin + ?Sized> AuthIo for T {}
+
+/// An object safe `AuthProvider`.
+#[async_trait]
+trait DynAuthProvider {
+    async fn select(&self, ctx: &AuthContext, methods: &[AuthMethod]) -> Result<AuthMethod>;
+    async fn validate(
+        &self,
+        ctx: &AuthContext,
+        version: Version,
+        method: AuthMethod,
+        io: &mut dyn AuthIo,
+    ) -> Result<Principal>;
+}
+
+#[async_trait]
+impl<P: AuthProvider + Send + Sync> DynAuthProvider for P {
+    async fn select(&self, ctx: &AuthContext, methods: &[AuthMethod]) -> Result<AuthMethod> {
+        AuthProvider::select(self, ctx, methods).await
+    }
+
+    async fn validate(
+        &self,
+        ctx: &AuthContext,
+        version: Version,
+        method: AuthMethod,
+        mut io: &mut dyn AuthIo,
+    ) -> Result<Principal> {
+        AuthProvider::validate(self, ctx, version, method, &mut io).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AuthCondition, CompositeAuthProvider};
+    use crate::auth::{AuthProvider, BasicAuthProvider, PlainAuthProvider};
+    use crate::testing;
+    use socks_rs_common::{AuthMethod, Error, Version};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    fn provider() -> CompositeAuthProvider {
+        CompositeAuthProvider::new()
+            .provider_if(
+                AuthCondition::new().client("10.0.0.0/8".parse().unwrap()),
+                PlainAuthProvider::new(),
+            )
+            .provider(BasicAuthProvider::new("alice", "secret"))
+    }
+
+    #[tokio::test]
+    async fn prefers_the_first_applicable_provider() {
+        let provider = provider();
+        let trusted = testing::auth_context("10.0.0.1:1234");
+        let other = testing::auth_context("192.0.2.1:1234");
+        let offered = [AuthMethod::UsernamePassword, AuthMethod::None];
+        assert_eq!(
+            provider.select(&trusted, &offered).await.unwrap(),
+            AuthMethod::None
+        );
+        assert_eq!(
+            provider
+                .select(&trusted, &[AuthMethod::UsernamePassword])
+                .await
+                .unwrap(),
+            AuthMethod::UsernamePassword
+        );
+        assert_eq!(
+            provider.select(&other, &offered).await.unwrap(),
+            AuthMethod::UsernamePassword
+        );
+        assert!(provider.select(&other, &[AuthMethod::None]).await.is_err());
+    }
+
+    #[test]
+    fn matches_listeners() {
+        let condition = AuthCondition::new()
+            .listener("0.0.0.0:1080".parse().unwrap())
+            .listener("127.0.0.2:1081".parse().unwrap());
+        let mut ctx = testing::auth_context("192.0.2.1:1234");
+        assert!(condition.matches(&ctx));
+        ctx.local_addr = "127.0.0.1:1081".parse().unwrap();
+        assert!(!condition.matches(&ctx));
+        ctx.local_addr = "127.0.0.2:1081".parse().unwrap();
+        assert!(condition.matches(&ctx));
+        let condition = condition.client("10.0.0.0/8".parse().unwrap());
+        assert!(!condition.matches(&ctx));
+        assert!(AuthCondition::new().matches(&ctx));
+    }
+
+    #[tokio::test]
+    async fn validates_with_the_selecting_provider() {
+        let provider = provider();
+        let ctx = testing::auth_context("192.0.2.1:1234");
+        let (mut client, mut server) = duplex(64);
+        client.write_all(b"\x01\x05alice\x06secret").await.unwrap();
+        let principal = provider
+            .validate(&ctx, Version::V5, AuthMethod::UsernamePassword, &mut server)
+            .await
+            .unwrap();
+        assert_eq!(principal.username(), Some("alice"));
+        let mut reply = [0; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x01, 0x00]);
+
+        let err = provider
+            .validate(&ctx, Version::V5, AuthMethod::None, &mut server)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::AuthMethodNotSupported(0x00)));
+    }
+}
@@ -0,0 +1,505 @@
+use crate::auth::{AuthContext, PasswordVerifier, Principal};
+use argon2::password_hash::SaltString;
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, Version};
+use async_trait::async_trait;
+use log::{info, warn};
+use serde::Deserialize;
+use socks_rs_common::{AuthMethod, Error, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::task::{self, JoinHandle};
+use tokio::time;
+
+/// Hash of an empty password checked for unknown users when the file has
+/// none to take the scheme and parameters from.
+const DUMMY_HASH: &str = "$2b$12$y6c531GZ17tmDIesKIoYMekPCqYdkw8ZnVU4dGwprcUMeEGgbFcSi";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Format {
+    Htpasswd,
+    Toml,
+}
+
+/// Users and password hashes loaded from a file, used as a `PasswordVerifier`.
+///
+/// Two formats are supported:
+/// - htpasswd, with one `username:hash` entry per line. Prefixing the hash with
+///   `!` disables the user.
+/// - TOML, with a table per user:
+///   ```toml
+///   [users.alice]
+///   password = "$argon2id$..."
+///   enabled = true # optional
+///   attributes = { team = "infra" } # optional, copied into the principal
+///   ```
+///
+/// Hashes are either bcrypt (`$2a$`, `$2b$`, `$2y$`) or argon2 (`$argon2id$`,
+/// ...) in PHC format.
+#[derive(Debug)]
+pub struct CredentialStore {
+    path: PathBuf,
+    format: Format,
+    users: RwLock<Arc<Users>>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+#[derive(Debug)]
+struct Users {
+    by_name: HashMap<String, User>,
+    /// Hash of an empty password with the scheme and parameters most users
+    /// have, checked for unknown users so that they take as long to reject as
+    /// wrong passwords. Computed on first use, hashing is slow.
+    dummy: OnceLock<String>,
+}
+
+impl Users {
+    fn new(by_name: HashMap<String, User>) -> Users {
+        Users {
+            by_name,
+            dummy: OnceLock::new(),
+        }
+    }
+
+    fn dummy_hash(&self) -> &str {
+        self.dummy.get_or_init(|| {
+            let mut counts: HashMap<&str, (usize, &str)> = HashMap::new();
+            for user in self.by_name.values() {
+                let count = counts
+                    .entry(parameters(&user.hash))
+                    .or_insert((0, &user.hash));
+                count.0 += 1;
+            }
+            counts
+                .into_values()
+                .max_by_key(|(count, _)| *count)
+                .and_then(|(_, hash)| empty_password_hash(hash))
+                .unwrap_or_else(|| DUMMY_HASH.to_owned())
+        })
+    }
+}
+
+#[derive(Debug)]
+struct User {
+    hash: String,
+    enabled: bool,
+    attributes: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlUsers {
+    #[serde(default)]
+    users: HashMap<String, TomlUser>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlUser {
+    password: String,
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+    #[serde(default)]
+    attributes: HashMap<String, String>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl CredentialStore {
+    /// Loads users from a htpasswd file.
+    pub fn from_htpasswd<P: AsRef<Path>>(path: P) -> io::Result<CredentialStore> {
+        CredentialStore::load(path.as_ref(), Format::Htpasswd)
+    }
+
+    /// Loads users from a TOML file.
+    pub fn from_toml<P: AsRef<Path>>(path: P) -> io::Result<CredentialStore> {
+        CredentialStore::load(path.as_ref(), Format::Toml)
+    }
+
+    fn load(path: &Path, format: Format) -> io::Result<CredentialStore> {
+        let (users, modified) = read(path, format)?;
+        Ok(CredentialStore {
+            path: path.to_owned(),
+            format,
+            users: RwLock::new(Arc::new(Users::new(users))),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// Reads the file again. The current users are kept if it is not valid.
+    pub fn reload(&self) -> io::Result<()> {
+        let (users, modified) = read(&self.path, self.format)?;
+        info!("Loaded {} users from {}", users.len(), self.path.display());
+        *self.users.write().unwrap() = Arc::new(Users::new(users));
+        *self.modified.lock().unwrap() = modified;
+        Ok(())
+    }
+
+    /// Reads the file again if it has been modified since it was last read.
+    pub fn reload_if_modified(&self) -> io::Result<bool> {
+        let modified = fs::metadata(&self.path)?.modified().ok();
+        if modified.is_some() && modified == *self.modified.lock().unwrap() {
+            return Ok(false);
+        }
+        self.reload()?;
+        Ok(true)
+    }
+
+    /// Checks the file for modifications every `interval` in the background,
+    /// until the store is dropped.
+    pub fn watch(self: &Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let store = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = time::interval(interval);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let store = match store.upgrade() {
+                    Some(store) => store,
+                    None => break,
+                };
+                if let Err(e) = store.reload_if_modified() {
+                    warn!("Couldn't reload users from {}: {}", store.path.display(), e);
+                }
+            }
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.read().unwrap().by_name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[async_trait]
+impl PasswordVerifier for CredentialStore {
+    async fn verify(
+        &self,
+        _ctx: &AuthContext,
+        username: &str,
+        password: &str,
+    ) -> Result<Principal> {
+        let users = self.users.read().unwrap().clone();
+        let username = username.to_owned();
+        let password = password.to_owned();
+        // Hashing is deliberately slow, keep it off the runtime threads.
+        task::spawn_blocking(move || {
+            let user = users.by_name.get(&username);
+            let hash = match user {
+                Some(user) => user.hash.as_str(),
+                None => users.dummy_hash(),
+            };
+            let matches = verify_hash(&password, hash);
+            match user {
+                Some(user) if matches && user.enabled => {
+                    let mut principal = Principal::new(username, AuthMethod::UsernamePassword);
+                    principal.attributes = user.attributes.clone();
+                    Ok(principal)
+                }
+                Some(user) if matches && !user.enabled => {
+                    Err(Error::AuthFailed(format!("user {} is disabled", username)))
+                }
+                _ => Err(Error::AuthFailed("incorrect credentials".to_owned())),
+            }
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+}
+
+fn read(path: &Path, format: Format) -> io::Result<(HashMap<String, User>, Option<SystemTime>)> {
+    let modified = fs::metadata(path)?.modified().ok();
+    let contents = fs::read_to_string(path)?;
+    let users = match format {
+        Format::Htpasswd => parse_htpasswd(&contents)?,
+        Format::Toml => parse_toml(&contents)?,
+    };
+    Ok((users, modified))
+}
+
+fn parse_htpasswd(contents: &str) -> io::Result<HashMap<String, User>> {
+    let mut users = HashMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (username, hash) = line
+            .split_once(':')
+            .ok_or_else(|| invalid_data(format!("line {}: missing ':'", i + 1)))?;
+        let (hash, enabled) = match hash.strip_prefix('!') {
+            Some(hash) => (hash, false),
+            None => (hash, true),
+        };
+        check_hash(hash).map_err(|e| invalid_data(format!("line {}: {}", i + 1, e)))?;
+        let user = User {
+            hash: hash.to_owned(),
+            enabled,
+            attributes: HashMap::new(),
+        };
+        users.insert(username.to_owned(), user);
+    }
+    Ok(users)
+}
+
+fn parse_toml(contents: &str) -> io::Result<HashMap<String, User>> {
+    let file: TomlUsers = toml::from_str(contents).map_err(invalid_data)?;
+    file.users
+        .into_iter()
+        .map(|(username, user)| {
+            check_hash(&user.password)
+                .map_err(|e| invalid_data(format!("user {}: {}", username, e)))?;
+            let user = User {
+                hash: user.password,
+                enabled: user.enabled,
+                attributes: user.attributes,
+            };
+            Ok((username, user))
+        })
+        .collect()
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+}
+
+fn check_hash(hash: &str) -> std::result::Result<(), String> {
+    if is_bcrypt(hash) {
+        return hash
+            .parse::<bcrypt::HashParts>()
+            .map(|_| ())
+            .map_err(|e| e.to_string());
+    }
+    if hash.starts_with("$argon2") {
+        return PasswordHash::new(hash)
+            .map(|_| ())
+            .map_err(|e| e.to_string());
+    }
+    Err("unsupported password hash".to_owned())
+}
+
+/// The scheme and parameters of `hash`, without its salt and digest.
+fn parameters(hash: &str) -> &str {
+    if is_bcrypt(hash) {
+        return hash.get(..7).unwrap_or(hash);
+    }
+    hash.rsplitn(3, '$').last().unwrap_or(hash)
+}
+
+/// Hashes an empty password like `hash` was.
+fn empty_password_hash(hash: &str) -> Option<String> {
+    if is_bcrypt(hash) {
+        let cost = hash.parse::<bcrypt::HashParts>().ok()?.get_cost();
+        return bcrypt::hash("", cost).ok();
+    }
+    let hash = PasswordHash::new(hash).ok()?;
+    let algorithm = Algorithm::try_from(hash.algorithm).ok()?;
+    let version = match hash.version {
+        Some(version) => Version::try_from(version).ok()?,
+        None => Version::default(),
+    };
+    let params = Params::try_from(&hash).ok()?;
+    let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>()).ok()?;
+    let dummy = Argon2::new(algorithm, version, params)
+        .hash_password(b"", &salt)
+        .ok()?;
+    Some(dummy.to_string())
+}
+
+/// Checks `password` against `hash`, both schemes compare in constant time.
+fn verify_hash(password: &str, hash: &str) -> bool {
+    if is_bcrypt(hash) {
+        return bcrypt::verify(password, hash).unwrap_or(false);
+    }
+    match PasswordHash::new(hash) {
+        Ok(hash) => hash
+            .verify_password(&[&Argon2::default()], password)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::fs::File;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A file removed once dropped.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(contents: &str) -> TempFile {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let name = format!(
+                "socks-rs-credentials-{}-{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            );
+            let file = TempFile(std::env::temp_dir().join(name));
+            file.write(contents, SystemTime::now());
+            file
+        }
+
+        fn write(&self, contents: &str, modified: SystemTime) {
+            fs::write(&self.0, contents).unwrap();
+            File::options()
+                .write(true)
+                .open(&self.0)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn bcrypt_hash(password: &str) -> String {
+        bcrypt::hash(password, 4).unwrap()
+    }
+
+    fn argon2_hash(password: &str) -> String {
+        let salt = SaltString::encode_b64(b"0123456789abcdef").unwrap();
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .unwrap()
+            .to_string()
+    }
+
+    async fn verify(store: &CredentialStore, username: &str, password: &str) -> Result<Principal> {
+        let ctx = testing::auth_context("192.0.2.1:1234");
+        store.verify(&ctx, username, password).await
+    }
+
+    #[tokio::test]
+    async fn verifies_htpasswd_users() {
+        let file = TempFile::new(&format!(
+            "# users\nalice:{}\n\nbob:{}\ncarol:!{}\n",
+            bcrypt_hash("secret"),
+            argon2_hash("hunter2"),
+            bcrypt_hash("password"),
+        ));
+        let store = CredentialStore::from_htpasswd(&file.0).unwrap();
+        assert_eq!(store.len(), 3);
+        let principal = verify(&store, "alice", "secret").await.unwrap();
+        assert_eq!(principal.username(), Some("alice"));
+        assert_eq!(principal.method, AuthMethod::UsernamePassword);
+        verify(&store, "bob", "hunter2").await.unwrap();
+        assert!(verify(&store, "alice", "hunter2").await.is_err());
+        assert!(verify(&store, "bob", "").await.is_err());
+        assert!(verify(&store, "dave", "secret").await.is_err());
+        let e = verify(&store, "carol", "password").await.unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            Error::AuthFailed("user carol is disabled".to_owned()).to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn verifies_toml_users() {
+        let file = TempFile::new(&format!(
+            "[users.alice]\npassword = \"{}\"\nattributes = {{ team = \"infra\" }}\n\n\
+             [users.bob]\npassword = \"{}\"\nenabled = false\n",
+            argon2_hash("secret"),
+            bcrypt_hash("hunter2"),
+        ));
+        let store = CredentialStore::from_toml(&file.0).unwrap();
+        let principal = verify(&store, "alice", "secret").await.unwrap();
+        assert_eq!(principal.attributes["team"], "infra");
+        assert!(verify(&store, "bob", "hunter2").await.is_err());
+        assert!(verify(&store, "alice", "hunter2").await.is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        for contents in ["alice", "alice:secret", "alice:$2b$04$invalid"] {
+            let file = TempFile::new(contents);
+            let e = CredentialStore::from_htpasswd(&file.0).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{}", contents);
+        }
+        for contents in [
+            "[users.alice]\npassword = \"secret\"\n",
+            "[users.alice]\nenabled = true\n",
+            "[users.alice]\npassword = \"$argon2id$\"\nadmin = true\n",
+        ] {
+            let file = TempFile::new(contents);
+            let e = CredentialStore::from_toml(&file.0).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{}", contents);
+        }
+        assert!(CredentialStore::from_toml("/nonexistent/users.toml").is_err());
+    }
+
+    #[test]
+    fn hashes_like_the_users() {
+        let users = Users::new(HashMap::new());
+        assert_eq!(users.dummy_hash(), DUMMY_HASH);
+
+        let user = |hash: String| User {
+            hash,
+            enabled: true,
+            attributes: HashMap::new(),
+        };
+        let users = Users::new(HashMap::from([
+            ("alice".to_owned(), user(bcrypt_hash("secret"))),
+            ("bob".to_owned(), user(bcrypt_hash("hunter2"))),
+            ("carol".to_owned(), user(argon2_hash("password"))),
+        ]));
+        assert!(users.dummy_hash().starts_with("$2b$04$"));
+        assert!(verify_hash("", users.dummy_hash()));
+
+        let params = Params::new(1024, 1, 1, None).unwrap();
+        let salt = SaltString::encode_b64(b"0123456789abcdef").unwrap();
+        let hash = Argon2::new(Algorithm::Argon2i, Version::V0x10, params)
+            .hash_password(b"secret", &salt)
+            .unwrap()
+            .to_string();
+        let users = Users::new(HashMap::from([("alice".to_owned(), user(hash))]));
+        assert_eq!(
+            parameters(users.dummy_hash()),
+            "$argon2i$v=16$m=1024,t=1,p=1"
+        );
+        assert!(verify_hash("", users.dummy_hash()));
+    }
+
+    #[tokio::test]
+    async fn reloads_modified_files() {
+        let modified = SystemTime::now() - Duration::from_secs(60);
+        let file = TempFile::new("");
+        file.write(&format!("alice:{}\n", bcrypt_hash("secret")), modified);
+        let store = CredentialStore::from_htpasswd(&file.0).unwrap();
+        assert!(!store.reload_if_modified().unwrap());
+
+        file.write(
+            &format!("bob:{}\n", bcrypt_hash("secret")),
+            SystemTime::now(),
+        );
+        assert!(store.reload_if_modified().unwrap());
+        assert!(!store.reload_if_modified().unwrap());
+        assert!(verify(&store, "alice", "secret").await.is_err());
+        verify(&store, "bob", "secret").await.unwrap();
+
+        // Invalid files are ignored.
+        file.write("bob", modified);
+        assert!(store.reload_if_modified().is_err());
+        verify(&store, "bob", "secret").await.unwrap();
+    }
+}
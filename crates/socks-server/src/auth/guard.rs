@@ -0,0 +1,444 @@
+use crate::auth::{AuthContext, PasswordVerifier, Principal};
+use crate::expiring::{ExpiringMap, DEFAULT_CAPACITY};
+use crate::limits;
+use async_trait::async_trait;
+use log::warn;
+use socks_rs_common::{Error, Result};
+use std::borrow::Cow;
+use std::fmt;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tokio::time;
+
+const EVENTS_CAPACITY: usize = 64;
+
+/// Thresholds of a `LoginGuard`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LoginGuardConfig {
+    /// Failures of a client or username within `window` after which it is
+    /// locked out.
+    pub max_failures: u32,
+    /// How long failures are remembered.
+    pub window: Duration,
+    /// Delay before answering the first failure, doubled with each subsequent
+    /// one up to `max_delay`.
+    pub delay: Duration,
+    pub max_delay: Duration,
+    /// How long a lockout lasts.
+    pub lockout: Duration,
+    /// How long locked out clients are held before being rejected, they are
+    /// rejected right away if not set.
+    pub tarpit: Option<Duration>,
+}
+
+impl Default for LoginGuardConfig {
+    fn default() -> Self {
+        LoginGuardConfig {
+            max_failures: 5,
+            window: Duration::from_secs(15 * 60),
+            delay: Duration::from_millis(250),
+            max_delay: Duration::from_secs(5),
+            lockout: Duration::from_secs(15 * 60),
+            tarpit: None,
+        }
+    }
+}
+
+impl LoginGuardConfig {
+    pub fn new() -> LoginGuardConfig {
+        LoginGuardConfig::default()
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum LockoutKey {
+    /// A client IP, IPv6 clients are tracked by /64 network.
+    Ip(IpAddr),
+    User(String),
+}
+
+impl fmt::Display for LockoutKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockoutKey::Ip(ip) => write!(f, "client {}", ip),
+            LockoutKey::User(user) => write!(f, "user {}", user),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Lockout {
+    pub key: LockoutKey,
+    /// Failures that led to the lockout.
+    pub failures: u32,
+    pub until: Instant,
+}
+
+impl LockoutKey {
+    /// The key failures of `self` are tracked under.
+    fn tracked(&self) -> Cow<'_, LockoutKey> {
+        match self {
+            LockoutKey::Ip(ip) => Cow::Owned(LockoutKey::Ip(limits::source(*ip))),
+            LockoutKey::User(_) => Cow::Borrowed(self),
+        }
+    }
+}
+
+/// Failures and lockouts are kept apart, so that failing from many new sources
+/// can only evict the failures of others and not their lockouts.
+#[derive(Debug)]
+struct Tracked {
+    failures: ExpiringMap<LockoutKey, Failures>,
+    lockouts: ExpiringMap<LockoutKey, Lockout>,
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    since: Instant,
+}
+
+/// Protects a `PasswordVerifier` from brute-force attacks.
+///
+/// Failures are tracked per client IP and per username: each one is answered
+/// after a growing delay, and sources exceeding `max_failures` are locked out
+/// for a while. Note that locking out usernames lets anyone lock a user out
+/// by failing on purpose.
+///
+/// To guard a `RoutingVerifier`, wrap the verifier of base users instead with
+/// `RoutingVerifier::login_guard`, so that failures are tracked per base user
+/// rather than per username with its routing parameters.
+///
+/// Up to 65536 sources with failures and as many lockouts are tracked, those
+/// expiring first are forgotten first.
+#[derive(Debug)]
+pub struct LoginGuard<V> {
+    verifier: V,
+    config: LoginGuardConfig,
+    failures: Mutex<Tracked>,
+    events: broadcast::Sender<Lockout>,
+}
+
+impl<V> LoginGuard<V> {
+    pub fn new(verifier: V, config: LoginGuardConfig) -> LoginGuard<V> {
+        let (events, _) = broadcast::channel(EVENTS_CAPACITY);
+        LoginGuard {
+            verifier,
+            config,
+            failures: Mutex::new(Tracked {
+                failures: ExpiringMap::new(DEFAULT_CAPACITY),
+                lockouts: ExpiringMap::new(DEFAULT_CAPACITY),
+            }),
+            events,
+        }
+    }
+
+    /// Receives a `Lockout` whenever one starts.
+    pub fn subscribe(&self) -> broadcast::Receiver<Lockout> {
+        self.events.subscribe()
+    }
+
+    /// Active lockouts.
+    pub fn lockouts(&self) -> Vec<Lockout> {
+        let tracked = self.failures.lock().unwrap();
+        tracked
+            .lockouts
+            .iter(Instant::now())
+            .map(|(_, lockout)| lockout.clone())
+            .collect()
+    }
+
+    pub fn lockout(&self, key: &LockoutKey) -> Option<Lockout> {
+        let tracked = self.failures.lock().unwrap();
+        tracked
+            .lockouts
+            .get(&key.tracked(), Instant::now())
+            .cloned()
+    }
+
+    /// Forgets the failures of `key`, lifting its lockout if any.
+    pub fn unlock(&self, key: &LockoutKey) -> bool {
+        let key = key.tracked();
+        let mut tracked = self.failures.lock().unwrap();
+        let failures = tracked.failures.remove(&key).is_some();
+        tracked.lockouts.remove(&key).is_some() || failures
+    }
+
+    fn locked(&self, keys: &[LockoutKey], now: Instant) -> Option<Lockout> {
+        let tracked = self.failures.lock().unwrap();
+        keys.iter()
+            .find_map(|key| tracked.lockouts.get(key, now).cloned())
+    }
+
+    /// Records a failure of each key and returns how long to wait before
+    /// answering it.
+    fn record_failure(&self, keys: &[LockoutKey], now: Instant) -> Duration {
+        let mut tracked = self.failures.lock().unwrap();
+        let mut count = 0;
+        for key in keys {
+            if let Some(lockout) = tracked.lockouts.get(key, now) {
+                count = count.max(lockout.failures);
+                continue;
+            }
+            let mut failures = tracked
+                .failures
+                .remove(key)
+                .filter(|failures| now.duration_since(failures.since) < self.config.window)
+                .unwrap_or(Failures {
+                    count: 0,
+                    since: now,
+                });
+            failures.count += 1;
+            count = count.max(failures.count);
+            if failures.count >= self.config.max_failures {
+                let until = now + self.config.lockout;
+                warn!(
+                    "Locked out {} for {:?} after {} failed authentications",
+                    key, self.config.lockout, failures.count
+                );
+                let lockout = Lockout {
+                    key: key.clone(),
+                    failures: failures.count,
+                    until,
+                };
+                let _ = self.events.send(lockout.clone());
+                tracked.lockouts.insert(key.clone(), lockout, until, now);
+            } else {
+                let expires_at = failures.since + self.config.window;
+                tracked
+                    .failures
+                    .insert(key.clone(), failures, expires_at, now);
+            }
+        }
+        let factor = 1u32 << (count.saturating_sub(1)).min(31);
+        self.config
+            .delay
+            .saturating_mul(factor)
+            .min(self.config.max_delay)
+    }
+}
+
+impl<V> LoginGuard<V> {
+    /// Runs `verify` unless the client IP or `username` is locked out, and
+    /// tracks its failures.
+    pub(crate) async fn check<F>(
+        &self,
+        ctx: &AuthContext,
+        username: &str,
+        verify: F,
+    ) -> Result<Principal>
+    where
+        F: Future<Output = Result<Principal>>,
+    {
+        let keys = [
+            LockoutKey::Ip(limits::source(ctx.peer_addr.ip())),
+            LockoutKey::User(username.to_owned()),
+        ];
+        if let Some(lockout) = self.locked(&keys, Instant::now()) {
+            if let Some(tarpit) = self.config.tarpit {
+                time::sleep(tarpit).await;
+            }
+            return Err(Error::AuthFailed(format!("{} is locked out", lockout.key)));
+        }
+        match verify.await {
+            Ok(principal) => {
+                // The client IP is not forgiven, so that a valid account can't
+                // be used to keep guessing others.
+                self.unlock(&keys[1]);
+                Ok(principal)
+            }
+            Err(Error::AuthFailed(reason)) => {
+                let delay = self.record_failure(&keys, Instant::now());
+                time::sleep(delay).await;
+                Err(Error::AuthFailed(reason))
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[async_trait]
+impl<V: PasswordVerifier + Send + Sync> PasswordVerifier for LoginGuard<V> {
+    async fn verify(&self, ctx: &AuthContext, username: &str, password: &str) -> Result<Principal> {
+        self.check(ctx, username, self.verifier.verify(ctx, username, password))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::BasicAuthProvider;
+    use crate::testing;
+    use std::net::SocketAddr;
+
+    fn guard(config: LoginGuardConfig) -> LoginGuard<BasicAuthProvider> {
+        LoginGuard::new(BasicAuthProvider::new("alice", "secret"), config)
+    }
+
+    fn config() -> LoginGuardConfig {
+        LoginGuardConfig {
+            max_failures: 3,
+            delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            ..LoginGuardConfig::new()
+        }
+    }
+
+    async fn login(
+        guard: &LoginGuard<BasicAuthProvider>,
+        peer: &str,
+        password: &str,
+    ) -> Result<Principal> {
+        let ctx = testing::auth_context(peer);
+        guard.verify(&ctx, "alice", password).await
+    }
+
+    fn ip(peer: &str) -> LockoutKey {
+        LockoutKey::Ip(peer.parse::<SocketAddr>().unwrap().ip())
+    }
+
+    fn user(user: &str) -> LockoutKey {
+        LockoutKey::User(user.to_owned())
+    }
+
+    #[tokio::test]
+    async fn delays_failures() {
+        let guard = guard(LoginGuardConfig {
+            max_failures: 10,
+            delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(40),
+            ..LoginGuardConfig::new()
+        });
+        let start = Instant::now();
+        assert!(login(&guard, "192.0.2.1:1", "wrong").await.is_err());
+        assert!(start.elapsed() >= Duration::from_millis(10));
+        let delays: Vec<_> = (0..3)
+            .map(|_| guard.record_failure(&[ip("192.0.2.1:1")], Instant::now()))
+            .collect();
+        let ms = Duration::from_millis;
+        assert_eq!(delays, [ms(20), ms(40), ms(40)]);
+        // The delay follows the worst of the client IP and the username.
+        let delay = guard.record_failure(&[ip("192.0.2.2:1"), user("alice")], Instant::now());
+        assert_eq!(delay, ms(20));
+    }
+
+    #[tokio::test]
+    async fn locks_out_sources() {
+        let guard = guard(config());
+        let mut events = guard.subscribe();
+        for _ in 0..3 {
+            assert!(login(&guard, "192.0.2.1:1", "wrong").await.is_err());
+        }
+        let e = login(&guard, "192.0.2.1:1", "secret").await.unwrap_err();
+        assert!(matches!(e, Error::AuthFailed(msg) if msg == "client 192.0.2.1 is locked out"));
+        let e = login(&guard, "192.0.2.2:1", "secret").await.unwrap_err();
+        assert!(matches!(e, Error::AuthFailed(msg) if msg == "user alice is locked out"));
+
+        let mut lockouts = guard.lockouts();
+        lockouts.sort_by_key(|lockout| lockout.key.to_string());
+        let keys: Vec<_> = lockouts.iter().map(|lockout| &lockout.key).collect();
+        assert_eq!(keys, [&ip("192.0.2.1:1"), &user("alice")]);
+        assert!(lockouts.iter().all(|lockout| lockout.failures == 3));
+        assert_eq!(events.try_recv().unwrap().key, ip("192.0.2.1:1"));
+        assert_eq!(events.try_recv().unwrap().key, user("alice"));
+
+        assert!(guard.unlock(&user("alice")));
+        login(&guard, "192.0.2.2:1", "secret").await.unwrap();
+        assert!(login(&guard, "192.0.2.1:1", "secret").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn forgives_the_username_only() {
+        let guard = guard(config());
+        for _ in 0..2 {
+            assert!(login(&guard, "192.0.2.1:1", "wrong").await.is_err());
+        }
+        login(&guard, "192.0.2.1:1", "secret").await.unwrap();
+        assert!(login(&guard, "192.0.2.1:1", "wrong").await.is_err());
+        assert!(guard.lockout(&ip("192.0.2.1:1")).is_some());
+        assert!(guard.lockout(&user("alice")).is_none());
+    }
+
+    #[tokio::test]
+    async fn lifts_expired_lockouts() {
+        let guard = guard(LoginGuardConfig {
+            lockout: Duration::from_millis(50),
+            tarpit: Some(Duration::from_millis(50)),
+            ..config()
+        });
+        for _ in 0..3 {
+            assert!(login(&guard, "192.0.2.1:1", "wrong").await.is_err());
+        }
+        let start = Instant::now();
+        assert!(login(&guard, "192.0.2.1:1", "secret").await.is_err());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        time::sleep(Duration::from_millis(50)).await;
+        login(&guard, "192.0.2.1:1", "secret").await.unwrap();
+        assert!(guard.lockouts().is_empty());
+    }
+
+    #[test]
+    fn drops_stale_sources() {
+        let guard = guard(LoginGuardConfig {
+            window: Duration::ZERO,
+            ..config()
+        });
+        let now = Instant::now();
+        for n in 0..1000 {
+            guard.record_failure(&[user(&n.to_string())], now);
+        }
+        assert!(guard.failures.lock().unwrap().failures.len() <= 1);
+    }
+
+    #[test]
+    fn bounds_the_tracked_sources() {
+        let guard = guard(LoginGuardConfig {
+            max_failures: 2,
+            delay: Duration::from_millis(1),
+            max_delay: Duration::from_secs(1),
+            ..LoginGuardConfig::new()
+        });
+        let now = Instant::now();
+        guard.record_failure(&[user("locked")], now);
+        guard.record_failure(&[user("locked")], now);
+        for n in 0..DEFAULT_CAPACITY {
+            guard.record_failure(&[user(&n.to_string())], now);
+        }
+        // New sources are still tracked, the failures expiring first are
+        // forgotten, and lockouts are kept.
+        assert_eq!(
+            guard.record_failure(&[user("new")], now),
+            Duration::from_millis(1)
+        );
+        {
+            let tracked = guard.failures.lock().unwrap();
+            assert_eq!(tracked.failures.len(), DEFAULT_CAPACITY);
+            assert!(tracked.failures.get(&user("0"), now).is_none());
+        }
+        assert_eq!(
+            guard.record_failure(&[user("new")], now),
+            Duration::from_millis(2)
+        );
+        assert!(guard.lockout(&user("new")).is_some());
+        assert!(guard.lockout(&user("locked")).is_some());
+    }
+
+    #[tokio::test]
+    async fn locks_out_ipv6_clients_by_network() {
+        let guard = guard(config());
+        for n in 1..=3 {
+            let peer = format!("[2001:db8::{}]:1", n);
+            assert!(login(&guard, &peer, "wrong").await.is_err());
+            guard.unlock(&user("alice"));
+        }
+        assert!(login(&guard, "[2001:db8::ffff]:1", "secret").await.is_err());
+        assert!(guard.lockout(&ip("[2001:db8::1]:1")).is_some());
+        login(&guard, "[2001:db8:0:1::1]:1", "secret")
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,476 @@
+use crate::auth::{password, AuthContext, AuthProvider, PasswordVerifier, Principal};
+use crate::rules::Destination;
+use async_trait::async_trait;
+use jsonwebtoken::jwk::{AlgorithmParameters, JwkSet, KeyAlgorithm};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde_json::{Map, Value};
+use socks_rs_common::token::{self, BEARER_TOKEN};
+use socks_rs_common::{AuthMethod, Error, Result, Version};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+const DEFAULT_LEEWAY: Duration = Duration::from_secs(60);
+
+struct Key {
+    id: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+/// Verifies JSON Web Tokens signed with HS256 secrets or RS256/EdDSA keys.
+///
+/// As an `AuthProvider`, it prefers the bearer token method of
+/// `socks_rs_common::token`, which takes tokens of up to 16 KiB, and falls
+/// back to tokens sent in the password field of the username/password method.
+/// RFC 1929 limits those to 255 bytes, which is too short for RS256 tokens in
+/// particular. As a `PasswordVerifier`, it only checks the password field.
+///
+/// Tokens must have an expiry. The username of the principal is taken from the
+/// `sub` claim, the allowed destinations from the `destinations` claim (a
+/// list of IP networks, domains and `*.` domain suffixes) if present, and the
+/// other string, number, boolean and string list claims become attributes.
+pub struct JwtVerifier {
+    keys: Vec<Key>,
+    audience: Vec<String>,
+    issuer: Vec<String>,
+    username_claim: String,
+    destinations_claim: String,
+    leeway: Duration,
+}
+
+impl Default for JwtVerifier {
+    fn default() -> Self {
+        JwtVerifier {
+            keys: Vec::new(),
+            audience: Vec::new(),
+            issuer: Vec::new(),
+            username_claim: "sub".to_owned(),
+            destinations_claim: "destinations".to_owned(),
+            leeway: DEFAULT_LEEWAY,
+        }
+    }
+}
+
+impl JwtVerifier {
+    /// A verifier without any key, see `hs256_key` and `jwks_file`.
+    pub fn new() -> JwtVerifier {
+        JwtVerifier::default()
+    }
+
+    /// Accepts tokens signed with the HS256 `secret`.
+    pub fn hs256_key(mut self, secret: &[u8]) -> Self {
+        self.keys.push(Key {
+            id: None,
+            algorithm: Algorithm::HS256,
+            key: DecodingKey::from_secret(secret),
+        });
+        self
+    }
+
+    /// Accepts tokens signed with the RSA (RS256) and Ed25519 (EdDSA) keys of
+    /// a JWKS file. Tokens with a `kid` header are only checked against the key
+    /// with that id.
+    pub fn jwks_file<P: AsRef<Path>>(mut self, path: P) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        let jwks: JwkSet = serde_json::from_str(&contents)?;
+        for jwk in &jwks.keys {
+            let algorithm = match (&jwk.algorithm, jwk.common.key_algorithm) {
+                (AlgorithmParameters::RSA(_), None | Some(KeyAlgorithm::RS256)) => Algorithm::RS256,
+                (AlgorithmParameters::OctetKeyPair(_), None | Some(KeyAlgorithm::EdDSA)) => {
+                    Algorithm::EdDSA
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unsupported key {:?}", jwk.common.key_id),
+                    ))
+                }
+            };
+            let key = DecodingKey::from_jwk(jwk)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            self.keys.push(Key {
+                id: jwk.common.key_id.clone(),
+                algorithm,
+                key,
+            });
+        }
+        Ok(self)
+    }
+
+    /// Requires tokens to be issued for `audience`, any of them if called more
+    /// than once.
+    pub fn audience<T: Into<String>>(mut self, audience: T) -> Self {
+        self.audience.push(audience.into());
+        self
+    }
+
+    /// Requires tokens to be issued by `issuer`, any of them if called more
+    /// than once.
+    pub fn issuer<T: Into<String>>(mut self, issuer: T) -> Self {
+        self.issuer.push(issuer.into());
+        self
+    }
+
+    pub fn username_claim<T: Into<String>>(mut self, claim: T) -> Self {
+        self.username_claim = claim.into();
+        self
+    }
+
+    pub fn destinations_claim<T: Into<String>>(mut self, claim: T) -> Self {
+        self.destinations_claim = claim.into();
+        self
+    }
+
+    /// Clock skew tolerated when checking expiry, defaults to 60 seconds.
+    pub fn leeway(mut self, leeway: Duration) -> Self {
+        self.leeway = leeway;
+        self
+    }
+
+    fn validation(&self, algorithm: Algorithm) -> Validation {
+        let mut validation = Validation::new(algorithm);
+        validation.leeway = self.leeway.as_secs();
+        validation.set_required_spec_claims(&["exp"]);
+        if self.audience.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&self.audience);
+        }
+        if !self.issuer.is_empty() {
+            validation.set_issuer(&self.issuer);
+        }
+        validation
+    }
+
+    fn decode(&self, token: &str) -> Result<Map<String, Value>> {
+        let header = decode_header(token).map_err(invalid_token)?;
+        let mut keys = self
+            .keys
+            .iter()
+            .filter(|key| key.algorithm == header.alg)
+            .filter(|key| header.kid.is_none() || key.id.is_none() || key.id == header.kid)
+            .peekable();
+        if keys.peek().is_none() {
+            return Err(Error::AuthFailed(format!(
+                "no key for {:?} token {:?}",
+                header.alg, header.kid
+            )));
+        }
+        let mut err = None;
+        for key in keys {
+            match decode(token, &key.key, &self.validation(key.algorithm)) {
+                Ok(data) => return Ok(data.claims),
+                Err(e) => err = Some(e),
+            }
+        }
+        Err(invalid_token(err.expect("at least one key was tried")))
+    }
+
+    fn principal(&self, claims: Map<String, Value>, method: AuthMethod) -> Result<Principal> {
+        let username = match claims.get(&self.username_claim) {
+            Some(Value::String(username)) => username.clone(),
+            _ => {
+                return Err(Error::AuthFailed(format!(
+                    "token has no {} claim",
+                    self.username_claim
+                )))
+            }
+        };
+        let mut principal = Principal::new(username, method);
+        for (name, value) in claims {
+            if name == self.destinations_claim {
+                principal.allowed_destinations = Some(destinations(&value)?);
+            } else if let Some(value) = attribute(&value) {
+                principal.attributes.insert(name, value);
+            }
+        }
+        Ok(principal)
+    }
+}
+
+#[async_trait]
+impl PasswordVerifier for JwtVerifier {
+    async fn verify(
+        &self,
+        _ctx: &AuthContext,
+        _username: &str,
+        password: &str,
+    ) -> Result<Principal> {
+        let claims = self.decode(password)?;
+        self.principal(claims, AuthMethod::UsernamePassword)
+    }
+}
+
+#[async_trait]
+impl AuthProvider for JwtVerifier {
+    async fn select(&self, _ctx: &AuthContext, methods: &[AuthMethod]) -> Result<AuthMethod> {
+        [BEARER_TOKEN, AuthMethod::UsernamePassword]
+            .into_iter()
+            .find(|method| methods.contains(method))
+            .ok_or(Error::AuthMethodNotSupported(0xff))
+    }
+
+    async fn validate<IO: AsyncRead + AsyncWrite + Send + Unpin>(
+        &self,
+        ctx: &AuthContext,
+        version: Version,
+        method: AuthMethod,
+        io: &mut IO,
+    ) -> Result<Principal> {
+        if method != BEARER_TOKEN {
+            return password::validate(self, ctx, version, method, io).await;
+        }
+        let (mut inbound, mut outbound) = tokio::io::split(io);
+        if version != Version::V5 {
+            token::write_status(&mut outbound, false).await?;
+            return Err(Error::VersionNotSupported(version.into()));
+        }
+        let token = token::read_token(&mut inbound).await?;
+        let res = match String::from_utf8(token) {
+            Ok(token) => self
+                .decode(&token)
+                .and_then(|claims| self.principal(claims, BEARER_TOKEN)),
+            Err(_) => Err(Error::AuthFailed("token isn't valid UTF-8".to_owned())),
+        };
+        token::write_status(&mut outbound, res.is_ok()).await?;
+        res
+    }
+}
+
+fn invalid_token(e: jsonwebtoken::errors::Error) -> Error {
+    Error::AuthFailed(format!("invalid token: {}", e))
+}
+
+fn attribute(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        Value::Bool(value) => Some(value.to_string()),
+        Value::Array(values) => values
+            .iter()
+            .map(|value| value.as_str())
+            .collect::<Option<Vec<_>>>()
+            .map(|values| values.join(",")),
+        _ => None,
+    }
+}
+
+fn destinations(value: &Value) -> Result<Vec<Destination>> {
+    let invalid = || Error::AuthFailed("invalid destinations claim".to_owned());
+    value
+        .as_array()
+        .ok_or_else(invalid)?
+        .iter()
+        .map(|value| {
+            value
+                .as_str()
+                .ok_or_else(invalid)?
+                .parse()
+                .map_err(Error::AuthFailed)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    const SECRET: &[u8] = b"secret";
+    /// The Ed25519 key of RFC 8037, as PKCS#8 and as a JWKS.
+    const ED25519_SEED: &str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+    const JWKS: &str = r#"{"keys":[{"kty":"OKP","crv":"Ed25519","kid":"k1","x":"11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo"}]}"#;
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn hs256(claims: Value) -> String {
+        encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(SECRET),
+        )
+        .unwrap()
+    }
+
+    fn eddsa(kid: &str, claims: Value) -> String {
+        let mut der = vec![
+            0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22,
+            0x04, 0x20,
+        ];
+        der.extend(
+            (0..32).map(|i| u8::from_str_radix(&ED25519_SEED[2 * i..2 * i + 2], 16).unwrap()),
+        );
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some(kid.to_owned());
+        encode(&header, &claims, &EncodingKey::from_ed_der(&der)).unwrap()
+    }
+
+    async fn verify(verifier: &JwtVerifier, token: &str) -> Result<Principal> {
+        let ctx = testing::auth_context("192.0.2.1:1234");
+        verifier.verify(&ctx, "token", token).await
+    }
+
+    #[tokio::test]
+    async fn maps_claims() {
+        let verifier = JwtVerifier::new().hs256_key(SECRET).audience("proxy");
+        let token = hs256(json!({
+            "sub": "alice",
+            "exp": now() + 3600,
+            "aud": "proxy",
+            "groups": ["admins", "users"],
+            "level": 3,
+            "destinations": ["10.0.0.0/8", "*.example.com"],
+            "nested": {"ignored": true},
+        }));
+        let principal = verify(&verifier, &token).await.unwrap();
+        assert_eq!(principal.username(), Some("alice"));
+        assert_eq!(principal.method, AuthMethod::UsernamePassword);
+        assert_eq!(principal.attributes["groups"], "admins,users");
+        assert_eq!(principal.attributes["level"], "3");
+        assert!(!principal.attributes.contains_key("nested"));
+        assert_eq!(
+            principal.allowed_destinations,
+            Some(vec![
+                "10.0.0.0/8".parse().unwrap(),
+                "*.example.com".parse().unwrap()
+            ])
+        );
+        let verifier = verifier.username_claim("email");
+        assert!(verify(&verifier, &token).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_tokens() {
+        let verifier = JwtVerifier::new()
+            .hs256_key(SECRET)
+            .audience("proxy")
+            .issuer("control-plane");
+        let claims = |changes: Value| {
+            let mut claims = json!({
+                "sub": "alice",
+                "exp": now() + 3600,
+                "aud": "proxy",
+                "iss": "control-plane",
+            });
+            for (key, value) in changes.as_object().unwrap() {
+                match value {
+                    Value::Null => claims.as_object_mut().unwrap().remove(key),
+                    value => claims
+                        .as_object_mut()
+                        .unwrap()
+                        .insert(key.clone(), value.clone()),
+                };
+            }
+            claims
+        };
+        verify(&verifier, &hs256(claims(json!({})))).await.unwrap();
+        for changes in [
+            json!({"exp": now() - 3600}),
+            json!({"exp": null}),
+            json!({"aud": "other"}),
+            json!({"iss": "other"}),
+            json!({"sub": null}),
+            json!({"destinations": "10.0.0.0/8"}),
+        ] {
+            let token = hs256(claims(changes.clone()));
+            assert!(verify(&verifier, &token).await.is_err(), "{}", changes);
+        }
+        let token = encode(
+            &Header::default(),
+            &claims(json!({})),
+            &EncodingKey::from_secret(b"other"),
+        )
+        .unwrap();
+        assert!(verify(&verifier, &token).await.is_err());
+        assert!(verify(&verifier, "not a token").await.is_err());
+        let token = eddsa("k1", claims(json!({})));
+        assert!(verify(&verifier, &token).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn verifies_jwks_keys() {
+        let path = std::env::temp_dir().join(format!("socks-rs-jwks-{}", std::process::id()));
+        fs::write(&path, JWKS).unwrap();
+        let verifier = JwtVerifier::new().jwks_file(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        let claims = json!({"sub": "alice", "exp": now() + 3600});
+        let principal = verify(&verifier, &eddsa("k1", claims.clone()))
+            .await
+            .unwrap();
+        assert_eq!(principal.username(), Some("alice"));
+        let e = verify(&verifier, &eddsa("k2", claims.clone()))
+            .await
+            .unwrap_err();
+        assert!(matches!(e, Error::AuthFailed(msg) if msg.starts_with("no key")));
+        assert!(verify(&verifier, &hs256(claims)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn takes_long_tokens_with_the_bearer_method() {
+        let verifier = JwtVerifier::new().hs256_key(SECRET);
+        let ctx = testing::auth_context("192.0.2.1:1234");
+        let methods = [AuthMethod::UsernamePassword, BEARER_TOKEN];
+        assert_eq!(verifier.select(&ctx, &methods).await.unwrap(), BEARER_TOKEN);
+
+        let token = hs256(json!({
+            "sub": "alice",
+            "exp": now() + 3600,
+            "padding": "a".repeat(1024),
+        }));
+        let (mut client, mut server) = duplex(4096);
+        token::write_token(&mut client, token.as_bytes())
+            .await
+            .unwrap();
+        let principal = verifier
+            .validate(&ctx, Version::V5, BEARER_TOKEN, &mut server)
+            .await
+            .unwrap();
+        assert_eq!(principal.method, BEARER_TOKEN);
+        token::read_status(&mut client).await.unwrap();
+
+        token::write_token(&mut client, b"invalid").await.unwrap();
+        assert!(verifier
+            .validate(&ctx, Version::V5, BEARER_TOKEN, &mut server)
+            .await
+            .is_err());
+        assert!(token::read_status(&mut client).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn takes_short_tokens_in_the_password() {
+        let verifier = JwtVerifier::new().hs256_key(SECRET);
+        let ctx = testing::auth_context("192.0.2.1:1234");
+        let methods = [AuthMethod::UsernamePassword];
+        assert_eq!(
+            verifier.select(&ctx, &methods).await.unwrap(),
+            AuthMethod::UsernamePassword
+        );
+        let token = hs256(json!({"sub": "alice", "exp": now() + 3600}));
+        let (mut client, mut server) = duplex(1024);
+        let mut request = vec![0x01, 5];
+        request.extend_from_slice(b"token");
+        request.push(token.len() as u8);
+        request.extend_from_slice(token.as_bytes());
+        client.write_all(&request).await.unwrap();
+        let principal = verifier
+            .validate(&ctx, Version::V5, AuthMethod::UsernamePassword, &mut server)
+            .await
+            .unwrap();
+        assert_eq!(principal.username(), Some("alice"));
+        let mut reply = [0; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x01, 0x00]);
+    }
+}
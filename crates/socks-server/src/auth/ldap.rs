@@ -0,0 +1,581 @@
+use crate::auth::{AuthContext, PasswordVerifier, Principal};
+use async_trait::async_trait;
+use ldap3::{dn_escape, ldap_escape, Ldap, LdapConnAsync, LdapConnSettings, LdapError, Scope};
+use ldap3::{ResultEntry, SearchEntry};
+use log::{debug, warn};
+use socks_rs_common::{AuthMethod, Error, Result};
+use std::io;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::time;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_POOL_SIZE: usize = 8;
+/// Result code of a bind with wrong credentials.
+const INVALID_CREDENTIALS: u32 = 49;
+
+enum UserLookup {
+    /// A DN with a `{username}` placeholder.
+    Template(String),
+    /// A base DN and a filter with a `{username}` placeholder.
+    Search { base: String, filter: String },
+}
+
+/// Verifies credentials with a simple bind to an LDAP directory.
+///
+/// The DN of the user is either built from a template, or searched for after
+/// binding with the service credentials, or anonymously. Once the user is bound,
+/// groups may be searched with their rights: the `cn` of the groups found
+/// are joined with `,` in the `groups` attribute of the principal, and the DN
+/// of the user is set in its `dn` attribute.
+///
+/// `ldaps://` URLs and StartTLS verify the server certificate against the
+/// system roots. Connections are kept in a pool and reused, they are bound
+/// again before searching for users so that searches never run with the
+/// rights of the previous user.
+pub struct LdapVerifier {
+    url: String,
+    starttls: bool,
+    timeout: Duration,
+    user: UserLookup,
+    bind_credentials: Option<(String, String)>,
+    group_search: Option<(String, String)>,
+    permits: Semaphore,
+    idle: Mutex<Vec<Ldap>>,
+}
+
+impl LdapVerifier {
+    /// Binds as the DN of `template` with `{username}` replaced by the
+    /// username, e.g. `uid={username},ou=people,dc=example,dc=com`.
+    pub fn with_dn_template<U: Into<String>, T: Into<String>>(url: U, template: T) -> LdapVerifier {
+        LdapVerifier::new(url.into(), UserLookup::Template(template.into()))
+    }
+
+    /// Binds as the DN of the single entry under `base` matching `filter` with
+    /// `{username}` replaced by the username, e.g. `(uid={username})`.
+    pub fn with_user_search<U, B, F>(url: U, base: B, filter: F) -> LdapVerifier
+    where
+        U: Into<String>,
+        B: Into<String>,
+        F: Into<String>,
+    {
+        LdapVerifier::new(
+            url.into(),
+            UserLookup::Search {
+                base: base.into(),
+                filter: filter.into(),
+            },
+        )
+    }
+
+    fn new(url: String, user: UserLookup) -> LdapVerifier {
+        LdapVerifier {
+            url,
+            starttls: false,
+            timeout: DEFAULT_TIMEOUT,
+            user,
+            bind_credentials: None,
+            group_search: None,
+            permits: Semaphore::new(DEFAULT_POOL_SIZE),
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Upgrades `ldap://` connections with StartTLS.
+    pub fn starttls(mut self, enabled: bool) -> Self {
+        self.starttls = enabled;
+        self
+    }
+
+    /// Deadline to connect and to verify credentials, 5 seconds by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Maximum number of connections, 8 by default. Further verifications wait
+    /// for one to be available.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is 0, as verifications would wait forever.
+    pub fn pool_size(mut self, size: usize) -> Self {
+        assert!(size > 0, "the LDAP pool size must be at least 1");
+        self.permits = Semaphore::new(size);
+        self
+    }
+
+    /// Credentials of the service account to search users with, anonymous
+    /// searches are used otherwise.
+    pub fn bind_credentials<D: Into<String>, P: Into<String>>(
+        mut self,
+        dn: D,
+        password: P,
+    ) -> Self {
+        self.bind_credentials = Some((dn.into(), password.into()));
+        self
+    }
+
+    /// Searches the groups of users under `base` with `filter`, in which
+    /// `{dn}` and `{username}` are replaced by the DN and name of the user,
+    /// e.g. `(member={dn})`.
+    pub fn group_search<B: Into<String>, F: Into<String>>(mut self, base: B, filter: F) -> Self {
+        self.group_search = Some((base.into(), filter.into()));
+        self
+    }
+
+    async fn connect(&self) -> io::Result<Ldap> {
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(self.timeout)
+            .set_starttls(self.starttls);
+        let (conn, ldap) = LdapConnAsync::with_settings(settings, &self.url)
+            .await
+            .map_err(ldap_error)?;
+        let url = self.url.clone();
+        tokio::spawn(async move {
+            if let Err(e) = conn.drive().await {
+                warn!("LDAP connection to {} failed: {}", url, e);
+            }
+        });
+        debug!("Connected to LDAP server {}", self.url);
+        Ok(ldap)
+    }
+
+    async fn user_dn(&self, ldap: &mut Ldap, username: &str) -> Result<String> {
+        let (base, filter) = match &self.user {
+            UserLookup::Template(template) => {
+                return Ok(template.replace("{username}", &dn_escape(username)));
+            }
+            UserLookup::Search { base, filter } => (base, filter),
+        };
+        // Pooled connections are still bound as the last user.
+        let (dn, password) = match &self.bind_credentials {
+            Some((dn, password)) => (dn.as_str(), password.as_str()),
+            None => ("", ""),
+        };
+        ldap.simple_bind(dn, password)
+            .await
+            .and_then(|res| res.success())
+            .map_err(ldap_error)?;
+        let filter = filter.replace("{username}", &ldap_escape(username));
+        let entries = search(ldap, base, &filter, &["1.1"]).await?;
+        match <[_; 1]>::try_from(entries) {
+            Ok([entry]) => Ok(SearchEntry::construct(entry).dn),
+            Err(entries) => Err(Error::AuthFailed(format!(
+                "{} LDAP entries match user {}",
+                entries.len(),
+                username
+            ))),
+        }
+    }
+
+    async fn authenticate(
+        &self,
+        ldap: &mut Ldap,
+        username: &str,
+        password: &str,
+    ) -> Result<Principal> {
+        let dn = self.user_dn(ldap, username).await?;
+        let res = ldap.simple_bind(&dn, password).await.map_err(ldap_error)?;
+        match res.rc {
+            0 => {}
+            INVALID_CREDENTIALS => {
+                return Err(Error::AuthFailed("incorrect credentials".to_owned()));
+            }
+            _ => return Err(ldap_error(LdapError::from(res)).into()),
+        }
+        let mut principal = Principal::new(username, AuthMethod::UsernamePassword);
+        if let Some((base, filter)) = &self.group_search {
+            let filter = filter
+                .replace("{dn}", &ldap_escape(dn.as_str()))
+                .replace("{username}", &ldap_escape(username));
+            let entries = search(ldap, base, &filter, &["cn"]).await?;
+            let groups = entries
+                .into_iter()
+                .filter_map(|entry| {
+                    let mut entry = SearchEntry::construct(entry);
+                    entry
+                        .attrs
+                        .remove("cn")
+                        .and_then(|cn| cn.into_iter().next())
+                })
+                .collect::<Vec<_>>();
+            principal = principal.with_attribute("groups", groups.join(","));
+        }
+        Ok(principal.with_attribute("dn", dn))
+    }
+}
+
+#[async_trait]
+impl PasswordVerifier for LdapVerifier {
+    async fn verify(
+        &self,
+        _ctx: &AuthContext,
+        username: &str,
+        password: &str,
+    ) -> Result<Principal> {
+        // A simple bind without a password is an unauthenticated bind, which
+        // directories accept whatever the DN.
+        if password.is_empty() {
+            return Err(Error::AuthFailed("empty password".to_owned()));
+        }
+        let _permit = self.permits.acquire().await.map_err(io::Error::other)?;
+        let res = time::timeout(self.timeout, async {
+            let idle = self.idle.lock().unwrap().pop();
+            let mut ldap = match idle {
+                Some(mut ldap) => {
+                    if ldap.is_closed() {
+                        self.connect().await?
+                    } else {
+                        ldap
+                    }
+                }
+                None => self.connect().await?,
+            };
+            let res = self.authenticate(&mut ldap, username, password).await;
+            // Connections in an unknown state are dropped.
+            if matches!(res, Ok(_) | Err(Error::AuthFailed(_))) {
+                self.idle.lock().unwrap().push(ldap);
+            }
+            res
+        })
+        .await;
+        res.map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "LDAP server timed out"))?
+    }
+}
+
+async fn search(
+    ldap: &mut Ldap,
+    base: &str,
+    filter: &str,
+    attrs: &[&str],
+) -> Result<Vec<ResultEntry>> {
+    let (entries, _) = ldap
+        .search(base, Scope::Subtree, filter, attrs.to_vec())
+        .await
+        .and_then(|res| res.success())
+        .map_err(ldap_error)?;
+    Ok(entries)
+}
+
+fn ldap_error(e: LdapError) -> io::Error {
+    io::Error::other(format!("LDAP error: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    const BASE: &str = "dc=example,dc=com";
+    const SERVICE: &str = "cn=service,dc=example,dc=com";
+    const ALICE: &str = "uid=alice,ou=people,dc=example,dc=com";
+
+    /// An entry of the stand-in directory: DN, password and attributes.
+    type Entry = (
+        &'static str,
+        Option<&'static str>,
+        Vec<(&'static str, &'static str)>,
+    );
+    /// The DN bound when each search ran, if any, and its filter.
+    type Searches = Arc<Mutex<Vec<(Option<String>, String)>>>;
+
+    fn directory() -> Vec<Entry> {
+        vec![
+            (SERVICE, Some("service-secret"), vec![("cn", "service")]),
+            (ALICE, Some("secret"), vec![("uid", "alice")]),
+            (
+                "uid=bob,ou=people,dc=example,dc=com",
+                Some("hunter2"),
+                vec![("uid", "bob")],
+            ),
+            (
+                "cn=staff,ou=groups,dc=example,dc=com",
+                None,
+                vec![("cn", "staff"), ("member", ALICE)],
+            ),
+            (
+                "cn=vpn,ou=groups,dc=example,dc=com",
+                None,
+                vec![("cn", "vpn"), ("member", ALICE)],
+            ),
+        ]
+    }
+
+    /// An in-process LDAP server answering simple binds and equality searches.
+    struct StandIn {
+        url: String,
+        connections: Arc<AtomicUsize>,
+        searches: Searches,
+    }
+
+    fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut res = vec![tag];
+        match content.len() {
+            len @ 0..=0x7f => res.push(len as u8),
+            len => {
+                let bytes = (len as u32).to_be_bytes();
+                let skip = bytes.iter().take_while(|b| **b == 0).count();
+                res.push(0x80 | (4 - skip) as u8);
+                res.extend_from_slice(&bytes[skip..]);
+            }
+        }
+        res.extend_from_slice(content);
+        res
+    }
+
+    fn integer(tag: u8, value: u32) -> Vec<u8> {
+        let bytes = value.to_be_bytes();
+        let mut start = 0;
+        while start < 3 && bytes[start] == 0 && bytes[start + 1] < 0x80 {
+            start += 1;
+        }
+        tlv(tag, &bytes[start..])
+    }
+
+    /// Splits the first element of `buf` into its tag and content.
+    fn element(buf: &[u8]) -> (u8, &[u8], &[u8]) {
+        let (len, header) = if buf[1] < 0x80 {
+            (buf[1] as usize, 2)
+        } else {
+            let n = (buf[1] & 0x7f) as usize;
+            let len = buf[2..2 + n]
+                .iter()
+                .fold(0, |len, b| len << 8 | *b as usize);
+            (len, 2 + n)
+        };
+        (buf[0], &buf[header..header + len], &buf[header + len..])
+    }
+
+    fn elements(mut buf: &[u8]) -> Vec<(u8, &[u8])> {
+        let mut res = Vec::new();
+        while !buf.is_empty() {
+            let (tag, content, rest) = element(buf);
+            res.push((tag, content));
+            buf = rest;
+        }
+        res
+    }
+
+    fn string(buf: &[u8]) -> String {
+        String::from_utf8(buf.to_vec()).unwrap()
+    }
+
+    fn result(tag: u8, code: u8) -> Vec<u8> {
+        let content = [integer(0x0a, code.into()), tlv(0x04, b""), tlv(0x04, b"")].concat();
+        tlv(tag, &content)
+    }
+
+    async fn read_message(stream: &mut TcpStream) -> Option<Vec<u8>> {
+        let mut header = [0; 2];
+        stream.read_exact(&mut header).await.ok()?;
+        let len = if header[1] < 0x80 {
+            header[1] as usize
+        } else {
+            let mut len = vec![0; (header[1] & 0x7f) as usize];
+            stream.read_exact(&mut len).await.ok()?;
+            len.iter().fold(0, |len, b| len << 8 | *b as usize)
+        };
+        let mut content = vec![0; len];
+        stream.read_exact(&mut content).await.ok()?;
+        Some(content)
+    }
+
+    async fn serve(mut stream: TcpStream, searches: Searches) -> Option<()> {
+        let directory = directory();
+        let mut bound = None;
+        loop {
+            let message = read_message(&mut stream).await?;
+            let message = elements(&message);
+            let id = message[0].1.iter().fold(0, |id, b| id << 8 | *b as u32);
+            let (op, request) = message[1];
+            let mut replies = Vec::new();
+            match op {
+                // BindRequest
+                0x60 => {
+                    let fields = elements(request);
+                    let dn = string(fields[1].1);
+                    let password = string(fields[2].1);
+                    let valid = directory
+                        .iter()
+                        .any(|(entry, pw, _)| *entry == dn && *pw == Some(password.as_str()));
+                    bound = None;
+                    let code = if dn.is_empty() && password.is_empty() {
+                        0
+                    } else if valid {
+                        bound = Some(dn);
+                        0
+                    } else {
+                        INVALID_CREDENTIALS as u8
+                    };
+                    replies.push(result(0x61, code));
+                }
+                // UnbindRequest
+                0x42 => return Some(()),
+                // SearchRequest
+                0x63 => {
+                    let fields = elements(request);
+                    let base = string(fields[0].1);
+                    let filter = elements(fields[6].1);
+                    let (attribute, value) = (string(filter[0].1), string(filter[1].1));
+                    let wanted = elements(fields[7].1)
+                        .into_iter()
+                        .map(|(_, name)| string(name))
+                        .collect::<Vec<_>>();
+                    searches
+                        .lock()
+                        .unwrap()
+                        .push((bound.clone(), format!("({}={})", attribute, value)));
+                    for (dn, _, attributes) in &directory {
+                        let matches = dn.ends_with(&base)
+                            && attributes
+                                .iter()
+                                .any(|(name, v)| *name == attribute && *v == value);
+                        if !matches {
+                            continue;
+                        }
+                        let attributes = attributes
+                            .iter()
+                            .filter(|(name, _)| wanted.iter().any(|w| w == name))
+                            .map(|(name, v)| {
+                                let content = [
+                                    tlv(0x04, name.as_bytes()),
+                                    tlv(0x31, &tlv(0x04, v.as_bytes())),
+                                ]
+                                .concat();
+                                tlv(0x30, &content)
+                            })
+                            .collect::<Vec<_>>()
+                            .concat();
+                        let content = [tlv(0x04, dn.as_bytes()), tlv(0x30, &attributes)].concat();
+                        replies.push(tlv(0x64, &content));
+                    }
+                    replies.push(result(0x65, 0));
+                }
+                op => panic!("unexpected LDAP operation {:#x}", op),
+            }
+            for reply in replies {
+                let message = tlv(0x30, &[integer(0x02, id), reply].concat());
+                stream.write_all(&message).await.ok()?;
+            }
+        }
+    }
+
+    async fn stand_in() -> StandIn {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ldap://{}", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let searches = Arc::new(Mutex::new(Vec::new()));
+        let (counter, log) = (connections.clone(), searches.clone());
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(serve(stream, log.clone()));
+            }
+        });
+        StandIn {
+            url,
+            connections,
+            searches,
+        }
+    }
+
+    async fn verify(verifier: &LdapVerifier, username: &str, password: &str) -> Result<Principal> {
+        let ctx = testing::auth_context("192.0.2.1:1234");
+        verifier.verify(&ctx, username, password).await
+    }
+
+    #[tokio::test]
+    async fn binds_with_a_dn_template() {
+        let directory = stand_in().await;
+        let verifier = LdapVerifier::with_dn_template(
+            directory.url.as_str(),
+            "uid={username},ou=people,dc=example,dc=com",
+        );
+        let principal = verify(&verifier, "alice", "secret").await.unwrap();
+        assert_eq!(principal.username(), Some("alice"));
+        assert_eq!(principal.method, AuthMethod::UsernamePassword);
+        assert_eq!(principal.attributes["dn"], ALICE);
+        assert!(!principal.attributes.contains_key("groups"));
+
+        assert!(matches!(
+            verify(&verifier, "alice", "wrong").await,
+            Err(Error::AuthFailed(_))
+        ));
+        assert!(matches!(
+            verify(&verifier, "alice", "").await,
+            Err(Error::AuthFailed(_))
+        ));
+        // Special characters can't alter the DN.
+        assert!(matches!(
+            verify(&verifier, "alice,ou=people", "secret").await,
+            Err(Error::AuthFailed(_))
+        ));
+        assert_eq!(directory.connections.load(Ordering::SeqCst), 1);
+        assert!(directory.searches.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn searches_users_and_groups() {
+        let directory = stand_in().await;
+        let verifier =
+            LdapVerifier::with_user_search(directory.url.as_str(), BASE, "(uid={username})")
+                .bind_credentials(SERVICE, "service-secret")
+                .group_search("ou=groups,dc=example,dc=com", "(member={dn})");
+        let principal = verify(&verifier, "alice", "secret").await.unwrap();
+        assert_eq!(principal.username(), Some("alice"));
+        assert_eq!(principal.attributes["dn"], ALICE);
+        assert_eq!(principal.attributes["groups"], "staff,vpn");
+        assert_eq!(
+            *directory.searches.lock().unwrap(),
+            [
+                (Some(SERVICE.to_owned()), "(uid=alice)".to_owned()),
+                (Some(ALICE.to_owned()), format!("(member={})", ALICE)),
+            ]
+        );
+
+        let principal = verify(&verifier, "bob", "hunter2").await.unwrap();
+        assert_eq!(principal.attributes["groups"], "");
+        assert!(matches!(
+            verify(&verifier, "bob", "secret").await,
+            Err(Error::AuthFailed(_))
+        ));
+        assert!(matches!(
+            verify(&verifier, "carol", "secret").await,
+            Err(Error::AuthFailed(_))
+        ));
+        // Wildcards are escaped rather than matching every user.
+        assert!(matches!(
+            verify(&verifier, "*", "secret").await,
+            Err(Error::AuthFailed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rebinds_pooled_connections() {
+        let directory = stand_in().await;
+        let verifier =
+            LdapVerifier::with_user_search(directory.url.as_str(), BASE, "(uid={username})")
+                .pool_size(1);
+        verify(&verifier, "alice", "secret").await.unwrap();
+        verify(&verifier, "bob", "hunter2").await.unwrap();
+        verify(&verifier, "bob", "wrong").await.unwrap_err();
+        verify(&verifier, "alice", "secret").await.unwrap();
+        assert_eq!(directory.connections.load(Ordering::SeqCst), 1);
+        let searches = directory.searches.lock().unwrap();
+        assert_eq!(searches.len(), 4);
+        assert!(searches.iter().all(|(bound, _)| bound.is_none()));
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejects_empty_pools() {
+        let _ = LdapVerifier::with_dn_template("ldap://localhost", "uid={username}").pool_size(0);
+    }
+}
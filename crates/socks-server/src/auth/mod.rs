@@ -0,0 +1,219 @@
+use crate::rules::Destination;
+use async_trait::async_trait;
+use socks_rs_common::{AuthMethod, Error, Result, TargetAddr, Version};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+mod certificate;
+mod checkpassword;
+mod composite;
+#[cfg(feature = "credentials")]
+mod credentials;
+mod guard;
+#[cfg(feature = "jwt")]
+mod jwt;
+#[cfg(feature = "ldap")]
+mod ldap;
+mod password;
+#[cfg(feature = "radius")]
+mod radius;
+mod routing;
+#[cfg(feature = "scram")]
+mod scram;
+
+pub use certificate::{CertificateAuthProvider, CertificateField, ClientCertificate};
+pub use checkpassword::{CheckPassword, CheckPasswordInput};
+pub use composite::{AuthCondition, CompositeAuthProvider};
+#[cfg(feature = "credentials")]
+pub use credentials::CredentialStore;
+pub use guard::{Lockout, LockoutKey, LoginGuard, LoginGuardConfig};
+#[cfg(feature = "jwt")]
+pub use jwt::JwtVerifier;
+#[cfg(feature = "ldap")]
+pub use ldap::LdapVerifier;
+pub use password::{PasswordAuthProvider, PasswordVerifier};
+#[cfg(feature = "radius")]
+pub use radius::RadiusVerifier;
+pub use routing::{RoutingVerifier, UsernameParser};
+#[cfg(feature = "scram")]
+pub use scram::{ScramAuthProvider, ScramCredential, ScramCredentialStore};
+
+#[async_trait]
+pub trait AuthProvider {
+    async fn select(&self, ctx: &AuthContext, methods: &[AuthMethod]) -> Result<AuthMethod>;
+    /// Authenticates the client and returns who it is.
+    async fn validate<IO: AsyncRead + AsyncWrite + Send + Unpin>(
+        &self,
+        ctx: &AuthContext,
+        version: Version,
+        method: AuthMethod,
+        io: &mut IO,
+    ) -> Result<Principal>;
+}
+
+/// What is known about a client while it authenticates.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AuthContext {
+    pub peer_addr: SocketAddr,
+    /// The address the client connected to.
+    pub local_addr: SocketAddr,
+    /// The verified certificate of clients connected over TLS with one.
+    pub client_certificate: Option<ClientCertificate>,
+}
+
+/// The identity of a client, as established by its `AuthProvider`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Principal {
+    /// `None` for clients that didn't authenticate.
+    pub username: Option<String>,
+    pub method: AuthMethod,
+    /// Provider specific information, e.g. groups or token claims.
+    pub attributes: HashMap<String, String>,
+    /// Destinations the client is restricted to, on top of the server's rules.
+    pub allowed_destinations: Option<Vec<Destination>>,
+    /// Maximum lifetime of the sessions of the client, on top of the server's
+    /// timeouts.
+    pub session_timeout: Option<Duration>,
+    /// Routing parameters passed along with the username, see
+    /// `UsernameParser`.
+    pub parameters: HashMap<String, String>,
+}
+
+impl Principal {
+    pub fn new<T: Into<String>>(username: T, method: AuthMethod) -> Principal {
+        Principal {
+            username: Some(username.into()),
+            method,
+            attributes: HashMap::new(),
+            allowed_destinations: None,
+            session_timeout: None,
+            parameters: HashMap::new(),
+        }
+    }
+
+    pub fn anonymous() -> Principal {
+        Principal {
+            username: None,
+            method: AuthMethod::None,
+            attributes: HashMap::new(),
+            allowed_destinations: None,
+            session_timeout: None,
+            parameters: HashMap::new(),
+        }
+    }
+
+    pub fn with_attribute<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.attributes.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
+    /// Whether `addr` is among the allowed destinations, if restricted.
+    pub fn may_reach(&self, addr: &TargetAddr) -> bool {
+        match &self.allowed_destinations {
+            Some(destinations) => destinations.iter().any(|dst| dst.matches(addr)),
+            None => true,
+        }
+    }
+}
+
+impl fmt::Display for Principal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.username {
+            Some(username) => write!(f, "{} ({:?})", username, self.method),
+            None => write!(f, "anonymous"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PlainAuthProvider;
+
+impl PlainAuthProvider {
+    pub fn new() -> PlainAuthProvider {
+        PlainAuthProvider {}
+    }
+}
+
+#[async_trait]
+impl AuthProvider for PlainAuthProvider {
+    async fn select(&self, _ctx: &AuthContext, methods: &[AuthMethod]) -> Result<AuthMethod> {
+        let res = methods.iter().find(|&&x| x == AuthMethod::None);
+        if res.is_some() {
+            Ok(AuthMethod::None)
+        } else {
+            Err(Error::AuthMethodNotSupported(0xff))
+        }
+    }
+
+    async fn validate<IO: AsyncRead + AsyncWrite + Send + Unpin>(
+        &self,
+        _ctx: &AuthContext,
+        _version: Version,
+        _method: AuthMethod,
+        _io: &mut IO,
+    ) -> Result<Principal> {
+        Ok(Principal::anonymous())
+    }
+}
+
+#[derive(Debug)]
+pub struct BasicAuthProvider {
+    username: String,
+    password: String,
+}
+
+impl BasicAuthProvider {
+    pub fn new(username: &str, password: &str) -> BasicAuthProvider {
+        BasicAuthProvider {
+            username: username.to_owned(),
+            password: password.to_owned(),
+        }
+    }
+}
+
+#[async_trait]
+impl AuthProvider for BasicAuthProvider {
+    async fn select(&self, _ctx: &AuthContext, methods: &[AuthMethod]) -> Result<AuthMethod> {
+        let res = methods.iter().find(|&&x| x == AuthMethod::UsernamePassword);
+        if res.is_some() {
+            return Ok(AuthMethod::UsernamePassword);
+        }
+        Err(Error::AuthMethodNotSupported(0xff))
+    }
+
+    async fn validate<IO: AsyncRead + AsyncWrite + Send + Unpin>(
+        &self,
+        ctx: &AuthContext,
+        version: Version,
+        method: AuthMethod,
+        conn: &mut IO,
+    ) -> Result<Principal> {
+        password::validate(self, ctx, version, method, conn).await
+    }
+}
+
+#[async_trait]
+impl PasswordVerifier for BasicAuthProvider {
+    async fn verify(
+        &self,
+        _ctx: &AuthContext,
+        username: &str,
+        password: &str,
+    ) -> Result<Principal> {
+        let username_ok = self.username.as_bytes().ct_eq(username.as_bytes());
+        let password_ok = self.password.as_bytes().ct_eq(password.as_bytes());
+        if (username_ok & password_ok).into() {
+            Ok(Principal::new(username, AuthMethod::UsernamePassword))
+        } else {
+            Err(Error::AuthFailed("incorrect credentials".to_owned()))
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use crate::auth::{AuthContext, AuthProvider, Principal};
+use async_trait::async_trait;
+use socks_rs_common::{AuthMethod, Error, Result, Version};
+use tokio::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Version of the username/password sub-negotiation.
+const SUBNEGOTIATION_VERSION: u8 = 0x01;
+
+/// Checks the credentials of the username/password method (RFC 1929).
+#[async_trait]
+pub trait PasswordVerifier {
+    /// Returns the principal of the user, or `Error::AuthFailed` if the
+    /// credentials are rejected.
+    async fn verify(&self, ctx: &AuthContext, username: &str, password: &str) -> Result<Principal>;
+}
+
+#[async_trait]
+impl<V: PasswordVerifier + Send + Sync + ?Sized> PasswordVerifier for std::sync::Arc<V> {
+    async fn verify(&self, ctx: &AuthContext, username: &str, password: &str) -> Result<Principal> {
+        (**self).verify(ctx, username, password).await
+    }
+}
+
+/// Authenticates clients with the username/password method, against a
+/// `PasswordVerifier`.
+#[derive(Debug)]
+pub struct PasswordAuthProvider<V> {
+    verifier: V,
+}
+
+impl<V: PasswordVerifier> PasswordAuthProvider<V> {
+    pub fn new(verifier: V) -> PasswordAuthProvider<V> {
+        PasswordAuthProvider { verifier }
+    }
+
+    pub fn verifier(&self) -> &V {
+        &self.verifier
+    }
+}
+
+#[async_trait]
+impl<V: PasswordVerifier + Send + Sync> AuthProvider for PasswordAuthProvider<V> {
+    async fn select(&self, _ctx: &AuthContext, methods: &[AuthMethod]) -> Result<AuthMethod> {
+        if methods.contains(&AuthMethod::UsernamePassword) {
+            return Ok(AuthMethod::UsernamePassword);
+        }
+        Err(Error::AuthMethodNotSupported(0xff))
+    }
+
+    async fn validate<IO: AsyncRead + AsyncWrite + Send + Unpin>(
+        &self,
+        ctx: &AuthContext,
+        version: Version,
+        method: AuthMethod,
+        conn: &mut IO,
+    ) -> Result<Principal> {
+        validate(&self.verifier, ctx, version, method, conn).await
+    }
+}
+
+/// Runs the username/password sub-negotiation and checks the credentials with
+/// `verifier`.
+pub(crate) async fn validate<V, IO>(
+    verifier: &V,
+    ctx: &AuthContext,
+    version: Version,
+    method: AuthMethod,
+    conn: &mut IO,
+) -> Result<Principal>
+where
+    V: PasswordVerifier + Sync + ?Sized,
+    IO: AsyncRead + AsyncWrite + Send + Unpin,
+{
+    let (mut inbound, mut outbound) = io::split(conn);
+    if version != Version::V5 {
+        auth_respond(false, &mut outbound).await?;
+        return Err(Error::VersionNotSupported(version.into()));
+    }
+    if method != AuthMethod::UsernamePassword {
+        auth_respond(false, &mut outbound).await?;
+        return Err(Error::AuthMethodNotSupported(method.into()));
+    }
+    let (username, password) = read_credentials(&mut inbound).await?;
+    let res = match (String::from_utf8(username), String::from_utf8(password)) {
+        (Ok(username), Ok(password)) => verifier.verify(ctx, &username, &password).await,
+        _ => Err(Error::AuthFailed(
+            "credentials aren't valid UTF-8".to_owned(),
+        )),
+    };
+    auth_respond(res.is_ok(), &mut outbound).await?;
+    res
+}
+
+async fn read_credentials<R>(inbound: &mut R) -> Result<(Vec<u8>, Vec<u8>)>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let auth_version_raw = inbound.read_u8().await?;
+    if auth_version_raw != SUBNEGOTIATION_VERSION {
+        return Err(Error::VersionNotSupported(auth_version_raw));
+    }
+    let ulen = inbound.read_u8().await?;
+    let mut username = vec![0; ulen as usize];
+    inbound.read_exact(&mut username).await?;
+    let plen = inbound.read_u8().await?;
+    let mut password = vec![0; plen as usize];
+    inbound.read_exact(&mut password).await?;
+    Ok((username, password))
+}
+
+async fn auth_respond<T>(success: bool, outbound: &mut T) -> Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+{
+    let res = if success { 0x00 } else { 0x01 };
+    outbound.write_all(&[SUBNEGOTIATION_VERSION, res]).await?;
+    Ok(())
+}
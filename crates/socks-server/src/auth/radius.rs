@@ -0,0 +1,613 @@
+use crate::auth::{AuthContext, PasswordVerifier, Principal};
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use log::{debug, warn};
+use md5::{Digest, Md5};
+use socks_rs_common::{AuthMethod, Error, Result};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use tokio::net::UdpSocket;
+use tokio::time::{self, Instant};
+
+const ACCESS_REQUEST: u8 = 1;
+const ACCESS_ACCEPT: u8 = 2;
+const ACCESS_REJECT: u8 = 3;
+const ACCESS_CHALLENGE: u8 = 11;
+
+const USER_NAME: u8 = 1;
+const USER_PASSWORD: u8 = 2;
+const FILTER_ID: u8 = 11;
+const REPLY_MESSAGE: u8 = 18;
+const CLASS: u8 = 25;
+const SESSION_TIMEOUT: u8 = 27;
+const CALLING_STATION_ID: u8 = 31;
+const NAS_IDENTIFIER: u8 = 32;
+const MESSAGE_AUTHENTICATOR: u8 = 80;
+
+const HEADER_LEN: usize = 20;
+const MAX_PACKET_LEN: usize = 4096;
+const MAX_ATTRIBUTE_LEN: usize = 253;
+const MAX_PASSWORD_LEN: usize = 128;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+const DEFAULT_RETRIES: u32 = 2;
+const DEFAULT_NAS_IDENTIFIER: &str = "socks-rs";
+
+#[derive(Clone)]
+struct RadiusServer {
+    addr: SocketAddr,
+    secret: Vec<u8>,
+}
+
+/// Verifies credentials with RADIUS servers (RFC 2865), sending PAP
+/// Access-Requests.
+///
+/// Servers are tried in the order they were added: each request is
+/// retransmitted `retries` times before failing over to the next server.
+/// Requests carry a Message-Authenticator, and replies must carry a valid one
+/// too so that they can't be forged by tampering with a genuine reply
+/// (Blast-RADIUS, CVE-2024-3596).
+///
+/// The Filter-Id and Class attributes of Access-Accepts are copied into the
+/// `filter_id` and `class` attributes of the principal, several values being
+/// joined with `,`, and Session-Timeout limits the lifetime of the session.
+pub struct RadiusVerifier {
+    servers: Vec<RadiusServer>,
+    timeout: Duration,
+    retries: u32,
+    nas_identifier: String,
+    require_message_authenticator: bool,
+}
+
+impl Default for RadiusVerifier {
+    fn default() -> Self {
+        RadiusVerifier {
+            servers: Vec::new(),
+            timeout: DEFAULT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            nas_identifier: DEFAULT_NAS_IDENTIFIER.to_owned(),
+            require_message_authenticator: true,
+        }
+    }
+}
+
+impl RadiusVerifier {
+    /// A verifier without any server, waiting 3 seconds for replies and
+    /// retransmitting twice.
+    pub fn new() -> RadiusVerifier {
+        RadiusVerifier::default()
+    }
+
+    /// Adds a server and the secret shared with it.
+    pub fn server<S: Into<Vec<u8>>>(mut self, addr: SocketAddr, secret: S) -> Self {
+        self.servers.push(RadiusServer {
+            addr,
+            secret: secret.into(),
+        });
+        self
+    }
+
+    /// How long to wait for a reply before retransmitting.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Retransmissions to a server before failing over to the next one.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// The NAS-Identifier of requests, `socks-rs` by default.
+    pub fn nas_identifier<T: Into<String>>(mut self, nas_identifier: T) -> Self {
+        self.nas_identifier = nas_identifier.into();
+        self
+    }
+
+    /// Whether replies must carry a Message-Authenticator, `true` by default.
+    ///
+    /// Only disable it for servers that can't be upgraded: without it, an
+    /// attacker on the path can turn an Access-Reject into an Access-Accept.
+    pub fn require_message_authenticator(mut self, require: bool) -> Self {
+        self.require_message_authenticator = require;
+        self
+    }
+
+    fn request(
+        &self,
+        server: &RadiusServer,
+        id: u8,
+        authenticator: &[u8; 16],
+        ctx: &AuthContext,
+        username: &str,
+        password: &str,
+    ) -> Result<Vec<u8>> {
+        let mut packet = vec![ACCESS_REQUEST, id, 0, 0];
+        packet.extend_from_slice(authenticator);
+        // Coming first, the Message-Authenticator can't be pushed out of the
+        // packet by a collision.
+        let offset = packet.len() + 2;
+        put_attribute(&mut packet, MESSAGE_AUTHENTICATOR, &[0; 16])?;
+        put_attribute(&mut packet, USER_NAME, username.as_bytes())?;
+        let password = hide_password(password.as_bytes(), &server.secret, authenticator)?;
+        put_attribute(&mut packet, USER_PASSWORD, &password)?;
+        put_attribute(&mut packet, NAS_IDENTIFIER, self.nas_identifier.as_bytes())?;
+        let client = ctx.peer_addr.ip().to_string();
+        put_attribute(&mut packet, CALLING_STATION_ID, client.as_bytes())?;
+        let len = packet.len() as u16;
+        packet[2..4].copy_from_slice(&len.to_be_bytes());
+        let mac = hmac_md5(&server.secret, &packet);
+        packet[offset..offset + 16].copy_from_slice(&mac);
+        Ok(packet)
+    }
+
+    /// Sends `request` to `server` until a valid reply is received or the
+    /// retries are exhausted.
+    async fn exchange(
+        &self,
+        server: &RadiusServer,
+        request: &[u8],
+        authenticator: &[u8; 16],
+    ) -> io::Result<Reply> {
+        let local: SocketAddr = if server.addr.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(server.addr).await?;
+        let mut buf = vec![0; MAX_PACKET_LEN];
+        for _ in 0..=self.retries {
+            socket.send(request).await?;
+            let deadline = Instant::now() + self.timeout;
+            // Replies failing verification are dropped, as if they were lost.
+            while let Ok(res) = time::timeout_at(deadline, socket.recv(&mut buf)).await {
+                let len = res?;
+                let reply = Reply::parse(
+                    &buf[..len],
+                    request[1],
+                    authenticator,
+                    &server.secret,
+                    self.require_message_authenticator,
+                );
+                match reply {
+                    Some(reply) => return Ok(reply),
+                    None => warn!("Dropped invalid RADIUS reply from {}", server.addr),
+                }
+            }
+            debug!("RADIUS server {} didn't reply in time", server.addr);
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("RADIUS server {} didn't reply", server.addr),
+        ))
+    }
+}
+
+#[async_trait]
+impl PasswordVerifier for RadiusVerifier {
+    async fn verify(&self, ctx: &AuthContext, username: &str, password: &str) -> Result<Principal> {
+        let id = rand::random::<u8>();
+        let authenticator = rand::random::<[u8; 16]>();
+        let mut last_err = None;
+        for server in &self.servers {
+            let request = self.request(server, id, &authenticator, ctx, username, password)?;
+            match self.exchange(server, &request, &authenticator).await {
+                Ok(reply) => return reply.principal(username),
+                Err(e) => {
+                    warn!("RADIUS server {} failed: {}", server.addr, e);
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err
+            .unwrap_or_else(|| io::Error::other("no RADIUS server configured"))
+            .into())
+    }
+}
+
+struct Reply {
+    code: u8,
+    attributes: Vec<(u8, Vec<u8>)>,
+}
+
+impl Reply {
+    /// Parses `packet` if it is a reply to the request `id` authenticated with
+    /// `secret`, and carries a Message-Authenticator if `require`d.
+    fn parse(
+        packet: &[u8],
+        id: u8,
+        authenticator: &[u8; 16],
+        secret: &[u8],
+        require: bool,
+    ) -> Option<Reply> {
+        if packet.len() < HEADER_LEN || packet[1] != id {
+            return None;
+        }
+        let len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+        if len < HEADER_LEN || len > packet.len() {
+            return None;
+        }
+        let packet = &packet[..len];
+        let expected = Md5::new()
+            .chain_update(&packet[..4])
+            .chain_update(authenticator)
+            .chain_update(&packet[HEADER_LEN..])
+            .chain_update(secret)
+            .finalize();
+        if !bool::from(expected.as_slice().ct_eq(&packet[4..HEADER_LEN])) {
+            return None;
+        }
+        let mut attributes = Vec::new();
+        let mut authenticated = false;
+        let mut offset = HEADER_LEN;
+        while offset < len {
+            let attribute_len = *packet.get(offset + 1)? as usize;
+            if attribute_len < 2 || offset + attribute_len > len {
+                return None;
+            }
+            let value = &packet[offset + 2..offset + attribute_len];
+            if packet[offset] == MESSAGE_AUTHENTICATOR {
+                if value.len() != 16 {
+                    return None;
+                }
+                let mut copy = packet.to_vec();
+                copy[4..HEADER_LEN].copy_from_slice(authenticator);
+                copy[offset + 2..offset + attribute_len].fill(0);
+                if !bool::from(hmac_md5(secret, &copy).ct_eq(value)) {
+                    return None;
+                }
+                authenticated = true;
+            }
+            attributes.push((packet[offset], value.to_vec()));
+            offset += attribute_len;
+        }
+        if require && !authenticated {
+            return None;
+        }
+        Some(Reply {
+            code: packet[0],
+            attributes,
+        })
+    }
+
+    fn values(&self, kind: u8) -> impl Iterator<Item = &[u8]> {
+        self.attributes
+            .iter()
+            .filter(move |(t, _)| *t == kind)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    fn strings(&self, kind: u8) -> Option<String> {
+        let values = self
+            .values(kind)
+            .filter_map(|value| std::str::from_utf8(value).ok())
+            .collect::<Vec<_>>();
+        if values.is_empty() {
+            None
+        } else {
+            Some(values.join(","))
+        }
+    }
+
+    fn principal(&self, username: &str) -> Result<Principal> {
+        match self.code {
+            ACCESS_ACCEPT => {
+                let mut principal = Principal::new(username, AuthMethod::UsernamePassword);
+                for (kind, name) in [(FILTER_ID, "filter_id"), (CLASS, "class")] {
+                    if let Some(value) = self.strings(kind) {
+                        principal.attributes.insert(name.to_owned(), value);
+                    }
+                }
+                principal.session_timeout = self
+                    .values(SESSION_TIMEOUT)
+                    .find_map(|value| <[u8; 4]>::try_from(value).ok())
+                    .map(|secs| Duration::from_secs(u32::from_be_bytes(secs).into()));
+                Ok(principal)
+            }
+            ACCESS_REJECT => Err(Error::AuthFailed(match self.strings(REPLY_MESSAGE) {
+                Some(message) => format!("rejected by RADIUS server: {}", message),
+                None => "rejected by RADIUS server".to_owned(),
+            })),
+            ACCESS_CHALLENGE => Err(Error::AuthFailed(
+                "RADIUS challenges aren't supported".to_owned(),
+            )),
+            code => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected RADIUS reply code {}", code),
+            )
+            .into()),
+        }
+    }
+}
+
+fn put_attribute(packet: &mut Vec<u8>, kind: u8, value: &[u8]) -> Result<()> {
+    if value.len() > MAX_ATTRIBUTE_LEN {
+        return Err(Error::AuthFailed(format!(
+            "RADIUS attribute {} is too long",
+            kind
+        )));
+    }
+    packet.push(kind);
+    packet.push(value.len() as u8 + 2);
+    packet.extend_from_slice(value);
+    Ok(())
+}
+
+/// Hides a User-Password as described in section 5.2 of RFC 2865.
+fn hide_password(password: &[u8], secret: &[u8], authenticator: &[u8; 16]) -> Result<Vec<u8>> {
+    if password.len() > MAX_PASSWORD_LEN {
+        return Err(Error::AuthFailed("password is too long".to_owned()));
+    }
+    let mut hidden = password.to_vec();
+    hidden.resize(password.len().div_ceil(16).max(1) * 16, 0);
+    let mut previous = authenticator.to_vec();
+    for chunk in hidden.chunks_mut(16) {
+        let digest = Md5::new()
+            .chain_update(secret)
+            .chain_update(&previous)
+            .finalize();
+        for (b, d) in chunk.iter_mut().zip(digest) {
+            *b ^= d;
+        }
+        previous = chunk.to_vec();
+    }
+    Ok(hidden)
+}
+
+fn hmac_md5(secret: &[u8], data: &[u8]) -> [u8; 16] {
+    let mut mac = Hmac::<Md5>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const SECRET: &[u8] = b"testing123";
+
+    /// How a stand-in server answers.
+    #[derive(Clone)]
+    struct Answer {
+        /// The code replied to alice with password `secret`, others get an
+        /// Access-Reject.
+        code: u8,
+        attributes: Vec<(u8, Vec<u8>)>,
+        message_authenticator: bool,
+        /// Spoils the Response Authenticator.
+        forged: bool,
+    }
+
+    impl Answer {
+        fn new(code: u8) -> Answer {
+            Answer {
+                code,
+                attributes: Vec::new(),
+                message_authenticator: true,
+                forged: false,
+            }
+        }
+    }
+
+    fn attributes(packet: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut attributes = Vec::new();
+        let mut offset = HEADER_LEN;
+        while offset < packet.len() {
+            let len = packet[offset + 1] as usize;
+            attributes.push((packet[offset], packet[offset + 2..offset + len].to_vec()));
+            offset += len;
+        }
+        attributes
+    }
+
+    /// Reverses `hide_password`.
+    fn reveal_password(hidden: &[u8], authenticator: &[u8]) -> Vec<u8> {
+        let mut password = Vec::new();
+        let mut previous = authenticator;
+        for chunk in hidden.chunks(16) {
+            let digest = Md5::new()
+                .chain_update(SECRET)
+                .chain_update(previous)
+                .finalize();
+            password.extend(chunk.iter().zip(digest).map(|(b, d)| b ^ d));
+            previous = chunk;
+        }
+        while password.last() == Some(&0) {
+            password.pop();
+        }
+        password
+    }
+
+    fn reply(request: &[u8], answer: &Answer) -> Vec<u8> {
+        let mut offset = request.len();
+        let attributes = attributes(request);
+        let value = |kind| {
+            attributes
+                .iter()
+                .find(|(t, _)| *t == kind)
+                .map(|(_, value)| value.as_slice())
+        };
+
+        // Requests are authenticated with their first attribute.
+        assert_eq!(attributes[0].0, MESSAGE_AUTHENTICATOR);
+        let mut copy = request.to_vec();
+        copy[HEADER_LEN + 2..HEADER_LEN + 18].fill(0);
+        assert_eq!(
+            hmac_md5(SECRET, &copy),
+            value(MESSAGE_AUTHENTICATOR).unwrap()
+        );
+        assert_eq!(value(NAS_IDENTIFIER), Some(&b"socks-rs"[..]));
+        assert_eq!(value(CALLING_STATION_ID), Some(&b"192.0.2.1"[..]));
+
+        let password = reveal_password(value(USER_PASSWORD).unwrap(), &request[4..HEADER_LEN]);
+        let (code, attributes) = if value(USER_NAME) == Some(b"alice") && password == b"secret" {
+            (answer.code, answer.attributes.clone())
+        } else {
+            (
+                ACCESS_REJECT,
+                vec![(REPLY_MESSAGE, b"Wrong password".to_vec())],
+            )
+        };
+        let mut packet = vec![code, request[1], 0, 0];
+        packet.extend_from_slice(&request[4..HEADER_LEN]);
+        if answer.message_authenticator {
+            offset = packet.len() + 2;
+            put_attribute(&mut packet, MESSAGE_AUTHENTICATOR, &[0; 16]).unwrap();
+        }
+        for (kind, value) in &attributes {
+            put_attribute(&mut packet, *kind, value).unwrap();
+        }
+        let len = packet.len() as u16;
+        packet[2..4].copy_from_slice(&len.to_be_bytes());
+        if answer.message_authenticator {
+            let mac = hmac_md5(SECRET, &packet);
+            packet[offset..offset + 16].copy_from_slice(&mac);
+        }
+        let mut authenticator = Md5::new()
+            .chain_update(&packet)
+            .chain_update(SECRET)
+            .finalize();
+        if answer.forged {
+            authenticator[0] ^= 1;
+        }
+        packet[4..HEADER_LEN].copy_from_slice(&authenticator);
+        packet
+    }
+
+    /// A RADIUS server answering every request with `answer`.
+    async fn stand_in(answer: Answer) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0; MAX_PACKET_LEN];
+            loop {
+                let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+                let reply = reply(&buf[..len], &answer);
+                socket.send_to(&reply, peer).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    /// A RADIUS server that never replies, counting the requests.
+    async fn silent() -> (SocketAddr, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0; MAX_PACKET_LEN];
+            loop {
+                socket.recv_from(&mut buf).await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        (addr, requests)
+    }
+
+    fn verifier(addr: SocketAddr) -> RadiusVerifier {
+        RadiusVerifier::new()
+            .server(addr, SECRET)
+            .timeout(Duration::from_millis(100))
+            .retries(0)
+    }
+
+    async fn verify(verifier: &RadiusVerifier, password: &str) -> Result<Principal> {
+        let ctx = testing::auth_context("192.0.2.1:1234");
+        verifier.verify(&ctx, "alice", password).await
+    }
+
+    #[test]
+    fn hides_passwords() {
+        let authenticator = [7; 16];
+        let password = b"a password longer than sixteen bytes";
+        let hidden = hide_password(password, SECRET, &authenticator).unwrap();
+        assert_eq!(hidden.len(), 48);
+        assert_eq!(reveal_password(&hidden, &authenticator), password);
+        assert_eq!(
+            hide_password(b"", SECRET, &authenticator).unwrap().len(),
+            16
+        );
+        assert!(hide_password(&[b'a'; 129], SECRET, &authenticator).is_err());
+    }
+
+    #[tokio::test]
+    async fn accepts_users() {
+        let mut answer = Answer::new(ACCESS_ACCEPT);
+        answer.attributes = vec![
+            (FILTER_ID, b"staff".to_vec()),
+            (FILTER_ID, b"vpn".to_vec()),
+            (CLASS, b"gold".to_vec()),
+            (SESSION_TIMEOUT, 3600u32.to_be_bytes().to_vec()),
+        ];
+        let verifier = verifier(stand_in(answer).await);
+        let principal = verify(&verifier, "secret").await.unwrap();
+        assert_eq!(principal.username(), Some("alice"));
+        assert_eq!(principal.method, AuthMethod::UsernamePassword);
+        assert_eq!(principal.attributes["filter_id"], "staff,vpn");
+        assert_eq!(principal.attributes["class"], "gold");
+        assert_eq!(principal.session_timeout, Some(Duration::from_secs(3600)));
+    }
+
+    #[tokio::test]
+    async fn rejects_users() {
+        let verifier = verifier(stand_in(Answer::new(ACCESS_ACCEPT)).await);
+        match verify(&verifier, "wrong").await {
+            Err(Error::AuthFailed(message)) => assert!(message.contains("Wrong password")),
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        let verifier = self::verifier(stand_in(Answer::new(ACCESS_CHALLENGE)).await);
+        assert!(matches!(
+            verify(&verifier, "secret").await,
+            Err(Error::AuthFailed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn drops_forged_replies() {
+        let mut answer = Answer::new(ACCESS_ACCEPT);
+        answer.forged = true;
+        let verifier = verifier(stand_in(answer).await);
+        assert!(matches!(
+            verify(&verifier, "secret").await,
+            Err(Error::IoError(e)) if e.kind() == io::ErrorKind::TimedOut
+        ));
+    }
+
+    #[tokio::test]
+    async fn requires_message_authenticators() {
+        let mut answer = Answer::new(ACCESS_ACCEPT);
+        answer.message_authenticator = false;
+        let addr = stand_in(answer).await;
+        assert!(verify(&verifier(addr), "secret").await.is_err());
+
+        let verifier = verifier(addr).require_message_authenticator(false);
+        assert!(verify(&verifier, "secret").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn fails_over_to_the_next_server() {
+        let (dead, requests) = silent().await;
+        let verifier = RadiusVerifier::new()
+            .server(dead, SECRET)
+            .server(stand_in(Answer::new(ACCESS_ACCEPT)).await, SECRET)
+            .timeout(Duration::from_millis(50))
+            .retries(1);
+        assert!(verify(&verifier, "secret").await.is_ok());
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        let verifier = RadiusVerifier::new()
+            .server(dead, SECRET)
+            .timeout(Duration::from_millis(50))
+            .retries(0);
+        assert!(verify(&verifier, "secret").await.is_err());
+        assert!(verify(&RadiusVerifier::new(), "secret").await.is_err());
+    }
+}
@@ -0,0 +1,254 @@
+use crate::auth::{AuthContext, LoginGuard, LoginGuardConfig, PasswordVerifier, Principal};
+use async_trait::async_trait;
+use socks_rs_common::{Error, Result};
+use std::collections::HashMap;
+
+/// Splits usernames into a base user and routing parameters, e.g.
+/// `alice-country-de-session-abc123` into `alice` with `country=de` and
+/// `session=abc123`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UsernameParser {
+    separator: char,
+    keys: Vec<String>,
+}
+
+impl Default for UsernameParser {
+    fn default() -> Self {
+        UsernameParser {
+            separator: '-',
+            keys: Vec::new(),
+        }
+    }
+}
+
+impl UsernameParser {
+    /// Splits usernames on `-`, the base user being the first part and the
+    /// others alternating keys and values.
+    pub fn new() -> UsernameParser {
+        UsernameParser::default()
+    }
+
+    pub fn separator(mut self, separator: char) -> Self {
+        self.separator = separator;
+        self
+    }
+
+    /// Only accepts the parameter `key`. Once keys are declared, the base
+    /// user may contain the separator as it ends at the first declared key.
+    pub fn key<T: Into<String>>(mut self, key: T) -> Self {
+        self.keys.push(key.into());
+        self
+    }
+
+    /// Returns the base user and the parameters of `username`.
+    pub fn parse(&self, username: &str) -> Result<(String, HashMap<String, String>)> {
+        let invalid = |reason: &str| {
+            Error::AuthFailed(format!("invalid username {:?}: {}", username, reason))
+        };
+        let parts = username.split(self.separator).collect::<Vec<_>>();
+        let base_len = if self.keys.is_empty() {
+            1
+        } else {
+            parts
+                .iter()
+                .skip(1)
+                .position(|part| self.keys.iter().any(|key| key == part))
+                .map_or(parts.len(), |i| i + 1)
+        };
+        let base = parts[..base_len].join(&self.separator.to_string());
+        if base.is_empty() {
+            return Err(invalid("empty user"));
+        }
+        let mut parameters = HashMap::new();
+        for pair in parts[base_len..].chunks(2) {
+            let (key, value) = match pair {
+                [key, value] if !value.is_empty() => (*key, *value),
+                _ => return Err(invalid("parameter without a value")),
+            };
+            if !self.keys.is_empty() && !self.keys.iter().any(|k| k == key) {
+                return Err(invalid(&format!("unknown parameter {}", key)));
+            }
+            if parameters
+                .insert(key.to_owned(), value.to_owned())
+                .is_some()
+            {
+                return Err(invalid(&format!("duplicate parameter {}", key)));
+            }
+        }
+        Ok((base, parameters))
+    }
+}
+
+/// Parses the routing parameters out of usernames before verifying the
+/// credentials of the base user with `verifier`, and sets them in the
+/// `parameters` of the principal.
+///
+/// A `LoginGuard` must wrap `verifier` rather than the `RoutingVerifier`, as
+/// `login_guard` does: wrapped around it, the guard would track failures per
+/// full username and picking new parameters would get around lockouts.
+pub struct RoutingVerifier<V> {
+    parser: UsernameParser,
+    verifier: V,
+}
+
+impl<V: PasswordVerifier> RoutingVerifier<V> {
+    pub fn new(parser: UsernameParser, verifier: V) -> RoutingVerifier<V> {
+        RoutingVerifier { parser, verifier }
+    }
+
+    pub fn verifier(&self) -> &V {
+        &self.verifier
+    }
+
+    /// Guards the verification of base users with a `LoginGuard`.
+    pub fn login_guard(self, config: LoginGuardConfig) -> RoutingVerifier<LoginGuard<V>> {
+        RoutingVerifier {
+            parser: self.parser,
+            verifier: LoginGuard::new(self.verifier, config),
+        }
+    }
+}
+
+#[async_trait]
+impl<V: PasswordVerifier + Send + Sync> PasswordVerifier for RoutingVerifier<V> {
+    async fn verify(&self, ctx: &AuthContext, username: &str, password: &str) -> Result<Principal> {
+        let (user, parameters) = self.parser.parse(username)?;
+        let mut principal = self.verifier.verify(ctx, &user, password).await?;
+        principal.parameters = parameters;
+        Ok(principal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::LockoutKey;
+    use crate::testing;
+    use socks_rs_common::AuthMethod;
+    use std::time::Duration;
+
+    /// Accepts alice with the password `secret`.
+    struct Alice;
+
+    #[async_trait]
+    impl PasswordVerifier for Alice {
+        async fn verify(
+            &self,
+            _ctx: &AuthContext,
+            username: &str,
+            password: &str,
+        ) -> Result<Principal> {
+            if username == "alice" && password == "secret" {
+                Ok(Principal::new(username, AuthMethod::UsernamePassword))
+            } else {
+                Err(Error::AuthFailed("incorrect credentials".to_owned()))
+            }
+        }
+    }
+
+    fn parameters(parameters: &[(&str, &str)]) -> HashMap<String, String> {
+        parameters
+            .iter()
+            .map(|(key, value)| ((*key).to_owned(), (*value).to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn parses_usernames() {
+        let parser = UsernameParser::new();
+        assert_eq!(
+            parser.parse("alice-country-de-session-abc123").unwrap(),
+            (
+                "alice".to_owned(),
+                parameters(&[("country", "de"), ("session", "abc123")])
+            )
+        );
+        assert_eq!(
+            parser.parse("alice").unwrap(),
+            ("alice".to_owned(), HashMap::new())
+        );
+        for invalid in [
+            "",
+            "-country-de",
+            "alice-country",
+            "alice-country-",
+            "alice-a-1-a-2",
+        ] {
+            assert!(parser.parse(invalid).is_err(), "{:?}", invalid);
+        }
+
+        let parser = UsernameParser::new().separator('_');
+        assert_eq!(
+            parser.parse("alice-smith_country_de").unwrap(),
+            ("alice-smith".to_owned(), parameters(&[("country", "de")]))
+        );
+    }
+
+    #[test]
+    fn parses_declared_keys() {
+        let parser = UsernameParser::new().key("country").key("session");
+        assert_eq!(
+            parser.parse("alice-smith-session-1").unwrap(),
+            ("alice-smith".to_owned(), parameters(&[("session", "1")]))
+        );
+        assert_eq!(
+            parser.parse("alice-smith").unwrap(),
+            ("alice-smith".to_owned(), HashMap::new())
+        );
+        assert!(parser.parse("alice-country-de-city-berlin").is_err());
+        // The first part is always the base user.
+        assert_eq!(
+            parser.parse("country-de").unwrap(),
+            ("country-de".to_owned(), HashMap::new())
+        );
+    }
+
+    #[tokio::test]
+    async fn verifies_the_base_user() {
+        let verifier = RoutingVerifier::new(UsernameParser::new(), Alice);
+        let ctx = testing::auth_context("192.0.2.1:1234");
+        let principal = verifier
+            .verify(&ctx, "alice-country-de", "secret")
+            .await
+            .unwrap();
+        assert_eq!(principal.username(), Some("alice"));
+        assert_eq!(principal.parameters, parameters(&[("country", "de")]));
+        assert!(verifier
+            .verify(&ctx, "alice-country-de", "wrong")
+            .await
+            .is_err());
+        assert!(verifier
+            .verify(&ctx, "bob-country-de", "secret")
+            .await
+            .is_err());
+        assert!(verifier
+            .verify(&ctx, "alice-country", "secret")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn locks_out_base_users() {
+        let verifier =
+            RoutingVerifier::new(UsernameParser::new(), Alice).login_guard(LoginGuardConfig {
+                max_failures: 2,
+                delay: Duration::ZERO,
+                max_delay: Duration::ZERO,
+                ..LoginGuardConfig::new()
+            });
+        for session in 0..2 {
+            let ctx = testing::auth_context(&format!("192.0.2.{}:1234", session + 1));
+            let username = format!("alice-session-{}", session);
+            assert!(verifier.verify(&ctx, &username, "wrong").await.is_err());
+        }
+        // Picking another session doesn't get around the lockout.
+        let ctx = testing::auth_context("192.0.2.3:1234");
+        let e = verifier
+            .verify(&ctx, "alice-session-2", "secret")
+            .await
+            .unwrap_err();
+        assert!(matches!(e, Error::AuthFailed(msg) if msg == "user alice is locked out"));
+        let alice = LockoutKey::User("alice".to_owned());
+        assert!(verifier.verifier().lockout(&alice).is_some());
+    }
+}
@@ -0,0 +1,436 @@
+use crate::auth::password;
+use crate::auth::{
+    AuthContext, AuthProvider, LoginGuard, LoginGuardConfig, PasswordVerifier, Principal,
+};
+use async_trait::async_trait;
+use socks_rs_common::scram::{self, Key, SCRAM_SHA_256};
+use socks_rs_common::{AuthMethod, Error, Result, Version};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+use tokio::io::{self, AsyncRead, AsyncWrite};
+use tokio::task;
+
+/// SCRAM-SHA-256 keys of a user, from which the password can't be recovered.
+///
+/// Formatted as in RFC 5803, i.e.
+/// `SCRAM-SHA-256$<iterations>:<salt>$<stored key>:<server key>` with base64
+/// salt and keys, the format PostgreSQL uses as well.
+#[derive(Clone, Eq, PartialEq)]
+pub struct ScramCredential {
+    salt: Vec<u8>,
+    iterations: u32,
+    stored_key: Key,
+    server_key: Key,
+}
+
+impl ScramCredential {
+    pub fn new(password: &str, salt: &[u8], iterations: u32) -> ScramCredential {
+        let salted_password = scram::salted_password(password, salt, iterations);
+        ScramCredential {
+            salt: salt.to_vec(),
+            iterations,
+            stored_key: scram::stored_key(&scram::client_key(&salted_password)),
+            server_key: scram::server_key(&salted_password),
+        }
+    }
+
+    /// Derives the keys of `password` with a random salt and 4096 iterations.
+    pub fn generate(password: &str) -> ScramCredential {
+        ScramCredential::new(password, &scram::salt(), scram::DEFAULT_ITERATIONS)
+    }
+
+    fn verify_password(&self, password: &str) -> bool {
+        let salted_password = scram::salted_password(password, &self.salt, self.iterations);
+        let stored_key = scram::stored_key(&scram::client_key(&salted_password));
+        stored_key.ct_eq(&self.stored_key).into()
+    }
+
+    fn verify_proof(&self, auth_message: &str, proof: &[u8]) -> bool {
+        let proof: Key = match proof.try_into() {
+            Ok(proof) => proof,
+            Err(_) => return false,
+        };
+        let signature = scram::hmac(&self.stored_key, auth_message.as_bytes());
+        let client_key = scram::xor(&proof, &signature);
+        scram::stored_key(&client_key)
+            .ct_eq(&self.stored_key)
+            .into()
+    }
+}
+
+impl fmt::Debug for ScramCredential {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScramCredential")
+            .field("iterations", &self.iterations)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Display for ScramCredential {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "SCRAM-SHA-256${}:{}${}:{}",
+            self.iterations,
+            scram::encode(&self.salt),
+            scram::encode(&self.stored_key),
+            scram::encode(&self.server_key)
+        )
+    }
+}
+
+impl FromStr for ScramCredential {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || "invalid SCRAM-SHA-256 credential".to_owned();
+        let rest = s.strip_prefix("SCRAM-SHA-256$").ok_or_else(invalid)?;
+        let (params, keys) = rest.split_once('$').ok_or_else(invalid)?;
+        let (iterations, salt) = params.split_once(':').ok_or_else(invalid)?;
+        let (stored_key, server_key) = keys.split_once(':').ok_or_else(invalid)?;
+        let key = |key: &str| {
+            scram::decode(key)
+                .ok()
+                .and_then(|key| Key::try_from(key).ok())
+                .ok_or_else(invalid)
+        };
+        Ok(ScramCredential {
+            salt: scram::decode(salt).map_err(|_| invalid())?,
+            iterations: iterations
+                .parse()
+                .ok()
+                .filter(|iterations| *iterations > 0)
+                .ok_or_else(invalid)?,
+            stored_key: key(stored_key)?,
+            server_key: key(server_key)?,
+        })
+    }
+}
+
+/// Looks up the SCRAM credentials of users.
+#[async_trait]
+pub trait ScramCredentialStore {
+    async fn credential(&self, username: &str) -> Result<Option<ScramCredential>>;
+}
+
+#[async_trait]
+impl ScramCredentialStore for HashMap<String, ScramCredential> {
+    async fn credential(&self, username: &str) -> Result<Option<ScramCredential>> {
+        Ok(self.get(username).cloned())
+    }
+}
+
+#[async_trait]
+impl<S: ScramCredentialStore + Send + Sync + ?Sized> ScramCredentialStore for Arc<S> {
+    async fn credential(&self, username: &str) -> Result<Option<ScramCredential>> {
+        (**self).credential(username).await
+    }
+}
+
+/// Authenticates users with SCRAM-SHA-256 (see `socks_rs_common::scram`), so
+/// that our own clients never send their password.
+///
+/// Clients that don't offer it are served username/password authentication,
+/// checked against the same credentials, unless `password_fallback` is
+/// disabled. Unknown users go through the whole exchange with a made up salt
+/// so that they can't be told apart from wrong passwords.
+pub struct ScramAuthProvider<S> {
+    store: S,
+    password_fallback: bool,
+    guard: Option<LoginGuard<()>>,
+    /// Key deriving the salts of unknown users.
+    secret: Vec<u8>,
+}
+
+impl<S: ScramCredentialStore + Send + Sync> ScramAuthProvider<S> {
+    pub fn new(store: S) -> ScramAuthProvider<S> {
+        ScramAuthProvider {
+            store,
+            password_fallback: true,
+            guard: None,
+            secret: scram::salt(),
+        }
+    }
+
+    /// Whether clients not offering SCRAM-SHA-256 may send their password.
+    pub fn password_fallback(mut self, enabled: bool) -> Self {
+        self.password_fallback = enabled;
+        self
+    }
+
+    /// Protects both methods from brute-force attacks like a `LoginGuard`.
+    pub fn login_guard(mut self, config: LoginGuardConfig) -> Self {
+        self.guard = Some(LoginGuard::new((), config));
+        self
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// The guard set with `login_guard`, to inspect or lift its lockouts.
+    pub fn guard(&self) -> Option<&LoginGuard<()>> {
+        self.guard.as_ref()
+    }
+
+    async fn guarded<F>(&self, ctx: &AuthContext, username: &str, verify: F) -> Result<Principal>
+    where
+        F: std::future::Future<Output = Result<Principal>>,
+    {
+        match &self.guard {
+            Some(guard) => guard.check(ctx, username, verify).await,
+            None => verify.await,
+        }
+    }
+
+    async fn exchange<IO>(&self, ctx: &AuthContext, io: &mut IO) -> Result<Principal>
+    where
+        IO: AsyncRead + AsyncWrite + Send + Unpin,
+    {
+        let (mut inbound, mut outbound) = io::split(io);
+        let client_first = scram::read_message(&mut inbound).await?;
+        let client_first_bare = match client_first.strip_prefix(scram::GS2_HEADER) {
+            Some(bare) => bare,
+            None => {
+                scram::write_message(&mut outbound, "e=channel-bindings-not-supported").await?;
+                return Err(Error::AuthFailed(
+                    "channel binding isn't supported".to_owned(),
+                ));
+            }
+        };
+        let username = scram::unescape_username(scram::attribute(client_first_bare, 'n')?)?;
+        let client_nonce = scram::attribute(client_first_bare, 'r')?;
+        let credential = self.store.credential(&username).await?;
+        let (salt, iterations) = match &credential {
+            Some(credential) => (credential.salt.clone(), credential.iterations),
+            None => (
+                scram::hmac(&self.secret, username.as_bytes())[..16].to_vec(),
+                scram::DEFAULT_ITERATIONS,
+            ),
+        };
+        let nonce = format!("{}{}", client_nonce, scram::nonce());
+        let server_first = format!("r={},s={},i={}", nonce, scram::encode(&salt), iterations);
+        scram::write_message(&mut outbound, &server_first).await?;
+
+        let client_final = scram::read_message(&mut inbound).await?;
+        let (client_final_without_proof, proof) = client_final
+            .rsplit_once(",p=")
+            .ok_or_else(|| Error::AuthFailed("missing SCRAM proof".to_owned()))?;
+        if scram::attribute(client_final_without_proof, 'c')? != scram::CHANNEL_BINDING
+            || scram::attribute(client_final_without_proof, 'r')? != nonce
+        {
+            scram::write_message(&mut outbound, "e=other-error").await?;
+            return Err(Error::AuthFailed("SCRAM nonce mismatch".to_owned()));
+        }
+        let auth_message = format!(
+            "{},{},{}",
+            client_first_bare, server_first, client_final_without_proof
+        );
+        let proof = scram::decode(proof)?;
+        let verify = async {
+            match &credential {
+                Some(credential) if credential.verify_proof(&auth_message, &proof) => {
+                    Ok(Principal::new(username.as_str(), SCRAM_SHA_256))
+                }
+                _ => Err(Error::AuthFailed("incorrect credentials".to_owned())),
+            }
+        };
+        match (self.guarded(ctx, &username, verify).await, &credential) {
+            (Ok(principal), Some(credential)) => {
+                let signature = scram::hmac(&credential.server_key, auth_message.as_bytes());
+                let server_final = format!("v={}", scram::encode(&signature));
+                scram::write_message(&mut outbound, &server_final).await?;
+                Ok(principal)
+            }
+            (res, _) => {
+                scram::write_message(&mut outbound, "e=invalid-proof").await?;
+                Err(res
+                    .err()
+                    .unwrap_or_else(|| Error::AuthFailed("incorrect credentials".to_owned())))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<S: ScramCredentialStore + Send + Sync> PasswordVerifier for ScramAuthProvider<S> {
+    async fn verify(&self, ctx: &AuthContext, username: &str, password: &str) -> Result<Principal> {
+        let verify = async {
+            let credential = self.store.credential(username).await?;
+            let password = password.to_owned();
+            let secret = self.secret.clone();
+            // Key derivation is deliberately slow, keep it off the runtime threads.
+            let matches = task::spawn_blocking(move || match credential {
+                Some(credential) => credential.verify_password(&password),
+                None => {
+                    // Unknown users take as long to reject as wrong passwords.
+                    scram::salted_password(&password, &secret, scram::DEFAULT_ITERATIONS);
+                    false
+                }
+            })
+            .await
+            .map_err(io::Error::other)?;
+            if matches {
+                Ok(Principal::new(username, AuthMethod::UsernamePassword))
+            } else {
+                Err(Error::AuthFailed("incorrect credentials".to_owned()))
+            }
+        };
+        self.guarded(ctx, username, verify).await
+    }
+}
+
+#[async_trait]
+impl<S: ScramCredentialStore + Send + Sync> AuthProvider for ScramAuthProvider<S> {
+    async fn select(&self, _ctx: &AuthContext, methods: &[AuthMethod]) -> Result<AuthMethod> {
+        if methods.contains(&SCRAM_SHA_256) {
+            return Ok(SCRAM_SHA_256);
+        }
+        if self.password_fallback && methods.contains(&AuthMethod::UsernamePassword) {
+            return Ok(AuthMethod::UsernamePassword);
+        }
+        Err(Error::AuthMethodNotSupported(0xff))
+    }
+
+    async fn validate<IO: AsyncRead + AsyncWrite + Send + Unpin>(
+        &self,
+        ctx: &AuthContext,
+        version: Version,
+        method: AuthMethod,
+        io: &mut IO,
+    ) -> Result<Principal> {
+        if version != Version::V5 {
+            return Err(Error::VersionNotSupported(version.into()));
+        }
+        match method {
+            SCRAM_SHA_256 => self.exchange(ctx, io).await,
+            AuthMethod::UsernamePassword if self.password_fallback => {
+                password::validate(self, ctx, version, method, io).await
+            }
+            _ => Err(Error::AuthMethodNotSupported(method.into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::LockoutKey;
+    use crate::testing;
+    use socks_rs_client::auth::scram::ScramAuthProvider as Client;
+    use socks_rs_client::auth::AuthProvider as _;
+    use std::time::Duration;
+    use tokio::io::duplex;
+
+    fn provider() -> ScramAuthProvider<HashMap<String, ScramCredential>> {
+        let mut users = HashMap::new();
+        users.insert(
+            "alice".to_owned(),
+            ScramCredential::new("pencil", b"salt", scram::DEFAULT_ITERATIONS),
+        );
+        ScramAuthProvider::new(users)
+    }
+
+    /// Authenticates `client` with `method`, returns the results of both sides.
+    async fn authenticate(
+        provider: &ScramAuthProvider<HashMap<String, ScramCredential>>,
+        client: &Client,
+        method: AuthMethod,
+    ) -> (Result<Principal>, Result<()>) {
+        let ctx = testing::auth_context("192.0.2.1:1234");
+        let (mut client_io, mut server_io) = duplex(1024);
+        tokio::join!(
+            provider.validate(&ctx, Version::V5, method, &mut server_io),
+            client.authenticate(Version::V5, method, &mut client_io),
+        )
+    }
+
+    #[test]
+    fn formats_credentials() {
+        let credential = ScramCredential::new("pencil", b"salt", 4096);
+        let formatted = credential.to_string();
+        assert!(formatted.starts_with("SCRAM-SHA-256$4096:c2FsdA==$"));
+        assert_eq!(formatted.parse(), Ok(credential));
+        for invalid in [
+            "",
+            "SCRAM-SHA-1$4096:c2FsdA==$a:b",
+            "SCRAM-SHA-256$0:c2FsdA==$AAAA:AAAA",
+            "SCRAM-SHA-256$4096:c2FsdA==$AAAA:AAAA",
+        ] {
+            assert!(invalid.parse::<ScramCredential>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn authenticates_with_scram() {
+        let provider = provider();
+        let ctx = testing::auth_context("192.0.2.1:1234");
+        let methods = [AuthMethod::UsernamePassword, SCRAM_SHA_256];
+        assert_eq!(
+            provider.select(&ctx, &methods).await.unwrap(),
+            SCRAM_SHA_256
+        );
+
+        let (server, client) =
+            authenticate(&provider, &Client::new("alice", "pencil"), SCRAM_SHA_256).await;
+        let principal = server.unwrap();
+        assert_eq!(principal.username(), Some("alice"));
+        assert_eq!(principal.method, SCRAM_SHA_256);
+        client.unwrap();
+
+        for (username, password) in [("alice", "wrong"), ("bob", "pencil")] {
+            let (server, client) =
+                authenticate(&provider, &Client::new(username, password), SCRAM_SHA_256).await;
+            assert!(server.is_err());
+            assert!(client.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_passwords() {
+        let client = Client::new("alice", "pencil").password_fallback(true);
+        let (server, client_res) =
+            authenticate(&provider(), &client, AuthMethod::UsernamePassword).await;
+        assert_eq!(server.unwrap().method, AuthMethod::UsernamePassword);
+        client_res.unwrap();
+
+        let client = Client::new("bob", "pencil").password_fallback(true);
+        let (server, _) = authenticate(&provider(), &client, AuthMethod::UsernamePassword).await;
+        assert!(server.is_err());
+
+        let provider = provider().password_fallback(false);
+        let ctx = testing::auth_context("192.0.2.1:1234");
+        assert!(provider
+            .select(&ctx, &[AuthMethod::UsernamePassword])
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn guards_logins() {
+        let provider = provider().login_guard(LoginGuardConfig {
+            max_failures: 2,
+            delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            ..LoginGuardConfig::new()
+        });
+        let wrong = Client::new("alice", "wrong").password_fallback(true);
+        let (server, _) = authenticate(&provider, &wrong, SCRAM_SHA_256).await;
+        assert!(server.is_err());
+        let (server, _) = authenticate(&provider, &wrong, AuthMethod::UsernamePassword).await;
+        assert!(server.is_err());
+        let guard = provider.guard().unwrap();
+        assert!(guard
+            .lockout(&LockoutKey::User("alice".to_owned()))
+            .is_some());
+
+        let right = Client::new("alice", "pencil");
+        let (server, client) = authenticate(&provider, &right, SCRAM_SHA_256).await;
+        let e = server.unwrap_err();
+        assert!(matches!(e, Error::AuthFailed(msg) if msg.ends_with("is locked out")));
+        assert!(client.is_err());
+    }
+}
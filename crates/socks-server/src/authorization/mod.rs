@@ -0,0 +1,39 @@
+use crate::auth::Principal;
+use async_trait::async_trait;
+use socks_rs_common::{Command, Result, TargetAddr};
+use std::net::SocketAddr;
+
+#[cfg(feature = "webhook")]
+mod webhook;
+
+#[cfg(feature = "webhook")]
+pub use webhook::WebhookAuthorizer;
+
+/// Decides on each request once the client is authenticated and the rules
+/// allowed it, e.g. by asking an external policy service.
+///
+/// Errors reject the request with a general failure.
+#[async_trait]
+pub trait Authorizer {
+    async fn authorize(&self, request: &AuthorizationRequest<'_>) -> Result<Verdict>;
+}
+
+/// A request waiting for a verdict.
+#[derive(Clone, Debug)]
+pub struct AuthorizationRequest<'a> {
+    pub client: SocketAddr,
+    pub principal: &'a Principal,
+    pub command: Command,
+    pub destination: &'a TargetAddr,
+}
+
+/// What to do with a request.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Verdict {
+    Allow,
+    /// Replies `ConnectionNotAllowedByRuleset`.
+    Deny,
+    /// Allows the request, but connects to another destination, which the
+    /// allowed destinations of the principal and the rules must allow too.
+    Rewrite(TargetAddr),
+}
@@ -0,0 +1,468 @@
+use crate::authorization::{AuthorizationRequest, Authorizer, Verdict};
+use crate::expiring::{ExpiringMap, DEFAULT_CAPACITY};
+use async_trait::async_trait;
+use log::{debug, warn};
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use socks_rs_common::{Command, Result, TargetAddr};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(60);
+/// Longest the service may have its verdicts cached for.
+const MAX_CACHE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Serialize)]
+struct Query<'a> {
+    client: SocketAddr,
+    username: Option<&'a str>,
+    attributes: &'a HashMap<String, String>,
+    parameters: &'a HashMap<String, String>,
+    command: &'static str,
+    destination: Endpoint,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Endpoint {
+    host: String,
+    port: u16,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Decision {
+    Allow,
+    Deny,
+    Rewrite,
+}
+
+#[derive(Deserialize)]
+struct Answer {
+    decision: Decision,
+    destination: Option<Endpoint>,
+    /// Seconds the verdict may be cached for.
+    ttl: Option<u64>,
+}
+
+#[derive(Clone, Eq, Hash, PartialEq)]
+struct CacheKey {
+    username: Option<String>,
+    attributes: BTreeMap<String, String>,
+    parameters: BTreeMap<String, String>,
+    client: IpAddr,
+    command: u8,
+    destination: String,
+}
+
+/// Asks an HTTP policy service for a verdict on each request.
+///
+/// The request is POSTed as JSON:
+///
+/// ```json
+/// {"client": "192.0.2.1:51234", "username": "alice", "attributes": {},
+///  "parameters": {}, "command": "connect",
+///  "destination": {"host": "example.com", "port": 443}}
+/// ```
+///
+/// and the service answers `{"decision": "allow"}`, `{"decision": "deny"}` or
+/// `{"decision": "rewrite", "destination": {"host": "10.0.0.1", "port": 8443}}`,
+/// with an optional `ttl` in seconds overriding how long the verdict is cached
+/// for, up to a day. Verdicts are cached per user, attributes, routing
+/// parameters, client IP, command and destination, up to 65536 of them: those
+/// expiring first are dropped first.
+///
+/// Requests fail when the service can't be reached or answers with an error,
+/// unless `fail_open` is set.
+pub struct WebhookAuthorizer {
+    url: Url,
+    client: Client,
+    bearer_token: Option<String>,
+    timeout: Duration,
+    cache_ttl: Duration,
+    fail_open: bool,
+    cache: Mutex<ExpiringMap<CacheKey, Verdict>>,
+}
+
+impl WebhookAuthorizer {
+    /// Posts requests to `url`, waiting 2 seconds for answers and caching
+    /// them for 60 seconds.
+    pub fn new(url: &str) -> io::Result<WebhookAuthorizer> {
+        let url = Url::parse(url).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid webhook URL {}: {}", url, e),
+            )
+        })?;
+        Ok(WebhookAuthorizer {
+            url,
+            client: Client::new(),
+            bearer_token: None,
+            timeout: DEFAULT_TIMEOUT,
+            cache_ttl: DEFAULT_CACHE_TTL,
+            fail_open: false,
+            cache: Mutex::new(ExpiringMap::new(DEFAULT_CAPACITY)),
+        })
+    }
+
+    /// Sent in the `Authorization` header of requests.
+    pub fn bearer_token<T: Into<String>>(mut self, token: T) -> Self {
+        self.bearer_token = Some(token.into());
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How long verdicts are cached for when the answer has no `ttl`, zero
+    /// disables the cache.
+    pub fn cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = ttl;
+        self
+    }
+
+    /// Allows requests when the service fails instead of rejecting them.
+    pub fn fail_open(mut self, enabled: bool) -> Self {
+        self.fail_open = enabled;
+        self
+    }
+
+    fn store(&self, key: CacheKey, verdict: &Verdict, ttl: Duration) {
+        if ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        if let Some(expiry) = now.checked_add(ttl.min(MAX_CACHE_TTL)) {
+            let mut cache = self.cache.lock().unwrap();
+            cache.insert(key, verdict.clone(), expiry, now);
+        }
+    }
+
+    async fn ask(&self, request: &AuthorizationRequest<'_>) -> Result<(Verdict, Duration)> {
+        let query = Query {
+            client: request.client,
+            username: request.principal.username(),
+            attributes: &request.principal.attributes,
+            parameters: &request.principal.parameters,
+            command: command_name(request.command),
+            destination: Endpoint {
+                host: request.destination.host(),
+                port: request.destination.port(),
+            },
+        };
+        let mut builder = self
+            .client
+            .post(self.url.clone())
+            .timeout(self.timeout)
+            .json(&query);
+        if let Some(token) = &self.bearer_token {
+            builder = builder.bearer_auth(token);
+        }
+        let answer: Answer = builder
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(webhook_error)?
+            .json()
+            .await
+            .map_err(webhook_error)?;
+        let verdict = match (answer.decision, answer.destination) {
+            (Decision::Allow, _) => Verdict::Allow,
+            (Decision::Deny, _) => Verdict::Deny,
+            (Decision::Rewrite, Some(Endpoint { host, port })) => {
+                Verdict::Rewrite(match host.parse::<IpAddr>() {
+                    Ok(ip) => TargetAddr::Addr(SocketAddr::new(ip, port)),
+                    Err(_) => TargetAddr::Host(host, port),
+                })
+            }
+            (Decision::Rewrite, None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "webhook rewrite without a destination",
+                )
+                .into())
+            }
+        };
+        let ttl = answer.ttl.map_or(self.cache_ttl, Duration::from_secs);
+        Ok((verdict, ttl))
+    }
+}
+
+#[async_trait]
+impl Authorizer for WebhookAuthorizer {
+    async fn authorize(&self, request: &AuthorizationRequest<'_>) -> Result<Verdict> {
+        let key = CacheKey {
+            username: request.principal.username().map(str::to_owned),
+            attributes: request
+                .principal
+                .attributes
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+            parameters: request
+                .principal
+                .parameters
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+            client: request.client.ip(),
+            command: request.command.into(),
+            destination: request.destination.to_string(),
+        };
+        if let Some(verdict) = self.cache.lock().unwrap().get(&key, Instant::now()) {
+            return Ok(verdict.clone());
+        }
+        match self.ask(request).await {
+            Ok((verdict, ttl)) => {
+                debug!("Webhook verdict for {}: {:?}", request.destination, verdict);
+                self.store(key, &verdict, ttl);
+                Ok(verdict)
+            }
+            Err(e) if self.fail_open => {
+                warn!("Authorization webhook failed, allowing request: {}", e);
+                Ok(Verdict::Allow)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn command_name(command: Command) -> &'static str {
+    match command {
+        Command::Connect => "connect",
+        Command::Bind => "bind",
+        Command::UdpAssociate => "udp_associate",
+    }
+}
+
+fn webhook_error(e: reqwest::Error) -> io::Error {
+    io::Error::other(format!("authorization webhook failed: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Principal;
+    use socks_rs_common::AuthMethod;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// The Authorization header and body of the requests a stand-in received.
+    type Queries = Arc<Mutex<Vec<(Option<String>, serde_json::Value)>>>;
+
+    async fn read_query(stream: &mut TcpStream) -> (Option<String>, serde_json::Value) {
+        let mut buf = Vec::new();
+        let headers_end = loop {
+            let mut chunk = [0; 1024];
+            let len = stream.read(&mut chunk).await.unwrap();
+            assert_ne!(len, 0);
+            buf.extend_from_slice(&chunk[..len]);
+            if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break end + 4;
+            }
+        };
+        let headers = String::from_utf8(buf[..headers_end].to_vec()).unwrap();
+        let header = |name: &str| {
+            headers.lines().find_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                key.eq_ignore_ascii_case(name)
+                    .then(|| value.trim().to_owned())
+            })
+        };
+        let len: usize = header("content-length").unwrap().parse().unwrap();
+        while buf.len() < headers_end + len {
+            let mut chunk = [0; 1024];
+            let n = stream.read(&mut chunk).await.unwrap();
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        let body = serde_json::from_slice(&buf[headers_end..headers_end + len]).unwrap();
+        (header("authorization"), body)
+    }
+
+    /// A policy service answering every request with `status` and `body`.
+    async fn stand_in(status: u16, body: &'static str) -> (String, Queries) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/authorize", listener.local_addr().unwrap());
+        let queries = Queries::default();
+        let log = queries.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let query = read_query(&mut stream).await;
+                log.lock().unwrap().push(query);
+                let response = format!(
+                    "HTTP/1.1 {} Status\r\ncontent-type: application/json\r\n\
+                     content-length: {}\r\nconnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (url, queries)
+    }
+
+    async fn authorize(authorizer: &WebhookAuthorizer, destination: &str) -> Result<Verdict> {
+        authorize_as(authorizer, "staff", destination).await
+    }
+
+    async fn authorize_as(
+        authorizer: &WebhookAuthorizer,
+        groups: &str,
+        destination: &str,
+    ) -> Result<Verdict> {
+        let principal =
+            Principal::new("alice", AuthMethod::UsernamePassword).with_attribute("groups", groups);
+        authorizer
+            .authorize(&AuthorizationRequest {
+                client: "192.0.2.1:51234".parse().unwrap(),
+                principal: &principal,
+                command: Command::Connect,
+                destination: &TargetAddr::Host(destination.to_owned(), 443),
+            })
+            .await
+    }
+
+    fn key(destination: usize) -> CacheKey {
+        CacheKey {
+            username: None,
+            attributes: BTreeMap::new(),
+            parameters: BTreeMap::new(),
+            client: "192.0.2.1".parse().unwrap(),
+            command: 1,
+            destination: destination.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn asks_the_service() {
+        let (url, queries) = stand_in(200, r#"{"decision": "deny"}"#).await;
+        let authorizer = WebhookAuthorizer::new(&url).unwrap().bearer_token("t0ken");
+        assert_eq!(
+            authorize(&authorizer, "example.com").await.unwrap(),
+            Verdict::Deny
+        );
+        let queries = queries.lock().unwrap();
+        let (authorization, query) = &queries[0];
+        assert_eq!(authorization.as_deref(), Some("Bearer t0ken"));
+        assert_eq!(
+            *query,
+            serde_json::json!({
+                "client": "192.0.2.1:51234",
+                "username": "alice",
+                "attributes": {"groups": "staff"},
+                "parameters": {},
+                "command": "connect",
+                "destination": {"host": "example.com", "port": 443},
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn rewrites_destinations() {
+        let (url, _) = stand_in(
+            200,
+            r#"{"decision": "rewrite", "destination": {"host": "10.0.0.1", "port": 8443}}"#,
+        )
+        .await;
+        let authorizer = WebhookAuthorizer::new(&url).unwrap();
+        assert_eq!(
+            authorize(&authorizer, "example.com").await.unwrap(),
+            Verdict::Rewrite(TargetAddr::Addr("10.0.0.1:8443".parse().unwrap()))
+        );
+
+        let (url, _) = stand_in(200, r#"{"decision": "rewrite"}"#).await;
+        let authorizer = WebhookAuthorizer::new(&url).unwrap();
+        assert!(authorize(&authorizer, "example.com").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn caches_verdicts() {
+        let (url, queries) = stand_in(200, r#"{"decision": "allow"}"#).await;
+        let authorizer = WebhookAuthorizer::new(&url).unwrap();
+        for _ in 0..2 {
+            assert_eq!(
+                authorize(&authorizer, "example.com").await.unwrap(),
+                Verdict::Allow
+            );
+        }
+        assert_eq!(queries.lock().unwrap().len(), 1);
+        authorize(&authorizer, "example.org").await.unwrap();
+        assert_eq!(queries.lock().unwrap().len(), 2);
+        authorize_as(&authorizer, "admins", "example.org")
+            .await
+            .unwrap();
+        assert_eq!(queries.lock().unwrap().len(), 3);
+
+        // The ttl of the answer overrides the default.
+        let (url, queries) = stand_in(200, r#"{"decision": "allow", "ttl": 0}"#).await;
+        let authorizer = WebhookAuthorizer::new(&url).unwrap();
+        for _ in 0..2 {
+            authorize(&authorizer, "example.com").await.unwrap();
+        }
+        assert_eq!(queries.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn fails_closed_unless_told_otherwise() {
+        let (url, _) = stand_in(500, "{}").await;
+        let authorizer = WebhookAuthorizer::new(&url).unwrap();
+        assert!(authorize(&authorizer, "example.com").await.is_err());
+        let authorizer = authorizer.fail_open(true);
+        assert_eq!(
+            authorize(&authorizer, "example.com").await.unwrap(),
+            Verdict::Allow
+        );
+        assert!(WebhookAuthorizer::new("not a url").is_err());
+    }
+
+    #[tokio::test]
+    async fn bounds_ttls() {
+        let (url, queries) =
+            stand_in(200, r#"{"decision": "allow", "ttl": 18446744073709551615}"#).await;
+        let authorizer = WebhookAuthorizer::new(&url).unwrap();
+        for _ in 0..2 {
+            authorize(&authorizer, "example.com").await.unwrap();
+        }
+        assert_eq!(queries.lock().unwrap().len(), 1);
+        authorizer.store(key(0), &Verdict::Allow, Duration::MAX);
+        let cache = authorizer.cache.lock().unwrap();
+        let later = Instant::now() + MAX_CACHE_TTL + Duration::from_secs(1);
+        assert!(cache.get(&key(0), later).is_none());
+    }
+
+    #[test]
+    fn drops_expired_verdicts() {
+        let authorizer = WebhookAuthorizer::new("http://127.0.0.1/").unwrap();
+        for i in 0..1000 {
+            authorizer.store(key(i), &Verdict::Allow, Duration::from_nanos(1));
+        }
+        std::thread::sleep(Duration::from_millis(1));
+        authorizer.store(key(usize::MAX), &Verdict::Allow, Duration::from_secs(60));
+        assert_eq!(authorizer.cache.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn bounds_the_cache() {
+        let authorizer = WebhookAuthorizer::new("http://127.0.0.1/").unwrap();
+        let ttl = Duration::from_secs(60);
+        for i in 0..DEFAULT_CAPACITY {
+            authorizer.store(key(i), &Verdict::Allow, ttl);
+        }
+        authorizer.store(key(1), &Verdict::Deny, ttl);
+        authorizer.store(key(DEFAULT_CAPACITY), &Verdict::Allow, ttl);
+        let cache = authorizer.cache.lock().unwrap();
+        let now = Instant::now();
+        assert_eq!(cache.len(), DEFAULT_CAPACITY);
+        assert!(cache.get(&key(0), now).is_none());
+        assert_eq!(cache.get(&key(1), now), Some(&Verdict::Deny));
+        assert!(cache.get(&key(DEFAULT_CAPACITY), now).is_some());
+    }
+}
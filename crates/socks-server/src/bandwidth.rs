@@ -0,0 +1,531 @@
+use crate::expiring::{ExpiringMap, DEFAULT_CAPACITY};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::time::{self, Instant};
+
+/// Traffic a bucket may let through at once, as a duration at its rate.
+const BURST: Duration = Duration::from_millis(100);
+/// Bounds of the chunks relayed at once, so that slow limits are shaped
+/// smoothly and sessions sharing a bucket take turns.
+const MIN_CHUNK: usize = 1024;
+const MAX_CHUNK: usize = 16 * 1024;
+/// How long the buckets of a user are kept after its last session started.
+const USER_BUCKETS_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Bytes per second allowed in each direction, unlimited when unset or
+/// zero.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Bandwidth {
+    /// From the client to upstream.
+    pub upload: Option<u64>,
+    /// From upstream to the client.
+    pub download: Option<u64>,
+}
+
+impl Bandwidth {
+    pub fn unlimited() -> Bandwidth {
+        Bandwidth::default()
+    }
+
+    /// The same rate in both directions.
+    pub fn symmetric(bytes_per_sec: u64) -> Bandwidth {
+        Bandwidth {
+            upload: Some(bytes_per_sec),
+            download: Some(bytes_per_sec),
+        }
+    }
+}
+
+/// Bandwidth limits of a server, all of them are disabled by default.
+///
+/// A session is held to all the limits it falls under, sessions sharing a
+/// limit get a fair share of it.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct BandwidthLimits {
+    /// Limit of each session.
+    pub session: Bandwidth,
+    /// Limit of all the sessions of each authenticated user.
+    pub user: Bandwidth,
+    /// Limit of all the sessions of the server.
+    pub listener: Bandwidth,
+}
+
+impl BandwidthLimits {
+    pub fn new() -> BandwidthLimits {
+        BandwidthLimits::default()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Direction {
+    Upload,
+    Download,
+}
+
+/// Rates of both directions in bytes per second, zero meaning unlimited.
+#[derive(Debug, Default)]
+struct Rates {
+    upload: AtomicU64,
+    download: AtomicU64,
+}
+
+impl Rates {
+    fn new(bandwidth: Bandwidth) -> Rates {
+        let rates = Rates::default();
+        rates.set(bandwidth);
+        rates
+    }
+
+    fn get(&self) -> Bandwidth {
+        let rate = |rate: &AtomicU64| Some(rate.load(Ordering::Relaxed)).filter(|r| *r > 0);
+        Bandwidth {
+            upload: rate(&self.upload),
+            download: rate(&self.download),
+        }
+    }
+
+    fn set(&self, bandwidth: Bandwidth) {
+        self.upload
+            .store(bandwidth.upload.unwrap_or(0), Ordering::Relaxed);
+        self.download
+            .store(bandwidth.download.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    fn rate(&self, direction: Direction) -> u64 {
+        match direction {
+            Direction::Upload => self.upload.load(Ordering::Relaxed),
+            Direction::Download => self.download.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    /// Negative when traffic was let through ahead of time, which the next
+    /// reservations wait for.
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    /// Takes `n` tokens and returns how long to wait before using them.
+    /// Reservations are served in order, so sessions taking turns get an
+    /// equal share.
+    fn reserve(&mut self, rate: u64, n: usize, now: Instant) -> Duration {
+        let rate = rate as f64;
+        let burst = rate * BURST.as_secs_f64();
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst) - n as f64;
+        self.updated_at = now;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+
+/// The token buckets of a scope, one per direction.
+#[derive(Debug)]
+struct Buckets {
+    rates: Arc<Rates>,
+    upload: Mutex<TokenBucket>,
+    download: Mutex<TokenBucket>,
+}
+
+impl Buckets {
+    fn new(rates: Arc<Rates>) -> Buckets {
+        let bucket = || {
+            Mutex::new(TokenBucket {
+                // Full, capped to the burst on the first reservation.
+                tokens: f64::INFINITY,
+                updated_at: Instant::now(),
+            })
+        };
+        Buckets {
+            rates,
+            upload: bucket(),
+            download: bucket(),
+        }
+    }
+
+    fn reserve(&self, direction: Direction, n: usize, now: Instant) -> Duration {
+        let rate = self.rates.rate(direction);
+        if rate == 0 {
+            return Duration::ZERO;
+        }
+        let bucket = match direction {
+            Direction::Upload => &self.upload,
+            Direction::Download => &self.download,
+        };
+        bucket.lock().unwrap().reserve(rate, n, now)
+    }
+}
+
+/// A bandwidth limit shared by several servers, e.g. that of the uplink.
+/// Clones share the same limit.
+#[derive(Clone)]
+pub struct SharedBandwidth {
+    buckets: Arc<Buckets>,
+}
+
+impl SharedBandwidth {
+    pub fn new(bandwidth: Bandwidth) -> SharedBandwidth {
+        SharedBandwidth {
+            buckets: Arc::new(Buckets::new(Arc::new(Rates::new(bandwidth)))),
+        }
+    }
+
+    pub fn get(&self) -> Bandwidth {
+        self.buckets.rates.get()
+    }
+
+    /// Changes the limit, sessions in progress included.
+    pub fn set(&self, bandwidth: Bandwidth) {
+        self.buckets.rates.set(bandwidth);
+    }
+}
+
+impl fmt::Debug for SharedBandwidth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SharedBandwidth").field(&self.get()).finish()
+    }
+}
+
+struct Users {
+    default: Bandwidth,
+    overrides: HashMap<String, Bandwidth>,
+    /// Buckets of the users with recent sessions, dead once they all closed.
+    buckets: ExpiringMap<String, Weak<Buckets>>,
+}
+
+impl Users {
+    fn bandwidth(&self, user: &str) -> Bandwidth {
+        self.overrides.get(user).copied().unwrap_or(self.default)
+    }
+}
+
+/// Shapes the traffic of the sessions of a server. Limits may be changed while
+/// it runs and apply to the sessions in progress.
+///
+/// The buckets of up to 65536 users are tracked for a day after their last
+/// session started, those of the users whose last session started first are
+/// forgotten first. Sessions of a forgotten user started after that are held
+/// to new buckets.
+pub struct BandwidthShaper {
+    session: Arc<Rates>,
+    users: Mutex<Users>,
+    listener: Arc<Buckets>,
+    global: Option<SharedBandwidth>,
+}
+
+impl BandwidthShaper {
+    pub(crate) fn new(limits: BandwidthLimits, global: Option<SharedBandwidth>) -> Self {
+        BandwidthShaper {
+            session: Arc::new(Rates::new(limits.session)),
+            users: Mutex::new(Users {
+                default: limits.user,
+                overrides: HashMap::new(),
+                buckets: ExpiringMap::new(DEFAULT_CAPACITY),
+            }),
+            listener: Arc::new(Buckets::new(Arc::new(Rates::new(limits.listener)))),
+            global,
+        }
+    }
+
+    /// The current limits, regardless of user overrides.
+    pub fn limits(&self) -> BandwidthLimits {
+        BandwidthLimits {
+            session: self.session.get(),
+            user: self.users.lock().unwrap().default,
+            listener: self.listener.rates.get(),
+        }
+    }
+
+    pub fn set_session_limit(&self, bandwidth: Bandwidth) {
+        self.session.set(bandwidth);
+    }
+
+    /// Changes the limit of the users without an override.
+    pub fn set_user_limit(&self, bandwidth: Bandwidth) {
+        let mut users = self.users.lock().unwrap();
+        users.default = bandwidth;
+        let users = &*users;
+        for (user, buckets) in users.buckets.iter(Instant::now().into_std()) {
+            if let Some(buckets) = buckets.upgrade() {
+                buckets.rates.set(users.bandwidth(user));
+            }
+        }
+    }
+
+    /// Overrides the limit of `user`, or restores the default one if `None`.
+    pub fn set_user_override(&self, user: &str, bandwidth: Option<Bandwidth>) {
+        let mut users = self.users.lock().unwrap();
+        match bandwidth {
+            Some(bandwidth) => users.overrides.insert(user.to_owned(), bandwidth),
+            None => users.overrides.remove(user),
+        };
+        let now = Instant::now().into_std();
+        if let Some(buckets) = users.buckets.get(user, now).and_then(Weak::upgrade) {
+            buckets.rates.set(users.bandwidth(user));
+        }
+    }
+
+    pub fn user_override(&self, user: &str) -> Option<Bandwidth> {
+        self.users.lock().unwrap().overrides.get(user).copied()
+    }
+
+    pub fn set_listener_limit(&self, bandwidth: Bandwidth) {
+        self.listener.rates.set(bandwidth);
+    }
+
+    /// The buckets a new session of `user` is held to.
+    pub(crate) fn session(&self, user: Option<&str>) -> SessionShaper {
+        let mut levels = vec![Arc::new(Buckets::new(self.session.clone()))];
+        if let Some(user) = user {
+            let now = Instant::now().into_std();
+            let expires_at = now + USER_BUCKETS_TTL;
+            let mut users = self.users.lock().unwrap();
+            let existing = users.buckets.get_mut(user, now).and_then(|b| b.upgrade());
+            let buckets = match existing {
+                Some(buckets) => {
+                    users.buckets.set_expiry(user, expires_at);
+                    buckets
+                }
+                None => {
+                    let rates = Arc::new(Rates::new(users.bandwidth(user)));
+                    let buckets = Arc::new(Buckets::new(rates));
+                    let weak = Arc::downgrade(&buckets);
+                    users.buckets.insert(user.to_owned(), weak, expires_at, now);
+                    buckets
+                }
+            };
+            levels.push(buckets);
+        }
+        levels.push(self.listener.clone());
+        if let Some(global) = &self.global {
+            levels.push(global.buckets.clone());
+        }
+        SessionShaper { levels }
+    }
+}
+
+/// Holds the traffic of a session to the limits it falls under.
+pub(crate) struct SessionShaper {
+    levels: Vec<Arc<Buckets>>,
+}
+
+impl SessionShaper {
+    /// How much to relay at once in `direction`.
+    pub(crate) fn chunk_size(&self, direction: Direction) -> usize {
+        self.levels
+            .iter()
+            .map(|buckets| buckets.rates.rate(direction))
+            .filter(|rate| *rate > 0)
+            .min()
+            .map_or(MAX_CHUNK, |rate| {
+                ((rate as f64 * BURST.as_secs_f64()) as usize).clamp(MIN_CHUNK, MAX_CHUNK)
+            })
+    }
+
+    /// Waits until `n` bytes may be relayed in `direction`.
+    ///
+    /// Levels are reserved from the tightest limit to the loosest, each one
+    /// once the previous reservation is due, so that a session held back by
+    /// its own limit doesn't take up the shared ones meanwhile.
+    pub(crate) async fn acquire(&self, direction: Direction, n: usize) {
+        let mut reserved = 0u32;
+        loop {
+            let tightest = self
+                .levels
+                .iter()
+                .enumerate()
+                .filter(|(i, buckets)| {
+                    reserved & (1 << i) == 0 && buckets.rates.rate(direction) > 0
+                })
+                .min_by_key(|(_, buckets)| buckets.rates.rate(direction));
+            let Some((i, buckets)) = tightest else {
+                break;
+            };
+            reserved |= 1 << i;
+            let wait = buckets.reserve(direction, n, Instant::now());
+            if !wait.is_zero() {
+                time::sleep(wait).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shaper(limits: BandwidthLimits) -> BandwidthShaper {
+        BandwidthShaper::new(limits, None)
+    }
+
+    fn rates(shaper: &SessionShaper) -> Vec<Bandwidth> {
+        shaper
+            .levels
+            .iter()
+            .map(|buckets| buckets.rates.get())
+            .collect()
+    }
+
+    #[test]
+    fn refills_token_buckets() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket {
+            tokens: f64::INFINITY,
+            updated_at: now,
+        };
+        // 1000 bytes per second allow bursts of 100 bytes.
+        assert_eq!(bucket.reserve(1000, 100, now), Duration::ZERO);
+        assert_eq!(bucket.reserve(1000, 50, now), Duration::from_millis(50));
+        assert_eq!(bucket.reserve(1000, 50, now), Duration::from_millis(100));
+        let later = now + Duration::from_millis(100);
+        assert_eq!(bucket.reserve(1000, 0, later), Duration::ZERO);
+        // Tokens don't pile up beyond the burst.
+        let later = later + Duration::from_secs(10);
+        assert_eq!(bucket.reserve(1000, 100, later), Duration::ZERO);
+        assert_eq!(bucket.reserve(1000, 10, later), Duration::from_millis(10));
+    }
+
+    #[test]
+    fn sizes_chunks_after_the_lowest_limit() {
+        let shaper = shaper(BandwidthLimits::new());
+        let session = shaper.session(None);
+        assert_eq!(session.chunk_size(Direction::Upload), MAX_CHUNK);
+
+        shaper.set_listener_limit(Bandwidth::symmetric(100_000));
+        shaper.set_session_limit(Bandwidth {
+            upload: Some(50_000),
+            download: Some(1000),
+        });
+        assert_eq!(session.chunk_size(Direction::Upload), 5000);
+        assert_eq!(session.chunk_size(Direction::Download), MIN_CHUNK);
+        shaper.set_listener_limit(Bandwidth::symmetric(1_000_000_000));
+        shaper.set_session_limit(Bandwidth::unlimited());
+        assert_eq!(session.chunk_size(Direction::Upload), MAX_CHUNK);
+    }
+
+    #[tokio::test]
+    async fn waits_for_tokens() {
+        let shaper = shaper(BandwidthLimits {
+            session: Bandwidth::symmetric(10_000),
+            ..BandwidthLimits::new()
+        });
+        let session = shaper.session(None);
+        let start = Instant::now();
+        session.acquire(Direction::Upload, 1000).await;
+        session.acquire(Direction::Download, 1000).await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+        session.acquire(Direction::Upload, 1000).await;
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn shares_user_buckets() {
+        let user = Bandwidth::symmetric(1000);
+        let shaper = shaper(BandwidthLimits {
+            user,
+            ..BandwidthLimits::new()
+        });
+        let first = shaper.session(Some("alice"));
+        let second = shaper.session(Some("alice"));
+        let other = shaper.session(Some("bob"));
+        assert!(Arc::ptr_eq(&first.levels[1], &second.levels[1]));
+        assert!(!Arc::ptr_eq(&first.levels[1], &other.levels[1]));
+        assert!(Arc::ptr_eq(&first.levels[2], &other.levels[2]));
+        assert_eq!(shaper.session(None).levels.len(), 2);
+
+        let limited = Bandwidth::symmetric(10);
+        shaper.set_user_override("alice", Some(limited));
+        assert_eq!(shaper.user_override("alice"), Some(limited));
+        assert_eq!(rates(&first)[1], limited);
+        assert_eq!(rates(&other)[1], user);
+
+        let default = Bandwidth::symmetric(500);
+        shaper.set_user_limit(default);
+        assert_eq!(rates(&first)[1], limited);
+        assert_eq!(rates(&other)[1], default);
+        assert_eq!(shaper.limits().user, default);
+
+        shaper.set_user_override("alice", None);
+        assert_eq!(rates(&second)[1], default);
+    }
+
+    #[test]
+    fn shares_global_limits() {
+        let global = SharedBandwidth::new(Bandwidth::symmetric(1000));
+        let shaper = BandwidthShaper::new(BandwidthLimits::new(), Some(global.clone()));
+        let other = BandwidthShaper::new(BandwidthLimits::new(), Some(global.clone()));
+        let (first, second) = (shaper.session(None), other.session(None));
+        assert!(Arc::ptr_eq(&first.levels[2], &second.levels[2]));
+        global.set(Bandwidth::symmetric(10));
+        assert_eq!(rates(&second)[2], Bandwidth::symmetric(10));
+        assert_eq!(global.get(), Bandwidth::symmetric(10));
+    }
+
+    #[tokio::test]
+    async fn reserves_the_tightest_limit_first() {
+        let shaper = Arc::new(shaper(BandwidthLimits {
+            listener: Bandwidth::symmetric(100_000),
+            ..BandwidthLimits::new()
+        }));
+        shaper.set_user_override("alice", Some(Bandwidth::symmetric(1000)));
+        // Sessions of alice wait on her limit without reserving the listener's.
+        let throttled = (0..50)
+            .map(|_| {
+                let session = shaper.session(Some("alice"));
+                tokio::spawn(async move { session.acquire(Direction::Upload, 10_000).await })
+            })
+            .collect::<Vec<_>>();
+        tokio::task::yield_now().await;
+        let bob = shaper.session(Some("bob"));
+        let start = Instant::now();
+        for _ in 0..10 {
+            bob.acquire(Direction::Upload, 10_000).await;
+        }
+        // 100000 bytes at 100000 bytes per second, the first 10000 at once.
+        assert!(start.elapsed() < Duration::from_millis(1500));
+        throttled.iter().for_each(|task| task.abort());
+    }
+
+    #[test]
+    fn drops_buckets_of_closed_sessions() {
+        let shaper = shaper(BandwidthLimits::new());
+        let open = shaper.session(Some("alice"));
+        drop(shaper.session(Some("bob")));
+        let reopened = shaper.session(Some("alice"));
+        assert!(Arc::ptr_eq(&open.levels[1], &reopened.levels[1]));
+        let bob = shaper.session(Some("bob"));
+        assert_eq!(Arc::strong_count(&bob.levels[1]), 1);
+    }
+
+    #[test]
+    fn bounds_the_tracked_users() {
+        let shaper = shaper(BandwidthLimits {
+            user: Bandwidth::symmetric(1000),
+            ..BandwidthLimits::new()
+        });
+        let sessions = (0..DEFAULT_CAPACITY)
+            .map(|i| shaper.session(Some(&i.to_string())))
+            .collect::<Vec<_>>();
+        // Known users keep their buckets, the user whose last session started
+        // first is forgotten.
+        let known = shaper.session(Some("0"));
+        assert!(Arc::ptr_eq(&known.levels[1], &sessions[0].levels[1]));
+        shaper.set_user_override("alice", Some(Bandwidth::symmetric(10)));
+        let alice = shaper.session(Some("alice"));
+        assert_eq!(rates(&alice)[1], Bandwidth::symmetric(10));
+        let users = shaper.users.lock().unwrap();
+        assert_eq!(users.buckets.len(), DEFAULT_CAPACITY);
+        let now = Instant::now().into_std();
+        assert!(users.buckets.get("1", now).is_none());
+        assert!(users.buckets.get("0", now).is_some());
+    }
+}
@@ -0,0 +1,387 @@
+use crate::acceptor::{Acceptor, PlainAcceptor};
+use crate::auth::{AuthProvider, PlainAuthProvider};
+use crate::authorization::Authorizer;
+use crate::bandwidth::{BandwidthLimits, BandwidthShaper, SharedBandwidth};
+use crate::handle::{ServerHandle, SessionTracker, ShutdownTrigger};
+use crate::ip_filter::IpFilter;
+use crate::limits::{Admission, Limits};
+use crate::metrics::Metrics;
+use crate::quota::QuotaTracker;
+use crate::routing::Router;
+use crate::rules::RuleSet;
+use crate::timeouts::Timeouts;
+use crate::{ServerContext, SocksServer};
+use log::{info, warn};
+use socks_rs_common::connector::{
+    ConnectOptions, DNSResolver, PlainWrappedTcpStream, WrappedTcpStream,
+};
+use socks_rs_common::resolver::SystemResolver;
+use socks_rs_common::Result;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io;
+use tokio::net::TcpListener;
+use tokio::runtime::Handle;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time;
+
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Builds and starts a `SocksServer`.
+///
+/// By default the server accepts plain TCP connections, requires no
+/// authentication and resolves hosts with the system resolver.
+pub struct ServerBuilder<U, S, A, D> {
+    auth_provider: U,
+    acceptor: A,
+    resolver: D,
+    settings: Settings,
+    _stream: PhantomData<fn() -> S>,
+}
+
+struct Settings {
+    listener: Option<TcpListener>,
+    bind_addr: Option<SocketAddr>,
+    runtime: Option<Handle>,
+    connect_options: ConnectOptions,
+    drain_timeout: Duration,
+    shutdown_on_sigterm: bool,
+    timeouts: Timeouts,
+    limits: Limits,
+    rules: RuleSet,
+    authorizer: Option<Box<dyn Authorizer + Send + Sync>>,
+    router: Option<Box<dyn Router + Send + Sync>>,
+    ip_filter: IpFilter,
+    bandwidth: BandwidthLimits,
+    global_bandwidth: Option<SharedBandwidth>,
+    quotas: Option<Arc<QuotaTracker>>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            listener: None,
+            bind_addr: None,
+            runtime: None,
+            connect_options: ConnectOptions::default(),
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            shutdown_on_sigterm: false,
+            timeouts: Timeouts::default(),
+            limits: Limits::default(),
+            rules: RuleSet::default(),
+            authorizer: None,
+            router: None,
+            ip_filter: IpFilter::new(),
+            bandwidth: BandwidthLimits::default(),
+            global_bandwidth: None,
+            quotas: None,
+        }
+    }
+}
+
+impl ServerBuilder<PlainAuthProvider, PlainWrappedTcpStream, PlainAcceptor, SystemResolver> {
+    pub fn new() -> Self {
+        ServerBuilder {
+            auth_provider: PlainAuthProvider::new(),
+            acceptor: PlainAcceptor::new(),
+            resolver: SystemResolver::new(),
+            settings: Settings::default(),
+            _stream: PhantomData,
+        }
+    }
+}
+
+impl Default
+    for ServerBuilder<PlainAuthProvider, PlainWrappedTcpStream, PlainAcceptor, SystemResolver>
+{
+    fn default() -> Self {
+        ServerBuilder::new()
+    }
+}
+
+impl<U, S, A, D> ServerBuilder<U, S, A, D> {
+    /// Listens on `addr` when the server starts.
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.settings.bind_addr = Some(addr);
+        self
+    }
+
+    /// Accepts connections from an already bound listener, takes precedence over `bind`.
+    pub fn listener(mut self, listener: TcpListener) -> Self {
+        self.settings.listener = Some(listener);
+        self
+    }
+
+    pub fn auth_provider<U2: AuthProvider>(self, auth_provider: U2) -> ServerBuilder<U2, S, A, D> {
+        ServerBuilder {
+            auth_provider,
+            acceptor: self.acceptor,
+            resolver: self.resolver,
+            settings: self.settings,
+            _stream: PhantomData,
+        }
+    }
+
+    pub fn acceptor<S2: WrappedTcpStream, A2: Acceptor<S2>>(
+        self,
+        acceptor: A2,
+    ) -> ServerBuilder<U, S2, A2, D> {
+        ServerBuilder {
+            auth_provider: self.auth_provider,
+            acceptor,
+            resolver: self.resolver,
+            settings: self.settings,
+            _stream: PhantomData,
+        }
+    }
+
+    pub fn resolver<D2: DNSResolver>(self, resolver: D2) -> ServerBuilder<U, S, A, D2> {
+        ServerBuilder {
+            auth_provider: self.auth_provider,
+            acceptor: self.acceptor,
+            resolver,
+            settings: self.settings,
+            _stream: PhantomData,
+        }
+    }
+
+    /// Options for the outbound connections made on behalf of clients.
+    pub fn connect_options(mut self, connect_options: ConnectOptions) -> Self {
+        self.settings.connect_options = connect_options;
+        self
+    }
+
+    /// Handshake, idle, lifetime and linger timeouts of each session.
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.settings.timeouts = timeouts;
+        self
+    }
+
+    /// Limits on the number and rate of sessions.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.settings.limits = limits;
+        self
+    }
+
+    /// Rules deciding which requests are allowed, everything is allowed by default.
+    pub fn rules(mut self, rules: RuleSet) -> Self {
+        self.settings.rules = rules;
+        self
+    }
+
+    /// Asks `authorizer` about the requests the rules allow, e.g. a
+    /// `WebhookAuthorizer`.
+    pub fn authorizer<Z: Authorizer + Send + Sync + 'static>(mut self, authorizer: Z) -> Self {
+        self.settings.authorizer = Some(Box::new(authorizer));
+        self
+    }
+
+    /// Lets `router` pick how to connect upstream for the allowed requests,
+    /// e.g. an `EgressPool`.
+    pub fn router<R: Router + Send + Sync + 'static>(mut self, router: R) -> Self {
+        self.settings.router = Some(Box::new(router));
+        self
+    }
+
+    /// Denies outbound connections to the resolved addresses matched by `filter`.
+    /// Defaults to `IpFilter::new()`, which blocks loopback, private and metadata
+    /// ranges, `IpFilter::empty()` denies nothing.
+    pub fn ip_filter(mut self, filter: IpFilter) -> Self {
+        self.settings.ip_filter = filter;
+        self
+    }
+
+    /// Bandwidth limits of sessions, users and the server, which may be
+    /// changed later with `ServerHandle::bandwidth`.
+    pub fn bandwidth(mut self, limits: BandwidthLimits) -> Self {
+        self.settings.bandwidth = limits;
+        self
+    }
+
+    /// Holds sessions to `bandwidth` as well, which may be shared with other
+    /// servers.
+    pub fn global_bandwidth(mut self, bandwidth: SharedBandwidth) -> Self {
+        self.settings.global_bandwidth = Some(bandwidth);
+        self
+    }
+
+    /// Accounts the traffic of users to `quotas`, which may be shared with
+    /// other servers and queried while they run.
+    pub fn quotas(mut self, quotas: Arc<QuotaTracker>) -> Self {
+        self.settings.quotas = Some(quotas);
+        self
+    }
+
+    /// How long sessions may keep running after a graceful shutdown is triggered,
+    /// defaults to 30 seconds.
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.settings.drain_timeout = timeout;
+        self
+    }
+
+    /// Triggers a graceful shutdown when the process receives SIGTERM.
+    #[cfg(unix)]
+    pub fn shutdown_on_sigterm(mut self) -> Self {
+        self.settings.shutdown_on_sigterm = true;
+        self
+    }
+
+    /// Runtime the server tasks are spawned on, defaults to the current runtime.
+    pub fn runtime(mut self, handle: Handle) -> Self {
+        self.settings.runtime = Some(handle);
+        self
+    }
+}
+
+impl<U, S, A, D> ServerBuilder<U, S, A, D>
+where
+    U: AuthProvider + Send + Sync + 'static,
+    S: WrappedTcpStream + Send + Sync + Unpin + 'static,
+    A: Acceptor<S> + Send + Sync + 'static,
+    D: DNSResolver + Send + Sync + 'static,
+{
+    /// Binds the listener if needed and starts accepting connections in the background.
+    pub async fn start(self) -> Result<ServerHandle> {
+        let settings = self.settings;
+        let listener = match (settings.listener, settings.bind_addr) {
+            (Some(listener), _) => listener,
+            (None, Some(addr)) => {
+                info!("Starting Socks server...");
+                TcpListener::bind(addr).await?
+            }
+            (None, None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "neither a listener nor a bind address is configured",
+                )
+                .into())
+            }
+        };
+        let local_addr = listener.local_addr()?;
+        info!("Socks server listening at {:?}", &local_addr);
+        let handle = settings.runtime.unwrap_or_else(Handle::current);
+        let trigger = ShutdownTrigger::new();
+        #[cfg(unix)]
+        if settings.shutdown_on_sigterm {
+            let mut sigterm = signal(SignalKind::terminate())?;
+            let trigger = trigger.clone();
+            handle.spawn(async move {
+                tokio::select! {
+                    _ = sigterm.recv() => {
+                        info!("Received SIGTERM, shutting down...");
+                        trigger.shutdown();
+                    }
+                    _ = trigger.stopped() => {}
+                }
+            });
+        }
+        if let Some(quotas) = &settings.quotas {
+            let quotas = quotas.clone();
+            let trigger = trigger.clone();
+            handle.spawn(async move {
+                loop {
+                    tokio::select! {
+                        _ = time::sleep(quotas.flush_interval) => {}
+                        _ = trigger.stopped() => break,
+                    }
+                    if let Err(e) = quotas.flush() {
+                        warn!("Couldn't persist quota usage: {:?}", e);
+                    }
+                }
+            });
+        }
+        let metrics = Arc::new(Metrics::default());
+        let bandwidth = Arc::new(BandwidthShaper::new(
+            settings.bandwidth,
+            settings.global_bandwidth,
+        ));
+        let ctx = Arc::new(ServerContext {
+            auth_provider: self.auth_provider,
+            resolver: self.resolver,
+            connect_options: settings.connect_options,
+            shutdown: trigger.clone(),
+            sessions: Arc::new(SessionTracker::new()),
+            drain_timeout: settings.drain_timeout,
+            timeouts: settings.timeouts,
+            admission: Arc::new(Admission::new(settings.limits)),
+            metrics: metrics.clone(),
+            rules: settings.rules,
+            authorizer: settings.authorizer,
+            router: settings.router,
+            ip_filter: settings.ip_filter,
+            bandwidth: bandwidth.clone(),
+            quotas: settings.quotas,
+        });
+        let task = handle.spawn(SocksServer::serve(
+            listener,
+            Arc::new(self.acceptor),
+            ctx,
+            handle.clone(),
+        ));
+        Ok(ServerHandle::new(
+            local_addr, trigger, metrics, bandwidth, task,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use socks_rs_common::response::ResponseCode;
+
+    #[tokio::test]
+    async fn starts_on_the_bind_address() {
+        let echo = testing::echo_server().await;
+        let server = SocksServer::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .ip_filter(IpFilter::empty())
+            .start()
+            .await
+            .unwrap();
+        assert_ne!(server.local_addr().port(), 0);
+        let (mut stream, code) = testing::connect(server.local_addr(), echo).await;
+        assert_eq!(code, ResponseCode::Success);
+        testing::assert_echoes(&mut stream).await;
+        drop(stream);
+        server.shutdown();
+        server.join().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn denies_private_ranges_by_default() {
+        let echo = testing::echo_server().await;
+        let server = SocksServer::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .start()
+            .await
+            .unwrap();
+        let (_, code) = testing::connect(server.local_addr(), echo).await;
+        assert_eq!(code, ResponseCode::ConnectionNotAllowedByRuleset);
+        server.shutdown_now();
+        server.join().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn prefers_the_listener() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = SocksServer::builder()
+            .bind("127.0.0.1:1".parse().unwrap())
+            .listener(listener)
+            .start()
+            .await
+            .unwrap();
+        assert_eq!(server.local_addr(), addr);
+        server.shutdown_now();
+        server.join().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn requires_an_address() {
+        assert!(SocksServer::builder().start().await.is_err());
+    }
+}
@@ -0,0 +1,195 @@
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::time::Instant;
+
+/// Capacity of the maps keyed by what clients send, e.g. their IP or
+/// username.
+pub(crate) const DEFAULT_CAPACITY: usize = 65536;
+
+/// A map whose entries expire, for state kept per client, user or request
+/// that must stay bounded whatever clients send.
+///
+/// Expired entries are dropped as the map is updated. A full map makes room
+/// for a new key by evicting the entry expiring first, so that new keys are
+/// always tracked and entries are only dropped early in favor of ones that
+/// would outlive them.
+#[derive(Debug)]
+pub(crate) struct ExpiringMap<K, V> {
+    entries: HashMap<K, Entry<V>>,
+    /// Keys by expiry, the sequence number telling apart entries expiring at
+    /// the same time.
+    expiries: BTreeMap<(Instant, u64), K>,
+    capacity: usize,
+    next_seq: u64,
+}
+
+#[derive(Debug)]
+struct Entry<V> {
+    value: V,
+    expires_at: Instant,
+    seq: u64,
+}
+
+impl<K: Clone + Eq + Hash, V> ExpiringMap<K, V> {
+    pub(crate) fn new(capacity: usize) -> ExpiringMap<K, V> {
+        ExpiringMap {
+            entries: HashMap::new(),
+            expiries: BTreeMap::new(),
+            capacity: capacity.max(1),
+            next_seq: 0,
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub(crate) fn get<Q>(&self, key: &Q, now: Instant) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.entries
+            .get(key)
+            .filter(|entry| entry.expires_at > now)
+            .map(|entry| &entry.value)
+    }
+
+    pub(crate) fn get_mut<Q>(&mut self, key: &Q, now: Instant) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.purge(now);
+        self.entries.get_mut(key).map(|entry| &mut entry.value)
+    }
+
+    /// Inserts or replaces the entry of `key`, evicting the entry expiring
+    /// first if the map is full.
+    pub(crate) fn insert(&mut self, key: K, value: V, expires_at: Instant, now: Instant) {
+        self.purge(now);
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            if let Some((_, evicted)) = self.expiries.pop_first() {
+                self.entries.remove(&evicted);
+            }
+        }
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.expiries.insert((expires_at, seq), key.clone());
+        let entry = Entry {
+            value,
+            expires_at,
+            seq,
+        };
+        if let Some(previous) = self.entries.insert(key, entry) {
+            self.expiries.remove(&(previous.expires_at, previous.seq));
+        }
+    }
+
+    /// Changes when the entry of `key` expires.
+    pub(crate) fn set_expiry<Q>(&mut self, key: &Q, expires_at: Instant)
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        if let Some(entry) = self.entries.get_mut(key) {
+            if let Some(key) = self.expiries.remove(&(entry.expires_at, entry.seq)) {
+                entry.expires_at = expires_at;
+                self.expiries.insert((expires_at, entry.seq), key);
+            }
+        }
+    }
+
+    pub(crate) fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let entry = self.entries.remove(key)?;
+        self.expiries.remove(&(entry.expires_at, entry.seq));
+        Some(entry.value)
+    }
+
+    /// The entries that haven't expired yet.
+    pub(crate) fn iter(&self, now: Instant) -> impl Iterator<Item = (&K, &V)> {
+        self.entries
+            .iter()
+            .filter(move |(_, entry)| entry.expires_at > now)
+            .map(|(key, entry)| (key, &entry.value))
+    }
+
+    /// Drops the expired entries.
+    fn purge(&mut self, now: Instant) {
+        while let Some(entry) = self.expiries.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            let key = entry.remove();
+            self.entries.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const SEC: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn expires_entries() {
+        let now = Instant::now();
+        let mut map = ExpiringMap::new(8);
+        map.insert("a", 1, now + SEC, now);
+        map.insert("b", 2, now + 2 * SEC, now);
+        assert_eq!(map.get("a", now), Some(&1));
+        assert_eq!(map.get("a", now + SEC), None);
+        assert_eq!(map.iter(now + SEC).count(), 1);
+        assert_eq!(map.get_mut("b", now + SEC), Some(&mut 2));
+        assert_eq!(map.len(), 1);
+
+        map.set_expiry("b", now + 10 * SEC);
+        assert_eq!(map.get_mut("b", now + 5 * SEC), Some(&mut 2));
+        map.insert("b", 3, now + 6 * SEC, now);
+        assert_eq!(map.get_mut("b", now + 5 * SEC), Some(&mut 3));
+        assert_eq!(map.get_mut("b", now + 6 * SEC), None);
+        assert_eq!(map.len(), 0);
+    }
+
+    #[test]
+    fn evicts_the_entry_expiring_first() {
+        let now = Instant::now();
+        let mut map = ExpiringMap::new(3);
+        map.insert(1, (), now + 3 * SEC, now);
+        map.insert(2, (), now + SEC, now);
+        map.insert(3, (), now + 2 * SEC, now);
+        map.insert(4, (), now + 4 * SEC, now);
+        assert_eq!(map.get_mut(&2, now), None);
+        // Replacing an entry doesn't evict anything.
+        map.insert(4, (), now + 5 * SEC, now);
+        assert_eq!(map.len(), 3);
+        map.insert(5, (), now + 5 * SEC, now);
+        assert_eq!(map.get_mut(&3, now), None);
+        assert!([1, 4, 5].iter().all(|key| map.get(key, now).is_some()));
+
+        assert_eq!(map.remove(&1), Some(()));
+        map.insert(6, (), now + SEC, now);
+        assert_eq!(map.len(), 3);
+    }
+
+    #[test]
+    fn stays_bounded() {
+        let now = Instant::now();
+        let mut map = ExpiringMap::new(1000);
+        for i in 0..10_000 {
+            map.insert(i, i, now + Duration::from_millis(i), now);
+        }
+        assert_eq!(map.len(), 1000);
+        assert_eq!(map.expiries.len(), 1000);
+        assert_eq!(map.get_mut(&9000, now), Some(&mut 9000));
+        assert_eq!(map.get_mut(&8999, now), None);
+    }
+}
@@ -0,0 +1,292 @@
+use crate::bandwidth::BandwidthShaper;
+use crate::metrics::Metrics;
+use socks_rs_common::Result;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ServerState {
+    /// Accepting connections and serving requests.
+    Running,
+    /// Accepting connections, but rejecting new CONNECT requests. Existing
+    /// sessions are kept.
+    Draining,
+    /// No longer accepting connections, existing sessions are given until the
+    /// drain timeout to finish.
+    ShuttingDown,
+    /// Remaining sessions are being closed.
+    Closed,
+}
+
+/// A handle to a running server.
+///
+/// Dropping the handle doesn't stop the server.
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    trigger: ShutdownTrigger,
+    metrics: Arc<Metrics>,
+    bandwidth: Arc<BandwidthShaper>,
+    task: JoinHandle<()>,
+}
+
+impl ServerHandle {
+    pub(crate) fn new(
+        local_addr: SocketAddr,
+        trigger: ShutdownTrigger,
+        metrics: Arc<Metrics>,
+        bandwidth: Arc<BandwidthShaper>,
+        task: JoinHandle<()>,
+    ) -> ServerHandle {
+        ServerHandle {
+            local_addr,
+            trigger,
+            metrics,
+            bandwidth,
+            task,
+        }
+    }
+
+    /// The address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Changes the bandwidth limits of the server while it runs.
+    pub fn bandwidth(&self) -> Arc<BandwidthShaper> {
+        self.bandwidth.clone()
+    }
+
+    pub fn shutdown_trigger(&self) -> ShutdownTrigger {
+        self.trigger.clone()
+    }
+
+    pub fn state(&self) -> ServerState {
+        self.trigger.state()
+    }
+
+    /// See `ShutdownTrigger::drain`.
+    pub fn drain(&self) {
+        self.trigger.drain();
+    }
+
+    /// See `ShutdownTrigger::resume`.
+    pub fn resume(&self) {
+        self.trigger.resume();
+    }
+
+    /// See `ShutdownTrigger::shutdown`.
+    pub fn shutdown(&self) {
+        self.trigger.shutdown();
+    }
+
+    /// See `ShutdownTrigger::shutdown_now`.
+    pub fn shutdown_now(&self) {
+        self.trigger.shutdown_now();
+    }
+
+    /// Waits for the server to stop and all of its sessions to be closed.
+    pub async fn join(self) -> Result<()> {
+        self.task.await.map_err(|e| io::Error::other(e).into())
+    }
+}
+
+/// Controls the lifecycle of a server, can be cloned and sent to other tasks.
+#[derive(Clone, Debug)]
+pub struct ShutdownTrigger {
+    tx: Arc<watch::Sender<ServerState>>,
+}
+
+impl ShutdownTrigger {
+    pub(crate) fn new() -> ShutdownTrigger {
+        let (tx, _) = watch::channel(ServerState::Running);
+        ShutdownTrigger { tx: Arc::new(tx) }
+    }
+
+    pub fn state(&self) -> ServerState {
+        *self.tx.borrow()
+    }
+
+    /// Rejects new CONNECT requests while keeping existing sessions, e.g. to
+    /// take the server out of a load balancer before a restart.
+    pub fn drain(&self) {
+        self.transition(ServerState::Running, ServerState::Draining);
+    }
+
+    /// Leaves the draining state.
+    pub fn resume(&self) {
+        self.transition(ServerState::Draining, ServerState::Running);
+    }
+
+    /// Stops accepting connections, lets in-flight handshakes and relays finish
+    /// and closes whatever is left once the drain timeout expires.
+    pub fn shutdown(&self) {
+        self.tx.send_if_modified(|state| match state {
+            ServerState::Running | ServerState::Draining => {
+                *state = ServerState::ShuttingDown;
+                true
+            }
+            _ => false,
+        });
+    }
+
+    /// Stops accepting connections and closes all sessions immediately.
+    pub fn shutdown_now(&self) {
+        self.tx.send_if_modified(|state| {
+            let modified = *state != ServerState::Closed;
+            *state = ServerState::Closed;
+            modified
+        });
+    }
+
+    fn transition(&self, from: ServerState, to: ServerState) {
+        self.tx.send_if_modified(|state| {
+            if *state != from {
+                return false;
+            }
+            *state = to;
+            true
+        });
+    }
+
+    /// Completes once the server stops accepting connections.
+    pub(crate) async fn stopped(&self) {
+        self.wait_for(|x| matches!(x, ServerState::ShuttingDown | ServerState::Closed))
+            .await
+    }
+
+    /// Completes once the remaining sessions have to be closed.
+    pub(crate) async fn closed(&self) {
+        self.wait_for(|x| *x == ServerState::Closed).await
+    }
+
+    async fn wait_for(&self, f: impl FnMut(&ServerState) -> bool) {
+        let mut rx = self.tx.subscribe();
+        // The sender is owned by `self`, so the channel can't be closed here.
+        let _ = rx.wait_for(f).await;
+    }
+}
+
+/// Counts the active sessions of a server.
+#[derive(Debug)]
+pub(crate) struct SessionTracker {
+    tx: watch::Sender<usize>,
+}
+
+impl SessionTracker {
+    pub(crate) fn new() -> SessionTracker {
+        let (tx, _) = watch::channel(0);
+        SessionTracker { tx }
+    }
+
+    pub(crate) fn enter(self: &Arc<Self>) -> SessionGuard {
+        self.tx.send_modify(|x| *x += 1);
+        SessionGuard {
+            tracker: self.clone(),
+        }
+    }
+
+    pub(crate) fn active(&self) -> usize {
+        *self.tx.borrow()
+    }
+
+    /// Completes once there is no active session.
+    pub(crate) async fn idle(&self) {
+        let mut rx = self.tx.subscribe();
+        let _ = rx.wait_for(|x| *x == 0).await;
+    }
+}
+
+pub(crate) struct SessionGuard {
+    tracker: Arc<SessionTracker>,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.tracker.tx.send_modify(|x| *x -= 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ip_filter::IpFilter;
+    use crate::testing::{self, StalledAcceptor};
+    use crate::SocksServer;
+    use socks_rs_common::response::ResponseCode;
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+    use tokio::time::{self, Instant};
+
+    #[tokio::test]
+    async fn rejects_requests_while_draining() {
+        let echo = testing::echo_server().await;
+        let server = SocksServer::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .ip_filter(IpFilter::empty())
+            .start()
+            .await
+            .unwrap();
+        let (mut session, _) = testing::connect(server.local_addr(), echo).await;
+        server.drain();
+        assert_eq!(server.state(), ServerState::Draining);
+        let (_, code) = testing::connect(server.local_addr(), echo).await;
+        assert_eq!(code, ResponseCode::GeneralSocksServerFailure);
+        testing::assert_echoes(&mut session).await;
+        server.resume();
+        let (_, code) = testing::connect(server.local_addr(), echo).await;
+        assert_eq!(code, ResponseCode::Success);
+        server.shutdown_now();
+        server.join().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn drains_sessions_on_shutdown() {
+        let echo = testing::echo_server().await;
+        let server = SocksServer::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .ip_filter(IpFilter::empty())
+            .drain_timeout(Duration::from_millis(200))
+            .start()
+            .await
+            .unwrap();
+        let (mut session, _) = testing::connect(server.local_addr(), echo).await;
+        let start = Instant::now();
+        server.shutdown();
+        assert_eq!(server.state(), ServerState::ShuttingDown);
+        testing::assert_echoes(&mut session).await;
+        assert!(TcpStream::connect(server.local_addr()).await.is_err());
+        server.join().await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn closes_stalled_accepts_on_shutdown() {
+        for graceful in [true, false] {
+            let server = SocksServer::builder()
+                .bind("127.0.0.1:0".parse().unwrap())
+                .acceptor(StalledAcceptor)
+                .drain_timeout(Duration::from_millis(100))
+                .start()
+                .await
+                .unwrap();
+            let _client = TcpStream::connect(server.local_addr()).await.unwrap();
+            time::sleep(Duration::from_millis(50)).await;
+            if graceful {
+                server.shutdown();
+            } else {
+                server.shutdown_now();
+            }
+            time::timeout(Duration::from_secs(2), server.join())
+                .await
+                .expect("server didn't stop")
+                .unwrap();
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use ipnet::IpNet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Ranges denied by `IpFilter::new`.
+const DEFAULT_DENY: &[&str] = &[
+    // "This" network, loopback and link-local, which includes the
+    // 169.254.169.254 metadata endpoint of most cloud providers.
+    "0.0.0.0/8",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    // Private and shared address space, which includes Alibaba Cloud's
+    // 100.100.100.200 metadata endpoint.
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    // IETF protocol assignments, benchmarking, multicast, reserved and broadcast.
+    "192.0.0.0/24",
+    "198.18.0.0/15",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    // Unspecified, loopback, link-local, unique local (which includes AWS'
+    // fd00:ec2::254 metadata endpoint) and multicast.
+    "::/128",
+    "::1/128",
+    "fe80::/10",
+    "fc00::/7",
+    "ff00::/8",
+];
+
+/// Checks the addresses the server connects to on behalf of clients, after
+/// resolution, so that a hostname can't be used to reach the ranges it denies.
+///
+/// IPv4 addresses embedded in IPv6 addresses are checked too: IPv4-mapped,
+/// IPv4-compatible (`::/96`), 6to4 (`2002::/16`), the server and client of
+/// Teredo (`2001::/32`), NAT64 under the well-known `64:ff9b::/96` and local-use
+/// `64:ff9b:1::/48` prefixes, and under the prefixes set with `nat64_prefix`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IpFilter {
+    deny: Vec<IpNet>,
+    allow: Vec<IpNet>,
+    nat64_prefixes: Vec<Ipv6Addr>,
+}
+
+impl IpFilter {
+    /// Denies loopback, link-local, private, cloud metadata, multicast and
+    /// reserved ranges.
+    pub fn new() -> IpFilter {
+        IpFilter {
+            deny: DEFAULT_DENY
+                .iter()
+                .map(|net| net.parse().unwrap())
+                .collect(),
+            ..IpFilter::empty()
+        }
+    }
+
+    /// A filter denying nothing.
+    pub fn empty() -> IpFilter {
+        IpFilter {
+            deny: Vec::new(),
+            allow: Vec::new(),
+            nat64_prefixes: Vec::new(),
+        }
+    }
+
+    pub fn deny(mut self, net: IpNet) -> Self {
+        self.deny.push(net);
+        self
+    }
+
+    /// Allows `net` even if it is within a denied range.
+    pub fn allow(mut self, net: IpNet) -> Self {
+        self.allow.push(net);
+        self
+    }
+
+    /// Checks the IPv4 addresses embedded under the /96 NAT64 `prefix`, which
+    /// should be set to the prefix of `AddrFamilyPolicy::Nat64` if used.
+    pub fn nat64_prefix(mut self, prefix: Ipv6Addr) -> Self {
+        self.nat64_prefixes.push(prefix);
+        self
+    }
+
+    pub fn is_denied(&self, ip: IpAddr) -> bool {
+        if self.deny.is_empty() {
+            return false;
+        }
+        let embedded = match ip {
+            IpAddr::V6(ip) => self.embedded_ipv4(ip),
+            IpAddr::V4(_) => Vec::new(),
+        };
+        std::iter::once(ip)
+            .chain(embedded.into_iter().map(IpAddr::V4))
+            .any(|ip| self.matches(&self.deny, ip) && !self.matches(&self.allow, ip))
+    }
+
+    fn matches(&self, nets: &[IpNet], ip: IpAddr) -> bool {
+        nets.iter().any(|net| net.contains(&ip))
+    }
+
+    /// The IPv4 addresses `ip` may embed.
+    fn embedded_ipv4(&self, ip: Ipv6Addr) -> Vec<Ipv4Addr> {
+        if let Some(ip) = ip.to_ipv4_mapped() {
+            return vec![ip];
+        }
+        let segments = ip.segments();
+        let octets = ip.octets();
+        let mut embedded = Vec::new();
+        if segments[..6] == [0; 6] {
+            embedded.push(Ipv4Addr::new(
+                octets[12], octets[13], octets[14], octets[15],
+            ));
+        }
+        if segments[..2] == [0x2001, 0] {
+            // The client address is obfuscated by flipping all its bits.
+            embedded.push(Ipv4Addr::new(octets[4], octets[5], octets[6], octets[7]));
+            let client = u32::from_be_bytes([octets[12], octets[13], octets[14], octets[15]]);
+            embedded.push(Ipv4Addr::from(!client));
+        }
+        if segments[0] == 0x2002 {
+            embedded.push(Ipv4Addr::new(octets[2], octets[3], octets[4], octets[5]));
+        }
+        if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+            embedded.push(nat64_embedded(&octets, 96));
+        }
+        if segments[..3] == [0x64, 0xff9b, 1] {
+            // Operators may pick any prefix length within the local-use prefix.
+            embedded.extend([48, 56, 64, 96].map(|len| nat64_embedded(&octets, len)));
+        }
+        for prefix in &self.nat64_prefixes {
+            if prefix.octets()[..12] == octets[..12] {
+                embedded.push(nat64_embedded(&octets, 96));
+            }
+        }
+        embedded
+    }
+}
+
+impl Default for IpFilter {
+    fn default() -> Self {
+        IpFilter::new()
+    }
+}
+
+/// The IPv4 address embedded in a NAT64 address with a prefix of `len` bits,
+/// skipping bits 64 to 71 as RFC 6052 requires.
+fn nat64_embedded(octets: &[u8; 16], len: usize) -> Ipv4Addr {
+    let start = len / 8;
+    let mut embedded = octets[start..]
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| start + i != 8)
+        .map(|(_, octet)| *octet);
+    let mut next = || embedded.next().unwrap_or(0);
+    Ipv4Addr::new(next(), next(), next(), next())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn denied(filter: &IpFilter, ip: &str) -> bool {
+        filter.is_denied(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn denies_the_default_ranges() {
+        let filter = IpFilter::default();
+        assert_eq!(filter, IpFilter::new());
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "169.254.169.254",
+            "100.100.100.200",
+            "::1",
+            "fd00:ec2::254",
+            "fe80::1",
+        ] {
+            assert!(denied(&filter, ip), "{} isn't denied", ip);
+        }
+        for ip in ["192.0.2.1", "8.8.8.8", "2001:4860:4860::8888"] {
+            assert!(!denied(&filter, ip), "{} is denied", ip);
+        }
+        assert!(!denied(&IpFilter::empty(), "127.0.0.1"));
+    }
+
+    #[test]
+    fn allows_exceptions() {
+        let filter = IpFilter::new()
+            .deny("192.0.2.0/24".parse().unwrap())
+            .allow("10.0.0.0/24".parse().unwrap());
+        assert!(denied(&filter, "192.0.2.1"));
+        assert!(!denied(&filter, "10.0.0.1"));
+        assert!(denied(&filter, "10.0.1.1"));
+        assert!(!denied(&filter, "::ffff:10.0.0.1"));
+    }
+
+    #[test]
+    fn checks_embedded_ipv4_addresses() {
+        let filter = IpFilter::new();
+        for ip in [
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            "64:ff9b::a9fe:a9fe",
+            "2002:a9fe:a9fe::1",
+            "::169.254.169.254",
+            "::10.0.0.1",
+            // Teredo with 169.254.169.254 as the server, then as the client.
+            "2001:0:a9fe:a9fe::1",
+            "2001:0:808:808:0:0:5601:5601",
+            // 169.254.169.254 under /48, /56, /64 and /96 local-use prefixes.
+            "64:ff9b:1:a9fe:a9:fe00::",
+            "64:ff9b:1:a9:fe:a9fe:0:0",
+            "64:ff9b:1:0:a9:fea9:fe00:0",
+            "64:ff9b:1::a9fe:a9fe",
+        ] {
+            assert!(denied(&filter, ip), "{} isn't denied", ip);
+        }
+        for ip in [
+            "::ffff:8.8.8.8",
+            "::8.8.8.8",
+            "64:ff9b::808:808",
+            "2002:808:808::1",
+            "2001:0:808:808:0:0:f7f7:f7f7",
+        ] {
+            assert!(!denied(&filter, ip), "{} is denied", ip);
+        }
+
+        let prefix = "2001:db8:64::".parse().unwrap();
+        assert!(!denied(&filter, "2001:db8:64::a9fe:a9fe"));
+        let filter = filter.nat64_prefix(prefix);
+        assert!(denied(&filter, "2001:db8:64::a9fe:a9fe"));
+        assert!(!denied(&filter, "2001:db8:64::808:808"));
+    }
+}
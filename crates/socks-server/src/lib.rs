@@ -1,44 +1,67 @@
-use crate::acceptor::Acceptor;
-use crate::auth::{AuthProvider, BasicAuthProvider, PlainAuthProvider};
-use socks_rs_common::connector::{tcp_connect, DNSResolver, WrappedTcpStream};
+use crate::acceptor::{Acceptor, PlainAcceptor};
+use crate::auth::{
+    AuthContext, AuthProvider, BasicAuthProvider, ClientCertificate, PlainAuthProvider, Principal,
+};
+use crate::authorization::{AuthorizationRequest, Authorizer, Verdict};
+use crate::bandwidth::BandwidthShaper;
+use crate::builder::ServerBuilder;
+use crate::handle::{ServerHandle, ServerState, SessionTracker, ShutdownTrigger};
+use crate::ip_filter::IpFilter;
+use crate::limits::{Admission, AdmissionGuard, Rejection};
+use crate::metrics::Metrics;
+use crate::quota::QuotaTracker;
+use crate::routing::{Route, RouteRequest, Router};
+use crate::rules::{Action, RuleRequest, RuleSet};
+use crate::timeouts::Timeouts;
+use futures::future;
+use log::{debug, info, warn};
+use socks_rs_client::connector::PlainConnector;
+use socks_rs_client::{ProxyScheme, SocksClient};
+use socks_rs_common::connector::{
+    connect_happy_eyeballs, ConnectOptions, DNSResolver, PlainWrappedTcpStream, WrappedTcpStream,
+};
 use socks_rs_common::request::{AuthMethodsRequest, Request};
+use socks_rs_common::resolver::SystemResolver;
 use socks_rs_common::response::{AuthMethodsResponse, Response, ResponseCode};
 use socks_rs_common::{Addr, Command, Error, ProxyAuthScheme, Result, TargetAddr, Version};
-use log::{debug, info, warn};
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{self, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
 use tokio::runtime::Handle;
+use tokio::time;
 
 pub mod acceptor;
 pub mod auth;
+pub mod authorization;
+pub mod bandwidth;
+pub mod builder;
+mod expiring;
+pub mod handle;
+pub mod ip_filter;
+pub mod limits;
+pub mod metrics;
+pub mod quota;
 mod relay;
+pub mod routing;
+pub mod rules;
+#[cfg(test)]
+mod testing;
+pub mod timeouts;
+
+/// Delays between two attempts to accept a connection after an error.
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(5);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
 
 pub struct SocksServer;
 
 impl SocksServer {
-    // pub async fn start_no_auth<
-    //     T: ToSocketAddrs,
-    //     S: WrappedTcpStream + Send + Sync + Unpin + 'static,
-    //     A: Acceptor<S> + Send + Sync + 'static,
-    //     D: DNSResolver + Send + Sync + 'static,
-    // >(
-    //     addr: T,
-    //     acceptor: A,
-    //     resolver: D,
-    //     handle: Handle,
-    // ) -> Result<SocksServer> {
-    //     info!("Starting Socks server with no authentication...");
-    //     SocksServer::start(
-    //         addr,
-    //         PlainAuthProvider::new(),
-    //         acceptor,
-    //         resolver,
-    //         handle,
-    //     )
-    //         .await
-    // }
+    pub fn builder(
+    ) -> ServerBuilder<PlainAuthProvider, PlainWrappedTcpStream, PlainAcceptor, SystemResolver>
+    {
+        ServerBuilder::new()
+    }
 
     pub async fn start<
         T: ToSocketAddrs,
@@ -50,69 +73,125 @@ impl SocksServer {
         scheme: ProxyAuthScheme,
         acceptor: A,
         resolver: D,
+        connect_options: ConnectOptions,
         handle: Handle,
-    ) -> Result<SocksServer> {
+    ) -> Result<ServerHandle> {
+        info!("Starting Socks server...");
+        let listener = TcpListener::bind(addr).await?;
+        let builder = SocksServer::builder()
+            .listener(listener)
+            .acceptor(acceptor)
+            .resolver(resolver)
+            .connect_options(connect_options)
+            .runtime(handle);
         match scheme {
-            ProxyAuthScheme::None => {
-                SocksServer::start_inner(addr, PlainAuthProvider::new(), acceptor, resolver, handle)
-                    .await
-            }
+            ProxyAuthScheme::None => builder.start().await,
             ProxyAuthScheme::BasicAuth(cfg) => {
-                SocksServer::start_inner(
-                    addr,
-                    BasicAuthProvider::new(cfg.username(), cfg.password()),
-                    acceptor,
-                    resolver,
-                    handle,
-                )
-                .await
+                builder
+                    .auth_provider(BasicAuthProvider::new(cfg.username(), cfg.password()))
+                    .start()
+                    .await
             }
         }
     }
 
-    async fn start_inner<
-        T: ToSocketAddrs,
+    pub async fn start_with_listener<
         U: AuthProvider + Send + Sync + 'static,
         S: WrappedTcpStream + Send + Sync + Unpin + 'static,
         A: Acceptor<S> + Send + Sync + 'static,
         D: DNSResolver + Send + Sync + 'static,
     >(
-        addr: T,
+        listener: TcpListener,
         auth_provider: U,
         acceptor: A,
         resolver: D,
+        connect_options: ConnectOptions,
         handle: Handle,
-    ) -> Result<SocksServer> {
-        info!("Starting Socks server...");
-        let listener = TcpListener::bind(addr).await?;
-        SocksServer::start_with_listener(listener, auth_provider, acceptor, resolver, handle).await
+    ) -> Result<ServerHandle> {
+        SocksServer::builder()
+            .listener(listener)
+            .auth_provider(auth_provider)
+            .acceptor(acceptor)
+            .resolver(resolver)
+            .connect_options(connect_options)
+            .runtime(handle)
+            .start()
+            .await
     }
 
-    pub async fn start_with_listener<
+    async fn serve<
         U: AuthProvider + Send + Sync + 'static,
         S: WrappedTcpStream + Send + Sync + Unpin + 'static,
         A: Acceptor<S> + Send + Sync + 'static,
         D: DNSResolver + Send + Sync + 'static,
     >(
         listener: TcpListener,
-        auth_provider: U,
-        acceptor: A,
-        resolver: D,
+        acceptor: Arc<A>,
+        ctx: Arc<ServerContext<U, D>>,
         handle: Handle,
-    ) -> Result<SocksServer> {
-        let local_addr = listener.local_addr()?;
-        info!("Socks server listening at {:?}", &local_addr);
-        let server_auth_provider = Arc::new(auth_provider);
-        let resolver = Arc::new(resolver);
-        let acceptor = Arc::new(acceptor);
+    ) {
+        let mut backoff = ACCEPT_BACKOFF_MIN;
         loop {
-            match listener.accept().await {
-                Ok((socket, _)) => {
-                    let auth_provider = server_auth_provider.clone();
-                    let resolver_inner = resolver.clone();
+            let accepted = tokio::select! {
+                res = listener.accept() => res,
+                _ = ctx.shutdown.stopped() => break,
+            };
+            match accepted {
+                Ok((socket, peer_addr)) => {
+                    backoff = ACCEPT_BACKOFF_MIN;
+                    let admission = match ctx.admission.admit(peer_addr.ip()) {
+                        Ok(admission) => admission,
+                        Err(rejection) => {
+                            warn!("Rejected connection from {}: {}", peer_addr, rejection);
+                            ctx.metrics.record_rejection(rejection);
+                            continue;
+                        }
+                    };
+                    ctx.metrics.record_accepted();
                     let acceptor_inner = acceptor.clone();
+                    let ctx_inner = ctx.clone();
+                    let session = ctx.sessions.enter();
+                    // The handshake deadline covers the acceptor, e.g. a TLS
+                    // handshake, as well.
+                    let handshake_deadline = ctx
+                        .timeouts
+                        .handshake
+                        .map(|timeout| time::Instant::now() + timeout);
                     handle.spawn(async move {
-                        let socket = match acceptor_inner.accept(socket).await {
+                        let _session = session;
+                        let accept = async {
+                            match handshake_deadline {
+                                Some(deadline) => {
+                                    time::timeout_at(deadline, acceptor_inner.accept(socket))
+                                        .await
+                                        .unwrap_or_else(|_| {
+                                            Err(io::Error::new(
+                                                io::ErrorKind::TimedOut,
+                                                "handshake timed out",
+                                            ))
+                                        })
+                                }
+                                None => acceptor_inner.accept(socket).await,
+                            }
+                        };
+                        // A client stalling the acceptor mustn't hold up the
+                        // shutdown.
+                        let accepted = tokio::select! {
+                            res = accept => res,
+                            _ = ctx_inner.shutdown.closed() => {
+                                debug!("Connection from {} closed on server shutdown", peer_addr);
+                                return;
+                            }
+                        };
+                        let socket = match accepted {
+                            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                                info!(
+                                    "Handshake with {} timed out after {:?}",
+                                    peer_addr,
+                                    ctx_inner.timeouts.handshake.unwrap_or_default()
+                                );
+                                return;
+                            }
                             Err(e) => {
                                 warn!("Couldn't accept TCP socket with acceptor: {:?}", e);
                                 return;
@@ -128,41 +207,153 @@ impl SocksServer {
                             socket.get_stream_ref().peer_addr(),
                             socket.get_stream_ref().local_addr()
                         );
-                        let mut connection =
-                            SocksConnection::new(identifier, socket, auth_provider);
-                        connection.process(resolver_inner).await;
+                        let mut connection = SocksConnection::new(
+                            identifier,
+                            socket,
+                            peer_addr,
+                            ctx_inner,
+                            admission,
+                            handshake_deadline,
+                        );
+                        connection.process().await;
                     });
                 }
                 Err(e) => {
-                    println!("couldn't accept new client: {:?}", e);
-                    continue;
+                    // Errors such as EMFILE persist until some sessions are closed,
+                    // so back off instead of spinning on them.
+                    ctx.metrics.record_accept_error();
+                    warn!(
+                        "Couldn't accept new client: {:?}, retrying in {:?}",
+                        e, backoff
+                    );
+                    tokio::select! {
+                        _ = time::sleep(backoff) => {}
+                        _ = ctx.shutdown.stopped() => break,
+                    }
+                    backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
                 }
             }
         }
+        drop(listener);
+        info!(
+            "Socks server stopped accepting connections, draining {} sessions...",
+            ctx.sessions.active()
+        );
+        tokio::select! {
+            _ = ctx.sessions.idle() => {}
+            _ = ctx.shutdown.closed() => {}
+            _ = time::sleep(ctx.drain_timeout) => {
+                warn!(
+                    "Drain timeout expired, closing {} remaining sessions",
+                    ctx.sessions.active()
+                );
+            }
+        }
+        ctx.shutdown.shutdown_now();
+        ctx.sessions.idle().await;
+        if let Some(quotas) = &ctx.quotas {
+            if let Err(e) = quotas.flush() {
+                warn!("Couldn't persist quota usage: {:?}", e);
+            }
+        }
+        info!("Socks server stopped");
     }
 }
 
-struct SocksConnection<S: WrappedTcpStream, T: AuthProvider> {
+/// State shared by all connections of a server.
+struct ServerContext<U, D> {
+    auth_provider: U,
+    resolver: D,
+    connect_options: ConnectOptions,
+    shutdown: ShutdownTrigger,
+    sessions: Arc<SessionTracker>,
+    drain_timeout: Duration,
+    timeouts: Timeouts,
+    admission: Arc<Admission>,
+    metrics: Arc<Metrics>,
+    rules: RuleSet,
+    authorizer: Option<Box<dyn Authorizer + Send + Sync>>,
+    router: Option<Box<dyn Router + Send + Sync>>,
+    ip_filter: IpFilter,
+    bandwidth: Arc<BandwidthShaper>,
+    quotas: Option<Arc<QuotaTracker>>,
+}
+
+struct SocksConnection<S: WrappedTcpStream, U: AuthProvider, D: DNSResolver> {
     identifier: String,
     socket: S,
-    auth_provider: Arc<T>,
+    peer_addr: SocketAddr,
+    ctx: Arc<ServerContext<U, D>>,
+    admission: AdmissionGuard,
+    handshake_deadline: Option<time::Instant>,
+    principal: Principal,
 }
 
-impl<S: WrappedTcpStream + Send + Sync + Unpin, T: AuthProvider> SocksConnection<S, T> {
-    fn new(identifier: String, socket: S, auth_provider: Arc<T>) -> SocksConnection<S, T> {
+impl<S: WrappedTcpStream + Send + Sync + Unpin, U: AuthProvider, D: DNSResolver + Send + Sync>
+    SocksConnection<S, U, D>
+{
+    fn new(
+        identifier: String,
+        socket: S,
+        peer_addr: SocketAddr,
+        ctx: Arc<ServerContext<U, D>>,
+        admission: AdmissionGuard,
+        handshake_deadline: Option<time::Instant>,
+    ) -> SocksConnection<S, U, D> {
         SocksConnection {
             identifier,
             socket,
-            auth_provider,
+            peer_addr,
+            ctx,
+            admission,
+            handshake_deadline,
+            principal: Principal::anonymous(),
         }
     }
 
-    async fn process<D: DNSResolver>(&mut self, resolver: Arc<D>) {
+    async fn process(&mut self) {
+        let ctx = self.ctx.clone();
+        let identifier = self.identifier.clone();
+        let lifetime = async {
+            match ctx.timeouts.lifetime {
+                Some(lifetime) => time::sleep(lifetime).await,
+                None => future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = self.run() => {}
+            _ = ctx.shutdown.closed() => {
+                info!("{}: Socks connection closed on server shutdown", identifier);
+            }
+            _ = lifetime => {
+                info!(
+                    "{}: Socks connection closed after reaching its maximum lifetime of {:?}",
+                    identifier,
+                    ctx.timeouts.lifetime.unwrap_or_default()
+                );
+            }
+        }
+    }
+
+    async fn run(&mut self) {
         let nodelay = self.socket.get_stream_ref().nodelay();
         if let Err(e) = self.socket.get_stream_mut_ref().set_nodelay(true) {
             warn!("Couldn't enable tcp_nodelay: {:?}", e);
         }
-        let res = self.handshake(&resolver).await;
+        let res = match self.handshake_deadline {
+            Some(deadline) => match time::timeout_at(deadline, self.handshake()).await {
+                Ok(res) => res,
+                Err(_) => {
+                    info!(
+                        "{}: Socks handshake timed out after {:?}",
+                        self.identifier,
+                        self.ctx.timeouts.handshake.unwrap_or_default()
+                    );
+                    Err(io::Error::new(io::ErrorKind::TimedOut, "handshake timed out").into())
+                }
+            },
+            None => self.handshake().await,
+        };
         match nodelay {
             Ok(nodelay) => {
                 if let Err(e) = self.socket.get_stream_mut_ref().set_nodelay(nodelay) {
@@ -183,12 +374,33 @@ impl<S: WrappedTcpStream + Send + Sync + Unpin, T: AuthProvider> SocksConnection
                     self.identifier, err
                 );
                 let _ = self.socket.get_stream_mut_ref().shutdown().await;
-                return;
             }
         }
     }
 
-    async fn handshake<D: DNSResolver>(&mut self, resolver: &Arc<D>) -> Result<TcpStream> {
+    /// Parses the certificate the client presented, if any. One we can't parse
+    /// is ignored, so the client can still authenticate another way or gets
+    /// the no acceptable methods reply.
+    fn client_certificate(&self) -> Option<ClientCertificate> {
+        let der = self.socket.peer_certificate()?;
+        match ClientCertificate::from_der(der) {
+            Ok(certificate) => Some(certificate),
+            Err(e) => {
+                warn!(
+                    "{}: Ignoring the client certificate: {:?}",
+                    self.identifier, e
+                );
+                None
+            }
+        }
+    }
+
+    async fn handshake(&mut self) -> Result<TcpStream> {
+        let auth_ctx = AuthContext {
+            peer_addr: self.peer_addr,
+            local_addr: self.socket.get_stream_ref().local_addr()?,
+            client_certificate: self.client_certificate(),
+        };
         let (mut inbound, mut outbound) = io::split(&mut self.socket);
         debug!("{}: Reading auth methods request...", &self.identifier);
         let auth_method_request = AuthMethodsRequest::read_from(&mut inbound).await?;
@@ -208,14 +420,16 @@ impl<S: WrappedTcpStream + Send + Sync + Unpin, T: AuthProvider> SocksConnection
             &self.identifier,
             methods.len()
         );
-        let auth_provider = self.auth_provider.clone();
-        let method = auth_provider.select(&methods[..]).await;
-        if method.is_err() {
-            let response = AuthMethodsResponse::new(version, None);
-            response.write_to(&mut outbound).await?;
-            return Err(method.unwrap_err());
-        }
-        let method = method.unwrap();
+        let ctx = self.ctx.clone();
+        let auth_provider = &ctx.auth_provider;
+        let method = match auth_provider.select(&auth_ctx, &methods[..]).await {
+            Ok(method) => method,
+            Err(e) => {
+                let response = AuthMethodsResponse::new(version, None);
+                response.write_to(&mut outbound).await?;
+                return Err(e);
+            }
+        };
         debug!(
             "{}: Select socks auth method: {:?}",
             &self.identifier, method
@@ -223,33 +437,221 @@ impl<S: WrappedTcpStream + Send + Sync + Unpin, T: AuthProvider> SocksConnection
         let response = AuthMethodsResponse::new(version, Some(method));
         response.write_to(&mut outbound).await?;
         let connection = inbound.unsplit(outbound);
-        auth_provider.validate(version, method, connection).await?;
+        self.principal = auth_provider
+            .validate(&auth_ctx, version, method, connection)
+            .await?;
+        debug!("{}: Authenticated as {}", &self.identifier, self.principal);
         let (mut inbound, _) = io::split(connection);
         debug!("{}: Reading socks request...", &self.identifier);
-        let request = Request::read_from(&mut inbound).await?;
+        let mut request = Request::read_from(&mut inbound).await?;
         debug!(
             "{}: Received socks request: {:?}",
             &self.identifier, request
         );
+        if let Some(user) = &self.principal.username {
+            if let Err(rejection) = self.ctx.admission.admit_user(&mut self.admission, user) {
+                warn!("{}: Rejected request: {}", &self.identifier, rejection);
+                self.ctx.metrics.record_rejection(rejection);
+                return self
+                    .reject(request.version, ResponseCode::GeneralSocksServerFailure)
+                    .await;
+            }
+            if ctx
+                .quotas
+                .as_ref()
+                .is_some_and(|quotas| quotas.is_exceeded(user))
+            {
+                let rejection = Rejection::QuotaExceeded;
+                warn!("{}: Rejected request: {}", &self.identifier, rejection);
+                self.ctx.metrics.record_rejection(rejection);
+                return self
+                    .reject(request.version, ResponseCode::ConnectionNotAllowedByRuleset)
+                    .await;
+            }
+        }
+        let Some(mut connect_options) =
+            self.check_destination(&ctx, request.command, request.addr.inner())
+        else {
+            return self
+                .reject(request.version, ResponseCode::ConnectionNotAllowedByRuleset)
+                .await;
+        };
+        if let Some(authorizer) = &ctx.authorizer {
+            let verdict = authorizer
+                .authorize(&AuthorizationRequest {
+                    client: self.peer_addr,
+                    principal: &self.principal,
+                    command: request.command,
+                    destination: request.addr.inner(),
+                })
+                .await;
+            match verdict {
+                Ok(Verdict::Allow) => {}
+                Ok(Verdict::Deny) => {
+                    info!("{}: Request denied by authorizer", &self.identifier);
+                    return self
+                        .reject(request.version, ResponseCode::ConnectionNotAllowedByRuleset)
+                        .await;
+                }
+                Ok(Verdict::Rewrite(addr)) => {
+                    info!(
+                        "{}: Request to {} rewritten to {} by authorizer",
+                        &self.identifier,
+                        request.addr.inner(),
+                        addr
+                    );
+                    request.addr = Addr::new(addr);
+                    // The new destination must be allowed as well.
+                    match self.check_destination(&ctx, request.command, request.addr.inner()) {
+                        Some(options) => connect_options = options,
+                        None => {
+                            return self
+                                .reject(
+                                    request.version,
+                                    ResponseCode::ConnectionNotAllowedByRuleset,
+                                )
+                                .await;
+                        }
+                    }
+                }
+                Err(e) => {
+                    warn!("{}: Couldn't authorize request: {}", &self.identifier, e);
+                    return self
+                        .reject(request.version, ResponseCode::GeneralSocksServerFailure)
+                        .await;
+                }
+            }
+        }
+        let route = match ctx.router.as_ref().and_then(|router| {
+            router.route(
+                &RouteRequest {
+                    client: self.peer_addr,
+                    principal: &self.principal,
+                    command: request.command,
+                    destination: request.addr.inner(),
+                },
+                connect_options,
+            )
+        }) {
+            Some(route) => {
+                debug!("{}: Request routed with {:?}", &self.identifier, route);
+                route
+            }
+            None => Route::new(connect_options.clone()),
+        };
         debug!(
             "{}: Making request to upstream: {:?}...",
             &self.identifier, request.addr
         );
-        return match request.command {
-            Command::Connect => self.handle_connect_command(request, resolver).await,
+        match request.command {
+            Command::Connect => self.handle_connect_command(request, &route).await,
             Command::Bind => self.handle_bind_command(request).await,
             Command::UdpAssociate => self.handle_udp_associate_command(request).await,
-        };
+        }
+    }
+
+    /// Checks that the principal may reach `addr` and that the rules allow it,
+    /// returns the options to connect with.
+    fn check_destination<'a>(
+        &self,
+        ctx: &'a ServerContext<U, D>,
+        command: Command,
+        addr: &TargetAddr,
+    ) -> Option<&'a ConnectOptions> {
+        if !self.principal.may_reach(addr) {
+            info!(
+                "{}: Request denied, {} is not allowed to reach {}",
+                &self.identifier, self.principal, addr
+            );
+            return None;
+        }
+        let decision = ctx.rules.evaluate(&RuleRequest {
+            client: self.peer_addr.ip(),
+            principal: &self.principal,
+            command,
+            addr,
+        });
+        match decision.action {
+            Action::Allow => {
+                debug!("{}: Request {}", &self.identifier, decision);
+                Some(&ctx.connect_options)
+            }
+            Action::Deny => {
+                info!("{}: Request {}", &self.identifier, decision);
+                None
+            }
+            Action::Route(connect_options) => {
+                debug!("{}: Request {}", &self.identifier, decision);
+                Some(connect_options)
+            }
+        }
     }
 
-    async fn handle_connect_command<D: DNSResolver>(
+    /// Replies to the request with a failure `code`.
+    async fn reject(&mut self, version: Version, code: ResponseCode) -> Result<TcpStream> {
+        let response = Response::new(
+            version,
+            code,
+            Addr::new(TargetAddr::Addr(SocketAddr::from(([0, 0, 0, 0], 0)))),
+        );
+        response.write_to(&mut self.socket).await?;
+        Err(Error::ConnectionFailed(code))
+    }
+
+    async fn handle_connect_command(
         &mut self,
         request: Request,
-        resolver: &Arc<D>,
+        route: &Route,
     ) -> Result<TcpStream> {
-        let (_, mut outbound) = io::split(&mut self.socket);
+        if self.ctx.shutdown.state() == ServerState::Draining {
+            debug!("{}: Rejecting request while draining", &self.identifier);
+            return self
+                .reject(request.version, ResponseCode::GeneralSocksServerFailure)
+                .await;
+        }
         let target_addr = request.addr.inner();
-        let remote_conn_res = tcp_connect(target_addr, resolver.as_ref()).await;
+        let remote_conn_res = match &route.upstream {
+            Some(upstream) => {
+                // Hostnames are resolved by the upstream proxy, only addresses
+                // can be checked here.
+                if let TargetAddr::Addr(addr) = target_addr {
+                    if self.ctx.ip_filter.is_denied(addr.ip()) {
+                        info!("{}: Denied upstream address {}", &self.identifier, addr);
+                        return self
+                            .reject(request.version, ResponseCode::ConnectionNotAllowedByRuleset)
+                            .await;
+                    }
+                }
+                self.connect_through(upstream, target_addr, &route.options)
+                    .await
+            }
+            None => match self.ctx.resolver.resolve(target_addr).await {
+                Ok(addrs) => {
+                    // Only the addresses checked here are dialed, so that a
+                    // second resolution can't return something else.
+                    let (denied, allowed): (Vec<_>, Vec<_>) = addrs
+                        .into_iter()
+                        .partition(|addr| self.ctx.ip_filter.is_denied(addr.ip()));
+                    if !denied.is_empty() {
+                        info!(
+                            "{}: Denied upstream addresses of {}: {:?}",
+                            &self.identifier, target_addr, denied
+                        );
+                        if allowed.is_empty() {
+                            return self
+                                .reject(
+                                    request.version,
+                                    ResponseCode::ConnectionNotAllowedByRuleset,
+                                )
+                                .await;
+                        }
+                    }
+                    connect_happy_eyeballs(allowed, &route.options).await
+                }
+                Err(e) => Err(e),
+            },
+        };
+        let (_, mut outbound) = io::split(&mut self.socket);
         let addr = SocketAddr::from(([0, 0, 0, 0], 0));
         let conn = match remote_conn_res {
             Ok(remote_conn) => {
@@ -277,6 +679,30 @@ impl<S: WrappedTcpStream + Send + Sync + Unpin, T: AuthProvider> SocksConnection
         Ok(conn)
     }
 
+    /// Connects to `addr` through the SOCKS proxy `upstream`.
+    async fn connect_through(
+        &self,
+        upstream: &ProxyScheme,
+        addr: &TargetAddr,
+        options: &ConnectOptions,
+    ) -> io::Result<TcpStream> {
+        debug!(
+            "{}: Chaining through {:?}",
+            &self.identifier,
+            upstream.addr()
+        );
+        let connector = PlainConnector::with_options(&self.ctx.resolver, options.clone());
+        match SocksClient::connect(upstream, addr.clone(), Command::Connect, connector).await {
+            Ok((_, stream)) => Ok(stream.into_inner()),
+            Err(Error::IoError(e)) => Err(e),
+            Err(e) => Err(io::Error::other(format!(
+                "upstream proxy {} failed: {}",
+                upstream.addr(),
+                e
+            ))),
+        }
+    }
+
     async fn handle_bind_command(&self, request: Request) -> Result<TcpStream> {
         Err(Error::CommandNotSupported(request.command.into()))
     }
@@ -286,12 +712,342 @@ impl<S: WrappedTcpStream + Send + Sync + Unpin, T: AuthProvider> SocksConnection
     }
 
     async fn relay(&mut self, mut outbound: TcpStream) -> io::Result<()> {
-        warn!("Starting relay...");
-        let (written, received) = relay::relay(&mut self.socket, &mut outbound).await?;
-        debug!(
-            "Client wrote {} bytes and received {} bytes",
-            written, received
+        debug!("{}: Starting relay...", &self.identifier);
+        let upstream = outbound.peer_addr();
+        let metrics = &self.ctx.metrics;
+        metrics.record_session(&self.principal);
+        let shaper = self.ctx.bandwidth.session(self.principal.username());
+        let quota = match (&self.ctx.quotas, self.principal.username()) {
+            (Some(quotas), Some(user)) => Some(quotas.session(user)),
+            _ => None,
+        };
+        let traffic = relay::Traffic::default();
+        let transfer = relay::relay(
+            &mut self.socket,
+            &mut outbound,
+            &self.ctx.timeouts,
+            &shaper,
+            quota.as_ref(),
+            &traffic,
         );
-        Ok(())
+        let transfer = async {
+            match &quota {
+                Some(quota) => {
+                    tokio::select! {
+                        res = transfer => res,
+                        e = quota.exceeded() => Err(e),
+                    }
+                }
+                None => transfer.await,
+            }
+        };
+        let res = match self.principal.session_timeout {
+            Some(timeout) => time::timeout(timeout, transfer).await.unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("session timeout of {:?} reached", timeout),
+                ))
+            }),
+            None => transfer.await,
+        };
+        let (written, received) = (traffic.sent(), traffic.received());
+        metrics.record_traffic(&self.principal, written, received);
+        match &res {
+            Ok(()) => info!(
+                "{}: {} relayed to {:?}, wrote {} bytes and received {} bytes",
+                &self.identifier, self.principal, upstream, written, received
+            ),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::TimedOut | io::ErrorKind::PermissionDenied
+                ) =>
+            {
+                info!(
+                    "{}: Relay closed after writing {} bytes and receiving {} bytes: {}",
+                    &self.identifier, written, received, e
+                )
+            }
+            Err(e) => debug!(
+                "{}: Relay failed after writing {} bytes and receiving {} bytes: {:?}",
+                &self.identifier, written, received, e
+            ),
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::CertificateAuthProvider;
+    use crate::routing::EgressPool;
+    use crate::rules::Rule;
+    use crate::testing::{self, StalledAcceptor};
+    use async_trait::async_trait;
+    use socks_rs_common::AuthMethod;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+    use tokio::time::Instant;
+
+    /// A connection on which the client presented the certificate `der`.
+    struct CertificateStream {
+        inner: TcpStream,
+        der: Vec<u8>,
+    }
+
+    impl AsyncRead for CertificateStream {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for CertificateStream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+        }
+    }
+
+    impl WrappedTcpStream for CertificateStream {
+        fn get_stream_ref(&self) -> &TcpStream {
+            &self.inner
+        }
+
+        fn get_stream_mut_ref(&mut self) -> &mut TcpStream {
+            &mut self.inner
+        }
+
+        fn peer_certificate(&self) -> Option<&[u8]> {
+            Some(&self.der)
+        }
+    }
+
+    /// Rewrites every destination to the same address.
+    struct RewriteTo(SocketAddr);
+
+    #[async_trait]
+    impl Authorizer for RewriteTo {
+        async fn authorize(&self, _request: &AuthorizationRequest<'_>) -> Result<Verdict> {
+            Ok(Verdict::Rewrite(TargetAddr::Addr(self.0)))
+        }
+    }
+
+    /// Hands out connections on which clients presented the certificate `der`.
+    struct CertificateAcceptor(Vec<u8>);
+
+    #[async_trait]
+    impl Acceptor<CertificateStream> for CertificateAcceptor {
+        async fn accept(&self, socket: TcpStream) -> io::Result<CertificateStream> {
+            Ok(CertificateStream {
+                inner: socket,
+                der: self.0.clone(),
+            })
+        }
+    }
+
+    /// Checks that the server closes `stream` about `timeout` after `start`.
+    async fn assert_closed_after(mut stream: TcpStream, start: Instant, timeout: Duration) {
+        let mut buf = [0; 1];
+        let res = time::timeout(timeout * 10, stream.read(&mut buf))
+            .await
+            .expect("connection wasn't closed");
+        assert!(matches!(res, Ok(0) | Err(_)));
+        assert!(start.elapsed() >= timeout);
+    }
+
+    #[tokio::test]
+    async fn times_out_handshakes() {
+        let timeout = Duration::from_millis(100);
+        let timeouts = Timeouts {
+            handshake: Some(timeout),
+            ..Timeouts::new()
+        };
+        let server = SocksServer::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .timeouts(timeouts.clone())
+            .start()
+            .await
+            .unwrap();
+        let start = Instant::now();
+        let stream = TcpStream::connect(server.local_addr()).await.unwrap();
+        assert_closed_after(stream, start, timeout).await;
+
+        // The deadline covers the acceptor too, e.g. a TLS handshake.
+        let server = SocksServer::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .acceptor(StalledAcceptor)
+            .timeouts(timeouts)
+            .start()
+            .await
+            .unwrap();
+        let start = Instant::now();
+        let stream = TcpStream::connect(server.local_addr()).await.unwrap();
+        assert_closed_after(stream, start, timeout).await;
+    }
+
+    #[tokio::test]
+    async fn closes_sessions_at_their_lifetime() {
+        let echo = testing::echo_server().await;
+        let timeout = Duration::from_millis(100);
+        let server = SocksServer::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .ip_filter(IpFilter::empty())
+            .timeouts(Timeouts {
+                lifetime: Some(timeout),
+                ..Timeouts::new()
+            })
+            .start()
+            .await
+            .unwrap();
+        let start = Instant::now();
+        let (mut stream, _) = testing::connect(server.local_addr(), echo).await;
+        testing::assert_echoes(&mut stream).await;
+        assert_closed_after(stream, start, timeout).await;
+    }
+
+    #[tokio::test]
+    async fn ignores_invalid_client_certificates() {
+        let server = SocksServer::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .acceptor(CertificateAcceptor(b"not a certificate".to_vec()))
+            .auth_provider(CertificateAuthProvider::new())
+            .start()
+            .await
+            .unwrap();
+        // The client is told no method is acceptable, as without a certificate.
+        let (_, method) = testing::negotiate(server.local_addr(), &[AuthMethod::None]).await;
+        assert_eq!(method, 0xff);
+    }
+
+    #[cfg(feature = "rustls")]
+    #[tokio::test]
+    async fn authenticates_client_certificates() {
+        let key = rcgen::KeyPair::generate().unwrap();
+        let der = rcgen::CertificateParams::new(vec!["alice.example.com".to_owned()])
+            .unwrap()
+            .self_signed(&key)
+            .unwrap()
+            .der()
+            .to_vec();
+        let echo = testing::echo_server().await;
+        let server = SocksServer::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .ip_filter(IpFilter::empty())
+            .acceptor(CertificateAcceptor(der))
+            .auth_provider(CertificateAuthProvider::new())
+            .start()
+            .await
+            .unwrap();
+        let (mut stream, code) = testing::connect(server.local_addr(), echo).await;
+        assert_eq!(code, ResponseCode::Success);
+        testing::assert_echoes(&mut stream).await;
+    }
+
+    #[tokio::test]
+    async fn checks_rewritten_destinations() {
+        let allowed = testing::echo_server().await;
+        let denied = testing::echo_server().await;
+        let rules = RuleSet::new()
+            .rule(Rule::new("denied", Action::Deny).ports(denied.port()..=denied.port()));
+        let server = SocksServer::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .ip_filter(IpFilter::empty())
+            .rules(rules.clone())
+            .authorizer(RewriteTo(denied))
+            .start()
+            .await
+            .unwrap();
+        let (_, code) = testing::connect(server.local_addr(), allowed).await;
+        assert_eq!(code, ResponseCode::ConnectionNotAllowedByRuleset);
+
+        let server = SocksServer::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .ip_filter(IpFilter::empty())
+            .rules(rules)
+            .authorizer(RewriteTo(allowed))
+            .start()
+            .await
+            .unwrap();
+        let (mut stream, code) = testing::connect(server.local_addr(), allowed).await;
+        assert_eq!(code, ResponseCode::Success);
+        testing::assert_echoes(&mut stream).await;
+    }
+
+    #[tokio::test]
+    async fn routes_connections() {
+        let echo = testing::echo_server().await;
+        let server = |addresses: &str| {
+            SocksServer::builder()
+                .bind("127.0.0.1:0".parse().unwrap())
+                .ip_filter(IpFilter::empty())
+                .router(
+                    EgressPool::new().addresses([addresses.parse::<std::net::IpAddr>().unwrap()]),
+                )
+                .start()
+        };
+        let routed = server("127.0.0.1").await.unwrap();
+        let (mut stream, code) = testing::connect(routed.local_addr(), echo).await;
+        assert_eq!(code, ResponseCode::Success);
+        testing::assert_echoes(&mut stream).await;
+
+        // An address that isn't local can't be bound.
+        let routed = server("192.0.2.1").await.unwrap();
+        let (_, code) = testing::connect(routed.local_addr(), echo).await;
+        assert_eq!(code, ResponseCode::NetworkUnreachable);
+    }
+
+    #[tokio::test]
+    async fn chains_through_upstream_proxies() {
+        let echo = testing::echo_server().await;
+        let upstream = SocksServer::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .ip_filter(IpFilter::empty())
+            .auth_provider(BasicAuthProvider::new("alice", "secret"))
+            .start()
+            .await
+            .unwrap();
+        let server = |password: &str, ip_filter: IpFilter| {
+            let proxy = ProxyScheme::new_with_basic_auth(
+                Version::V5,
+                TargetAddr::Addr(upstream.local_addr()),
+                "alice".to_owned(),
+                password.to_owned(),
+            );
+            SocksServer::builder()
+                .bind("127.0.0.1:0".parse().unwrap())
+                .ip_filter(ip_filter)
+                .router(EgressPool::new().addresses([proxy]))
+                .start()
+        };
+        let chained = server("secret", IpFilter::empty()).await.unwrap();
+        let (mut stream, code) = testing::connect(chained.local_addr(), echo).await;
+        assert_eq!(code, ResponseCode::Success);
+        testing::assert_echoes(&mut stream).await;
+
+        let chained = server("wrong", IpFilter::empty()).await.unwrap();
+        let (_, code) = testing::connect(chained.local_addr(), echo).await;
+        assert_eq!(code, ResponseCode::NetworkUnreachable);
+
+        // Destination addresses are still checked.
+        let chained = server("secret", IpFilter::new()).await.unwrap();
+        let (_, code) = testing::connect(chained.local_addr(), echo).await;
+        assert_eq!(code, ResponseCode::ConnectionNotAllowedByRuleset);
     }
 }
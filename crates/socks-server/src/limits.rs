@@ -0,0 +1,372 @@
+use crate::expiring::{ExpiringMap, DEFAULT_CAPACITY};
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Admission limits of a server, all of them are disabled by default.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Limits {
+    /// Maximum number of concurrent sessions.
+    pub max_sessions: Option<usize>,
+    /// Maximum number of concurrent sessions per client IP, or per /64
+    /// network for IPv6 clients.
+    pub max_sessions_per_ip: Option<usize>,
+    /// Maximum number of concurrent sessions per authenticated user.
+    pub max_sessions_per_user: Option<usize>,
+    /// Rate of new connections allowed per client IP, or per /64 network for
+    /// IPv6 clients. Up to 65536 clients are tracked, those whose bucket
+    /// refills first are forgotten first.
+    pub connection_rate: Option<RateLimit>,
+}
+
+impl Limits {
+    pub fn new() -> Limits {
+        Limits::default()
+    }
+}
+
+/// Allows `burst` events at once, refilled at `burst` per `period`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub period: Duration,
+}
+
+impl RateLimit {
+    pub fn new(burst: u32, period: Duration) -> RateLimit {
+        RateLimit { burst, period }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Rejection {
+    /// The server reached `max_sessions`.
+    MaxSessions,
+    /// The client IP reached `max_sessions_per_ip`.
+    MaxSessionsPerIp,
+    /// The user reached `max_sessions_per_user`.
+    MaxSessionsPerUser,
+    /// The client IP exceeded `connection_rate`.
+    ConnectionRate,
+    /// The user is over quota.
+    QuotaExceeded,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::MaxSessions => write!(f, "too many sessions"),
+            Rejection::MaxSessionsPerIp => write!(f, "too many sessions from client IP"),
+            Rejection::MaxSessionsPerUser => write!(f, "too many sessions for user"),
+            Rejection::ConnectionRate => write!(f, "connection rate exceeded"),
+            Rejection::QuotaExceeded => write!(f, "quota exceeded"),
+        }
+    }
+}
+
+/// Enforces `Limits` on incoming connections.
+pub(crate) struct Admission {
+    limits: Limits,
+    state: Mutex<AdmissionState>,
+}
+
+struct AdmissionState {
+    sessions: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    per_user: HashMap<String, usize>,
+    /// Buckets expiring once full, as they are then as good as new ones.
+    rate: ExpiringMap<IpAddr, TokenBucket>,
+}
+
+/// The address clients are limited by: their IP, or the /64 network of IPv6
+/// clients, which is usually assigned as a whole to a single subscriber.
+pub(crate) fn source(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & !u128::from(u64::MAX))),
+        ip => ip,
+    }
+}
+
+impl Admission {
+    pub(crate) fn new(limits: Limits) -> Admission {
+        Admission {
+            limits,
+            state: Mutex::new(AdmissionState {
+                sessions: 0,
+                per_ip: HashMap::new(),
+                per_user: HashMap::new(),
+                rate: ExpiringMap::new(DEFAULT_CAPACITY),
+            }),
+        }
+    }
+
+    /// Admits a new connection from `ip`, the returned guard releases it once dropped.
+    pub(crate) fn admit(self: &Arc<Self>, ip: IpAddr) -> Result<AdmissionGuard, Rejection> {
+        let ip = source(ip);
+        let mut state = self.state.lock().unwrap();
+        if let Some(rate) = &self.limits.connection_rate {
+            let now = Instant::now();
+            let allowed = match state.rate.get_mut(&ip, now) {
+                Some(bucket) => {
+                    let allowed = bucket.take(rate, now);
+                    let full_at = bucket.full_at(rate);
+                    state.rate.set_expiry(&ip, full_at);
+                    allowed
+                }
+                None => {
+                    let mut bucket = TokenBucket::new(rate, now);
+                    let allowed = bucket.take(rate, now);
+                    let full_at = bucket.full_at(rate);
+                    state.rate.insert(ip, bucket, full_at, now);
+                    allowed
+                }
+            };
+            if !allowed {
+                return Err(Rejection::ConnectionRate);
+            }
+        }
+        if matches!(self.limits.max_sessions, Some(max) if state.sessions >= max) {
+            return Err(Rejection::MaxSessions);
+        }
+        let per_ip = state.per_ip.get(&ip).copied().unwrap_or(0);
+        if matches!(self.limits.max_sessions_per_ip, Some(max) if per_ip >= max) {
+            return Err(Rejection::MaxSessionsPerIp);
+        }
+        state.sessions += 1;
+        *state.per_ip.entry(ip).or_insert(0) += 1;
+        Ok(AdmissionGuard {
+            admission: self.clone(),
+            ip,
+            user: None,
+        })
+    }
+
+    /// Admits a session of an authenticated `user`.
+    pub(crate) fn admit_user(
+        &self,
+        guard: &mut AdmissionGuard,
+        user: &str,
+    ) -> Result<(), Rejection> {
+        let mut state = self.state.lock().unwrap();
+        let per_user = state.per_user.get(user).copied().unwrap_or(0);
+        if matches!(self.limits.max_sessions_per_user, Some(max) if per_user >= max) {
+            return Err(Rejection::MaxSessionsPerUser);
+        }
+        *state.per_user.entry(user.to_owned()).or_insert(0) += 1;
+        guard.user = Some(user.to_owned());
+        Ok(())
+    }
+}
+
+fn release<K: Eq + Hash>(map: &mut HashMap<K, usize>, key: &K) {
+    if let Some(count) = map.get_mut(key) {
+        *count -= 1;
+        if *count == 0 {
+            map.remove(key);
+        }
+    }
+}
+
+pub(crate) struct AdmissionGuard {
+    admission: Arc<Admission>,
+    /// The `source` of the client.
+    ip: IpAddr,
+    user: Option<String>,
+}
+
+impl Drop for AdmissionGuard {
+    fn drop(&mut self) {
+        let mut state = self.admission.state.lock().unwrap();
+        state.sessions -= 1;
+        release(&mut state.per_ip, &self.ip);
+        if let Some(user) = &self.user {
+            release(&mut state.per_user, user);
+        }
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: &RateLimit, now: Instant) -> TokenBucket {
+        TokenBucket {
+            tokens: rate.burst as f64,
+            updated_at: now,
+        }
+    }
+
+    fn per_sec(rate: &RateLimit) -> f64 {
+        rate.burst as f64 / rate.period.as_secs_f64().max(f64::EPSILON)
+    }
+
+    fn refill(&mut self, rate: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * Self::per_sec(rate)).min(rate.burst as f64);
+        self.updated_at = now;
+    }
+
+    fn take(&mut self, rate: &RateLimit, now: Instant) -> bool {
+        self.refill(rate, now);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// When the bucket will be full again.
+    fn full_at(&self, rate: &RateLimit) -> Instant {
+        let missing = (rate.burst as f64 - self.tokens).max(0.0);
+        let wait =
+            Duration::try_from_secs_f64(missing / Self::per_sec(rate)).unwrap_or(rate.period);
+        self.updated_at + wait.min(rate.period)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn ip(n: u32) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::from(n))
+    }
+
+    #[test]
+    fn refills_token_buckets() {
+        let rate = RateLimit::new(2, Duration::from_secs(1));
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(&rate, now);
+        assert!(bucket.take(&rate, now));
+        assert!(bucket.take(&rate, now));
+        assert!(!bucket.take(&rate, now));
+        assert!(!bucket.take(&rate, now + Duration::from_millis(400)));
+        assert!(bucket.take(&rate, now + Duration::from_millis(600)));
+        let full_at = bucket.full_at(&rate) - now;
+        assert!(full_at > Duration::from_millis(1499) && full_at < Duration::from_millis(1501));
+        assert!(bucket.take(&rate, now + Duration::from_secs(10)));
+        assert!(bucket.take(&rate, now + Duration::from_secs(10)));
+        assert!(!bucket.take(&rate, now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn limits_sessions() {
+        let admission = Arc::new(Admission::new(Limits {
+            max_sessions: Some(3),
+            max_sessions_per_ip: Some(2),
+            ..Limits::new()
+        }));
+        let first = admission.admit(ip(1)).unwrap();
+        let _second = admission.admit(ip(1)).unwrap();
+        assert_eq!(
+            admission.admit(ip(1)).err(),
+            Some(Rejection::MaxSessionsPerIp)
+        );
+        let _third = admission.admit(ip(2)).unwrap();
+        assert_eq!(admission.admit(ip(3)).err(), Some(Rejection::MaxSessions));
+        drop(first);
+        let _fourth = admission.admit(ip(1)).unwrap();
+    }
+
+    #[test]
+    fn limits_sessions_per_user() {
+        let admission = Arc::new(Admission::new(Limits {
+            max_sessions_per_user: Some(1),
+            ..Limits::new()
+        }));
+        let mut first = admission.admit(ip(1)).unwrap();
+        admission.admit_user(&mut first, "alice").unwrap();
+        let mut second = admission.admit(ip(2)).unwrap();
+        assert_eq!(
+            admission.admit_user(&mut second, "alice"),
+            Err(Rejection::MaxSessionsPerUser)
+        );
+        admission.admit_user(&mut second, "bob").unwrap();
+        drop(first);
+        let mut third = admission.admit(ip(3)).unwrap();
+        admission.admit_user(&mut third, "alice").unwrap();
+        drop((second, third));
+        let state = admission.state.lock().unwrap();
+        assert_eq!(state.sessions, 0);
+        assert!(state.per_ip.is_empty());
+        assert!(state.per_user.is_empty());
+    }
+
+    #[test]
+    fn limits_the_connection_rate() {
+        let admission = Arc::new(Admission::new(Limits {
+            connection_rate: Some(RateLimit::new(2, Duration::from_secs(3600))),
+            ..Limits::new()
+        }));
+        admission.admit(ip(1)).unwrap();
+        admission.admit(ip(1)).unwrap();
+        assert_eq!(
+            admission.admit(ip(1)).err(),
+            Some(Rejection::ConnectionRate)
+        );
+        admission.admit(ip(2)).unwrap();
+    }
+
+    #[test]
+    fn drops_refilled_rate_buckets() {
+        let admission = Arc::new(Admission::new(Limits {
+            connection_rate: Some(RateLimit::new(1, Duration::from_nanos(1))),
+            ..Limits::new()
+        }));
+        for n in 0..1000 {
+            admission.admit(ip(n)).unwrap();
+        }
+        assert!(admission.state.lock().unwrap().rate.len() < 1000);
+    }
+
+    #[test]
+    fn bounds_the_tracked_sources() {
+        let admission = Arc::new(Admission::new(Limits {
+            connection_rate: Some(RateLimit::new(2, Duration::from_secs(3600))),
+            ..Limits::new()
+        }));
+        admission.admit(ip(0)).unwrap();
+        admission.admit(ip(0)).unwrap();
+        for n in 1..=DEFAULT_CAPACITY as u32 {
+            admission.admit(ip(n)).unwrap();
+        }
+        // New sources are still let in, the drained bucket is kept while
+        // those that were used the least are evicted.
+        assert_eq!(
+            admission.admit(ip(0)).err(),
+            Some(Rejection::ConnectionRate)
+        );
+        let state = admission.state.lock().unwrap();
+        assert_eq!(state.rate.len(), DEFAULT_CAPACITY);
+    }
+
+    #[test]
+    fn limits_ipv6_clients_by_network() {
+        let admission = Arc::new(Admission::new(Limits {
+            max_sessions_per_ip: Some(1),
+            connection_rate: Some(RateLimit::new(2, Duration::from_secs(3600))),
+            ..Limits::new()
+        }));
+        let first = admission.admit("2001:db8::1".parse().unwrap()).unwrap();
+        assert_eq!(
+            admission.admit("2001:db8::2:1".parse().unwrap()).err(),
+            Some(Rejection::MaxSessionsPerIp)
+        );
+        drop(first);
+        assert_eq!(
+            admission.admit("2001:db8::3:1".parse().unwrap()).err(),
+            Some(Rejection::ConnectionRate)
+        );
+        admission.admit("2001:db8:0:1::1".parse().unwrap()).unwrap();
+        assert_eq!(
+            source("::ffff:192.0.2.1".parse().unwrap()),
+            "192.0.2.1".parse::<IpAddr>().unwrap()
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use crate::auth::Principal;
+use crate::limits::Rejection;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Number of users counted individually, the others are counted together.
+const MAX_USERS: usize = 65536;
+
+/// Counters of a running server.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    accepted: AtomicU64,
+    accept_errors: AtomicU64,
+    rejected_max_sessions: AtomicU64,
+    rejected_max_sessions_per_ip: AtomicU64,
+    rejected_max_sessions_per_user: AtomicU64,
+    rejected_connection_rate: AtomicU64,
+    rejected_quota: AtomicU64,
+    users: Mutex<Users>,
+}
+
+#[derive(Debug, Default)]
+struct Users {
+    users: HashMap<String, UserMetrics>,
+    others: UserMetrics,
+}
+
+/// A point-in-time copy of `Metrics`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MetricsSnapshot {
+    pub accepted: u64,
+    pub accept_errors: u64,
+    pub rejected_max_sessions: u64,
+    pub rejected_max_sessions_per_ip: u64,
+    pub rejected_max_sessions_per_user: u64,
+    pub rejected_connection_rate: u64,
+    pub rejected_quota: u64,
+    /// Per authenticated user counters.
+    pub users: HashMap<String, UserMetrics>,
+    /// Counters of the users beyond the first 65536, which aren't counted
+    /// individually.
+    pub other_users: UserMetrics,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct UserMetrics {
+    /// Sessions that reached the relay.
+    pub sessions: u64,
+    /// Bytes sent by the user to upstream, counted once a session is closed.
+    pub bytes_sent: u64,
+    /// Bytes received by the user from upstream, counted once a session is closed.
+    pub bytes_received: u64,
+}
+
+impl Metrics {
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let users = self.users.lock().unwrap();
+        MetricsSnapshot {
+            accepted: self.accepted.load(Ordering::Relaxed),
+            accept_errors: self.accept_errors.load(Ordering::Relaxed),
+            rejected_max_sessions: self.rejected_max_sessions.load(Ordering::Relaxed),
+            rejected_max_sessions_per_ip: self.rejected_max_sessions_per_ip.load(Ordering::Relaxed),
+            rejected_max_sessions_per_user: self
+                .rejected_max_sessions_per_user
+                .load(Ordering::Relaxed),
+            rejected_connection_rate: self.rejected_connection_rate.load(Ordering::Relaxed),
+            rejected_quota: self.rejected_quota.load(Ordering::Relaxed),
+            users: users.users.clone(),
+            other_users: users.others.clone(),
+        }
+    }
+
+    pub(crate) fn record_accepted(&self) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_accept_error(&self) {
+        self.accept_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_rejection(&self, rejection: Rejection) {
+        let counter = match rejection {
+            Rejection::MaxSessions => &self.rejected_max_sessions,
+            Rejection::MaxSessionsPerIp => &self.rejected_max_sessions_per_ip,
+            Rejection::MaxSessionsPerUser => &self.rejected_max_sessions_per_user,
+            Rejection::ConnectionRate => &self.rejected_connection_rate,
+            Rejection::QuotaExceeded => &self.rejected_quota,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_session(&self, principal: &Principal) {
+        self.update_user(principal, |user| user.sessions += 1);
+    }
+
+    pub(crate) fn record_traffic(&self, principal: &Principal, sent: u64, received: u64) {
+        self.update_user(principal, |user| {
+            user.bytes_sent += sent;
+            user.bytes_received += received;
+        });
+    }
+
+    fn update_user(&self, principal: &Principal, f: impl FnOnce(&mut UserMetrics)) {
+        if let Some(username) = principal.username() {
+            let mut users = self.users.lock().unwrap();
+            let Users { users, others } = &mut *users;
+            let full = users.len() >= MAX_USERS;
+            match users.get_mut(username) {
+                Some(user) => f(user),
+                None if full => f(others),
+                None => f(users.entry(username.to_owned()).or_default()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use socks_rs_common::AuthMethod;
+
+    #[test]
+    fn counts_per_user() {
+        let metrics = Metrics::default();
+        let alice = Principal::new("alice", AuthMethod::UsernamePassword);
+        metrics.record_session(&alice);
+        metrics.record_traffic(&alice, 5, 2);
+        metrics.record_session(&alice);
+        metrics.record_traffic(&alice, 1, 1);
+        metrics.record_session(&Principal::anonymous());
+        metrics.record_rejection(Rejection::ConnectionRate);
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.rejected_connection_rate, 1);
+        assert_eq!(snapshot.users.len(), 1);
+        assert_eq!(
+            snapshot.users["alice"],
+            UserMetrics {
+                sessions: 2,
+                bytes_sent: 6,
+                bytes_received: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn bounds_the_counted_users() {
+        let metrics = Metrics::default();
+        for n in 0..=MAX_USERS {
+            let user = Principal::new(n.to_string(), AuthMethod::UsernamePassword);
+            metrics.record_session(&user);
+            metrics.record_traffic(&user, 1, 0);
+        }
+        metrics.record_session(&Principal::new("0", AuthMethod::UsernamePassword));
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.users.len(), MAX_USERS);
+        assert_eq!(snapshot.users["0"].sessions, 2);
+        assert_eq!(
+            snapshot.other_users,
+            UserMetrics {
+                sessions: 1,
+                bytes_sent: 1,
+                bytes_received: 0,
+            }
+        );
+    }
+}
@@ -0,0 +1,579 @@
+#[cfg(feature = "quota")]
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+#[cfg(feature = "quota")]
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+
+const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+/// Traffic a session counts on its own before adding it to the usage of its
+/// user.
+const FOLD_BYTES: u64 = 64 * 1024;
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Bytes a user may transfer, in both directions, unlimited when unset.
+/// Days and months are counted in UTC.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Quota {
+    pub daily: Option<u64>,
+    pub monthly: Option<u64>,
+    pub total: Option<u64>,
+}
+
+impl Quota {
+    pub fn new() -> Quota {
+        Quota::default()
+    }
+}
+
+/// Bytes a user transferred, in both directions.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "quota", derive(Deserialize, Serialize))]
+pub struct Usage {
+    pub daily: u64,
+    pub monthly: u64,
+    pub total: u64,
+    /// Day of the daily usage, in days since the Unix epoch.
+    pub day: i64,
+    /// Month of the monthly usage, in months since January 1970.
+    pub month: i64,
+}
+
+impl Usage {
+    /// Starts the counts over if the day or month changed.
+    fn roll_over(&mut self, (day, month): (i64, i64)) {
+        if self.day != day {
+            self.day = day;
+            self.daily = 0;
+        }
+        if self.month != month {
+            self.month = month;
+            self.monthly = 0;
+        }
+    }
+
+    fn exceeds(&self, quota: &Quota) -> bool {
+        let reached = |limit: Option<u64>, usage: u64| limit.is_some_and(|limit| usage >= limit);
+        reached(quota.daily, self.daily)
+            || reached(quota.monthly, self.monthly)
+            || reached(quota.total, self.total)
+    }
+}
+
+#[derive(Default)]
+struct State {
+    default: Quota,
+    quotas: HashMap<String, Quota>,
+    usage: HashMap<String, Usage>,
+    /// Whether usage changed since the last flush.
+    dirty: bool,
+    /// Notified when the user may have gone over quota, shared by the sessions
+    /// of the user and dropped with the last one.
+    notifiers: HashMap<String, Weak<Notify>>,
+}
+
+impl State {
+    fn quota(&self, user: &str) -> Quota {
+        self.quotas.get(user).copied().unwrap_or(self.default)
+    }
+
+    fn notify(&self, user: &str) {
+        if let Some(notify) = self.notifiers.get(user).and_then(Weak::upgrade) {
+            notify.notify_waiters();
+        }
+    }
+}
+
+/// Counts the daily, monthly and total traffic of users and holds them to
+/// their quota: requests of users over quota are rejected, and their sessions
+/// in progress closed if `terminate_sessions` is set.
+///
+/// Sessions add their traffic to the usage of their user every 64 KiB and once
+/// they close, so a session may go over quota by that much before it's noticed.
+///
+/// Usage may be persisted to a JSON file with the `quota` feature, which is
+/// written periodically by the servers using it and when they stop.
+pub struct QuotaTracker {
+    #[cfg(feature = "quota")]
+    path: Option<PathBuf>,
+    terminate_sessions: bool,
+    pub(crate) flush_interval: Duration,
+    state: Mutex<State>,
+}
+
+impl Default for QuotaTracker {
+    fn default() -> Self {
+        QuotaTracker {
+            #[cfg(feature = "quota")]
+            path: None,
+            terminate_sessions: false,
+            flush_interval: DEFAULT_FLUSH_INTERVAL,
+            state: Mutex::new(State::default()),
+        }
+    }
+}
+
+impl QuotaTracker {
+    /// A tracker keeping usage in memory only, without any quota.
+    pub fn new() -> QuotaTracker {
+        QuotaTracker::default()
+    }
+
+    /// A tracker persisting usage to `path`, loading it if the file exists.
+    #[cfg(feature = "quota")]
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<QuotaTracker> {
+        let path = path.as_ref();
+        let usage = match std::fs::read(path) {
+            Ok(contents) => serde_json::from_slice(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        let tracker = QuotaTracker {
+            path: Some(path.to_owned()),
+            ..QuotaTracker::default()
+        };
+        tracker.state.lock().unwrap().usage = usage;
+        Ok(tracker)
+    }
+
+    /// Quota of the users without one of their own.
+    pub fn default_quota(mut self, quota: Quota) -> Self {
+        self.state.get_mut().unwrap().default = quota;
+        self
+    }
+
+    /// Closes the sessions of users as soon as they go over quota.
+    pub fn terminate_sessions(mut self, enabled: bool) -> Self {
+        self.terminate_sessions = enabled;
+        self
+    }
+
+    /// How often usage is persisted, every minute by default.
+    pub fn flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = interval;
+        self
+    }
+
+    /// Sets the quota of `user`, or restores the default one if `None`.
+    pub fn set_quota(&self, user: &str, quota: Option<Quota>) {
+        let mut state = self.state.lock().unwrap();
+        match quota {
+            Some(quota) => state.quotas.insert(user.to_owned(), quota),
+            None => state.quotas.remove(user),
+        };
+        if self.terminate_sessions {
+            state.notify(user);
+        }
+    }
+
+    /// The quota `user` is held to.
+    pub fn quota(&self, user: &str) -> Quota {
+        self.state.lock().unwrap().quota(user)
+    }
+
+    pub fn usage(&self, user: &str) -> Usage {
+        let mut usage = self
+            .state
+            .lock()
+            .unwrap()
+            .usage
+            .get(user)
+            .cloned()
+            .unwrap_or_default();
+        usage.roll_over(period(SystemTime::now()));
+        usage
+    }
+
+    /// Usage of all the users who transferred anything since the last reset.
+    pub fn usages(&self) -> HashMap<String, Usage> {
+        let now = period(SystemTime::now());
+        let mut usages = self.state.lock().unwrap().usage.clone();
+        for usage in usages.values_mut() {
+            usage.roll_over(now);
+        }
+        usages
+    }
+
+    pub fn is_exceeded(&self, user: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let quota = state.quota(user);
+        match state.usage.get_mut(user) {
+            Some(usage) => {
+                usage.roll_over(period(SystemTime::now()));
+                usage.exceeds(&quota)
+            }
+            None => Usage::default().exceeds(&quota),
+        }
+    }
+
+    /// Starts the usage of `user` over.
+    pub fn reset(&self, user: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.usage.remove(user);
+        state.dirty = true;
+    }
+
+    pub fn reset_all(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.usage.clear();
+        state.dirty = true;
+    }
+
+    /// Persists usage if it changed since the last flush.
+    pub fn flush(&self) -> io::Result<()> {
+        #[cfg(feature = "quota")]
+        if let Some(path) = &self.path {
+            let contents = {
+                let mut state = self.state.lock().unwrap();
+                if !state.dirty {
+                    return Ok(());
+                }
+                state.dirty = false;
+                serde_json::to_vec_pretty(&state.usage)?
+            };
+            // Renaming makes the update atomic, so that a crash can't leave a
+            // truncated file behind.
+            let tmp = path.with_extension("tmp");
+            let res = std::fs::write(&tmp, contents).and_then(|_| std::fs::rename(&tmp, path));
+            if res.is_err() {
+                self.state.lock().unwrap().dirty = true;
+            }
+            return res;
+        }
+        Ok(())
+    }
+
+    fn record(&self, user: &str, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        let quota = state.quota(user);
+        state.dirty = true;
+        if !state.usage.contains_key(user) {
+            state.usage.insert(user.to_owned(), Usage::default());
+        }
+        let usage = state.usage.get_mut(user).expect("usage was just inserted");
+        usage.roll_over(period(SystemTime::now()));
+        let exceeded = usage.exceeds(&quota);
+        usage.daily += bytes;
+        usage.monthly += bytes;
+        usage.total += bytes;
+        if self.terminate_sessions && !exceeded && usage.exceeds(&quota) {
+            state.notify(user);
+        }
+    }
+
+    pub(crate) fn session(self: &Arc<Self>, user: &str) -> QuotaSession {
+        let mut state = self.state.lock().unwrap();
+        let notify = match state.notifiers.get(user).and_then(Weak::upgrade) {
+            Some(notify) => notify,
+            None => {
+                let notify = Arc::new(Notify::new());
+                state
+                    .notifiers
+                    .insert(user.to_owned(), Arc::downgrade(&notify));
+                notify
+            }
+        };
+        QuotaSession {
+            tracker: self.clone(),
+            user: user.to_owned(),
+            pending: AtomicU64::new(0),
+            notify,
+        }
+    }
+}
+
+/// Accounts the traffic of a session to its user.
+pub(crate) struct QuotaSession {
+    tracker: Arc<QuotaTracker>,
+    user: String,
+    /// Traffic not added to the usage of the user yet.
+    pending: AtomicU64,
+    notify: Arc<Notify>,
+}
+
+impl QuotaSession {
+    pub(crate) fn record(&self, bytes: u64) {
+        let pending = self.pending.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        if pending >= FOLD_BYTES {
+            self.fold();
+        }
+    }
+
+    /// Adds the pending traffic to the usage of the user.
+    fn fold(&self) {
+        let pending = self.pending.swap(0, Ordering::Relaxed);
+        if pending > 0 {
+            self.tracker.record(&self.user, pending);
+        }
+    }
+
+    /// Completes once the user goes over quota if sessions are to be
+    /// terminated then, never otherwise.
+    pub(crate) async fn exceeded(&self) -> io::Error {
+        loop {
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            // Registered before checking, so that no notification is missed.
+            notified.as_mut().enable();
+            self.fold();
+            if self.tracker.terminate_sessions && self.tracker.is_exceeded(&self.user) {
+                return io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("quota of {} exceeded", self.user),
+                );
+            }
+            notified.await;
+        }
+    }
+}
+
+impl Drop for QuotaSession {
+    fn drop(&mut self) {
+        self.fold();
+        let mut state = self.tracker.state.lock().unwrap();
+        // Other sessions can only get the notifier under the lock.
+        if Arc::strong_count(&self.notify) == 1 {
+            state.notifiers.remove(&self.user);
+        }
+    }
+}
+
+/// Day since the Unix epoch and month since January 1970 of `time`, in UTC.
+fn period(time: SystemTime) -> (i64, i64) {
+    let days = (time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / SECS_PER_DAY) as i64;
+    // Converts the day to a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (days, (year - 1970) * 12 + month - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time;
+
+    const DAY: Duration = Duration::from_secs(SECS_PER_DAY);
+
+    /// `days` days after the epoch, plus `secs` seconds.
+    fn at(days: u32, secs: u64) -> SystemTime {
+        UNIX_EPOCH + DAY * days + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn computes_periods() {
+        assert_eq!(period(UNIX_EPOCH), (0, 0));
+        assert_eq!(period(at(30, SECS_PER_DAY - 1)), (30, 0));
+        assert_eq!(period(at(31, 0)), (31, 1));
+        assert_eq!(period(at(10957, 0)), (10957, 360));
+        assert_eq!(period(at(19722, 86399)), (19722, 647));
+        // 2024 is a leap year.
+        assert_eq!(period(at(19782, 0)), (19782, 649));
+        assert_eq!(period(at(19783, 0)), (19783, 650));
+        assert_eq!(period(UNIX_EPOCH - DAY), (0, 0));
+    }
+
+    #[test]
+    fn rolls_usage_over() {
+        let mut usage = Usage {
+            daily: 10,
+            monthly: 20,
+            total: 30,
+            day: 19782,
+            month: 649,
+        };
+        usage.roll_over((19782, 649));
+        assert_eq!((usage.daily, usage.monthly, usage.total), (10, 20, 30));
+        usage.roll_over((19783, 649));
+        assert_eq!((usage.daily, usage.monthly, usage.total), (0, 20, 30));
+        usage.daily = 5;
+        usage.roll_over((19784, 650));
+        assert_eq!((usage.daily, usage.monthly, usage.total), (0, 0, 30));
+        assert_eq!((usage.day, usage.month), (19784, 650));
+    }
+
+    #[test]
+    fn checks_each_limit() {
+        let usage = Usage {
+            daily: 10,
+            monthly: 20,
+            total: 30,
+            ..Usage::default()
+        };
+        assert!(!usage.exceeds(&Quota::new()));
+        let quota = |daily, monthly, total| Quota {
+            daily,
+            monthly,
+            total,
+        };
+        assert!(usage.exceeds(&quota(Some(10), None, None)));
+        assert!(!usage.exceeds(&quota(Some(11), None, None)));
+        assert!(usage.exceeds(&quota(None, Some(20), None)));
+        assert!(!usage.exceeds(&quota(Some(11), Some(21), None)));
+        assert!(usage.exceeds(&quota(Some(11), Some(21), Some(30))));
+        assert!(Usage::default().exceeds(&quota(Some(0), None, None)));
+    }
+
+    #[test]
+    fn counts_usage_per_user() {
+        let tracker = QuotaTracker::new().default_quota(Quota {
+            daily: Some(100),
+            ..Quota::new()
+        });
+        tracker.set_quota("bob", Some(Quota::new()));
+        tracker.record("alice", 60);
+        tracker.record("bob", 200);
+        assert!(!tracker.is_exceeded("alice"));
+        assert!(!tracker.is_exceeded("bob"));
+        tracker.record("alice", 40);
+        assert!(tracker.is_exceeded("alice"));
+
+        let usage = tracker.usage("alice");
+        assert_eq!((usage.daily, usage.monthly, usage.total), (100, 100, 100));
+        assert_eq!((usage.day, usage.month), period(SystemTime::now()));
+        assert_eq!(
+            tracker.usage("carol"),
+            Usage {
+                day: usage.day,
+                month: usage.month,
+                ..Usage::default()
+            }
+        );
+        assert_eq!(tracker.usages().len(), 2);
+
+        tracker.set_quota("alice", Some(Quota::new()));
+        assert!(!tracker.is_exceeded("alice"));
+        tracker.set_quota("alice", None);
+        assert!(tracker.is_exceeded("alice"));
+        tracker.reset("alice");
+        assert!(!tracker.is_exceeded("alice"));
+        assert_eq!(tracker.usage("bob").total, 200);
+        tracker.reset_all();
+        assert!(tracker.usages().is_empty());
+    }
+
+    #[test]
+    fn starts_counts_over_every_day() {
+        let tracker = QuotaTracker::new().default_quota(Quota {
+            daily: Some(100),
+            total: Some(1000),
+            ..Quota::new()
+        });
+        tracker.record("alice", 100);
+        assert!(tracker.is_exceeded("alice"));
+        // Yesterday's usage.
+        let (day, _) = period(SystemTime::now());
+        tracker
+            .state
+            .lock()
+            .unwrap()
+            .usage
+            .get_mut("alice")
+            .unwrap()
+            .day = day - 1;
+        assert!(!tracker.is_exceeded("alice"));
+        let usage = tracker.usage("alice");
+        assert_eq!((usage.daily, usage.total), (0, 100));
+    }
+
+    #[tokio::test]
+    async fn terminates_sessions_over_quota() {
+        let tracker = Arc::new(
+            QuotaTracker::new()
+                .default_quota(Quota {
+                    total: Some(100),
+                    ..Quota::new()
+                })
+                .terminate_sessions(true),
+        );
+        let alice = tracker.session("alice");
+        let bob = tracker.session("bob");
+        let exceeded = tokio::spawn(async move { alice.exceeded().await });
+        bob.record(200);
+        time::sleep(Duration::from_millis(20)).await;
+        assert!(!exceeded.is_finished());
+        tracker.session("alice").record(100);
+        let e = time::timeout(Duration::from_secs(1), exceeded)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+
+        // Lowering the quota closes sessions too.
+        let carol = tracker.session("carol");
+        carol.record(50);
+        let exceeded = tokio::spawn(async move { carol.exceeded().await });
+        time::sleep(Duration::from_millis(20)).await;
+        assert!(!exceeded.is_finished());
+        tracker.set_quota(
+            "carol",
+            Some(Quota {
+                total: Some(50),
+                ..Quota::new()
+            }),
+        );
+        time::timeout(Duration::from_secs(1), exceeded)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn folds_traffic_into_the_tracker() {
+        let tracker = Arc::new(QuotaTracker::new());
+        let session = tracker.session("alice");
+        session.record(1000);
+        assert_eq!(tracker.usage("alice").total, 0);
+        session.record(FOLD_BYTES);
+        assert_eq!(tracker.usage("alice").total, FOLD_BYTES + 1000);
+        session.record(10);
+        drop(session);
+        assert_eq!(tracker.usage("alice").total, FOLD_BYTES + 1010);
+    }
+
+    #[test]
+    fn notifies_sessions_per_user() {
+        let tracker = Arc::new(QuotaTracker::new());
+        let (first, second) = (tracker.session("alice"), tracker.session("alice"));
+        let bob = tracker.session("bob");
+        assert!(Arc::ptr_eq(&first.notify, &second.notify));
+        assert!(!Arc::ptr_eq(&first.notify, &bob.notify));
+        drop((first, bob));
+        assert_eq!(tracker.state.lock().unwrap().notifiers.len(), 1);
+        drop(second);
+        assert!(tracker.state.lock().unwrap().notifiers.is_empty());
+    }
+
+    #[cfg(feature = "quota")]
+    #[test]
+    fn persists_usage() {
+        let path = std::env::temp_dir().join(format!("socks-rs-quota-{}.json", std::process::id()));
+        let tracker = QuotaTracker::open(&path).unwrap();
+        tracker.flush().unwrap();
+        assert!(!path.exists());
+        tracker.record("alice", 42);
+        tracker.flush().unwrap();
+        assert_eq!(QuotaTracker::open(&path).unwrap().usage("alice").total, 42);
+        tracker.reset("alice");
+        tracker.flush().unwrap();
+        assert!(QuotaTracker::open(&path).unwrap().usages().is_empty());
+
+        std::fs::write(&path, "not json").unwrap();
+        assert!(QuotaTracker::open(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,39 +1,346 @@
+use crate::bandwidth::{Direction, SessionShaper};
+use crate::quota::QuotaSession;
+use crate::timeouts::Timeouts;
 use futures::future;
-use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{self, Instant};
 
-pub async fn relay<'a, L, R>(l: &'a mut L, r: &'a mut R) -> io::Result<(u64, u64)>
+/// Bytes relayed so far, kept up to date even if the relay fails.
+#[derive(Debug, Default)]
+pub struct Traffic {
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+impl Traffic {
+    /// Bytes sent from the client to upstream.
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    /// Bytes received by the client from upstream.
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+}
+
+/// Relays data between `l` and `r` until both directions are closed, counting
+/// the bytes sent from `l` to `r` and from `r` to `l` in `traffic`.
+pub async fn relay<'a, L, R>(
+    l: &'a mut L,
+    r: &'a mut R,
+    timeouts: &Timeouts,
+    shaper: &SessionShaper,
+    quota: Option<&QuotaSession>,
+    traffic: &Traffic,
+) -> io::Result<()>
 where
     L: AsyncRead + AsyncWrite + Unpin + ?Sized,
     R: AsyncRead + AsyncWrite + Unpin + ?Sized,
 {
     let (mut lr, mut lw) = io::split(l);
     let (mut rr, mut rw) = io::split(r);
-    return relay_split(&mut lr, &mut lw, &mut rr, &mut rw).await;
+    let activity = Activity::new();
+    let client_to_server = transfer(
+        &mut lr,
+        &mut rw,
+        &activity.client_to_server,
+        &traffic.sent,
+        shaper,
+        quota,
+        Direction::Upload,
+    );
+    let server_to_client = transfer(
+        &mut rr,
+        &mut lw,
+        &activity.server_to_client,
+        &traffic.received,
+        shaper,
+        quota,
+        Direction::Download,
+    );
+    tokio::pin!(client_to_server, server_to_client);
+    let both = async {
+        tokio::select! {
+            res = &mut client_to_server => {
+                res?;
+                linger(&mut server_to_client, timeouts.linger).await
+            }
+            res = &mut server_to_client => {
+                res?;
+                linger(&mut client_to_server, timeouts.linger).await
+            }
+        }
+    };
+    tokio::select! {
+        res = both => res,
+        e = activity.expired(timeouts) => Err(e),
+    }
 }
 
-pub async fn relay_split<'a, LR, LW, RR, RW>(
-    mut lr: &'a mut LR,
-    mut lw: &'a mut LW,
-    mut rr: &'a mut RR,
-    mut rw: &'a mut RW,
-) -> io::Result<(u64, u64)>
-where
-    LR: AsyncRead + Unpin + ?Sized,
-    LW: AsyncWrite + Unpin + ?Sized,
-    RR: AsyncRead + Unpin + ?Sized,
-    RW: AsyncWrite + Unpin + ?Sized,
-{
-    let client_to_server = transfer(&mut lr, &mut rw);
-    let server_to_client = transfer(&mut rr, &mut lw);
-    return future::try_join(client_to_server, server_to_client).await;
+/// Lets the remaining direction finish after the other one has been closed.
+async fn linger<F: std::future::Future<Output = io::Result<()>> + Unpin>(
+    transfer: &mut F,
+    timeout: Option<Duration>,
+) -> io::Result<()> {
+    match timeout {
+        Some(timeout) => time::timeout(timeout, transfer).await.map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("half-closed relay lingered for {:?}", timeout),
+            )
+        })?,
+        None => transfer.await,
+    }
 }
 
-pub async fn transfer<'a, R, W>(reader: &'a mut R, writer: &'a mut W) -> io::Result<u64>
+pub async fn transfer<'a, R, W>(
+    reader: &'a mut R,
+    writer: &'a mut W,
+    last_active: &AtomicU64,
+    bytes: &AtomicU64,
+    shaper: &SessionShaper,
+    quota: Option<&QuotaSession>,
+    direction: Direction,
+) -> io::Result<()>
 where
     R: AsyncRead + Unpin + ?Sized,
     W: AsyncWrite + Unpin + ?Sized,
 {
-    let len = io::copy(reader, writer).await?;
-    writer.shutdown().await?;
-    Ok(len)
+    let start = Instant::now();
+    let mut buf = Vec::new();
+    loop {
+        // Limits may change during the session.
+        buf.resize(shaper.chunk_size(direction), 0);
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        shaper.acquire(direction, n).await;
+        writer.write_all(&buf[..n]).await?;
+        bytes.fetch_add(n as u64, Ordering::Relaxed);
+        if let Some(quota) = quota {
+            quota.record(n as u64);
+        }
+        last_active.fetch_max(start.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+    writer.shutdown().await
+}
+
+/// Time of the last transfer in each direction, in milliseconds since the
+/// relay started.
+struct Activity {
+    start: Instant,
+    client_to_server: AtomicU64,
+    server_to_client: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Activity {
+        Activity {
+            start: Instant::now(),
+            client_to_server: AtomicU64::new(0),
+            server_to_client: AtomicU64::new(0),
+        }
+    }
+
+    /// Completes once the relay or one of its directions has been idle for
+    /// longer than its timeout, never if no idle timeout is set.
+    async fn expired(&self, timeouts: &Timeouts) -> io::Error {
+        loop {
+            let client_to_server =
+                Duration::from_millis(self.client_to_server.load(Ordering::Relaxed));
+            let server_to_client =
+                Duration::from_millis(self.server_to_client.load(Ordering::Relaxed));
+            let elapsed = self.start.elapsed();
+            let deadlines = [
+                (
+                    "relay",
+                    timeouts.idle,
+                    client_to_server.max(server_to_client),
+                ),
+                ("client to upstream", timeouts.upload_idle, client_to_server),
+                (
+                    "upstream to client",
+                    timeouts.download_idle,
+                    server_to_client,
+                ),
+            ];
+            let mut next = None;
+            for (what, timeout, last_active) in deadlines {
+                let timeout = match timeout {
+                    Some(timeout) => timeout,
+                    None => continue,
+                };
+                if elapsed >= last_active + timeout {
+                    return io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!(
+                            "{} idle for {:?} (client to upstream idle for {:?}, upstream to client idle for {:?})",
+                            what,
+                            timeout,
+                            elapsed.saturating_sub(client_to_server),
+                            elapsed.saturating_sub(server_to_client),
+                        ),
+                    );
+                }
+                let deadline = self.start + last_active + timeout;
+                next = Some(next.map_or(deadline, |next: Instant| next.min(deadline)));
+            }
+            match next {
+                Some(deadline) => time::sleep_until(deadline).await,
+                None => future::pending().await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bandwidth::{BandwidthLimits, BandwidthShaper};
+    use tokio::io::{duplex, DuplexStream};
+
+    /// Relays between two in-memory pipes, returns the client and upstream
+    /// ends along with the relay.
+    fn pipes() -> (DuplexStream, DuplexStream, DuplexStream, DuplexStream) {
+        let (client, l) = duplex(1024);
+        let (r, upstream) = duplex(1024);
+        (client, l, r, upstream)
+    }
+
+    async fn run(
+        mut l: DuplexStream,
+        mut r: DuplexStream,
+        timeouts: Timeouts,
+    ) -> io::Result<(u64, u64)> {
+        let shaper = BandwidthShaper::new(BandwidthLimits::new(), None).session(None);
+        let traffic = Traffic::default();
+        relay(&mut l, &mut r, &timeouts, &shaper, None, &traffic).await?;
+        Ok((traffic.sent(), traffic.received()))
+    }
+
+    #[tokio::test]
+    async fn relays_both_directions() {
+        let (mut client, l, r, mut upstream) = pipes();
+        let relay = tokio::spawn(run(l, r, Timeouts::new()));
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        upstream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        upstream.write_all(b"hi").await.unwrap();
+        upstream.shutdown().await.unwrap();
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"hi");
+        client.shutdown().await.unwrap();
+        assert_eq!(relay.await.unwrap().unwrap(), (5, 2));
+    }
+
+    #[tokio::test]
+    async fn counts_traffic_on_errors() {
+        let (mut client, mut l, mut r, mut upstream) = pipes();
+        let shaper = BandwidthShaper::new(BandwidthLimits::new(), None).session(None);
+        let traffic = Traffic::default();
+        let timeouts = Timeouts {
+            idle: Some(Duration::from_millis(100)),
+            ..Timeouts::new()
+        };
+        let relay = relay(&mut l, &mut r, &timeouts, &shaper, None, &traffic);
+        let peers = async {
+            client.write_all(b"hello").await.unwrap();
+            upstream.read_exact(&mut [0; 5]).await.unwrap();
+            upstream.write_all(b"hi").await.unwrap();
+            client.read_exact(&mut [0; 2]).await.unwrap();
+        };
+        let (res, _) = tokio::join!(relay, peers);
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert_eq!((traffic.sent(), traffic.received()), (5, 2));
+    }
+
+    /// Keeps sending to `stream` every 20ms.
+    fn keep_sending(mut stream: DuplexStream) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            while stream.write_all(b".").await.is_ok() {
+                time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn times_out_idle_directions() {
+        let timeout = Duration::from_millis(100);
+        let (client, l, r, upstream) = pipes();
+        let sender = keep_sending(client);
+        let start = Instant::now();
+        let err = run(
+            l,
+            r,
+            Timeouts {
+                download_idle: Some(timeout),
+                ..Timeouts::new()
+            },
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(err.to_string().starts_with("upstream to client idle"));
+        assert!(start.elapsed() >= timeout);
+        sender.abort();
+        drop(upstream);
+
+        // The relay as a whole isn't idle as long as one direction is active.
+        let (client, l, r, _upstream) = pipes();
+        let sender = keep_sending(client);
+        let res = time::timeout(
+            timeout * 3,
+            run(
+                l,
+                r,
+                Timeouts {
+                    idle: Some(timeout),
+                    upload_idle: Some(timeout),
+                    ..Timeouts::new()
+                },
+            ),
+        )
+        .await;
+        assert!(res.is_err());
+        sender.abort();
+
+        let (_client, l, r, _upstream) = pipes();
+        let err = run(
+            l,
+            r,
+            Timeouts {
+                idle: Some(timeout),
+                ..Timeouts::new()
+            },
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().starts_with("relay idle"));
+    }
+
+    #[tokio::test]
+    async fn lingers_after_a_half_close() {
+        let timeout = Duration::from_millis(100);
+        let (mut client, l, r, _upstream) = pipes();
+        client.shutdown().await.unwrap();
+        let start = Instant::now();
+        let err = run(
+            l,
+            r,
+            Timeouts {
+                linger: Some(timeout),
+                ..Timeouts::new()
+            },
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() >= timeout);
+    }
 }
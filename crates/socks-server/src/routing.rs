@@ -0,0 +1,288 @@
+use crate::auth::Principal;
+use socks_rs_client::ProxyScheme;
+use socks_rs_common::connector::ConnectOptions;
+use socks_rs_common::{Command, TargetAddr};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Picks how to connect upstream for each request, once it is allowed, e.g.
+/// from the routing parameters of the principal.
+pub trait Router {
+    /// Route to take instead of connecting directly with `options`, the ones
+    /// the server or the matching rule set, `None` to keep them.
+    fn route(&self, request: &RouteRequest<'_>, options: &ConnectOptions) -> Option<Route>;
+}
+
+/// How to connect upstream.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Route {
+    /// Options to connect to the destination, or to `upstream` if set, with.
+    pub options: ConnectOptions,
+    /// SOCKS proxy to chain through, which then resolves and connects to the
+    /// destination. Only CONNECT requests can be chained.
+    pub upstream: Option<ProxyScheme>,
+}
+
+impl Route {
+    /// Connects directly with `options`.
+    pub fn new(options: ConnectOptions) -> Route {
+        Route {
+            options,
+            upstream: None,
+        }
+    }
+}
+
+/// A request to route.
+#[derive(Clone, Debug)]
+pub struct RouteRequest<'a> {
+    pub client: SocketAddr,
+    pub principal: &'a Principal,
+    pub command: Command,
+    pub destination: &'a TargetAddr,
+}
+
+/// Where an `EgressPool` sends connections out through.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Egress {
+    /// A local address to bind.
+    Address(IpAddr),
+    /// An upstream SOCKS proxy to chain through.
+    Proxy(ProxyScheme),
+}
+
+impl From<IpAddr> for Egress {
+    fn from(addr: IpAddr) -> Self {
+        Egress::Address(addr)
+    }
+}
+
+impl From<ProxyScheme> for Egress {
+    fn from(proxy: ProxyScheme) -> Self {
+        Egress::Proxy(proxy)
+    }
+}
+
+/// Spreads outbound connections over local addresses or upstream proxies,
+/// picked by routing parameter, e.g. proxies per `country`.
+///
+/// Connections of the same user with the same sticky parameter, e.g.
+/// `session`, go out through the same address or proxy as long as the pool
+/// doesn't change. The others take turns.
+#[derive(Debug, Default)]
+pub struct EgressPool {
+    addresses: Vec<Egress>,
+    pools: Vec<(String, String, Vec<Egress>)>,
+    sticky_parameter: Option<String>,
+    next: AtomicUsize,
+}
+
+impl EgressPool {
+    /// A pool without any address, leaving connections as they are.
+    pub fn new() -> EgressPool {
+        EgressPool::default()
+    }
+
+    /// Addresses or proxies of principals matching no parameter pool.
+    pub fn addresses<I, E>(mut self, addresses: I) -> Self
+    where
+        I: IntoIterator<Item = E>,
+        E: Into<Egress>,
+    {
+        self.addresses = addresses.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Addresses or proxies of principals with the parameter `key` set to
+    /// `value`. The first matching pool is used.
+    pub fn pool<K, V, I, E>(mut self, key: K, value: V, addresses: I) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+        I: IntoIterator<Item = E>,
+        E: Into<Egress>,
+    {
+        let addresses = addresses.into_iter().map(Into::into).collect();
+        self.pools.push((key.into(), value.into(), addresses));
+        self
+    }
+
+    pub fn sticky_parameter<T: Into<String>>(mut self, key: T) -> Self {
+        self.sticky_parameter = Some(key.into());
+        self
+    }
+
+    fn select(&self, principal: &Principal) -> Option<&Egress> {
+        let addresses = self
+            .pools
+            .iter()
+            .find(|(key, value, _)| principal.parameters.get(key) == Some(value))
+            .map_or(&self.addresses, |(_, _, addresses)| addresses);
+        if addresses.is_empty() {
+            return None;
+        }
+        let sticky = self
+            .sticky_parameter
+            .as_ref()
+            .and_then(|key| principal.parameters.get(key));
+        let index = match sticky {
+            Some(session) => {
+                let mut hasher = DefaultHasher::new();
+                (principal.username(), session).hash(&mut hasher);
+                hasher.finish() as usize
+            }
+            None => self.next.fetch_add(1, Ordering::Relaxed),
+        };
+        Some(&addresses[index % addresses.len()])
+    }
+}
+
+impl Router for EgressPool {
+    fn route(&self, request: &RouteRequest<'_>, options: &ConnectOptions) -> Option<Route> {
+        let route = match self.select(request.principal)? {
+            Egress::Address(addr) => Route::new(ConnectOptions {
+                bind_addr: Some(*addr),
+                ..options.clone()
+            }),
+            Egress::Proxy(proxy) => Route {
+                options: options.clone(),
+                upstream: Some(proxy.clone()),
+            },
+        };
+        Some(route)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use socks_rs_common::{AuthMethod, Version};
+
+    fn principal(parameters: &[(&str, &str)]) -> Principal {
+        let mut principal = Principal::new("alice", AuthMethod::UsernamePassword);
+        for (key, value) in parameters {
+            principal
+                .parameters
+                .insert((*key).to_owned(), (*value).to_owned());
+        }
+        principal
+    }
+
+    fn ips(ips: &[&str]) -> Vec<Egress> {
+        ips.iter()
+            .map(|ip| Egress::Address(ip.parse().unwrap()))
+            .collect()
+    }
+
+    fn pool() -> EgressPool {
+        EgressPool::new()
+            .addresses(ips(&["192.0.2.1", "192.0.2.2"]))
+            .pool(
+                "country",
+                "de",
+                ips(&["198.51.100.1", "198.51.100.2", "198.51.100.3"]),
+            )
+            .pool("country", "fr", ips(&["203.0.113.1"]))
+    }
+
+    #[test]
+    fn picks_addresses_by_parameter() {
+        let pool = pool();
+        let de = ips(&["198.51.100.1", "198.51.100.2", "198.51.100.3"]);
+        for _ in 0..3 {
+            assert!(de.contains(pool.select(&principal(&[("country", "de")])).unwrap()));
+        }
+        assert_eq!(
+            pool.select(&principal(&[("country", "fr"), ("session", "1")])),
+            ips(&["203.0.113.1"]).first()
+        );
+        let default = ips(&["192.0.2.1", "192.0.2.2"]);
+        assert!(default.contains(pool.select(&principal(&[("country", "us")])).unwrap()));
+        assert!(default.contains(pool.select(&principal(&[])).unwrap()));
+
+        let pool = EgressPool::new().pool("country", "de", ips(&["198.51.100.1"]));
+        assert_eq!(pool.select(&principal(&[])), None);
+    }
+
+    #[test]
+    fn takes_turns() {
+        let pool = EgressPool::new().addresses(ips(&["192.0.2.1", "192.0.2.2", "192.0.2.3"]));
+        let picked = (0..6)
+            .map(|_| pool.select(&principal(&[])).unwrap().clone())
+            .collect::<Vec<_>>();
+        let addresses = ips(&["192.0.2.1", "192.0.2.2", "192.0.2.3"]);
+        assert_eq!(picked, [addresses.clone(), addresses].concat());
+    }
+
+    #[test]
+    fn sticks_sessions_to_addresses() {
+        let pool = pool().sticky_parameter("session");
+        let alice = principal(&[("country", "de"), ("session", "abc123")]);
+        let addr = pool.select(&alice).unwrap();
+        for _ in 0..10 {
+            assert_eq!(pool.select(&alice), Some(addr));
+        }
+        // Other sessions spread over the pool.
+        let picked = (0..64)
+            .map(|i| {
+                let session = i.to_string();
+                pool.select(&principal(&[("country", "de"), ("session", &session)]))
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let de = ips(&["198.51.100.1", "198.51.100.2", "198.51.100.3"]);
+        assert!(de.iter().all(|egress| picked.contains(&egress)));
+    }
+
+    #[test]
+    fn keeps_the_other_options() {
+        let pool = EgressPool::new().addresses(ips(&["192.0.2.1"]));
+        let principal = principal(&[]);
+        let request = RouteRequest {
+            client: "203.0.113.9:1234".parse().unwrap(),
+            principal: &principal,
+            command: Command::Connect,
+            destination: &TargetAddr::Host("example.com".to_owned(), 443),
+        };
+        let options = ConnectOptions {
+            nodelay: Some(true),
+            ..ConnectOptions::new()
+        };
+        let routed = pool.route(&request, &options).unwrap();
+        assert_eq!(routed.options.bind_addr, "192.0.2.1".parse().ok());
+        assert_eq!(routed.options.nodelay, Some(true));
+        assert_eq!(routed.upstream, None);
+        assert!(EgressPool::new().route(&request, &options).is_none());
+    }
+
+    #[test]
+    fn picks_upstream_proxies() {
+        let proxy =
+            |host: &str| ProxyScheme::new(Version::V5, TargetAddr::Host(host.to_owned(), 1080));
+        let pool = EgressPool::new().addresses(ips(&["192.0.2.1"])).pool(
+            "country",
+            "de",
+            [proxy("de.example.com")],
+        );
+        let principal = principal(&[("country", "de")]);
+        let request = RouteRequest {
+            client: "203.0.113.9:1234".parse().unwrap(),
+            principal: &principal,
+            command: Command::Connect,
+            destination: &TargetAddr::Host("example.com".to_owned(), 443),
+        };
+        let options = ConnectOptions {
+            nodelay: Some(true),
+            ..ConnectOptions::new()
+        };
+        assert_eq!(
+            pool.route(&request, &options),
+            Some(Route {
+                options,
+                upstream: Some(proxy("de.example.com")),
+            })
+        );
+    }
+}
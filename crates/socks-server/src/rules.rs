@@ -0,0 +1,416 @@
+use crate::auth::Principal;
+use socks_rs_common::connector::ConnectOptions;
+use socks_rs_common::{Command, TargetAddr};
+use std::fmt;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+pub use ipnet::IpNet;
+pub use regex::Regex;
+
+/// What to do with a request.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Action {
+    Allow,
+    /// Replies `ConnectionNotAllowedByRuleset`.
+    Deny,
+    /// Allows the request and connects upstream with the given options instead
+    /// of the server's ones, e.g. to go out through another interface.
+    Route(ConnectOptions),
+}
+
+/// A destination condition of a rule.
+#[derive(Clone, Debug)]
+pub enum Destination {
+    /// An IP destination within the network, IPv4-mapped IPv6 addresses being
+    /// matched as the IPv4 address they embed. Domains are matched as requested
+    /// and not resolved, so they never match a CIDR.
+    Cidr(IpNet),
+    /// The exact domain, case-insensitive.
+    Domain(String),
+    /// The domain or any of its subdomains, e.g. `example.com` matches
+    /// `example.com` and `www.example.com`.
+    DomainSuffix(String),
+    /// A domain matching the regex, matched against the lowercased domain.
+    DomainRegex(Regex),
+}
+
+impl Destination {
+    pub(crate) fn matches(&self, addr: &TargetAddr) -> bool {
+        match (self, addr) {
+            (Destination::Cidr(net), TargetAddr::Addr(addr)) => {
+                net.contains(&addr.ip().to_canonical())
+            }
+            (Destination::Domain(domain), TargetAddr::Host(host, _)) => {
+                normalize(host).eq_ignore_ascii_case(normalize(domain))
+            }
+            (Destination::DomainSuffix(suffix), TargetAddr::Host(host, _)) => {
+                let host = normalize(host).to_ascii_lowercase();
+                let suffix = normalize(suffix)
+                    .trim_start_matches('.')
+                    .to_ascii_lowercase();
+                host == suffix
+                    || host
+                        .strip_suffix(&suffix)
+                        .is_some_and(|prefix| prefix.ends_with('.'))
+            }
+            (Destination::DomainRegex(regex), TargetAddr::Host(host, _)) => {
+                regex.is_match(&normalize(host).to_ascii_lowercase())
+            }
+            _ => false,
+        }
+    }
+}
+
+impl PartialEq for Destination {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Destination::Cidr(a), Destination::Cidr(b)) => a == b,
+            (Destination::Domain(a), Destination::Domain(b)) => a == b,
+            (Destination::DomainSuffix(a), Destination::DomainSuffix(b)) => a == b,
+            (Destination::DomainRegex(a), Destination::DomainRegex(b)) => a.as_str() == b.as_str(),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Destination {}
+
+impl FromStr for Destination {
+    type Err = String;
+
+    /// Parses an IP address or network, a domain, or a domain suffix written as
+    /// `*.example.com` or `.example.com`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("empty destination".to_owned());
+        }
+        if let Ok(ip) = s.parse::<IpAddr>() {
+            return Ok(Destination::Cidr(IpNet::from(ip)));
+        }
+        if s.contains('/') {
+            return s
+                .parse()
+                .map(Destination::Cidr)
+                .map_err(|e| format!("invalid destination {}: {}", s, e));
+        }
+        match s.strip_prefix("*.").or_else(|| s.strip_prefix('.')) {
+            Some(suffix) => Ok(Destination::DomainSuffix(suffix.to_owned())),
+            None => Ok(Destination::Domain(s.to_owned())),
+        }
+    }
+}
+
+/// Strips the trailing dot of a fully qualified domain.
+fn normalize(domain: &str) -> &str {
+    domain.strip_suffix('.').unwrap_or(domain)
+}
+
+/// A rule matches a request when all of its conditions match. Each condition
+/// matches when any of its values does, and an empty condition matches anything.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    name: String,
+    clients: Vec<IpNet>,
+    users: Vec<String>,
+    attributes: Vec<(String, String)>,
+    parameters: Vec<(String, String)>,
+    commands: Vec<Command>,
+    destinations: Vec<Destination>,
+    ports: Vec<RangeInclusive<u16>>,
+    action: Action,
+}
+
+impl Rule {
+    /// Creates a rule matching every request, `name` identifies it in the logs.
+    pub fn new<T: Into<String>>(name: T, action: Action) -> Rule {
+        Rule {
+            name: name.into(),
+            clients: Vec::new(),
+            users: Vec::new(),
+            attributes: Vec::new(),
+            parameters: Vec::new(),
+            commands: Vec::new(),
+            destinations: Vec::new(),
+            ports: Vec::new(),
+            action,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn action(&self) -> &Action {
+        &self.action
+    }
+
+    /// Matches clients connecting from `net`.
+    pub fn client(mut self, net: IpNet) -> Self {
+        self.clients.push(net);
+        self
+    }
+
+    /// Matches requests of the authenticated `user`.
+    pub fn user<T: Into<String>>(mut self, user: T) -> Self {
+        self.users.push(user.into());
+        self
+    }
+
+    /// Matches principals having the attribute `key` set to `value`, or to a
+    /// comma separated list containing it, e.g. groups.
+    pub fn attribute<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.attributes.push((key.into(), value.into()));
+        self
+    }
+
+    /// Matches principals passing the routing parameter `key` set to `value`.
+    pub fn parameter<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.parameters.push((key.into(), value.into()));
+        self
+    }
+
+    pub fn command(mut self, command: Command) -> Self {
+        self.commands.push(command);
+        self
+    }
+
+    pub fn destination(mut self, destination: Destination) -> Self {
+        self.destinations.push(destination);
+        self
+    }
+
+    /// Matches destination ports within `ports`.
+    pub fn ports(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.ports.push(ports);
+        self
+    }
+
+    fn matches(&self, request: &RuleRequest<'_>) -> bool {
+        fn any<T>(values: &[T], f: impl Fn(&T) -> bool) -> bool {
+            values.is_empty() || values.iter().any(f)
+        }
+        any(&self.clients, |net| {
+            net.contains(&request.client.to_canonical())
+        }) && any(&self.users, |user| {
+            Some(user.as_str()) == request.principal.username()
+        }) && any(&self.attributes, |(key, value)| {
+            request
+                .principal
+                .attributes
+                .get(key)
+                .is_some_and(|values| values == value || values.split(',').any(|v| v == value))
+        }) && any(&self.parameters, |(key, value)| {
+            request.principal.parameters.get(key) == Some(value)
+        }) && any(&self.commands, |command| *command == request.command)
+            && any(&self.destinations, |dst| dst.matches(request.addr))
+            && any(&self.ports, |ports| ports.contains(&request.addr.port()))
+    }
+}
+
+/// An ordered list of rules, the first matching rule decides what to do with a
+/// request. Requests matching no rule get the default action, which allows them
+/// unless set otherwise.
+#[derive(Clone, Debug)]
+pub struct RuleSet {
+    rules: Vec<Rule>,
+    default: Action,
+}
+
+impl RuleSet {
+    pub fn new() -> RuleSet {
+        RuleSet {
+            rules: Vec::new(),
+            default: Action::Allow,
+        }
+    }
+
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Action for requests matching no rule.
+    pub fn default_action(mut self, action: Action) -> Self {
+        self.default = action;
+        self
+    }
+
+    pub(crate) fn evaluate(&self, request: &RuleRequest<'_>) -> Decision<'_> {
+        match self.rules.iter().find(|rule| rule.matches(request)) {
+            Some(rule) => Decision {
+                rule: Some(&rule.name),
+                action: &rule.action,
+            },
+            None => Decision {
+                rule: None,
+                action: &self.default,
+            },
+        }
+    }
+}
+
+impl Default for RuleSet {
+    fn default() -> Self {
+        RuleSet::new()
+    }
+}
+
+/// The parts of a request rules are matched against.
+pub(crate) struct RuleRequest<'a> {
+    pub(crate) client: IpAddr,
+    pub(crate) principal: &'a Principal,
+    pub(crate) command: Command,
+    pub(crate) addr: &'a TargetAddr,
+}
+
+pub(crate) struct Decision<'a> {
+    /// Name of the matching rule, `None` for the default action.
+    pub(crate) rule: Option<&'a str>,
+    pub(crate) action: &'a Action,
+}
+
+impl fmt::Display for Decision<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self.action {
+            Action::Allow => "allowed",
+            Action::Deny => "denied",
+            Action::Route(_) => "routed",
+        };
+        match self.rule {
+            Some(rule) => write!(f, "{} by rule {:?}", action, rule),
+            None => write!(f, "{} by default", action),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use socks_rs_common::AuthMethod;
+
+    fn host(host: &str) -> TargetAddr {
+        TargetAddr::Host(host.to_owned(), 443)
+    }
+
+    fn addr(addr: &str) -> TargetAddr {
+        TargetAddr::Addr(addr.parse().unwrap())
+    }
+
+    fn evaluate<'a>(rules: &'a RuleSet, principal: &Principal, addr: &TargetAddr) -> Decision<'a> {
+        rules.evaluate(&RuleRequest {
+            client: "192.0.2.1".parse().unwrap(),
+            principal,
+            command: Command::Connect,
+            addr,
+        })
+    }
+
+    #[test]
+    fn parses_destinations() {
+        let net = |s: &str| Destination::Cidr(s.parse().unwrap());
+        assert_eq!("10.0.0.1".parse(), Ok(net("10.0.0.1/32")));
+        assert_eq!("10.0.0.0/8".parse(), Ok(net("10.0.0.0/8")));
+        assert_eq!("::1".parse(), Ok(net("::1/128")));
+        assert_eq!(
+            "*.example.com".parse(),
+            Ok(Destination::DomainSuffix("example.com".to_owned()))
+        );
+        assert_eq!(
+            ".example.com".parse(),
+            Ok(Destination::DomainSuffix("example.com".to_owned()))
+        );
+        assert_eq!(
+            "example.com".parse(),
+            Ok(Destination::Domain("example.com".to_owned()))
+        );
+        assert!("".parse::<Destination>().is_err());
+        assert!("10.0.0.0/33".parse::<Destination>().is_err());
+    }
+
+    #[test]
+    fn matches_domains() {
+        let domain: Destination = "Example.com.".parse().unwrap();
+        assert!(domain.matches(&host("example.COM")));
+        assert!(domain.matches(&host("example.com.")));
+        assert!(!domain.matches(&host("www.example.com")));
+
+        let suffix: Destination = "*.example.com".parse().unwrap();
+        assert!(suffix.matches(&host("example.com")));
+        assert!(suffix.matches(&host("WWW.Example.com.")));
+        assert!(!suffix.matches(&host("badexample.com")));
+        assert!(!suffix.matches(&addr("10.0.0.1:443")));
+
+        let regex = Destination::DomainRegex(Regex::new(r"^[a-z]+\.example\.com$").unwrap());
+        assert!(regex.matches(&host("WWW.Example.com.")));
+        assert!(!regex.matches(&host("www1.example.com")));
+    }
+
+    #[test]
+    fn matches_networks() {
+        let net: Destination = "10.0.0.0/8".parse().unwrap();
+        assert!(net.matches(&addr("10.1.2.3:443")));
+        assert!(net.matches(&addr("[::ffff:10.1.2.3]:443")));
+        assert!(!net.matches(&addr("192.0.2.1:443")));
+        assert!(!net.matches(&host("10.1.2.3")));
+    }
+
+    #[test]
+    fn applies_the_first_matching_rule() {
+        let rules = RuleSet::new()
+            .rule(
+                Rule::new("admins", Action::Allow)
+                    .attribute("groups", "admins")
+                    .destination("10.0.0.0/8".parse().unwrap()),
+            )
+            .rule(Rule::new("internal", Action::Deny).destination("10.0.0.0/8".parse().unwrap()))
+            .rule(
+                Rule::new("web", Action::Allow)
+                    .user("alice")
+                    .ports(80..=80)
+                    .ports(443..=443),
+            )
+            .default_action(Action::Deny);
+        let alice = Principal::new("alice", AuthMethod::UsernamePassword);
+        let admin = Principal::new("bob", AuthMethod::UsernamePassword)
+            .with_attribute("groups", "users,admins");
+
+        let decision = evaluate(&rules, &admin, &addr("10.0.0.1:22"));
+        assert_eq!(decision.rule, Some("admins"));
+        assert_eq!(decision.action, &Action::Allow);
+        let decision = evaluate(&rules, &alice, &addr("10.0.0.1:443"));
+        assert_eq!(decision.to_string(), "denied by rule \"internal\"");
+        let decision = evaluate(&rules, &alice, &host("example.com"));
+        assert_eq!(decision.to_string(), "allowed by rule \"web\"");
+        let decision = evaluate(
+            &rules,
+            &alice,
+            &TargetAddr::Host("example.com".to_owned(), 22),
+        );
+        assert_eq!(decision.to_string(), "denied by default");
+        let decision = evaluate(&rules, &Principal::anonymous(), &host("example.com"));
+        assert_eq!(decision.rule, None);
+    }
+
+    #[test]
+    fn matches_clients() {
+        let rules = RuleSet::new()
+            .rule(Rule::new("lan", Action::Allow).client("192.0.2.0/24".parse().unwrap()))
+            .default_action(Action::Deny);
+        let principal = Principal::anonymous();
+        let addr = host("example.com");
+        let request = |client: &str| RuleRequest {
+            client: client.parse().unwrap(),
+            principal: &principal,
+            command: Command::Connect,
+            addr: &addr,
+        };
+        assert_eq!(rules.evaluate(&request("192.0.2.7")).rule, Some("lan"));
+        assert_eq!(
+            rules.evaluate(&request("::ffff:192.0.2.7")).rule,
+            Some("lan")
+        );
+        assert_eq!(rules.evaluate(&request("198.51.100.1")).rule, None);
+    }
+}
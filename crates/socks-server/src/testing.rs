@@ -0,0 +1,87 @@
+//! Helpers shared by the tests of the server.
+
+use crate::acceptor::Acceptor;
+use crate::auth::AuthContext;
+use async_trait::async_trait;
+use futures::future;
+use socks_rs_common::connector::PlainWrappedTcpStream;
+use socks_rs_common::request::{AuthMethodsRequest, Request};
+use socks_rs_common::response::{Response, ResponseCode};
+use socks_rs_common::{Addr, AuthMethod, Command, Result, TargetAddr, Version};
+use std::net::SocketAddr;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// A server echoing back whatever its clients send.
+pub(crate) async fn echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    addr
+}
+
+/// The context of a client connecting from `peer` to 127.0.0.1:1080.
+pub(crate) fn auth_context(peer: &str) -> AuthContext {
+    AuthContext {
+        peer_addr: peer.parse().unwrap(),
+        local_addr: "127.0.0.1:1080".parse().unwrap(),
+        client_certificate: None,
+    }
+}
+
+/// Offers `methods` to the server at `proxy`, returns the connection and the
+/// method the server selected, 0xff if none.
+pub(crate) async fn negotiate(proxy: SocketAddr, methods: &[AuthMethod]) -> (TcpStream, u8) {
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    AuthMethodsRequest::new(Version::V5, methods.to_vec())
+        .write_to(&mut stream)
+        .await
+        .unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).await.unwrap();
+    (stream, reply[1])
+}
+
+/// Sends a CONNECT request for `target`, returns the reply code.
+pub(crate) async fn request(stream: &mut TcpStream, target: TargetAddr) -> Result<ResponseCode> {
+    Request::new(Version::V5, Command::Connect, Addr::new(target))
+        .write_to(stream)
+        .await?;
+    Ok(Response::read_from(stream).await?.code)
+}
+
+/// Connects to `target` through `proxy` without authentication.
+pub(crate) async fn connect(proxy: SocketAddr, target: SocketAddr) -> (TcpStream, ResponseCode) {
+    let (mut stream, method) = negotiate(proxy, &[AuthMethod::None]).await;
+    assert_eq!(method, 0x00);
+    let code = request(&mut stream, TargetAddr::Addr(target))
+        .await
+        .unwrap();
+    (stream, code)
+}
+
+/// Checks that `stream` relays to an echo server.
+pub(crate) async fn assert_echoes(stream: &mut TcpStream) {
+    stream.write_all(b"ping").await.unwrap();
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+}
+
+/// Never completes the accept, like a client stalling a TLS handshake.
+pub(crate) struct StalledAcceptor;
+
+#[async_trait]
+impl Acceptor<PlainWrappedTcpStream> for StalledAcceptor {
+    async fn accept(&self, _socket: TcpStream) -> io::Result<PlainWrappedTcpStream> {
+        future::pending().await
+    }
+}
@@ -0,0 +1,29 @@
+use std::time::Duration;
+
+/// Timeouts applied to each session, all of them are disabled by default.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Timeouts {
+    /// Deadline for a client to complete the handshake, from the moment the
+    /// connection is accepted until the outbound connection is established.
+    pub handshake: Option<Duration>,
+    /// Closes the session once both directions of the relay have been idle
+    /// that long.
+    pub idle: Option<Duration>,
+    /// Closes the session once the client hasn't sent anything for that long,
+    /// whatever upstream sends.
+    pub upload_idle: Option<Duration>,
+    /// Closes the session once upstream hasn't sent anything for that long,
+    /// whatever the client sends.
+    pub download_idle: Option<Duration>,
+    /// Maximum lifetime of a session, whatever its activity.
+    pub lifetime: Option<Duration>,
+    /// How long the remaining direction may keep running once the other one
+    /// has been closed.
+    pub linger: Option<Duration>,
+}
+
+impl Timeouts {
+    pub fn new() -> Timeouts {
+        Timeouts::default()
+    }
+}